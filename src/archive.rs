@@ -0,0 +1,243 @@
+/**
+ * archive.rs
+ *
+ * Static libraries of SArch32 objects. Default extension: .sal
+ */
+
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Cursor, Error, Read, Write};
+use std::fs;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::objgen::{ObjectFormat, SymbolBinding, read_cstring, write_cstring};
+
+const ARCHIVE_MAGIC: u64 = 0x3A6863FC616C6173;
+const ARCHIVE_VERSION: u32 = 1;
+
+/**
+ * Object stored in an archive, kept as the bytes it was saved as
+ */
+#[derive(Debug, Clone)]
+pub struct ArchiveMember {
+    pub name: String,
+    pub data: Vec<u8>
+}
+
+impl ArchiveMember {
+    /**
+     * Parses the member, naming it 'archive(member)' for diagnostics
+     */
+    pub fn object(&self, archive_name: &str) -> Result<ObjectFormat, String> {
+        let mut object = match ObjectFormat::from_bytes(self.data.clone()) {
+            Ok(o) => o,
+            Err(e) => {
+                return Err(format!("Failed to read member '{}' of '{}': {}", self.name, archive_name, e))
+            }
+        };
+        object.file = Some(format!("{}({})", archive_name, self.name));
+        Ok(object)
+    }
+}
+
+/**
+ * Member names are bare file names, so extracting a member can't write
+ * outside the current directory
+ */
+fn check_member_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name.contains("..") || name.contains(['/', '\\', ':']) {
+        return Err(format!("Invalid member name '{}': members must be plain file names", name))
+    }
+    Ok(())
+}
+
+/**
+ * 0 - 8: magic
+ * 8 - 12: version
+ * 12 - 20: symbol count
+ * symbols: name, index of the member defining it (u32)
+ * 8 bytes: member count
+ * members: name, length (u64), object
+ */
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub members: Vec<ArchiveMember>,
+    // Global and weak symbol -> index of the first member defining it
+    index: HashMap<String, usize>
+}
+
+impl Archive {
+    pub fn new() -> Self {
+        Self {
+            members: Vec::new(),
+            index: HashMap::new()
+        }
+    }
+
+    /**
+     * Adds an object, replacing a member with the same name
+     */
+    pub fn add_object(&mut self, name: &str, object: &ObjectFormat) -> Result<(), String> {
+        check_member_name(name)?;
+
+        let mut data = Cursor::new(Vec::<u8>::new());
+        object.write_object(&mut data)?;
+
+        let member = ArchiveMember { name: name.to_string(), data: data.into_inner() };
+
+        match self.members.iter().position(|m| m.name == name) {
+            Some(pos) => self.members[pos] = member,
+            None => self.members.push(member)
+        }
+        self.build_index()
+    }
+
+    /**
+     * Member defining a symbol, if any
+     */
+    pub fn member_defining(&self, symbol: &str) -> Option<usize> {
+        self.index.get(symbol).copied()
+    }
+
+    /**
+     * Indexed symbols with the member defining them, sorted by name
+     */
+    pub fn symbols(&self) -> Vec<(&str, &ArchiveMember)> {
+        let mut symbols: Vec<(&str, &ArchiveMember)> = self.index.iter()
+            .map(|(name, idx)| (name.as_str(), &self.members[*idx]))
+            .collect();
+        symbols.sort_by(|a, b| a.0.cmp(b.0));
+        symbols
+    }
+
+    fn build_index(&mut self) -> Result<(), String> {
+        self.index.clear();
+
+        for (idx, member) in self.members.iter().enumerate() {
+            let object = member.object("<archive>")?;
+            for symbol in object.symbol_table() {
+                if symbol.binding != SymbolBinding::Local {
+                    self.index.entry(symbol.name).or_insert(idx);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Tells archives from objects by their first bytes
+     */
+    pub fn is_archive(path: &str) -> bool {
+        let mut magic = [0u8; 8];
+        match fs::File::open(path).and_then(|mut f| f.read_exact(&mut magic)) {
+            Ok(()) => u64::from_le_bytes(magic) == ARCHIVE_MAGIC,
+            Err(_) => false
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let bytes = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) => {
+                return Err(format!("Error occured while reading file:\n{}", e))
+            }
+        };
+
+        match Self::from_bytes(&mut bytes.as_slice()) {
+            Ok(a) => Ok(a),
+            Err(e) => Err(format!("Error occured while parsing archive '{}': {}", path, e))
+        }
+    }
+
+    fn from_bytes(binary: &mut dyn Read) -> Result<Self, Error> {
+        if binary.read_u64::<LittleEndian>()? != ARCHIVE_MAGIC {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Invalid magic number! Not an archive!")))
+        }
+        let version = binary.read_u32::<LittleEndian>()?;
+        if version > ARCHIVE_VERSION {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Archive version {} is newer than supported ({})", version, ARCHIVE_VERSION)))
+        }
+
+        let mut me = Self::new();
+
+        let symbol_count = binary.read_u64::<LittleEndian>()?;
+        let mut symbols = Vec::<(String, usize)>::new();
+        for _ in 0..symbol_count {
+            let name = read_cstring(binary)?;
+            let member = binary.read_u32::<LittleEndian>()? as usize;
+            symbols.push((name, member));
+        }
+
+        let member_count = binary.read_u64::<LittleEndian>()?;
+        for _ in 0..member_count {
+            let name = read_cstring(binary)?;
+            if let Err(e) = check_member_name(&name) {
+                return Err(Error::new(io::ErrorKind::InvalidData, e))
+            }
+            let length = binary.read_u64::<LittleEndian>()?;
+            let mut data = Vec::<u8>::new();
+            binary.take(length).read_to_end(&mut data)?;
+            if data.len() as u64 != length {
+                return Err(Error::new(io::ErrorKind::UnexpectedEof,
+                    format!("Member '{}' is cut short", name)))
+            }
+            me.members.push(ArchiveMember { name, data });
+        }
+
+        for (name, member) in symbols {
+            if member >= me.members.len() {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                    format!("Symbol '{}' points to member {}, but there are only {}",
+                        name, member, me.members.len())))
+            }
+            me.index.insert(name, member);
+        }
+
+        Ok(me)
+    }
+
+    fn write_bytes(&self, binary: &mut dyn Write) -> Result<(), Error> {
+        binary.write_u64::<LittleEndian>(ARCHIVE_MAGIC)?;
+        binary.write_u32::<LittleEndian>(ARCHIVE_VERSION)?;
+
+        let mut symbols: Vec<(&String, &usize)> = self.index.iter().collect();
+        symbols.sort();
+        binary.write_u64::<LittleEndian>(symbols.len() as u64)?;
+        for (name, member) in symbols {
+            write_cstring(binary, name)?;
+            binary.write_u32::<LittleEndian>(*member as u32)?;
+        }
+
+        binary.write_u64::<LittleEndian>(self.members.len() as u64)?;
+        for member in self.members.iter() {
+            write_cstring(binary, &member.name)?;
+            binary.write_u64::<LittleEndian>(member.data.len() as u64)?;
+            binary.write_all(&member.data)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let file = match fs::File::create(path) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(format!("Failed to open file to write: {e}"))
+            }
+        };
+        let mut writer = BufWriter::new(file);
+
+        match self.write_bytes(&mut writer).and_then(|_| writer.flush()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Failed to write archive to file: {}", e))
+        }
+    }
+}
+
+impl Default for Archive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,657 @@
+/**
+ * elf.rs
+ *
+ * ELF32 containers for linked SArch32 images, so readelf and third party
+ * loaders can read them. SArch32 has no official machine id, EM_SARCH32 is
+ * the one this assembler uses.
+ */
+
+use std::{fs, io::Read, collections::{HashMap, HashSet}};
+
+use crate::objgen::{ObjectFormat, SectionData, InstructionData, Reference, BinaryUnit, BinaryConstant,
+    BinaryReference, BinaryAlignment, Constant, CommonSymbol, ObjectLabelSymbol, RawOperand,
+    ConstantSize, Endianness, SectionFlags, SymbolBinding, MAX_RAW_OPERANDS};
+
+pub const EM_SARCH32: u16 = 0x5341;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+
+const ELF_HEADER_SIZE: u64 = 52;
+const PROGRAM_HEADER_SIZE: u64 = 32;
+const SECTION_HEADER_SIZE: u64 = 40;
+const SYMBOL_SIZE: u64 = 16;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+
+const SHN_ABS: u16 = 0xFFF1;
+const SHN_COMMON: u16 = 0xFFF2;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+/**
+ * Relocation types of SArch32 objects. Absolute ones store S + A,
+ * R_SARCH32_PC32 stores S + A - P like the 'rel' operands of '.insn'.
+ */
+pub const R_SARCH32_NONE: u32 = 0;
+pub const R_SARCH32_8: u32 = 1;
+pub const R_SARCH32_16: u32 = 2;
+pub const R_SARCH32_32: u32 = 3;
+pub const R_SARCH32_PC32: u32 = 4;
+
+/**
+ * Output section of an image. Noload sections have a size but no data.
+ */
+#[derive(Debug, Clone)]
+pub struct ElfSection {
+    pub name: String,
+    pub address: u64,
+    // Where the section is stored, which differs from its address for sections copied at startup
+    pub load_address: u64,
+    pub size: u64,
+    pub data: Vec<u8>,
+    pub flags: SectionFlags
+}
+
+#[derive(Debug, Clone)]
+pub struct ElfSymbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    // Index into the image sections, None for absolute symbols
+    pub section: Option<usize>,
+    pub binding: SymbolBinding
+}
+
+/**
+ * ELF header, a loadable segment for every section, the sections,
+ * then the symbol table, its strings and the section headers
+ */
+#[derive(Debug, Clone)]
+pub struct ElfImage {
+    pub endianness: Endianness,
+    pub entry: u64,
+    pub sections: Vec<ElfSection>,
+    pub symbols: Vec<ElfSymbol>
+}
+
+/**
+ * Strings of a string table, the first one is always empty
+ */
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, string: &str) -> u64 {
+        let offset = self.0.len() as u64;
+        self.0.extend_from_slice(string.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+fn address(what: &str, value: u64) -> Result<u64, String> {
+    match value > u32::MAX as u64 {
+        true => Err(format!("ELF: {} {:#x} does not fit in 32 bits", what, value)),
+        false => Ok(value)
+    }
+}
+
+impl ElfImage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let e = self.endianness;
+        let word = |binary: &mut Vec<u8>, value: u64| e.write(binary, ConstantSize::DoubleWord, value);
+        let half = |binary: &mut Vec<u8>, value: u64| e.write(binary, ConstantSize::Word, value);
+
+        // Section header indices: null, image sections, symtab, strtab, shstrtab
+        let symtab_index = self.sections.len() as u64 + 1;
+        let section_count = self.sections.len() as u64 + 4;
+
+        let mut shstrtab = StringTable::new();
+        let mut strtab = StringTable::new();
+
+        // Locals have to come before every other symbol
+        let mut symbols: Vec<&ElfSymbol> = self.symbols.iter().collect();
+        symbols.sort_by_key(|s| s.binding != SymbolBinding::Local);
+        let first_global = 1 + symbols.iter().filter(|s| s.binding == SymbolBinding::Local).count() as u64;
+
+        let mut symtab = vec![0u8; SYMBOL_SIZE as usize];
+        for symbol in symbols {
+            let binding = match symbol.binding {
+                SymbolBinding::Local => STB_LOCAL,
+                SymbolBinding::Weak => STB_WEAK,
+                SymbolBinding::Global | SymbolBinding::Default => STB_GLOBAL
+            };
+            let (symbol_type, index) = match symbol.section {
+                Some(idx) if self.sections[idx].flags.contains(SectionFlags::EXEC) => (STT_FUNC, idx as u64 + 1),
+                Some(idx) => (STT_OBJECT, idx as u64 + 1),
+                None => (STT_NOTYPE, SHN_ABS as u64)
+            };
+            word(&mut symtab, strtab.add(&symbol.name));
+            word(&mut symtab, address(&format!("value of '{}'", symbol.name), symbol.value)?);
+            word(&mut symtab, symbol.size.min(u32::MAX as u64));
+            symtab.push(binding << 4 | symbol_type);
+            symtab.push(0);
+            half(&mut symtab, index);
+        }
+
+        let loadable: Vec<&ElfSection> = self.sections.iter().filter(|s| s.size > 0).collect();
+
+        let mut header = Vec::<u8>::new();
+        header.extend_from_slice(&ELF_MAGIC);
+        header.push(ELFCLASS32);
+        header.push(match e { Endianness::Little => 1, Endianness::Big => 2 });
+        header.push(EV_CURRENT);
+        header.resize(16, 0);
+        half(&mut header, ET_EXEC as u64);
+        half(&mut header, EM_SARCH32 as u64);
+        word(&mut header, EV_CURRENT as u64);
+        word(&mut header, address("entry point", self.entry)?);
+        // Program headers come right after, section headers are filled in below
+        word(&mut header, ELF_HEADER_SIZE);
+        let shoff_position = header.len();
+        word(&mut header, 0);
+        word(&mut header, 0);
+        half(&mut header, ELF_HEADER_SIZE);
+        half(&mut header, PROGRAM_HEADER_SIZE);
+        half(&mut header, loadable.len() as u64);
+        half(&mut header, SECTION_HEADER_SIZE);
+        half(&mut header, section_count);
+        half(&mut header, section_count - 1);
+
+        let mut contents = Vec::<u8>::new();
+        let contents_start = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * loadable.len() as u64;
+        // File offset of every image section
+        let mut offsets = Vec::<u64>::new();
+
+        let mut program_headers = Vec::<u8>::new();
+        for section in self.sections.iter() {
+            let offset = contents_start + contents.len() as u64;
+            offsets.push(offset);
+            contents.extend_from_slice(&section.data);
+
+            if section.size == 0 {
+                continue
+            }
+            let flags = section.flags;
+            let mut segment_flags = 0;
+            if flags.contains(SectionFlags::READ) { segment_flags |= PF_R }
+            if flags.contains(SectionFlags::WRITE) { segment_flags |= PF_W }
+            if flags.contains(SectionFlags::EXEC) { segment_flags |= PF_X }
+
+            let name = format!("address of section '{}'", section.name);
+            word(&mut program_headers, PT_LOAD as u64);
+            word(&mut program_headers, offset);
+            word(&mut program_headers, address(&name, section.address)?);
+            word(&mut program_headers, address(&format!("load address of section '{}'", section.name),
+                section.load_address)?);
+            word(&mut program_headers, section.data.len() as u64);
+            word(&mut program_headers, address(&format!("end of section '{}'", section.name),
+                section.address + section.size)? - section.address);
+            word(&mut program_headers, segment_flags as u64);
+            word(&mut program_headers, 1);
+        }
+
+        // Symbols are read as words, so the table is aligned to one
+        while !contents.len().is_multiple_of(4) {
+            contents.push(0);
+        }
+        let symtab_offset = contents_start + contents.len() as u64;
+        contents.extend_from_slice(&symtab);
+        let strtab_offset = contents_start + contents.len() as u64;
+        contents.extend_from_slice(&strtab.0);
+
+        // Section names go last so every one is in
+        let mut section_headers = vec![0u8; SECTION_HEADER_SIZE as usize];
+        let mut section_header = |name: u64, section_type: u32, flags: u32, address: u64,
+            offset: u64, size: u64, link: u64, info: u64, entry_size: u64|
+        {
+            for value in [name, section_type as u64, flags as u64, address, offset, size, link, info, 1, entry_size] {
+                word(&mut section_headers, value);
+            }
+        };
+
+        for (section, offset) in self.sections.iter().zip(offsets) {
+            let mut flags = SHF_ALLOC;
+            if section.flags.contains(SectionFlags::WRITE) { flags |= SHF_WRITE }
+            if section.flags.contains(SectionFlags::EXEC) { flags |= SHF_EXECINSTR }
+            let section_type = match section.flags.contains(SectionFlags::NOLOAD) {
+                true => SHT_NOBITS,
+                false => SHT_PROGBITS
+            };
+            section_header(shstrtab.add(&section.name), section_type, flags, section.address,
+                offset, section.size, 0, 0, 0);
+        }
+        section_header(shstrtab.add(".symtab"), SHT_SYMTAB, 0, 0, symtab_offset,
+            symtab.len() as u64, symtab_index + 1, first_global, SYMBOL_SIZE);
+        section_header(shstrtab.add(".strtab"), SHT_STRTAB, 0, 0, strtab_offset,
+            strtab.0.len() as u64, 0, 0, 0);
+        let shstrtab_name = shstrtab.add(".shstrtab");
+        let shstrtab_offset = contents_start + contents.len() as u64;
+        section_header(shstrtab_name, SHT_STRTAB, 0, 0, shstrtab_offset,
+            shstrtab.0.len() as u64, 0, 0, 0);
+        contents.extend_from_slice(&shstrtab.0);
+
+        // Section headers are aligned to a word
+        while !contents.len().is_multiple_of(4) {
+            contents.push(0);
+        }
+        let shoff = address("file size", contents_start + contents.len() as u64)?;
+
+        let mut shoff_bytes = Vec::<u8>::new();
+        word(&mut shoff_bytes, shoff);
+        header[shoff_position..shoff_position + 4].copy_from_slice(&shoff_bytes);
+
+        let mut binary = header;
+        binary.append(&mut program_headers);
+        binary.append(&mut contents);
+        binary.append(&mut section_headers);
+
+        Ok(binary)
+    }
+}
+
+/**
+ * Tells ELF files from SArch32 objects by their first bytes
+ */
+pub fn is_elf(path: &str) -> bool {
+    let mut magic = [0u8; 4];
+    match fs::File::open(path).and_then(|mut f| f.read_exact(&mut magic)) {
+        Ok(()) => magic == ELF_MAGIC,
+        Err(_) => false
+    }
+}
+
+struct SectionHeader {
+    name: String,
+    section_type: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: usize,
+    info: usize,
+    alignment: u64
+}
+
+struct Symbol {
+    name: String,
+    value: u64,
+    size: u64,
+    binding: u8,
+    symbol_type: u8,
+    section: u16
+}
+
+struct Relocation {
+    offset: u64,
+    relocation_type: u32,
+    symbol: usize,
+    // REL relocations keep the addend in the relocated field
+    addend: Option<i64>
+}
+
+struct ElfReader<'a> {
+    bytes: &'a [u8],
+    endianness: Endianness
+}
+
+impl<'a> ElfReader<'a> {
+    fn slice(&self, offset: u64, size: u64) -> Result<&'a [u8], String> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.bytes.len() as u64 => Ok(&self.bytes[offset as usize..end as usize]),
+            _ => Err(format!("{:#x} bytes at {:#x} are past the end of the file", size, offset))
+        }
+    }
+
+    fn read(&self, offset: u64, size: ConstantSize) -> Result<u64, String> {
+        let bytes = self.slice(offset, size.get_size() as u64)?;
+        Ok(self.endianness.read(bytes, size))
+    }
+
+    fn string(&self, table: &SectionHeader, offset: u64) -> Result<String, String> {
+        let strings = self.slice(table.offset, table.size)?;
+        let start = offset as usize;
+        match strings.get(start..).and_then(|s| s.iter().position(|b| *b == 0)) {
+            Some(length) => Ok(String::from_utf8_lossy(&strings[start..start + length]).to_string()),
+            None => Err(format!("String at {:#x} is outside of its table", offset))
+        }
+    }
+}
+
+/**
+ * Label marking the start of an ELF section, for relocations against the section
+ */
+fn section_label(section: &SectionHeader) -> String {
+    section.name.clone()
+}
+
+/**
+ * Reads an ELF32 relocatable object for SArch32. Allocated sections keep their
+ * names without the leading '.', so ".text" and ".rodata.str" link like "text"
+ * and "rodata.str". Code becomes raw code units and relocated fields turn into
+ * references, so the sections merge with assembled ones.
+ */
+pub fn read_relocatable(bytes: &[u8]) -> Result<ObjectFormat, String> {
+    match read_object(bytes) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(format!("Error occured while reading ELF object: {}", e))
+    }
+}
+
+fn read_object(bytes: &[u8]) -> Result<ObjectFormat, String> {
+    if bytes.len() < ELF_HEADER_SIZE as usize || bytes[0..4] != ELF_MAGIC {
+        return Err(format!("Not an ELF file"))
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(format!("Only 32-bit ELF files are supported"))
+    }
+    let endianness = match bytes[5] {
+        1 => Endianness::Little,
+        2 => Endianness::Big,
+        n => return Err(format!("Unknown data encoding {}", n))
+    };
+    let elf = ElfReader { bytes, endianness };
+
+    let file_type = elf.read(16, ConstantSize::Word)? as u16;
+    if file_type != ET_REL {
+        return Err(format!("Expected a relocatable object, found ELF type {}", file_type))
+    }
+    let machine = elf.read(18, ConstantSize::Word)? as u16;
+    if machine != EM_SARCH32 {
+        return Err(format!("Object is for machine {:#x}, not SArch32 ({:#x})", machine, EM_SARCH32))
+    }
+
+    let section_offset = elf.read(32, ConstantSize::DoubleWord)?;
+    let section_header_size = elf.read(46, ConstantSize::Word)?;
+    let section_count = elf.read(48, ConstantSize::Word)?;
+    let names_index = elf.read(50, ConstantSize::Word)? as usize;
+    if section_header_size < SECTION_HEADER_SIZE {
+        return Err(format!("Section headers are {} bytes, expected {}", section_header_size, SECTION_HEADER_SIZE))
+    }
+
+    let mut headers = Vec::<SectionHeader>::new();
+    let mut name_offsets = Vec::<u64>::new();
+    for idx in 0..section_count {
+        let at = section_offset + idx * section_header_size;
+        let field = |n: u64| elf.read(at + n * 4, ConstantSize::DoubleWord);
+        name_offsets.push(field(0)?);
+        headers.push(SectionHeader {
+            name: String::new(),
+            section_type: field(1)? as u32,
+            flags: field(2)?,
+            offset: field(4)?,
+            size: field(5)?,
+            link: field(6)? as usize,
+            info: field(7)? as usize,
+            alignment: field(8)?
+        });
+    }
+    if names_index >= headers.len() {
+        return Err(format!("Section name table {} doesn't exist", names_index))
+    }
+    for idx in 0..headers.len() {
+        let name = elf.string(&headers[names_index], name_offsets[idx])?;
+        headers[idx].name = name;
+    }
+
+    let mut symbols = Vec::<Symbol>::new();
+    if let Some(symtab) = headers.iter().find(|h| h.section_type == SHT_SYMTAB) {
+        let strtab = match headers.get(symtab.link) {
+            Some(s) => s,
+            None => return Err(format!("Symbol names are in section {}, which doesn't exist", symtab.link))
+        };
+        for idx in 0..symtab.size / SYMBOL_SIZE {
+            let at = symtab.offset + idx * SYMBOL_SIZE;
+            let info = elf.read(at + 12, ConstantSize::Byte)? as u8;
+            symbols.push(Symbol {
+                name: elf.string(strtab, elf.read(at, ConstantSize::DoubleWord)?)?,
+                value: elf.read(at + 4, ConstantSize::DoubleWord)?,
+                size: elf.read(at + 8, ConstantSize::DoubleWord)?,
+                binding: info >> 4,
+                symbol_type: info & 0xF,
+                section: elf.read(at + 14, ConstantSize::Word)? as u16
+            });
+        }
+    }
+
+    let mut relocations = HashMap::<usize, Vec<Relocation>>::new();
+    for header in headers.iter().filter(|h| h.section_type == SHT_REL || h.section_type == SHT_RELA) {
+        let entry_size = if header.section_type == SHT_RELA { 12 } else { 8 };
+        for idx in 0..header.size / entry_size {
+            let at = header.offset + idx * entry_size;
+            let info = elf.read(at + 4, ConstantSize::DoubleWord)?;
+            relocations.entry(header.info).or_default().push(Relocation {
+                offset: elf.read(at, ConstantSize::DoubleWord)?,
+                relocation_type: (info & 0xFF) as u32,
+                symbol: (info >> 8) as usize,
+                addend: match header.section_type == SHT_RELA {
+                    true => Some(elf.read(at + 8, ConstantSize::DoubleWord)? as i32 as i64),
+                    false => None
+                }
+            });
+        }
+    }
+
+    let mut object = ObjectFormat::new();
+    object.header.endianness = endianness;
+    // New objects start with an empty "text" section
+    object.sections.clear();
+
+    // Allocated sections are linked, the rest (debug info, notes) is dropped
+    let linked = |header: &SectionHeader| header.flags & SHF_ALLOC as u64 != 0
+        && (header.section_type == SHT_PROGBITS || header.section_type == SHT_NOBITS);
+
+    let reference_name = |symbol: usize| -> Result<String, String> {
+        let symbol = match symbols.get(symbol) {
+            Some(s) => s,
+            None => return Err(format!("Relocation refers to symbol {}, which doesn't exist", symbol))
+        };
+        if symbol.symbol_type == STT_SECTION {
+            return match headers.get(symbol.section as usize) {
+                Some(h) if linked(h) => Ok(section_label(h)),
+                _ => Err(format!("Relocation against section {}, which isn't linked", symbol.section))
+            }
+        }
+        if symbol.section == SHN_ABS {
+            return Err(format!("Relocation against absolute symbol '{}' is not supported", symbol.name))
+        }
+        Ok(symbol.name.clone())
+    };
+
+    let mut defined = HashSet::<String>::new();
+
+    for (idx, header) in headers.iter().enumerate() {
+        if !linked(header) {
+            continue
+        }
+        let name = header.name.strip_prefix('.').unwrap_or(&header.name).to_string();
+        if name.is_empty() || object.sections.contains_key(&name) {
+            return Err(format!("Section '{}' can't be linked: its name is empty or used twice", header.name))
+        }
+
+        let mut flags = SectionFlags::READ;
+        if header.flags & SHF_WRITE as u64 != 0 { flags |= SectionFlags::WRITE }
+        if header.flags & SHF_EXECINSTR as u64 != 0 { flags |= SectionFlags::EXEC }
+        if header.section_type == SHT_NOBITS { flags |= SectionFlags::NOLOAD }
+        let mut section = SectionData::with_flags(&name, SectionFlags(flags));
+
+        // Symbols defined in this section and the start label if relocations need it
+        let mut labels: Vec<(String, u64, SymbolBinding)> = symbols.iter()
+            .filter(|s| s.section as usize == idx && s.symbol_type != STT_SECTION && !s.name.is_empty())
+            .map(|s| {
+                let binding = match s.binding {
+                    STB_LOCAL => SymbolBinding::Local,
+                    STB_WEAK => SymbolBinding::Weak,
+                    _ => SymbolBinding::Global
+                };
+                (s.name.clone(), s.value, binding)
+            })
+            .collect();
+        labels.push((section_label(header), 0, SymbolBinding::Local));
+
+        let mut section_relocations = relocations.remove(&idx).unwrap_or_default();
+        section_relocations.retain(|r| r.relocation_type != R_SARCH32_NONE);
+        section_relocations.sort_by_key(|r| r.offset);
+
+        // Unit each byte offset starts, for labels
+        let mut unit_at = HashMap::<u64, u64>::new();
+
+        if section.is_nobits() {
+            if !section_relocations.is_empty() {
+                return Err(format!("Noload section '{}' has relocations", header.name))
+            }
+            section.bss_size = header.size;
+            for offset in labels.iter().map(|l| l.1) {
+                unit_at.insert(offset, offset);
+            }
+        } else {
+            let contents = elf.slice(header.offset, header.size)?;
+            let code = section.flags.contains(SectionFlags::EXEC);
+            let cuts: HashSet<u64> = labels.iter().map(|l| l.1).collect();
+
+            let mut units: u64 = 0;
+            if header.alignment > 1 {
+                match code {
+                    true => section.instructions.push(InstructionData::alignment(header.alignment as i64, 0)),
+                    false => section.binary_data.push(BinaryUnit {
+                        constant: None,
+                        reference: None,
+                        alignment: Some(BinaryAlignment { alignment: header.alignment, fill: 0 })
+                    })
+                }
+                units += 1;
+            }
+            section.binary_section = !code;
+
+            let mut position: u64 = 0;
+            // Index of the next relocation to apply
+            let mut next = 0;
+
+            while position < header.size {
+                unit_at.insert(position, units);
+                units += 1;
+
+                if let Some(relocation) = section_relocations.get(next).filter(|r| r.offset == position) {
+                    let (size, relative) = match relocation.relocation_type {
+                        R_SARCH32_8 => (ConstantSize::Byte, false),
+                        R_SARCH32_16 => (ConstantSize::Word, false),
+                        R_SARCH32_32 => (ConstantSize::DoubleWord, false),
+                        R_SARCH32_PC32 => (ConstantSize::DoubleWord, true),
+                        n => return Err(format!("Unknown relocation type {} at {:#x} in '{}'",
+                            n, relocation.offset, header.name))
+                    };
+                    let field = match contents.get(position as usize..(position as usize + size.get_size())) {
+                        Some(f) => f,
+                        None => return Err(format!("Relocation at {:#x} is past the end of '{}'",
+                            relocation.offset, header.name))
+                    };
+                    let addend = match relocation.addend {
+                        Some(a) => a,
+                        None if relative => elf.endianness.read(field, size) as i32 as i64,
+                        None => elf.endianness.read(field, size) as i64
+                    };
+                    let rf = reference_name(relocation.symbol)?;
+
+                    if code {
+                        let mut instruction = InstructionData::raw(None, &[RawOperand { size, relative }]);
+                        instruction.references.push(Reference { argument_pos: 2, rf, subtrahend: None, addend });
+                        section.instructions.push(instruction);
+                    } else if relative {
+                        return Err(format!("PC relative relocation at {:#x} in data section '{}' is not supported",
+                            relocation.offset, header.name))
+                    } else {
+                        section.binary_data.push(BinaryUnit {
+                            constant: None,
+                            reference: Some(BinaryReference { rf, subtrahend: None, addend, size }),
+                            alignment: None
+                        });
+                    }
+
+                    position += size.get_size() as u64;
+                    next += 1;
+                    if section_relocations.get(next).is_some_and(|r| r.offset < position) {
+                        return Err(format!("Relocations at {:#x} in '{}' overlap", relocation.offset, header.name))
+                    }
+                    continue
+                }
+
+                if !code {
+                    section.binary_data.push(BinaryUnit {
+                        constant: Some(BinaryConstant { size: ConstantSize::Byte, value: contents[position as usize] as i64 }),
+                        reference: None,
+                        alignment: None
+                    });
+                    position += 1;
+                    continue
+                }
+
+                // Code bytes are grouped into raw units that end before the next label or relocation
+                let mut end = (position + MAX_RAW_OPERANDS as u64).min(header.size);
+                if let Some(relocation) = section_relocations.get(next) {
+                    end = end.min(relocation.offset);
+                }
+                if let Some(cut) = (position + 1..end).find(|p| cuts.contains(p)) {
+                    end = cut;
+                }
+                let operands = vec![RawOperand { size: ConstantSize::Byte, relative: false }; (end - position) as usize];
+                let mut instruction = InstructionData::raw(None, &operands);
+                for (i, byte) in contents[position as usize..end as usize].iter().enumerate() {
+                    instruction.constants.push(Constant {
+                        argument_pos: (i + 2) as u8,
+                        size: ConstantSize::Byte,
+                        value: *byte as i64
+                    });
+                }
+                section.instructions.push(instruction);
+                position = end;
+            }
+            unit_at.insert(header.size, units);
+        }
+
+        for (label, offset, binding) in labels {
+            let ptr = match unit_at.get(&offset) {
+                Some(p) => *p,
+                None => return Err(format!("Symbol '{}' at {:#x} in '{}' is inside a relocated field or past the end",
+                    label, offset, header.name))
+            };
+            if !defined.insert(label.clone()) {
+                return Err(format!("Symbol '{}' is defined twice", label))
+            }
+            section.labels.insert(label.clone(), ObjectLabelSymbol { name: label, ptr, binding });
+        }
+
+        object.sections.insert(name, section);
+    }
+
+    for symbol in symbols.iter().filter(|s| s.section == SHN_COMMON) {
+        object.commons.push(CommonSymbol { name: symbol.name.clone(), size: symbol.size, alignment: symbol.value.max(1) });
+    }
+
+    object.header.sections_length = object.sections.len() as u64;
+
+    Ok(object)
+}
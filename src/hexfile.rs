@@ -0,0 +1,317 @@
+/**
+ * hexfile.rs
+ *
+ * Text formats linked images are written in, for EPROM programmers,
+ * flashers and host tools embedding the image
+ */
+
+use crate::objgen::{ConstantSize, Endianness};
+
+const DEFAULT_RECORD_SIZE: usize = 16;
+const DEFAULT_MEMH_WIDTH: usize = 8;
+
+/**
+ * How the linked image is written out
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Binary,
+    IntelHex,
+    Elf,
+    // Bytes per address: 2 for S19, 3 for S28, 4 for S37, None for the fewest that fit
+    SRecord(Option<usize>),
+    // A .c file with the image as an array and a .h file declaring it
+    CArray,
+    // Words in hex for Verilog's $readmemh
+    ReadMemH
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "binary" | "bin" => Some(Self::Binary),
+            "ihex" | "hex" => Some(Self::IntelHex),
+            "elf" | "elf32" => Some(Self::Elf),
+            "srec" => Some(Self::SRecord(None)),
+            "s19" => Some(Self::SRecord(Some(2))),
+            "s28" => Some(Self::SRecord(Some(3))),
+            "s37" => Some(Self::SRecord(Some(4))),
+            "c" => Some(Self::CArray),
+            "memh" | "readmemh" => Some(Self::ReadMemH),
+            _ => None
+        }
+    }
+
+    /**
+     * Extension of the output file when none is given
+     */
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Binary => "bin",
+            Self::IntelHex => "hex",
+            Self::Elf => "elf",
+            Self::SRecord(None) => "srec",
+            Self::SRecord(Some(2)) => "s19",
+            Self::SRecord(Some(3)) => "s28",
+            Self::SRecord(Some(_)) => "s37",
+            Self::CArray => "c",
+            Self::ReadMemH => "mem"
+        }
+    }
+}
+
+/**
+ * Checks a record size given on the command line or picks the default
+ */
+pub fn record_size(size: Option<usize>, max: usize) -> Result<usize, String> {
+    match size {
+        None => Ok(DEFAULT_RECORD_SIZE.min(max)),
+        Some(0) => Err(format!("Records must hold at least one byte")),
+        Some(s) if s > max => Err(format!("Records hold at most {} bytes, {} were asked for", max, s)),
+        Some(s) => Ok(s)
+    }
+}
+
+fn intel_hex_record(result: &mut String, record_type: u8, address: u16, data: &[u8]) {
+    let mut checksum = (data.len() as u8)
+        .wrapping_add((address >> 8) as u8)
+        .wrapping_add(address as u8)
+        .wrapping_add(record_type);
+
+    *result += &format!(":{:02X}{:04X}{:02X}", data.len(), address, record_type);
+    for byte in data {
+        *result += &format!("{:02X}", byte);
+        checksum = checksum.wrapping_add(*byte);
+    }
+    *result += &format!("{:02X}\n", checksum.wrapping_neg());
+}
+
+/**
+ * Writes the image loaded at `base` as Intel HEX. Addresses above 64K
+ * get extended linear address records, and a start linear address record
+ * points at the entry point if there is one.
+ */
+pub fn intel_hex(image: &[u8], base: u64, record_size: Option<usize>, entry: Option<u64>) -> Result<String, String> {
+    let record_size = self::record_size(record_size, u8::MAX as usize)?;
+    let end = base + image.len() as u64;
+    if end > u32::MAX as u64 + 1 {
+        return Err(format!("Image ends at {:#x}, Intel HEX addresses only go up to 0xffffffff", end))
+    }
+
+    let mut result = String::new();
+    let mut upper: u64 = 0;
+    let mut position: usize = 0;
+
+    while position < image.len() {
+        let address = base + position as u64;
+        if address >> 16 != upper {
+            upper = address >> 16;
+            intel_hex_record(&mut result, 0x04, 0, &(upper as u16).to_be_bytes());
+        }
+
+        // Records can't cross into the next 64K
+        let to_boundary = 0x10000 - (address & 0xFFFF) as usize;
+        let length = record_size.min(to_boundary).min(image.len() - position);
+
+        intel_hex_record(&mut result, 0x00, address as u16, &image[position..position + length]);
+        position += length;
+    }
+
+    if let Some(entry) = entry {
+        match u32::try_from(entry) {
+            Ok(e) => intel_hex_record(&mut result, 0x05, 0, &e.to_be_bytes()),
+            Err(_) => {
+                return Err(format!("Entry point {:#x} does not fit in a start address record", entry))
+            }
+        }
+    }
+
+    intel_hex_record(&mut result, 0x01, 0, &[]);
+
+    Ok(result)
+}
+
+fn srecord(result: &mut String, record_type: u8, address_size: usize, address: u64, data: &[u8]) {
+    let count = (address_size + data.len() + 1) as u8;
+    let address = &address.to_be_bytes()[8 - address_size..];
+
+    let mut checksum = count;
+    *result += &format!("S{}{:02X}", record_type, count);
+    for byte in address.iter().chain(data) {
+        *result += &format!("{:02X}", byte);
+        checksum = checksum.wrapping_add(*byte);
+    }
+    *result += &format!("{:02X}\n", !checksum);
+}
+
+/**
+ * Writes the image loaded at `base` as Motorola S-records, with `address_size`
+ * bytes per address or the fewest that fit. The header record carries `name`
+ * and the termination record the entry point, or 0 without one.
+ */
+pub fn srecords(image: &[u8], base: u64, address_size: Option<usize>, record_size: Option<usize>,
+    entry: Option<u64>, name: &str) -> Result<String, String>
+{
+    let end = base + image.len() as u64;
+    let highest = end.saturating_sub(1).max(base).max(entry.unwrap_or(0));
+
+    let address_size = match address_size {
+        Some(s) => s,
+        None if highest <= 0xFFFF => 2,
+        None if highest <= 0xFFFFFF => 3,
+        None => 4
+    };
+    if highest >> (address_size * 8) != 0 {
+        return Err(format!("Address {:#x} does not fit in the {} bytes of S{}{} records",
+            highest, address_size, address_size - 1, 11 - address_size))
+    }
+    let record_size = self::record_size(record_size, u8::MAX as usize - address_size - 1)?;
+
+    let mut result = String::new();
+    srecord(&mut result, 0, 2, 0, &name.as_bytes()[..name.len().min(u8::MAX as usize - 3)]);
+
+    let mut count: u64 = 0;
+    for (idx, chunk) in image.chunks(record_size).enumerate() {
+        let address = base + (idx * record_size) as u64;
+        srecord(&mut result, address_size as u8 - 1, address_size, address, chunk);
+        count += 1;
+    }
+
+    // Counts too large for S6 records are left out
+    if count <= 0xFFFF {
+        srecord(&mut result, 5, 2, count, &[]);
+    } else if count <= 0xFFFFFF {
+        srecord(&mut result, 6, 3, count, &[]);
+    }
+
+    srecord(&mut result, 11 - address_size as u8, address_size, entry.unwrap_or(0), &[]);
+
+    Ok(result)
+}
+
+const C_BYTES_PER_LINE: usize = 12;
+
+/**
+ * C identifier made from a file name, e.g. "rom-v2" becomes "rom_v2"
+ */
+pub fn c_identifier(name: &str) -> String {
+    let mut result: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !result.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        result.insert(0, '_');
+    }
+    result
+}
+
+/**
+ * Writes the image loaded at `base` as a C array called `name`, with its
+ * length, load address and entry point. Returns the source, which includes
+ * `header_name`, and the header declaring all of them.
+ */
+pub fn c_array(image: &[u8], base: u64, entry: Option<u64>, name: &str, header_name: &str) -> (String, String) {
+    let mut source = format!("#include \"{}\"\n\n", header_name);
+
+    source += &format!("const uint8_t {}[] = {{\n", name);
+    for line in image.chunks(C_BYTES_PER_LINE) {
+        let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+        source += &format!("    {},\n", bytes.join(", "));
+    }
+    // C doesn't allow empty arrays
+    if image.is_empty() {
+        source += "    0x00\n";
+    }
+    source += "};\n";
+    source += &format!("const uint32_t {}_length = {};\n", name, image.len());
+    source += &format!("const uint32_t {}_base = {:#010x};\n", name, base);
+    source += &format!("const uint32_t {}_entry = {:#010x};\n", name, entry.unwrap_or(base));
+
+    let guard = format!("{}_H", name.to_ascii_uppercase());
+    let mut header = format!("#ifndef {guard}\n#define {guard}\n\n#include <stdint.h>\n\n");
+    header += &format!("extern const uint8_t {}[];\n", name);
+    for constant in ["length", "base", "entry"] {
+        header += &format!("extern const uint32_t {}_{};\n", name, constant);
+    }
+    header += &format!("\n#endif\n");
+
+    (source, header)
+}
+
+/**
+ * Writes the image loaded at `base` as one `width` bit word per line for
+ * $readmemh. Words are put together in the target byte order, and an image
+ * that doesn't start at 0 starts with the '@' address of its first word.
+ * With a `depth`, the memory is filled up to that many words.
+ */
+pub fn readmemh(image: &[u8], base: u64, width: Option<usize>, depth: Option<u64>, fill: u8,
+    endianness: Endianness) -> Result<String, String>
+{
+    let width = width.unwrap_or(DEFAULT_MEMH_WIDTH);
+    let size = match width {
+        // Unwrap because these are all valid constant sizes
+        8 | 16 | 32 | 64 => ConstantSize::from_u8((width / 8) as u8).unwrap(),
+        _ => return Err(format!("Memory words can be 8, 16, 32 or 64 bits wide, not {}", width))
+    };
+    let word_size = size.get_size();
+    if !base.is_multiple_of(word_size as u64) {
+        return Err(format!("Image starts at {:#x}, which isn't a multiple of the {} byte words", base, word_size))
+    }
+
+    let first_word = base / word_size as u64;
+    let mut words = (image.len() as u64).div_ceil(word_size as u64);
+    if let Some(depth) = depth {
+        if words == 0 && first_word > depth {
+            return Err(format!("Image starts at word {:#x}, past the {} words of memory", first_word, depth))
+        }
+        if first_word + words > depth {
+            return Err(format!("Image takes up words {:#x} to {:#x}, past the {} words of memory",
+                first_word, first_word + words - 1, depth))
+        }
+        words = depth.saturating_sub(first_word);
+    }
+
+    let mut result = String::new();
+    if first_word != 0 {
+        result += &format!("@{:x}\n", first_word);
+    }
+
+    let mut word = vec![fill; word_size];
+    for idx in 0..words as usize {
+        word.fill(fill);
+        let start = (idx * word_size).min(image.len());
+        let end = ((idx + 1) * word_size).min(image.len());
+        word[..end - start].copy_from_slice(&image[start..end]);
+        result += &format!("{:0width$x}\n", endianness.read(&word, size), width = word_size * 2);
+    }
+
+    Ok(result)
+}
+
+/**
+ * Splits the image loaded at `base` into the byte lanes of a bus made of
+ * `lanes` memories, each `stride` bytes wide: the first `stride` bytes go
+ * to lane 0, the next to lane 1 and so on. Returns every lane with the
+ * address it starts at in its own memory. A last word that isn't complete
+ * is filled with `fill`.
+ */
+pub fn split_lanes(image: &[u8], base: u64, lanes: usize, stride: usize, fill: u8) -> Result<(Vec<Vec<u8>>, u64), String> {
+    if lanes == 0 || stride == 0 {
+        return Err(format!("Split output needs at least one lane of at least one byte"))
+    }
+    let bus_width = lanes * stride;
+    if !base.is_multiple_of(bus_width as u64) {
+        return Err(format!("Image starts at {:#x}, which isn't a multiple of the {} byte bus", base, bus_width))
+    }
+
+    let mut result = vec![Vec::<u8>::new(); lanes];
+    for word in image.chunks(bus_width) {
+        for (lane, bytes) in result.iter_mut().enumerate() {
+            let start = (lane * stride).min(word.len());
+            let end = ((lane + 1) * stride).min(word.len());
+            bytes.extend_from_slice(&word[start..end]);
+            bytes.resize(bytes.len() + stride - (end - start), fill);
+        }
+    }
+
+    Ok((result, base / lanes as u64))
+}
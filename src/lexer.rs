@@ -1,9 +1,44 @@
+use std::ops::Range;
+
 use regex_lexer::{LexerBuilder, Lexer, Token};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum LexerToken {
     Label, Identifier, Integer, Newline, String, Char, CompilerInstruction,
-    Comment, LParen, RParen, Comma, Plus, Minus, FloatingPoint, Multiply, Divide
+    Comment, LParen, RParen, Comma, Plus, Minus, FloatingPoint, Multiply, Divide,
+    PreprocessorInstruction, Equal, NotEqual, Less, Greater, LessEqual, GreaterEqual,
+    LogicalAnd, LogicalOr, Ellipsis, MacroLocal, MacroLocalLabel, Paste,
+    AnonymousLabel, AnonymousReference,
+    // Emitted by the preprocessor, never lexed. Text is "<line>:<file>"
+    LineMarker
+}
+
+/**
+ * Token that owns its text, so it can outlive the source it was lexed from.
+ * Produced by the preprocessor.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedToken {
+    pub kind: LexerToken,
+    pub text: String,
+    pub span: Range<usize>
+}
+
+impl OwnedToken {
+    pub fn from_token(token: &Token<'_, LexerToken>) -> Self {
+        Self {
+            kind: token.kind,
+            text: token.text.to_string(),
+            span: token.span.clone()
+        }
+    }
+    pub fn as_token(&self) -> Token<'_, LexerToken> {
+        Token {
+            kind: self.kind,
+            text: &self.text,
+            span: self.span.clone()
+        }
+    }
 }
 
 pub struct AsmLexer {
@@ -19,7 +54,7 @@ impl AsmLexer {
             .token(r"(?:(0x)[0-9a-fA-F]+|(0b)[01]+|(0d|)\d+)", LexerToken::Integer)
             .token(r"\d+\.\d*", LexerToken::FloatingPoint)
             .token(r"\n", LexerToken::Newline)
-            .token(r#"".*""#, LexerToken::String)
+            .token(r#""(?:[^"\\\n]|\\.)*""#, LexerToken::String)
             .token(r"^\.\w+", LexerToken::CompilerInstruction)
             .token(r"'.'", LexerToken::Char)
             .token(r"[;#].*\n", LexerToken::Comment)
@@ -30,6 +65,21 @@ impl AsmLexer {
             .token(r"-", LexerToken::Minus)
             .token(r"\*", LexerToken::Multiply)
             .token(r"\/", LexerToken::Divide)
+            .token(r"%\w+", LexerToken::PreprocessorInstruction)
+            .token(r"==", LexerToken::Equal)
+            .token(r"!=", LexerToken::NotEqual)
+            .token(r"<", LexerToken::Less)
+            .token(r">", LexerToken::Greater)
+            .token(r"<=", LexerToken::LessEqual)
+            .token(r">=", LexerToken::GreaterEqual)
+            .token(r"&&", LexerToken::LogicalAnd)
+            .token(r"\|\|", LexerToken::LogicalOr)
+            .token(r"\.\.\.", LexerToken::Ellipsis)
+            .token(r"%%\w+", LexerToken::MacroLocal)
+            .token(r"%%\w+:", LexerToken::MacroLocalLabel)
+            .token(r"%\+", LexerToken::Paste)
+            .token(r":", LexerToken::AnonymousLabel)
+            .token(r":[+-]+", LexerToken::AnonymousReference)
             .ignore(r"[\t\r ]")
             .build().unwrap();
         result
@@ -37,7 +87,13 @@ impl AsmLexer {
     pub fn new() -> Self {
         Self { lex_internal: AsmLexer::build_lexer() }
     }
-    pub fn tokenize<'a>(self, query: &'a str) -> Vec<Token<'a, LexerToken>> {
+    /**
+     * Lexes `query` lazily, a token at a time
+     */
+    pub fn tokens<'a>(&'a self, query: &'a str) -> impl Iterator<Item = Token<'a, LexerToken>> + 'a {
+        self.lex_internal.tokens(query)
+    }
+    pub fn tokenize<'a>(&self, query: &'a str) -> Vec<Token<'a, LexerToken>> {
         let tokens = self.lex_internal.tokens(query);
 
         let mut result = Vec::<Token<LexerToken>>::new();
@@ -1,7 +1,7 @@
-use crate::{objgen::{ObjectFormat, SectionData, InstructionData, ConstantSize, BinaryUnit}, symbols::{Instructions, ArgumentTypes}};
-use std::{fs, io::{Write, Read}, collections::HashMap};
-use byteorder::{LittleEndian, WriteBytesExt};
-use serde::{Serialize, Deserialize};
+use crate::{objgen::{ObjectFormat, SectionData, InstructionData, Reference, ConstantSize, BinaryUnit, Endianness, CommonSymbol, ObjectLabelSymbol, SymbolBinding, SectionFlags, SectionOrigin, LineInfo, SIZE_SYMBOL_SUFFIX, RawOperand, raw_opcode_size, size_symbol_label}, symbols::{Instructions, ArgumentTypes}, linkscript::{LinkStructure, SymbolAssignment, ScriptExpression}, archive::Archive, hexfile::{self, OutputFormat}, elf::{ElfImage, ElfSection, ElfSymbol}};
+use std::{fs, io::Write, cell::RefCell, collections::{BTreeMap, HashMap, HashSet}};
+use byteorder::WriteBytesExt;
+use serde::Serialize;
 
 macro_rules! calculate_alignment {
     ($num:expr, $alignment:expr) => {
@@ -14,87 +14,103 @@ macro_rules! calculate_alignment {
     };
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LinkStructureSection {
-    name: String,
-    alignment: u64
+// Section that common symbols are allocated in
+const COMMON_SECTION_NAME: &str = "bss";
+// How deep link script symbols can refer to other link script symbols
+const MAX_SCRIPT_SYMBOL_DEPTH: usize = 64;
+// Object name the boot stub is listed under
+const BOOT_STUB_OBJECT: &str = "<boot stub>";
+// Object name islands of veneers are listed under
+const VENEER_OBJECT: &str = "<veneers>";
+// "SAIM" in little endian
+const IMAGE_HEADER_MAGIC: u32 = 0x4D494153;
+const IMAGE_HEADER_VERSION: u16 = 1;
+// '--wrap' names of the wrapper and of the wrapped symbol
+const WRAP_PREFIX: &str = "__wrap_";
+const WRAP_REAL_PREFIX: &str = "__real_";
+
+// Prefixes of the symbols describing every output section: '__start_data' and so on.
+// '__load_' ones give where the section is stored in the image.
+const SECTION_BOUNDARY_PREFIXES: [&str; 5] = ["__start_", "__end_", "__size_", "__load_start_", "__load_end_"];
+
+/**
+ * Splits a section boundary symbol like '__end_bss' into its prefix and section
+ */
+fn section_boundary_symbol(name: &str) -> Option<(&'static str, &str)> {
+    SECTION_BOUNDARY_PREFIXES.iter().find_map(|prefix| {
+        name.strip_prefix(prefix)
+            .filter(|section| !section.is_empty())
+            .map(|section| (*prefix, section))
+    })
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LinkStructure {
-    sections: Vec<LinkStructureSection>
-}
-
-impl LinkStructure {
-    /**
-     * Creates a default link structure
-     * 
-     * Default structure includes sections: text, data, rodata (ordered)
-     * All sections by default are aligned to 0x100 bytes in hex
-     */
-    fn new() -> Self {
-        Self {
-            sections: vec![
-                LinkStructureSection {
-                    name: "text".to_string(),
-                    alignment: 0x100
-                },
-                LinkStructureSection {
-                    name: "data".to_string(),
-                    alignment: 0x100
-                },
-                LinkStructureSection {
-                    name: "rodata".to_string(),
-                    alignment: 0x100
-                },
-            ]
+fn write_output(path: &str, bytes: &[u8]) -> Result<(), String> {
+    let mut file = match fs::File::create(path) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(format!("Error occured while trying to open '{}' for saving: {e}", path))
         }
-    }
-
-    fn get_section(&self, name: &str) -> Option<&LinkStructureSection> {
-        let mut sec_iter = self.sections.iter();
-
-        sec_iter.find(|x| x.name == name)
-    }
+    };
 
-    fn get_section_index(&self, name: &str) -> Option<usize> {
-        for (idx, sec) in self.sections.iter().enumerate() {
-            if sec.name == name {
-                return Some(idx)
-            }
+    match file.write_all(bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            Err(format!("Error occured while writing binary to file: {e}"))
         }
-        None
     }
+}
 
-    fn from_file(path: &str) -> Result<Self, String> {
-        let mut file = match fs::File::open(path) {
-            Ok(f) => f,
-            Err(e) => {
-                return Err(format!("Failed to open file '{}' for reading!\n{}", path, e))
-            }
-        };
-
-        let mut txt = String::new();
+/**
+ * Name of a file output next to `path`, like 'game.bank1.bin' for the suffix 'bank1',
+ * with the extension of `path` unless one is given
+ */
+fn output_file_name(path: &str, suffix: &str, extension: Option<&str>) -> String {
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().to_string());
+    let extension = match extension {
+        Some(e) => Some(e.to_string()),
+        None => path.extension().map(|e| e.to_string_lossy().to_string())
+    };
+    let name = match extension {
+        Some(e) => format!("{}.{}.{}", stem, suffix, e),
+        None => format!("{}.{}", stem, suffix)
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
 
-        match file.read_to_string(&mut txt) {
-            Ok(l) => l,
-            Err(e) => {
-                return Err(format!("Error reading file '{}': {}", path, e))
+/**
+ * Describes every overlap between the non-empty (start, end, section) ranges,
+ * returned with the names of both sections
+ */
+fn overlapping_ranges<'a>(mut ranges: Vec<(u64, u64, &'a str)>, verb: &str) -> Vec<(String, &'a str, &'a str)> {
+    ranges.retain(|(start, end, _)| start != end);
+    ranges.sort();
+
+    let mut overlaps = Vec::new();
+    for (idx, (start, end, name)) in ranges.iter().enumerate() {
+        for (other_start, other_end, other) in ranges[idx + 1..].iter() {
+            if other_start >= end {
+                break
             }
-        };
-
-        Self::from_text(txt)
+            overlaps.push((format!("Sections '{}' ({:#06x}..{:#06x}) and '{}' ({:#06x}..{:#06x}) {} by {} bytes",
+                name, start, end, other, other_start, other_end, verb, end.min(other_end) - other_start), *name, *other));
+        }
     }
+    overlaps
+}
 
-    fn from_text(txt: String) -> Result<Self, String> {
-        let link_struct = match serde_json::from_str::<LinkStructure>(&txt) {
-            Ok(l) => l,
-            Err(e) => {
-                return Err(format!("Error occured while parsing JSON: {e}"))
-            }
-        };
-        Ok(link_struct)
-    }
+/**
+ * Which input sections are reachable and through what, as they were before
+ * garbage collection
+ */
+struct LiveTrace {
+    // Input section -> (section, symbol) edge it was first reached through, None for roots
+    reached: HashMap<String, Option<(String, String)>>,
+    // Why each root is live
+    roots: HashMap<String, String>,
+    // Input section defining each label
+    symbols: HashMap<String, String>,
+    sections: Vec<String>
 }
 
 struct ResolvedReference {
@@ -104,290 +120,2379 @@ struct ResolvedReference {
 
 pub struct Linker {
     link_structure: LinkStructure,
-    section_symbols: HashMap<String, SectionData>,
-    section_binaries: HashMap<String, Vec<u8>>
+    // Sorted maps, so identical inputs always link to identical outputs
+    section_symbols: BTreeMap<String, SectionData>,
+    section_binaries: BTreeMap<String, Vec<u8>>,
+    // Final address of every placed section, see `layout_sections`
+    section_addresses: HashMap<String, u64>,
+    // Where sections loaded into another region ('AT>') are stored
+    load_addresses: HashMap<String, u64>,
+    // Images of the link script's banks, made alongside the main image
+    bank_images: BTreeMap<u64, Vec<u8>>,
+    // Number of objects loaded so far
+    object_count: usize,
+    pub warn_misaligned: bool,
+    // Warnings of the last link, also printed as they come up
+    warnings: RefCell<Vec<String>>,
+    // Merge identical labeled constant runs in read-only sections
+    pub merge_constants: bool,
+    // Byte order of the loaded objects
+    endianness: Option<Endianness>,
+    // '.comm' symbols, allocated in "bss" when the link structure is loaded
+    commons: BTreeMap<String, CommonSymbol>,
+    // Drop input sections unreachable from the start of the image
+    pub gc_sections: bool,
+    // Leave local symbols and debug lines out of objects saved with `save_object`,
+    // except for the ones retained
+    pub strip_local: bool,
+    pub retain_symbols: HashSet<String>,
+    removed_sections: Vec<String>,
+    // Liveness of the input sections, for '--why-live' and '--print-gc-sections'
+    live_trace: Option<LiveTrace>,
+    // Source lines of the loaded objects, kept for relocatable output
+    debug_lines: Vec<LineInfo>,
+    // '--defsym' symbols, they replace link script symbols of the same name
+    defsyms: Vec<SymbolAssignment>,
+    // Symbol the image starts executing at
+    pub entry: Option<String>,
+    // Write a header describing the image before it. Without one, a boot
+    // stub jumping to the entry point starts the image.
+    pub image_header: bool,
+    // Size the image is padded to, with the given byte or the global fill
+    pub pad_to: Option<(u64, Option<u8>)>,
+    // Fill bytes from the command line, they replace the ones in the link script
+    pub fill: Option<u8>,
+    pub section_fills: Vec<(String, u8)>,
+    pub output_format: OutputFormat,
+    // Data bytes per record of text formats, None for the format's default
+    pub record_size: Option<usize>,
+    // Bits per word and words of memory of $readmemh output
+    pub memh_width: Option<usize>,
+    pub memh_depth: Option<u64>,
+    // Memories the image is split across by byte lane, and bytes per lane
+    pub split_lanes: Option<usize>,
+    pub split_stride: Option<usize>,
+    // Symbols whose definitions and references are printed while linking
+    pub trace_symbols: Vec<String>,
+    traces: Vec<String>,
+    // '--wrap' symbols, see `wrap_symbols`
+    pub wrap_symbols: Vec<String>
 }
 
 impl Linker {
     pub fn new() -> Self {
         Self {
             link_structure: LinkStructure::new(),
-            section_symbols: HashMap::new(),
-            section_binaries: HashMap::new()
+            section_symbols: BTreeMap::new(),
+            section_binaries: BTreeMap::new(),
+            section_addresses: HashMap::new(),
+            load_addresses: HashMap::new(),
+            bank_images: BTreeMap::new(),
+            object_count: 0,
+            warn_misaligned: false,
+            warnings: RefCell::new(Vec::new()),
+            merge_constants: false,
+            endianness: None,
+            commons: BTreeMap::new(),
+            gc_sections: false,
+            strip_local: false,
+            retain_symbols: HashSet::new(),
+            removed_sections: Vec::new(),
+            live_trace: None,
+            debug_lines: Vec::new(),
+            defsyms: Vec::new(),
+            entry: None,
+            image_header: false,
+            pad_to: None,
+            fill: None,
+            section_fills: Vec::new(),
+            output_format: OutputFormat::Binary,
+            record_size: None,
+            memh_width: None,
+            memh_depth: None,
+            split_lanes: None,
+            split_stride: None,
+            trace_symbols: Vec::new(),
+            traces: Vec::new(),
+            wrap_symbols: Vec::new()
         }
     }
 
+    /**
+     * Defines a symbol for the link, as if the link script assigned it
+     */
+    pub fn define_symbol(&mut self, symbol: SymbolAssignment) {
+        self.defsyms.retain(|s| s.name != symbol.name);
+        self.defsyms.push(symbol);
+    }
+
+    /**
+     * Byte order of the output, little endian until an object says otherwise
+     */
+    pub fn endianness(&self) -> Endianness {
+        self.endianness.unwrap_or(Endianness::Little)
+    }
+
+    /**
+     * Saves the loaded objects as one relocatable object. Sections are
+     * merged by name, references are left for the final link to resolve.
+     */
     pub fn save_object(&self, path: &str) -> Result<(), String> {
         let mut object = ObjectFormat::new();
         for (sec_name, sec) in self.section_symbols.iter() {
             object.sections.insert(sec_name.clone(), sec.clone());
         }
+        object.debug_lines = self.debug_lines.clone();
 
         object.header.sections_length = object.sections.len() as u64;
+        object.header.endianness = self.endianness();
+
+        let mut commons: Vec<&CommonSymbol> = self.commons.values().collect();
+        commons.sort_by(|a, b| a.name.cmp(&b.name));
+        object.commons = commons.into_iter().cloned().collect();
+
+        if self.strip_local {
+            object.strip_local_symbols(&self.retain_symbols);
+        }
 
         object.save_object(path)
     }
 
-    pub fn load_symbols(&mut self, objfmt: ObjectFormat) -> Result<(), String> {
-        for (sec_name, sec) in objfmt.sections {
-            if self.section_symbols.contains_key(&sec_name) {
-                self.section_symbols.get_mut(&sec_name).unwrap()
-                    .append_other(sec)?;
-            } else {
-                self.section_symbols.insert(sec_name, sec);
-            }
+    /**
+     * Gives local labels of an object a per-object suffix, so they can neither
+     * clash with nor be referenced by labels of other objects
+     */
+    fn localize_symbols(objfmt: &mut ObjectFormat, object_index: usize) {
+        let local: HashSet<String> = objfmt.sections.values()
+            .flat_map(|s| s.labels.values())
+            .filter(|l| l.binding == SymbolBinding::Local)
+            .map(|l| l.name.clone())
+            .collect();
+
+        if local.is_empty() {
+            return
         }
 
-        Ok(())
-    }
+        let rename = |name: &str| format!("{}#{}", name, object_index);
+        // Size symbols follow the label they measure
+        let rename_reference = |name: &mut String| {
+            if local.contains(name.as_str()) {
+                *name = rename(name);
+            } else if let Some(label) = size_symbol_label(name).filter(|l| local.contains(*l)) {
+                *name = format!("{}{}", rename(label), SIZE_SYMBOL_SUFFIX);
+            }
+        };
 
-    fn find_section_with_label(&self, label: &str) -> Option<&str> {
-        let mut sec_iter = self.section_symbols.iter();
+        for sec in objfmt.sections.values_mut() {
+            sec.labels = std::mem::take(&mut sec.labels).into_iter().map(|(name, mut label)| {
+                if local.contains(&name) {
+                    label.name = rename(&name);
+                }
+                (label.name.clone(), label)
+            }).collect();
 
-        // FIXME: This is messy. Maybe needs a refactor
+            Self::rename_references(sec, &rename_reference);
+        }
+    }
 
-        match sec_iter.find(|(_, x)| {
-            if x.labels.contains_key(label) {
-                return true
+    fn rename_references(section: &mut SectionData, rename: &dyn Fn(&mut String)) {
+        for instruction in section.instructions.iter_mut() {
+            for reference in instruction.references.iter_mut() {
+                rename(&mut reference.rf);
+                if let Some(sub) = &mut reference.subtrahend {
+                    rename(sub);
+                }
+            }
+        }
+        for unit in section.binary_data.iter_mut() {
+            if let Some(reference) = &mut unit.reference {
+                rename(&mut reference.rf);
+                if let Some(sub) = &mut reference.subtrahend {
+                    rename(sub);
+                }
             }
-            false
-        }) {
-            Some(s) => Some(s.0),
-            None => None
         }
     }
 
-    fn get_section_offset(&self, section_name: &str) -> Result<u64, String> {
-        let link_section_index = match self.link_structure.get_section_index(section_name) {
-            Some(lsi) => lsi,
-            None => return Err(format!("Linker script doesn't define section '{}': Undefined reference.", section_name))
+    /**
+     * Sends references to a wrapped symbol that the object doesn't define
+     * itself to '__wrap_<symbol>', and ones to '__real_<symbol>' to the
+     * symbol, like '--wrap' of GNU ld
+     */
+    fn wrap_symbols(&self, objfmt: &mut ObjectFormat) {
+        if self.wrap_symbols.is_empty() {
+            return
+        }
+
+        let defined: HashSet<String> = objfmt.sections.values()
+            .flat_map(|s| s.labels.values())
+            .filter(|l| l.binding != SymbolBinding::Local)
+            .map(|l| l.name.clone())
+            .collect();
+
+        let rename_reference = |name: &mut String| {
+            if let Some(real) = name.strip_prefix(WRAP_REAL_PREFIX).filter(|r| self.wrap_symbols.iter().any(|w| w == r)) {
+                *name = real.to_string();
+            } else if self.wrap_symbols.contains(name) && !defined.contains(name) {
+                *name = format!("{}{}", WRAP_PREFIX, name);
+            }
         };
 
-        let mut offset = 0u64;
+        for sec in objfmt.sections.values_mut() {
+            Self::rename_references(sec, &rename_reference);
+        }
+    }
 
-        // For every section before this
-        for (idx, link_section) in self.link_structure.sections.iter().enumerate() {
-            if idx == link_section_index { break }
-            let section = match self.section_symbols.get(&link_section.name) {
-                Some(s) => s,
-                None => {
-                    return Err(format!("No section '{}' found!", link_section.name))
-                }
-            };
+    fn label_binding(&self, name: &str) -> Option<SymbolBinding> {
+        self.section_symbols.values()
+            .find_map(|s| s.labels.get(name))
+            .map(|l| l.binding)
+    }
 
-            offset += section.get_binary_size() as u64;
+    /**
+     * Hides the weak one of two labels with the same name. When both are
+     * weak, the label loaded first wins.
+     */
+    fn resolve_weak_symbols(&mut self, objfmt: &mut ObjectFormat) {
+        let hidden_name = |name: &str, object_index: usize| format!("{}#weak{}", name, object_index);
+
+        for sec in objfmt.sections.values_mut() {
+            let names: Vec<String> = sec.labels.keys().cloned().collect();
+
+            for name in names {
+                let existing = match self.label_binding(&name) {
+                    Some(b) => b,
+                    None => continue
+                };
+                let incoming = sec.labels[&name].binding;
+
+                if incoming == SymbolBinding::Weak {
+                    let mut label = sec.labels.remove(&name).unwrap();
+                    label.name = hidden_name(&name, self.object_count);
+                    sec.labels.insert(label.name.clone(), label);
+                } else if existing == SymbolBinding::Weak {
+                    for linked in self.section_symbols.values_mut() {
+                        if let Some(mut label) = linked.labels.remove(&name) {
+                            label.name = hidden_name(&name, self.object_count);
+                            linked.labels.insert(label.name.clone(), label);
+                        }
+                    }
+                }
+            }
         }
+    }
 
-        let alignment = self.link_structure.get_section(section_name)
-            .unwrap().alignment;
-
-        let result = calculate_alignment!(offset, alignment);
+    /**
+     * Object and input section a label of a linked section came from
+     */
+    fn label_origin(&self, sec_name: &str, label: &str) -> String {
+        let section = &self.section_symbols[sec_name];
+        let ptr = section.labels[label].ptr;
 
-        Ok(result)
+        match section.origins.iter().rev().find(|o| o.ptr <= ptr) {
+            Some(o) => format!("{}({})", o.object, o.section),
+            None => format!("<unknown object>({})", sec_name)
+        }
     }
 
-    fn write_instruction_binary(&self, binary: &mut Vec<u8>, instruction: &InstructionData) -> Result<(), String> {
-        let instructions = Instructions::new();
-        // Unwrap, because we assume valid section data from object files
-        let instr_symbol = instructions.get_instruction(instruction.opcode).unwrap();
+    /**
+     * Fails listing every label of `objfmt` that a loaded object already defines.
+     * Weak labels are resolved before, so only strong ones can collide.
+     */
+    fn check_duplicate_symbols(&self, objfmt: &ObjectFormat, object_name: &str) -> Result<(), String> {
+        let mut duplicates = Vec::<String>::new();
+
+        for (sec_name, sec) in objfmt.sections.iter() {
+            for name in sec.labels.keys() {
+                if let Some(existing) = self.find_section_with_label(name) {
+                    duplicates.push(format!("Duplicate symbol '{}' defined in {} and {}({})",
+                        name, self.label_origin(existing, name), object_name, sec_name));
+                }
+            }
+        }
 
-        let start_position = binary.len() as i64;
+        if duplicates.is_empty() {
+            return Ok(())
+        }
+        duplicates.sort();
 
-        let mut bin = Vec::<u8>::new();
+        Err(duplicates.join("\n"))
+    }
 
-        // Write opcode
-        if instr_symbol.extended_opcode() {
-            match bin.write_u16::<LittleEndian>(instr_symbol.opcode) {
-                Ok(()) => {},
-                Err(e) => {
-                    return Err(format!("Failed to write binary: {e}"))
-                }
-            }
-        } else {
-            match bin.write_u8(instr_symbol.opcode as u8) {
-                Ok(()) => {},
-                Err(e) => {
-                    return Err(format!("Failed to write binary: {e}"))
-                }
+    pub fn load_symbols(&mut self, mut objfmt: ObjectFormat) -> Result<(), String> {
+        self.live_trace = None;
+        match self.endianness {
+            Some(e) if e != objfmt.header.endianness => {
+                return Err(format!("Cannot link a {} endian object with {} endian ones",
+                    objfmt.header.endianness, e))
             }
+            _ => self.endianness = Some(objfmt.header.endianness)
         }
 
-        // Resolve symbols
-        let mut resolved_references = HashMap::<u8, ResolvedReference>::new();
+        self.object_count += 1;
+        let object_name = match (&objfmt.file, objfmt.get_metadata("source")) {
+            (Some(file), _) => file.clone(),
+            (None, Some(source)) => source.to_string(),
+            (None, None) => format!("<object {}>", self.object_count)
+        };
+        self.trace_object(&objfmt, &object_name);
 
-        for reference in instruction.references.iter() {
-            let sec_name = match self.find_section_with_label(&reference.rf) {
-                Some(s) => s,
+        for common in objfmt.commons.drain(..) {
+            match self.commons.get_mut(&common.name) {
+                Some(c) => c.merge(&common),
                 None => {
-                    return Err(format!("Failed to resolve reference '{}': Undefined reference.", reference.rf))
+                    self.commons.insert(common.name.clone(), common);
                 }
-            };
-            let section = &self.section_symbols[sec_name];
+            }
+        }
 
-            // Unwrap because previous statement, read it again pls;;;
-            let section_local_offset = section.get_label_binary_offset(&reference.rf).unwrap();
+        self.wrap_symbols(&mut objfmt);
+        Linker::localize_symbols(&mut objfmt, self.object_count);
+        self.resolve_weak_symbols(&mut objfmt);
 
-            let section_offset = self.get_section_offset(sec_name)?;
+        self.check_duplicate_symbols(&objfmt, &object_name)?;
 
-            let offset = section_offset + section_local_offset;
+        for mut info in objfmt.debug_lines.drain(..) {
+            if let Some(sec) = self.section_symbols.get(&info.section) {
+                info.ptr += sec.get_unit_count();
+            }
+            self.debug_lines.push(info);
+        }
 
-            let arg_size = instr_symbol.args[reference.argument_pos as usize].get_size();
+        for (sec_name, mut sec) in objfmt.sections {
+            sec.origins = vec![SectionOrigin {
+                object: object_name.clone(),
+                section: sec_name.clone(),
+                ptr: 0
+            }];
 
-            // FIXME: Unwraps
-            resolved_references.insert(reference.argument_pos, ResolvedReference { 
-                size: ConstantSize::from_u8(arg_size as u8).unwrap(), value: offset as i64 
-            });
+            if self.section_symbols.contains_key(&sec_name) {
+                self.section_symbols.get_mut(&sec_name).unwrap()
+                    .append_other(sec)?;
+            } else {
+                self.section_symbols.insert(sec_name, sec);
+            }
         }
 
-        for constant in instruction.constants.iter() {
-            resolved_references.insert(constant.argument_pos, ResolvedReference {
-                size: constant.size, value: constant.value
-            });
+        Ok(())
+    }
+
+    fn trace(&mut self, message: String) {
+        println!("{}", message);
+        self.traces.push(message);
+    }
+
+    /**
+     * Traces definitions of and references to traced symbols in an object
+     * that is about to be loaded
+     */
+    fn trace_object(&mut self, objfmt: &ObjectFormat, object_name: &str) {
+        if self.trace_symbols.is_empty() {
+            return
         }
-        
-        // FIXME: Actually i am stupid and have no idea how to do this otherwise.
-        // If anyone has any idea on how to improve this piece of... code...
-        // Please help me. I would appreciate any direction anyone is willing to give me.
 
-        // Why do i have to borrow a ZERO?
-        if let Some(arg) = resolved_references.get_mut(&0) {
-            let sym_arg = instr_symbol.args[0];
-            match sym_arg {
-                // Calculate relative offset
-                ArgumentTypes::RelPointer => {
-                    arg.value = arg.value - start_position;
+        let mut messages = Vec::<String>::new();
+        let mut sections: Vec<(&String, &SectionData)> = objfmt.sections.iter().collect();
+        sections.sort_by_key(|(name, _)| *name);
+
+        for name in self.trace_symbols.iter() {
+            for (sec_name, section) in sections.iter() {
+                if let Some(label) = section.labels.get(name) {
+                    let note = match (label.binding, self.label_binding(name)) {
+                        (SymbolBinding::Local, _) => " (local)",
+                        (SymbolBinding::Weak, Some(_)) => " (weak, the earlier definition wins)",
+                        (SymbolBinding::Weak, None) => " (weak)",
+                        (_, Some(SymbolBinding::Weak)) => " (replaces the earlier weak definition)",
+                        _ => ""
+                    };
+                    messages.push(format!("{}({}): definition of {}{}", object_name, sec_name, name, note));
                 }
-                _ => {}
-            }
-            match arg.size {
-                // FIXME: UNWRAPS
-                ConstantSize::Byte => bin.write_i8(arg.value as i8).unwrap(),
-                ConstantSize::Word => bin.write_i16::<LittleEndian>(arg.value as i16).unwrap(),
-                ConstantSize::DoubleWord => bin.write_i32::<LittleEndian>(arg.value as i32).unwrap()
-            }
-        }
-        // instructions are packed, and not aligned, so it should be fine to do this, right?
-        if let Some(arg) = resolved_references.get_mut(&1) {
-            let sym_arg = instr_symbol.args[1];
-            match sym_arg {
-                ArgumentTypes::RelPointer => {
-                    arg.value = arg.value - start_position;
+                if Self::section_references(section).contains(&name.as_str()) {
+                    messages.push(format!("{}({}): reference to {}", object_name, sec_name, name));
                 }
-                _ => {}
             }
-            match arg.size {
-                // FIXME: UNWRAPS
-                ConstantSize::Byte => bin.write_i8(arg.value as i8).unwrap(),
-                ConstantSize::Word => bin.write_i16::<LittleEndian>(arg.value as i16).unwrap(),
-                ConstantSize::DoubleWord => bin.write_i32::<LittleEndian>(arg.value as i32).unwrap()
+            if let Some(common) = objfmt.commons.iter().find(|c| c.name == *name) {
+                messages.push(format!("{}: common definition of {} ({} bytes)", object_name, name, common.size));
             }
         }
 
-        binary.append(&mut bin);
+        for message in messages {
+            self.trace(message);
+        }
+    }
 
-        Ok(())
+    /**
+     * Messages traced so far for `trace_symbols`
+     */
+    pub fn traces(&self) -> &[String] {
+        &self.traces
     }
 
-    fn write_binary_unit_binary(&self, binary: &mut Vec<u8>, unit: &BinaryUnit) -> Result<(), String> {
-        if let Some(reference) = &unit.reference {
-            let sec_name = match self.find_section_with_label(&reference.rf) {
-                Some(s) => s,
-                None => {
-                    return Err(format!("Failed to resolve reference '{}': Undefined reference.", reference.rf))
+    /**
+     * Symbols referenced by the loaded sections that nothing defines yet
+     */
+    fn undefined_symbols(&self) -> Vec<String> {
+        let mut undefined: Vec<String> = self.section_symbols.values()
+            .flat_map(|sec| Self::section_references(sec))
+            .filter(|symbol| !self.is_defined(symbol) && !self.commons.contains_key(*symbol))
+            .map(|symbol| symbol.to_string())
+            .collect();
+        undefined.sort();
+        undefined.dedup();
+
+        undefined
+    }
+
+    /**
+     * Loads only the archive members defining symbols that are still
+     * undefined. Repeats until no member adds anything, so members can
+     * pull in each other and members of other archives.
+     */
+    pub fn load_archives(&mut self, archives: &[(String, Archive)]) -> Result<(), String> {
+        // (archive, member) pairs already loaded
+        let mut loaded = HashSet::<(usize, usize)>::new();
+
+        loop {
+            // (archive, member, symbol it is loaded for)
+            let mut wanted = Vec::<(usize, usize, String)>::new();
+
+            for symbol in self.undefined_symbols() {
+                let definition = archives.iter().enumerate().find_map(|(idx, (_, archive))| {
+                    archive.member_defining(&symbol)
+                        .or_else(|| size_symbol_label(&symbol).and_then(|l| archive.member_defining(l)))
+                        .map(|member| (idx, member))
+                });
+                if let Some(d) = definition.filter(|d| !loaded.contains(d) && !wanted.iter().any(|w| (w.0, w.1) == *d)) {
+                    wanted.push((d.0, d.1, symbol));
                 }
-            };
+            }
 
-            let section = &self.section_symbols[sec_name];
+            if wanted.is_empty() {
+                return Ok(())
+            }
 
-            let section_local_offset = section.get_label_binary_offset(&reference.rf).unwrap();
+            for (idx, member, symbol) in wanted {
+                loaded.insert((idx, member));
+                let (name, archive) = &archives[idx];
+                if self.trace_symbols.contains(&symbol) {
+                    self.trace(format!("{}({}): loaded for {}", name, archive.members[member].name, symbol));
+                }
+                self.load_symbols(archive.members[member].object(name)?)?;
+            }
+        }
+    }
 
-            let section_offset = self.get_section_offset(sec_name)?;
+    fn find_section_with_label(&self, label: &str) -> Option<&str> {
+        let mut sec_iter = self.section_symbols.iter();
 
-            let symbol_position = section_offset + section_local_offset;
+        // FIXME: This is messy. Maybe needs a refactor
 
-            match reference.size {
-                ConstantSize::Byte => binary.write_u8(symbol_position as u8).unwrap(),
-                ConstantSize::Word => binary.write_u16::<LittleEndian>(symbol_position as u16).unwrap(),
-                ConstantSize::DoubleWord => binary.write_u32::<LittleEndian>(symbol_position as u32).unwrap(),
-            }
-        } else if let Some(constant) = &unit.constant {
-            match constant.size {
-                ConstantSize::Byte => binary.write_i8(constant.value as i8).unwrap(),
-                ConstantSize::Word => binary.write_i16::<LittleEndian>(constant.value as i16).unwrap(),
-                ConstantSize::DoubleWord => binary.write_i32::<LittleEndian>(constant.value as i32).unwrap()
+        match sec_iter.find(|(_, x)| {
+            if x.labels.contains_key(label) {
+                return true
             }
-        } else {
-            return Err(format!("Binary unit contains no information to write!"))
+            false
+        }) {
+            Some(s) => Some(s.0),
+            None => None
         }
-        Ok(())
     }
 
-    fn section_binary(&self, binary: &mut Vec<u8>, section: &SectionData) -> Result<(), String> {
-        if section.binary_section {
-            for unit in section.binary_data.iter() {
-                self.write_binary_unit_binary(binary, unit)?;
+    /**
+     * Section defining a symbol, or the label a size symbol measures
+     */
+    fn symbol_section(&self, name: &str) -> Option<&str> {
+        self.find_section_with_label(name)
+            .or_else(|| size_symbol_label(name).and_then(|l| self.find_section_with_label(l)))
+            .or_else(|| self.boundary_section(name)
+                .and_then(|(_, sec)| self.section_symbols.get_key_value(sec))
+                .map(|(sec, _)| sec.as_str()))
+    }
+
+    /**
+     * Prefix and section of a '__start_', '__end_', '__size_' or '__load_' symbol,
+     * if the section exists
+     */
+    fn boundary_section<'a>(&self, name: &'a str) -> Option<(&'static str, &'a str)> {
+        section_boundary_symbol(name).filter(|(_, sec)| self.section_symbols.contains_key(*sec))
+    }
+
+    /**
+     * Returns every symbol name referenced from a section
+     */
+    fn section_references(section: &SectionData) -> Vec<&str> {
+        let mut references = Vec::<&str>::new();
+
+        for instruction in section.instructions.iter() {
+            for reference in instruction.references.iter() {
+                references.extend(reference.symbols().map(|s| s.as_str()));
             }
-            //binary.append(&mut section.binary_data.clone());
-        } else {
-            for instruction in section.instructions.iter() {
-                self.write_instruction_binary(binary, instruction)?;
+        }
+        for unit in section.binary_data.iter() {
+            if let Some(reference) = &unit.reference {
+                references.extend(reference.symbols().map(|s| s.as_str()));
             }
         }
 
-        Ok(())
+        references
     }
 
-    pub fn generate_binary(&mut self, ls_path: Option<&str>) -> Result<Vec<u8>, String> {
-        self.link_structure = match ls_path {
-            Some(lsp) => LinkStructure::from_file(lsp)?,
-            None => LinkStructure::new()
-        };
+    /**
+     * Input sections garbage collection starts from, each with why it is live:
+     * the one holding the entry point, the input the image starts with, inputs
+     * matched by KEEP patterns and sections defining symbols the link script uses.
+     * `claims` are the claims of `claim_input_sections`.
+     */
+    fn gc_roots(&self, claims: &[(String, Vec<(String, bool)>)]) -> Vec<(String, String)> {
+        let mut roots = Vec::<(String, String)>::new();
 
-        for (sec_name, section) in self.section_symbols.iter() {
-            let mut section_bin = Vec::<u8>::new();
-            self.section_binary(&mut section_bin, section)?;
-            self.section_binaries.insert(sec_name.clone(), section_bin);
+        if let (Some(sec), Some(entry)) = (self.entry_section(), self.entry.as_deref()) {
+            roots.push((sec.to_string(), format!("holds the entry point '{}'", entry)));
         }
 
-        let mut binary = Vec::<u8>::new();
+        // The image starts at the first input of the first output section.
+        // Subsections nobody claims are folded in ahead of the claimed inputs.
+        if let Some((first, inputs)) = claims.first() {
+            let mut folded: Vec<&String> = self.section_symbols.keys()
+                .filter(|name| !claims.iter().any(|(_, inputs)| inputs.iter().any(|(input, _)| input == *name)))
+                .filter(|name| name.split('.').next() == Some(first.as_str()))
+                .collect();
+            folded.sort();
+
+            let start = match folded.first() {
+                Some(name) => Some(name.to_string()),
+                None => inputs.first().map(|(name, _)| name.clone())
+            };
+            roots.extend(start.map(|name| (name, "is the start of the image".to_string())));
+        }
 
-        for section in self.link_structure.sections.iter() {
-            if let Some(mut bin) = self.section_binaries.get_mut(&section.name) {
-                binary.append(&mut bin);
-            } else {
-                return Err(format!("Undefined reference to section '{}': \
-                linker section is defined but not found in binaries!", section.name))
+        for (input, _) in claims.iter().flat_map(|(_, inputs)| inputs.iter()).filter(|(_, keep)| *keep) {
+            roots.push((input.clone(), "is kept by the link script".to_string()));
+        }
+
+        let script_symbols = self.link_structure.symbols.iter().map(|s| &s.value)
+            .chain(self.link_structure.assertions.iter().map(|a| &a.condition))
+            .flat_map(|expr| expr.symbols());
+        for symbol in script_symbols {
+            if let Some(sec) = self.symbol_section(symbol) {
+                roots.push((sec.to_string(), format!("defines '{}', which the link script uses", symbol)));
             }
+        }
 
-            let offset = self.get_section_offset(&section.name)?;
-            let end = offset + self.section_symbols[&section.name].get_binary_size() as u64;
+        roots
+    }
 
-            let alignment_bit_count = calculate_alignment!(end, section.alignment) - end;
+    /**
+     * Records which input sections are live and why, before garbage
+     * collection and gathering replace them with output sections
+     */
+    fn trace_live_sections(&self) -> LiveTrace {
+        let roots = self.gc_roots(&self.claim_input_sections());
 
-            // God forgive me
-            for _ in 0..alignment_bit_count {
-                binary.push(0);
-            }
+        let mut reasons = HashMap::<String, String>::new();
+        for (name, reason) in roots.iter() {
+            reasons.entry(name.clone()).or_insert_with(|| reason.clone());
         }
 
-        Ok(binary)
+        let mut sections: Vec<String> = self.section_symbols.keys().cloned().collect();
+        sections.sort();
+
+        LiveTrace {
+            reached: self.reachable_sections(roots.into_iter().map(|(name, _)| name).collect()),
+            roots: reasons,
+            symbols: self.section_symbols.iter()
+                .flat_map(|(sec, data)| data.labels.keys().map(move |label| (label.clone(), sec.clone())))
+                .collect(),
+            sections
+        }
     }
 
-    pub fn save_binary(&mut self, path: &str, ls_path: Option<&str>) -> Result<(), String> {
-        let bin = self.generate_binary(ls_path)?;
+    /**
+     * Section defining the entry symbol, if there is one
+     */
+    fn entry_section(&self) -> Option<&str> {
+        self.entry.as_deref().and_then(|e| self.symbol_section(e))
+    }
 
-        let mut file = match fs::File::create(path) {
-            Ok(f) => f,
-            Err(e) => {
-                return Err(format!("Error occured while trying to open file for saving: {e}"))
+    /**
+     * Maps every section reachable from the roots to the (section, symbol)
+     * edge it was first reached through. Roots map to None.
+     */
+    fn reachable_sections(&self, roots: Vec<String>) -> HashMap<String, Option<(String, String)>> {
+        let mut live = HashMap::<String, Option<(String, String)>>::new();
+        let mut queue = Vec::<String>::new();
+
+        for root in roots {
+            if self.section_symbols.contains_key(&root) && !live.contains_key(&root) {
+                live.insert(root.clone(), None);
+                queue.push(root);
             }
-        };
+        }
 
-        match file.write_all(bin.as_slice()) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                Err(format!("Error occured while writing binary to file: {e}"))
+        let mut idx = 0;
+        while idx < queue.len() {
+            let sec_name = queue[idx].clone();
+            idx += 1;
+
+            for rf in Self::section_references(&self.section_symbols[&sec_name]) {
+                let target = match self.symbol_section(rf) {
+                    Some(t) => t,
+                    None => continue
+                };
+                if live.contains_key(target) {
+                    continue
+                }
+                live.insert(target.to_string(), Some((sec_name.clone(), rf.to_string())));
+                queue.push(target.to_string());
             }
         }
+
+        live
+    }
+
+    /**
+     * Lists input sections that are not reachable from any garbage collection
+     * root. Requires the link structure to be loaded.
+     */
+    pub fn garbage_sections(&self) -> Vec<String> {
+        let trace = match &self.live_trace {
+            Some(t) => t,
+            None => return Vec::new()
+        };
+
+        trace.sections.iter()
+            .filter(|s| !trace.reached.contains_key(*s))
+            .cloned()
+            .collect()
+    }
+
+    /**
+     * Explains which chain of references keeps a symbol or input section alive.
+     * Requires the link structure to be loaded.
+     */
+    pub fn why_live(&self, name: &str) -> Result<String, String> {
+        let trace = match &self.live_trace {
+            Some(t) => t,
+            None => return Err(format!("The link structure isn't loaded"))
+        };
+
+        let sec_name = if trace.sections.iter().any(|s| s == name) {
+            name
+        } else {
+            match trace.symbols.get(name) {
+                Some(s) => s.as_str(),
+                None => {
+                    return Err(format!("Symbol or section '{}' is not defined", name))
+                }
+            }
+        };
+
+        if !trace.reached.contains_key(sec_name) {
+            return Ok(format!("'{}' is garbage: section '{}' is not referenced \
+            from the start of the image.\n", name, sec_name))
+        }
+
+        let mut chain = Vec::<String>::new();
+        let mut current = sec_name.to_string();
+
+        while let Some(Some((from, symbol))) = trace.reached.get(&current) {
+            chain.push(format!("  '{}' references '{}' defined in '{}'\n", from, symbol, current));
+            current = from.clone();
+        }
+        // Unwrap because every section the walk starts from is a root
+        chain.push(format!("  '{}' {}\n", current, trace.roots[&current]));
+        chain.reverse();
+
+        let mut result = format!("'{}' is live:\n", name);
+        for line in chain {
+            result += &line;
+        }
+        if name != sec_name {
+            result += &format!("  '{}' is defined in '{}'\n", name, sec_name);
+        }
+
+        Ok(result)
+    }
+
+    /**
+     * Gives every section the link script places its final address, packing
+     * sections one after another from the start of their memory region.
+     * Sections stored in another region are packed there too, after the
+     * sections already in it.
+     */
+    fn layout_sections(&mut self) -> Result<(), String> {
+        self.section_addresses.clear();
+        self.load_addresses.clear();
+
+        // Memory region and bank -> where its next section goes
+        let mut region_ends = HashMap::<(Option<&str>, Option<u64>), u64>::new();
+        let region_origin = |region: Option<&str>| match region {
+            // Unwrap because link structures are validated when loaded
+            Some(r) => self.link_structure.get_region(r).unwrap().origin,
+            None => 0
+        };
+
+        for link_section in self.link_structure.sections.iter() {
+            let region = link_section.region.as_deref();
+            let end = *region_ends.entry((region, link_section.bank)).or_insert_with(|| region_origin(region));
+
+            // Sections after a fixed one continue from its end
+            let address = match link_section.address {
+                Some(a) => a,
+                None => calculate_alignment!(end, link_section.alignment)
+            };
+            self.section_addresses.insert(link_section.name.clone(), address);
+
+            let section = match self.section_symbols.get(&link_section.name) {
+                Some(s) => s,
+                None => {
+                    return Err(format!("No section '{}' found!", link_section.name))
+                }
+            };
+
+            // Every section is padded to its alignment at the end
+            let size = section.get_binary_size() as u64;
+            let padded_end = |start: u64| calculate_alignment!(
+                calculate_alignment!(start, link_section.alignment) + size, link_section.alignment);
+            region_ends.insert((region, link_section.bank), padded_end(address));
+
+            let load_region = match link_section.load_region.as_deref() {
+                Some(r) if !section.is_nobits() => Some(r),
+                _ => continue
+            };
+            let load_end = *region_ends.entry((load_region, link_section.bank))
+                .or_insert_with(|| region_origin(load_region));
+            let load_address = calculate_alignment!(load_end, link_section.alignment);
+            self.load_addresses.insert(link_section.name.clone(), load_address);
+            region_ends.insert((load_region, link_section.bank), padded_end(load_address));
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Address a section's bytes are stored at in the image,
+     * the same as its address unless it is loaded into another region
+     */
+    fn get_section_load_address(&self, section_name: &str) -> Result<u64, String> {
+        match self.load_addresses.get(section_name) {
+            Some(address) => Ok(*address),
+            None => self.get_section_offset(section_name)
+        }
+    }
+
+    fn get_section_offset(&self, section_name: &str) -> Result<u64, String> {
+        if self.link_structure.get_section(section_name).is_none() {
+            return Err(format!("Linker script doesn't define section '{}': Undefined reference.", section_name))
+        }
+
+        match self.section_addresses.get(section_name) {
+            Some(address) => Ok(*address),
+            None => Err(format!("Section '{}' hasn't been laid out", section_name))
+        }
+    }
+
+    /**
+     * Writes a raw code unit: opcode (if any) and operands in the sizes its layout gives
+     */
+    fn write_raw_binary(&self, binary: &mut Vec<u8>, base: u64, instruction: &InstructionData,
+        opcode: Option<u16>, operands: &[RawOperand]) -> Result<(), String> {
+        let start_position = base + binary.len() as u64;
+
+        if let Some(opcode) = opcode {
+            let size = if raw_opcode_size(opcode) == 2 { ConstantSize::Word } else { ConstantSize::Byte };
+            self.endianness().write(binary, size, opcode as u64);
+        }
+
+        for (i, operand) in operands.iter().enumerate() {
+            let pos = (i + 2) as u8;
+
+            let mut value = match instruction.references.iter().find(|r| r.argument_pos == pos) {
+                Some(reference) => {
+                    let mut value = self.symbol_value(&reference.rf)?.wrapping_add(reference.addend as u64);
+                    if let Some(sub) = &reference.subtrahend {
+                        value = value.wrapping_sub(self.symbol_value(sub)?);
+                    }
+                    value
+                }
+                None => match instruction.constants.iter().find(|c| c.argument_pos == pos) {
+                    Some(c) => c.value as u64,
+                    None => return Err(format!("Raw code is missing operand {}", pos))
+                }
+            };
+            if operand.relative {
+                value = value.wrapping_sub(start_position);
+                self.check_displacement(instruction, ".insn", pos, operand.size,
+                    value as i64, start_position as i64)?;
+            }
+
+            self.endianness().write(binary, operand.size, value);
+        }
+
+        Ok(())
+    }
+    /**
+     * Writes an instruction to the binary of a section placed at `base`
+     */
+    fn write_instruction_binary(&self, binary: &mut Vec<u8>, base: u64, instruction: &InstructionData) -> Result<(), String> {
+        if let Some((_, fill)) = instruction.get_alignment() {
+            for _ in 0..instruction.get_binary_size(binary.len() as u64) {
+                binary.push(fill as u8);
+            }
+            return Ok(())
+        }
+        if let Some((opcode, operands)) = instruction.get_raw() {
+            return self.write_raw_binary(binary, base, instruction, opcode, &operands)
+        }
+
+        let instructions = Instructions::new();
+        // Unwrap, because we assume valid section data from object files
+        let instr_symbol = instructions.get_instruction(instruction.opcode).unwrap();
+
+        // Relative operands are relative to the absolute address of the instruction
+        let start_position = (base + binary.len() as u64) as i64;
+
+        let mut bin = Vec::<u8>::new();
+
+        // Write opcode
+        if instr_symbol.extended_opcode() {
+            self.endianness().write(&mut bin, ConstantSize::Word, instr_symbol.opcode as u64);
+        } else {
+            match bin.write_u8(instr_symbol.opcode as u8) {
+                Ok(()) => {},
+                Err(e) => {
+                    return Err(format!("Failed to write binary: {e}"))
+                }
+            }
+        }
+
+        // Resolve symbols
+        let mut resolved_references = HashMap::<u8, ResolvedReference>::new();
+
+        for reference in instruction.references.iter() {
+            let arg = match instr_symbol.args.get(reference.argument_pos as usize) {
+                Some(a) => a,
+                None => {
+                    return Err(format!("Reference to '{}' is argument {}, but '{}' takes {}",
+                        reference.expression(), reference.argument_pos, instr_symbol.name, instr_symbol.args.len()))
+                }
+            };
+
+            let mut offset = self.symbol_value(&reference.rf)?.wrapping_add(reference.addend as u64);
+
+            if let Some(sub) = &reference.subtrahend {
+                offset = offset.wrapping_sub(self.symbol_value(sub)?);
+            }
+
+            // Unwrap because every argument type has a constant size
+            resolved_references.insert(reference.argument_pos, ResolvedReference {
+                size: ConstantSize::from_u8(arg.get_size() as u8).unwrap(), value: offset as i64
+            });
+        }
+
+        for constant in instruction.constants.iter() {
+            resolved_references.insert(constant.argument_pos, ResolvedReference {
+                size: constant.size, value: constant.value
+            });
+        }
+
+        if self.warn_misaligned {
+            self.check_alignment(instruction, &resolved_references, start_position);
+        }
+
+        // Instructions are packed, operands follow the opcode in the order they are declared
+        for (pos, sym_arg) in instr_symbol.args.iter().enumerate() {
+            let arg = match resolved_references.get_mut(&(pos as u8)) {
+                Some(a) => a,
+                None => {
+                    return Err(format!("Argument {} of '{}' at {:#06x} is missing",
+                        pos, instr_symbol.name, start_position))
+                }
+            };
+            if arg.size.get_size() != sym_arg.get_size() {
+                return Err(format!("Argument {} of '{}' at {:#06x} has size {}, {} expected",
+                    pos, instr_symbol.name, start_position, arg.size.get_size(), sym_arg.get_size()))
+            }
+
+            // Calculate relative offset
+            if let ArgumentTypes::RelPointer = sym_arg {
+                arg.value = arg.value.wrapping_sub(start_position);
+                self.check_displacement(instruction, instr_symbol.name, pos as u8, arg.size,
+                    arg.value, start_position)?;
+            }
+            self.endianness().write(&mut bin, arg.size, arg.value as u64);
+        }
+
+        if resolved_references.len() > instr_symbol.args.len() {
+            return Err(format!("'{}' at {:#06x} is given more than its {} arguments",
+                instr_symbol.name, start_position, instr_symbol.args.len()))
+        }
+
+        binary.append(&mut bin);
+
+        Ok(())
+    }
+
+    /**
+     * Fails if a relative operand can't reach its target, naming the target
+     * and how far away it is
+     */
+    fn check_displacement(&self, instruction: &InstructionData, name: &str, argument_pos: u8,
+        size: ConstantSize, displacement: i64, position: i64) -> Result<(), String>
+    {
+        let bits = size.get_size() * 8;
+        if bits >= 64 {
+            return Ok(())
+        }
+        let limit = 1i64 << (bits - 1);
+        if (-limit..limit).contains(&displacement) {
+            return Ok(())
+        }
+
+        let address = position.wrapping_add(displacement);
+        let target = match instruction.references.iter().find(|r| r.argument_pos == argument_pos) {
+            Some(r) => format!("'{}' ({:#06x})", r.expression(), address),
+            None => format!("{:#06x}", address)
+        };
+        Err(format!("Relative operand of '{}' at {:#06x} can't reach {}: distance {} does not fit in {} bytes",
+            name, position, target, displacement, size.get_size()))
+    }
+
+    fn check_alignment(&self, instruction: &InstructionData,
+        resolved_references: &HashMap<u8, ResolvedReference>, position: i64)
+    {
+        let instructions = Instructions::new();
+        let instr_symbol = instructions.get_instruction(instruction.opcode).unwrap();
+
+        let access_size = match instr_symbol.memory_access_size() {
+            Some(s) => s as i64,
+            None => return
+        };
+
+        for (i, arg) in instr_symbol.args.iter().enumerate() {
+            if !matches!(arg, ArgumentTypes::AbsPointer) {
+                continue
+            }
+            let address = match resolved_references.get(&(i as u8)) {
+                Some(r) => r.value,
+                None => continue
+            };
+            if address % access_size == 0 {
+                continue
+            }
+            let target = match instruction.references.iter().find(|r| r.argument_pos == i as u8) {
+                Some(r) => format!("'{}' ({:#06x})", r.expression(), address),
+                None => format!("{:#06x}", address)
+            };
+            self.warn(format!("misaligned {}-byte access to {} by '{}' at offset {:#06x}",
+                access_size, target, instr_symbol.name, position));
+        }
+    }
+
+    fn warn(&self, warning: String) {
+        eprintln!("Warning: {}", warning);
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /**
+     * Warnings given while linking the last image
+     */
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
+    /**
+     * Whether a symbol is defined by an object, by the link script, or is the size of a defined label
+     */
+    fn is_defined(&self, name: &str) -> bool {
+        self.find_section_with_label(name).is_some()
+            || self.link_structure.get_symbol(name).is_some()
+            || self.defsyms.iter().any(|s| s.name == name)
+            || self.boundary_section(name).is_some()
+            || size_symbol_label(name).is_some_and(|l| self.find_section_with_label(l).is_some())
+    }
+
+    /**
+     * Fails listing every undefined symbol referenced by an output section,
+     * with where each reference is and which object it came from
+     */
+    fn check_undefined_references(&self) -> Result<(), String> {
+        // Symbol -> descriptions of the places referencing it
+        let mut undefined = HashMap::<&str, Vec<String>>::new();
+        let instructions = Instructions::new();
+
+        for (sec_name, section) in self.section_symbols.iter() {
+            if self.link_structure.get_section(sec_name).is_none() {
+                continue
+            }
+
+            let code = section.instructions.iter().enumerate().flat_map(|(idx, instr)| {
+                let name = if instr.is_raw() {
+                    ".insn"
+                } else {
+                    instructions.get_instruction(instr.opcode).map_or("?", |i| i.name)
+                };
+                instr.references.iter()
+                    .flat_map(|r| r.symbols())
+                    .map(move |symbol| (symbol, idx, format!("instruction '{}'", name)))
+            });
+            let data = section.binary_data.iter().enumerate().flat_map(|(idx, unit)| {
+                unit.reference.iter()
+                    .flat_map(|r| r.symbols())
+                    .map(move |symbol| (symbol, idx, format!("data unit {}", idx)))
+            });
+
+            for (symbol, idx, what) in code.chain(data) {
+                if self.is_defined(symbol) {
+                    continue
+                }
+                let origin = match section.origins.iter().rev().find(|o| o.ptr <= idx as u64) {
+                    Some(o) => format!("{}({})", o.object, o.section),
+                    None => format!("<unknown object>")
+                };
+                undefined.entry(symbol).or_default().push(format!("{} at {}+{:#06x} in {}",
+                    what, sec_name, section.get_binary_position(idx as u64), origin));
+            }
+        }
+
+        if undefined.is_empty() {
+            return Ok(())
+        }
+
+        let mut symbols: Vec<(&str, Vec<String>)> = undefined.into_iter().collect();
+        symbols.sort();
+
+        let mut result = format!("{} undefined symbol{}:", symbols.len(), if symbols.len() == 1 { "" } else { "s" });
+        for (symbol, mut uses) in symbols {
+            uses.sort();
+            result += &format!("\n  '{}' referenced by:", symbol);
+            for place in uses {
+                result += &format!("\n    {}", place);
+            }
+        }
+
+        Err(result)
+    }
+
+    /**
+     * Absolute address of a label in the linked image.
+     * Undefined `<label>_size` symbols resolve to the size of `<label>`.
+     */
+    fn symbol_value(&self, name: &str) -> Result<u64, String> {
+        self.resolve_symbol(name, 0)
+    }
+
+    /**
+     * Link script symbols can be defined in terms of each other,
+     * `depth` counts how deep into such definitions the lookup is
+     */
+    fn resolve_symbol(&self, name: &str, depth: usize) -> Result<u64, String> {
+        let sec_name = match self.find_section_with_label(name) {
+            Some(s) => s,
+            None => {
+                // Object labels are found first, so they take over symbols the script provides
+                if let Some(symbol) = self.link_structure.get_symbol(name) {
+                    if depth > MAX_SCRIPT_SYMBOL_DEPTH {
+                        return Err(format!("Link script symbol '{}' is defined in terms of itself", name))
+                    }
+                    return symbol.value.evaluate(&self.link_structure, &|n| self.resolve_symbol(n, depth + 1))
+                }
+                if let Some((prefix, sec_name)) = self.boundary_section(name) {
+                    let start = self.get_section_offset(sec_name)?;
+                    let load_start = self.get_section_load_address(sec_name)?;
+                    let size = self.section_symbols[sec_name].get_binary_size() as u64;
+                    return Ok(match prefix {
+                        "__start_" => start,
+                        "__end_" => start + size,
+                        "__load_start_" => load_start,
+                        "__load_end_" => load_start + size,
+                        _ => size
+                    })
+                }
+                if let Some(label) = size_symbol_label(name) {
+                    if let Some(sec_name) = self.find_section_with_label(label) {
+                        // Unwrap because the label was just found in this section
+                        return Ok(self.section_symbols[sec_name].get_label_size(label).unwrap())
+                    }
+                }
+                return Err(format!("Failed to resolve reference '{}': Undefined reference.", name))
+            }
+        };
+        let section = &self.section_symbols[sec_name];
+
+        // Unwrap because previous statement, read it again pls;;;
+        let section_local_offset = section.get_label_binary_offset(name).unwrap();
+
+        let section_offset = self.get_section_offset(sec_name)?;
+
+        Ok(section_offset + section_local_offset)
+    }
+
+    fn write_binary_unit_binary(&self, binary: &mut Vec<u8>, unit: &BinaryUnit) -> Result<(), String> {
+        if let Some(reference) = &unit.reference {
+            let mut symbol_position = self.symbol_value(&reference.rf)?.wrapping_add(reference.addend as u64);
+
+            if let Some(sub) = &reference.subtrahend {
+                symbol_position = symbol_position.wrapping_sub(self.symbol_value(sub)?);
+            }
+
+            self.endianness().write(binary, reference.size, symbol_position);
+        } else if let Some(constant) = &unit.constant {
+            self.endianness().write(binary, constant.size, constant.value as u64);
+        } else if let Some(align) = &unit.alignment {
+            // Unwrap because alignment units always have a size
+            for _ in 0..unit.get_size(binary.len() as u64).unwrap() {
+                binary.push(align.fill);
+            }
+        } else {
+            return Err(format!("Binary unit contains no information to write!"))
+        }
+        Ok(())
+    }
+
+    fn section_binary(&self, binary: &mut Vec<u8>, base: u64, section: &SectionData) -> Result<(), String> {
+        if section.binary_section {
+            for unit in section.binary_data.iter() {
+                self.write_binary_unit_binary(binary, unit)?;
+            }
+            //binary.append(&mut section.binary_data.clone());
+        } else {
+            for instruction in section.instructions.iter() {
+                self.write_instruction_binary(binary, base, instruction)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn load_link_structure(&mut self, ls_path: Option<&str>) -> Result<(), String> {
+        self.link_structure = match ls_path {
+            Some(lsp) => LinkStructure::from_file(lsp)?,
+            None => LinkStructure::new()
+        };
+        for symbol in self.defsyms.iter() {
+            self.link_structure.symbols.retain(|s| s.name != symbol.name);
+            self.link_structure.symbols.push(symbol.clone());
+        }
+        if self.fill.is_some() {
+            self.link_structure.fill = self.fill;
+        }
+        for (name, fill) in self.section_fills.iter() {
+            match self.link_structure.sections.iter_mut().find(|s| s.name == *name) {
+                Some(section) => section.fill = Some(*fill),
+                None => {
+                    return Err(format!("Cannot set the fill of section '{}': the link script doesn't place it", name))
+                }
+            }
+        }
+        if self.live_trace.is_none() {
+            self.live_trace = Some(self.trace_live_sections());
+        }
+        let stand_ins = if self.gc_sections {
+            self.collect_garbage()
+        } else {
+            HashMap::new()
+        };
+        self.gather_input_sections()?;
+        self.merge_subsections()?;
+
+        for (name, sec) in stand_ins {
+            if self.link_structure.get_section(&name).is_some() && !self.section_symbols.contains_key(&name) {
+                self.section_symbols.insert(name, sec);
+            }
+        }
+        if let (Some(entry), false) = (self.entry.clone(), self.image_header) {
+            self.insert_boot_stub(&entry)?;
+        }
+        self.allocate_commons()?;
+
+        for symbol in self.link_structure.symbols.iter().filter(|s| !s.provide) {
+            if let Some(sec_name) = self.find_section_with_label(&symbol.name) {
+                let source = match self.defsyms.iter().any(|s| s.name == symbol.name) {
+                    true => "with --defsym",
+                    false => "in the link script"
+                };
+                return Err(format!("Symbol '{}' is assigned {} but also defined in section '{}'",
+                    symbol.name, source, sec_name))
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Puts 'jpr <entry>' at the very start of the first output section, so an
+     * image without a header starts executing at its entry point
+     */
+    fn insert_boot_stub(&mut self, entry: &str) -> Result<(), String> {
+        let first = match self.link_structure.sections.first() {
+            Some(s) => s.name.clone(),
+            None => return Ok(())
+        };
+
+        let mut stub = SectionData::boot_stub(entry);
+        stub.name = first.clone();
+        stub.origins.push(SectionOrigin {
+            object: BOOT_STUB_OBJECT.to_string(),
+            section: first.clone(),
+            ptr: 0
+        });
+
+        if let Some(sec) = self.section_symbols.remove(&first) {
+            // Loading the link structure again must not add a second stub
+            if sec.origins.first().is_some_and(|o| o.object == BOOT_STUB_OBJECT) {
+                self.section_symbols.insert(first, sec);
+                return Ok(())
+            }
+            if sec.binary_section || sec.is_nobits() {
+                return Err(format!("Cannot put the boot stub jumping to '{}' into section '{}': \
+                the first section must hold code", entry, first))
+            }
+            stub.flags = sec.flags;
+            stub.append_other(sec)?;
+        }
+
+        self.section_symbols.insert(first, stub);
+
+        Ok(())
+    }
+
+    /**
+     * Places every common symbol that no object defines at the end of "bss"
+     */
+    fn allocate_commons(&mut self) -> Result<(), String> {
+        let mut commons: Vec<CommonSymbol> = std::mem::take(&mut self.commons).into_values()
+            .filter(|common| self.find_section_with_label(&common.name).is_none())
+            .collect();
+        commons.sort_by(|a, b| a.name.cmp(&b.name));
+
+        if commons.is_empty() {
+            return Ok(())
+        }
+
+        let bss = self.section_symbols.entry(COMMON_SECTION_NAME.to_string())
+            .or_insert_with(|| SectionData::nobits(COMMON_SECTION_NAME));
+        if !bss.is_nobits() {
+            return Err(format!("Cannot allocate common symbols: section '{}' is not a noload section",
+                COMMON_SECTION_NAME))
+        }
+
+        bss.origins.push(SectionOrigin {
+            object: "<common symbols>".to_string(),
+            section: COMMON_SECTION_NAME.to_string(),
+            ptr: bss.bss_size
+        });
+
+        for common in commons {
+            bss.bss_size = bss.bss_size.next_multiple_of(common.alignment);
+            bss.labels.insert(common.name.clone(), ObjectLabelSymbol {
+                name: common.name,
+                ptr: bss.bss_size,
+                binding: SymbolBinding::Global
+            });
+            bss.bss_size += common.size;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Gathers the input sections matching the patterns of each output section
+     * into it, in pattern order and by name for each pattern. Every input goes
+     * to the first output section that matches it, output sections without
+     * patterns claim the input section with their own name.
+     */
+    fn gather_input_sections(&mut self) -> Result<(), String> {
+        for (output, inputs) in self.claim_input_sections() {
+            // Unwrap because the claims were made from the link structure
+            let sec = self.link_structure.get_section(&output).unwrap();
+            if sec.inputs.is_empty() {
+                continue
+            }
+
+            let mut gathered: Option<SectionData> = None;
+
+            for (name, _) in inputs {
+                // Unwrap because the names were collected from the map
+                let mut sec = self.section_symbols.remove(&name).unwrap();
+                if sec.is_empty() {
+                    continue
+                }
+                match gathered.as_mut() {
+                    Some(g) => g.append_other(sec)?,
+                    None => {
+                        sec.name = output.clone();
+                        gathered = Some(sec);
+                    }
+                }
+            }
+
+            if let Some(sec) = gathered {
+                self.section_symbols.insert(output, sec);
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Lists the input sections each output section claims, in the order
+     * they are gathered, with whether a KEEP pattern matched them
+     */
+    fn claim_input_sections(&self) -> Vec<(String, Vec<(String, bool)>)> {
+        let mut unclaimed: Vec<String> = self.section_symbols.keys().cloned().collect();
+        unclaimed.sort();
+
+        let mut claims = Vec::new();
+
+        for output in self.link_structure.sections.iter() {
+            let mut claimed = Vec::<(String, bool)>::new();
+
+            if output.inputs.is_empty() {
+                if let Some(pos) = unclaimed.iter().position(|name| *name == output.name) {
+                    claimed.push((unclaimed.remove(pos), false));
+                }
+            }
+
+            for input in output.inputs.iter() {
+                let (matched, rest): (Vec<String>, Vec<String>) = unclaimed.into_iter()
+                    .partition(|name| input.matches(name));
+                unclaimed = rest;
+                claimed.extend(matched.into_iter().map(|name| (name, input.keep)));
+            }
+
+            claims.push((output.name.clone(), claimed));
+        }
+
+        claims
+    }
+
+    /**
+     * Drops the input sections nothing reaches from the start of the image.
+     * 
+     * The walk starts from every root of `gc_roots`, not just the first
+     * section. Returns an empty stand-in for every output section that lost
+     * all of its inputs, so the layout still finds it.
+     */
+    fn collect_garbage(&mut self) -> HashMap<String, SectionData> {
+        let claims = self.claim_input_sections();
+
+        // Input section -> output section it ends up in
+        let outputs: HashMap<String, String> = claims.iter()
+            .flat_map(|(output, inputs)| inputs.iter().map(move |(input, _)| (input.clone(), output.clone())))
+            .collect();
+        let roots = self.gc_roots(&claims).into_iter().map(|(name, _)| name).collect();
+
+        let live = self.reachable_sections(roots);
+
+        let mut garbage: Vec<String> = self.section_symbols.keys()
+            .filter(|name| !live.contains_key(*name))
+            .cloned()
+            .collect();
+        garbage.sort();
+
+        let mut stand_ins = HashMap::<String, SectionData>::new();
+
+        for name in garbage {
+            // Unwrap because the names were collected from the map
+            let sec = self.section_symbols.remove(&name).unwrap();
+            let output = match outputs.get(&name) {
+                Some(o) => o.clone(),
+                None => name.split('.').next().unwrap_or(&name).to_string()
+            };
+
+            stand_ins.entry(output.clone()).or_insert_with(|| {
+                let mut empty = sec.empty_like();
+                empty.name = output;
+                empty
+            });
+            if !self.removed_sections.contains(&name) {
+                self.removed_sections.push(name);
+            }
+        }
+
+        stand_ins
+    }
+
+    /**
+     * Input sections dropped by garbage collection
+     */
+    pub fn removed_sections(&self) -> &[String] {
+        &self.removed_sections
+    }
+
+    /**
+     * Folds subsections like "text.init" into their parent "text".
+     * 
+     * Subsections come first, ordered by the part after the dot, so
+     * "text.0vectors" lands before "text.1init", which lands before
+     * anything in plain "text". Subsections named in the linker script
+     * are kept as separate sections.
+     */
+    fn merge_subsections(&mut self) -> Result<(), String> {
+        let mut subsections: Vec<String> = self.section_symbols.keys()
+            .filter(|name| name.contains('.'))
+            .filter(|name| self.link_structure.get_section(name).is_none())
+            .cloned()
+            .collect();
+        subsections.sort();
+
+        let mut merged = HashMap::<String, SectionData>::new();
+
+        for name in subsections {
+            // Unwraps because the names were just collected from the map
+            let sub = self.section_symbols.remove(&name).unwrap();
+            let parent = name.split('.').next().unwrap().to_string();
+
+            if sub.is_empty() {
+                continue
+            }
+            match merged.get_mut(&parent) {
+                Some(sec) => sec.append_other(sub)?,
+                None => {
+                    let mut sec = sub;
+                    sec.name = parent.clone();
+                    merged.insert(parent, sec);
+                }
+            }
+        }
+
+        for (parent, mut sec) in merged {
+            if let Some(own) = self.section_symbols.remove(&parent) {
+                if !own.is_empty() {
+                    sec.append_other(own)?;
+                }
+            }
+            self.section_symbols.insert(parent, sec);
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Deduplicates labeled runs of constants in read-only binary sections.
+     * 
+     * A run starts at a label and ends at the next one. When two runs hold
+     * the same bytes, the later one is dropped and its labels point to the
+     * earlier one. Runs whose labels take part in label differences are kept,
+     * since their distance to the next label matters.
+     */
+    fn merge_constant_runs(&mut self) {
+        let measured: HashSet<String> = self.section_symbols.values()
+            .flat_map(|sec| {
+                let code = sec.instructions.iter().flat_map(|i| i.references.iter())
+                    .filter(|r| r.subtrahend.is_some())
+                    .flat_map(|r| r.symbols().cloned().collect::<Vec<_>>());
+                let data = sec.binary_data.iter().filter_map(|u| u.reference.as_ref())
+                    .filter(|r| r.subtrahend.is_some())
+                    .flat_map(|r| r.symbols().cloned().collect::<Vec<_>>());
+                code.chain(data).collect::<Vec<_>>()
+            })
+            .collect();
+
+        for sec in self.section_symbols.values_mut() {
+            if !sec.binary_section || sec.flags.contains(SectionFlags::WRITE) {
+                continue
+            }
+
+            let mut starts: Vec<u64> = sec.labels.values().map(|l| l.ptr).collect();
+            starts.sort();
+            starts.dedup();
+
+            let mut first_run = HashMap::<Vec<(u8, i64)>, u64>::new();
+            // Start of a dropped run -> start of the run replacing it
+            let mut redirects = HashMap::<u64, u64>::new();
+            let mut removed = vec![false; sec.binary_data.len()];
+
+            for (i, &start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).copied().unwrap_or(sec.binary_data.len() as u64);
+
+                let units = &sec.binary_data[start as usize..end as usize];
+                let key: Option<Vec<(u8, i64)>> = units.iter()
+                    .map(|u| u.constant.as_ref().map(|c| (c.size.get_size() as u8, c.value)))
+                    .collect();
+                let key = match key {
+                    Some(k) if !k.is_empty() => k,
+                    _ => continue
+                };
+
+                let is_measured = sec.labels.values()
+                    .any(|l| l.ptr == start && measured.contains(&l.name));
+                if is_measured {
+                    continue
+                }
+
+                match first_run.get(&key) {
+                    Some(&original) => {
+                        redirects.insert(start, original);
+                        for idx in start..end {
+                            removed[idx as usize] = true;
+                        }
+                    }
+                    None => {
+                        first_run.insert(key, start);
+                    }
+                }
+            }
+
+            if redirects.is_empty() {
+                continue
+            }
+
+            // New index of every unit, plus one past the end
+            let mut new_index = Vec::<u64>::with_capacity(removed.len() + 1);
+            let mut kept = 0u64;
+            for is_removed in removed.iter() {
+                new_index.push(kept);
+                if !is_removed {
+                    kept += 1;
+                }
+            }
+            new_index.push(kept);
+
+            for label in sec.labels.values_mut() {
+                let ptr = *redirects.get(&label.ptr).unwrap_or(&label.ptr);
+                label.ptr = new_index[ptr as usize];
+            }
+            for origin in sec.origins.iter_mut() {
+                origin.ptr = new_index[origin.ptr as usize];
+            }
+
+            let mut idx = 0;
+            sec.binary_data.retain(|_| {
+                idx += 1;
+                !removed[idx - 1]
+            });
+        }
+    }
+
+    pub fn generate_binary(&mut self, ls_path: Option<&str>) -> Result<Vec<u8>, String> {
+        self.warnings.get_mut().clear();
+        self.load_link_structure(ls_path)?;
+
+        if self.merge_constants {
+            self.merge_constant_runs();
+        }
+
+        self.check_undefined_references()?;
+        // Lays the sections out again every time islands are added
+        self.insert_veneers()?;
+
+        for (sec_name, section) in self.section_symbols.iter() {
+            let mut section_bin = Vec::<u8>::new();
+            // Sections the link script doesn't place are never output
+            let base = self.get_section_offset(sec_name).unwrap_or(0);
+            self.section_binary(&mut section_bin, base, section)?;
+            self.section_binaries.insert(sec_name.clone(), section_bin);
+        }
+
+        // Loadable sections of every bank with where they are stored, in link order
+        let mut placed = BTreeMap::<Option<u64>, Vec<(u64, &str)>>::new();
+        // Noload section of each memory region of each bank, if any
+        let mut nobits_sections = HashMap::<(Option<&str>, Option<u64>), &str>::new();
+
+        for section in self.link_structure.sections.iter() {
+            let region = (section.region.as_deref(), section.bank);
+
+            if let Some(sec) = self.section_symbols.get(&section.name) {
+                // Noload sections get addresses but no bytes, so they can only come last
+                if sec.is_nobits() {
+                    nobits_sections.insert(region, &section.name);
+                    continue
+                }
+                if let Some(nobits) = nobits_sections.get(&region) {
+                    return Err(format!("Section '{}' is placed after noload section '{}': \
+                    noload sections must come after every loadable section", section.name, nobits))
+                }
+
+                let code_alignment = sec.get_code_alignment();
+                if section.alignment % code_alignment != 0 {
+                    return Err(format!("Section '{}' is aligned to {} bytes internally but is only aligned \
+                    to {:#x} bytes in linker script", section.name, code_alignment, section.alignment))
+                }
+            }
+
+            if !self.section_binaries.contains_key(&section.name) {
+                return Err(format!("Undefined reference to section '{}': \
+                linker section is defined but not found in binaries!", section.name))
+            }
+
+            placed.entry(section.bank).or_default()
+                .push((self.get_section_load_address(&section.name)?, &section.name));
+        }
+
+        self.check_regions()?;
+        self.check_overlaps()?;
+        self.check_assertions()?;
+
+        let mut images = BTreeMap::<Option<u64>, Vec<u8>>::new();
+
+        for (bank, mut sections) in placed {
+            // The image starts at the lowest loadable section, gaps between regions are zero filled
+            sections.sort_by_key(|(offset, _)| *offset);
+            let base = sections.first().map_or(0, |(offset, _)| *offset);
+
+            let binary = images.entry(bank).or_default();
+
+            for (offset, sec_name) in sections {
+                let position = base + binary.len() as u64;
+                if offset > position {
+                    binary.resize(binary.len() + (offset - position) as usize, self.link_structure.fill.unwrap_or(0));
+                } else if offset < position {
+                    return Err(format!("Section '{}' is placed at {:#06x}, but the image already extends to {:#06x}",
+                        sec_name, offset, position))
+                }
+
+                // Unwrap because every placed section was checked to have a binary
+                binary.append(self.section_binaries.get_mut(sec_name).unwrap());
+
+                let link_section = self.link_structure.get_section(sec_name).unwrap();
+                let end = offset + self.section_symbols[sec_name].get_binary_size() as u64;
+
+                let alignment_bit_count = calculate_alignment!(end, link_section.alignment) - end;
+
+                // God forgive me
+                for _ in 0..alignment_bit_count {
+                    binary.push(self.link_structure.fill_for(link_section));
+                }
+            }
+        }
+
+        for name in self.trace_symbols.clone() {
+            let message = match self.symbol_value(&name) {
+                Ok(value) => {
+                    let origin = match self.find_section_with_label(&name) {
+                        Some(sec_name) => self.label_origin(sec_name, &name),
+                        None => format!("the linker")
+                    };
+                    format!("{} resolved to {:#010x}, defined in {}", name, value, origin)
+                }
+                Err(_) => format!("{} is not defined", name)
+            };
+            self.trace(message);
+        }
+
+        // Banks are padded like the image, so every bank has the same size
+        if let Some((size, fill)) = self.pad_to {
+            let fill = fill.or(self.link_structure.fill).unwrap_or(0);
+            for (bank, binary) in images.iter_mut() {
+                if binary.len() as u64 > size {
+                    let what = match bank {
+                        Some(b) => format!("Bank {}", b),
+                        None => format!("Image")
+                    };
+                    return Err(format!("{} is {} bytes, {} more than the {} it is padded to",
+                        what, binary.len(), binary.len() as u64 - size, size))
+                }
+                binary.resize(size as usize, fill);
+            }
+        }
+
+        let mut binary = images.remove(&None).unwrap_or_default();
+        self.apply_checksums(&mut binary)?;
+        self.bank_images = images.into_iter()
+            .map(|(bank, image)| (bank.unwrap(), image))
+            .collect();
+
+        Ok(binary)
+    }
+
+    /**
+     * Computes the link script's checksums over the image and writes them
+     * in, one after another, so a checksum can cover the ones before it
+     */
+    fn apply_checksums(&self, binary: &mut [u8]) -> Result<(), String> {
+        if self.link_structure.checksums.is_empty() {
+            return Ok(())
+        }
+        let base = self.image_base()?;
+        let image_end = base + binary.len() as u64;
+
+        for checksum in self.link_structure.checksums.iter() {
+            let evaluate = |expr: &ScriptExpression| expr.evaluate(&self.link_structure, &|n| self.symbol_value(n));
+            let start = evaluate(&checksum.start)?;
+            let end = evaluate(&checksum.end)?;
+            let location = evaluate(&checksum.location)?;
+            let location_end = location + checksum.algorithm.size() as u64;
+
+            if start > end || start < base || end > image_end {
+                return Err(format!("Checksum range {:#06x}..{:#06x} is not inside the image ({:#06x}..{:#06x})",
+                    start, end, base, image_end))
+            }
+            if location < base || location_end > image_end {
+                return Err(format!("Checksum location {:#06x} is not inside the image ({:#06x}..{:#06x})",
+                    location, base, image_end))
+            }
+            if location < end && start < location_end {
+                return Err(format!("Checksum location {:#06x} is inside the range {:#06x}..{:#06x} it covers",
+                    location, start, end))
+            }
+
+            let value = checksum.algorithm.compute(&binary[(start - base) as usize..(end - base) as usize]);
+
+            let mut bytes = Vec::<u8>::new();
+            // Unwrap because checksums are 1, 2 or 4 bytes
+            self.endianness().write(&mut bytes, ConstantSize::from_u8(checksum.algorithm.size() as u8).unwrap(), value);
+            binary[(location - base) as usize..(location_end - base) as usize].copy_from_slice(&bytes);
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Images of the banks of the link script, by bank.
+     * Requires the binary to be generated.
+     */
+    pub fn bank_images(&self) -> &BTreeMap<u64, Vec<u8>> {
+        &self.bank_images
+    }
+
+    /**
+     * Symbol file of a bank: the address and name of every label in its
+     * sections, one per line, sorted by address.
+     * Requires the binary to be generated.
+     */
+    pub fn bank_symbols(&self, bank: u64) -> Result<String, String> {
+        let mut symbols = Vec::<(u64, &str)>::new();
+
+        for section in self.link_structure.sections.iter().filter(|s| s.bank == Some(bank)) {
+            if let Some(sec) = self.section_symbols.get(&section.name) {
+                for name in sec.labels.keys() {
+                    symbols.push((self.symbol_value(name)?, name));
+                }
+            }
+        }
+        symbols.sort();
+
+        let mut result = String::new();
+        for (value, name) in symbols {
+            result += &format!("{:#010x} {}\n", value, name);
+        }
+        Ok(result)
+    }
+
+    /**
+     * Sends relative branches that can't reach their target through an
+     * island of absolute jumps at the end of their section. Islands move
+     * the sections after them, so this repeats until every branch reaches.
+     */
+    fn insert_veneers(&mut self) -> Result<(), String> {
+        let instructions = Instructions::new();
+
+        loop {
+            self.layout_sections()?;
+
+            // (section, instruction index, argument position) of every branch to redirect
+            let mut far = Vec::<(String, usize, u8)>::new();
+
+            let mut sec_names: Vec<&String> = self.section_symbols.keys()
+                .filter(|name| self.link_structure.get_section(name).is_some())
+                .collect();
+            sec_names.sort();
+
+            for sec_name in sec_names {
+                let section = &self.section_symbols[sec_name];
+                if section.binary_section || section.is_nobits() {
+                    continue
+                }
+                let base = self.get_section_offset(sec_name)?;
+
+                for (idx, instruction) in section.instructions.iter().enumerate() {
+                    if instruction.is_raw() || instruction.get_alignment().is_some() {
+                        continue
+                    }
+                    let symbol = match instructions.get_instruction(instruction.opcode) {
+                        Some(s) => s,
+                        None => continue
+                    };
+                    let position = base + section.get_binary_position(idx as u64);
+
+                    for reference in instruction.references.iter().filter(|r| r.subtrahend.is_none()) {
+                        let arg = symbol.args.get(reference.argument_pos as usize);
+                        if !matches!(arg, Some(ArgumentTypes::RelPointer)) {
+                            continue
+                        }
+                        let target = self.symbol_value(&reference.rf)?.wrapping_add(reference.addend as u64);
+                        let displacement = target.wrapping_sub(position) as i64;
+
+                        // Absolute jumps can't go past 32 bits either
+                        if i32::try_from(displacement).is_err() && target <= u32::MAX as u64 {
+                            far.push((sec_name.clone(), idx, reference.argument_pos));
+                        }
+                    }
+                }
+            }
+
+            if far.is_empty() {
+                return Ok(())
+            }
+
+            for (sec_name, idx, argument_pos) in far {
+                // Unwraps because the branches were just found there
+                let section = self.section_symbols.get_mut(&sec_name).unwrap();
+                let reference = section.instructions[idx].references.iter_mut()
+                    .find(|r| r.argument_pos == argument_pos).unwrap();
+
+                let label = format!("__veneer_{}@{}", reference.expression(), sec_name);
+                let target = Reference {
+                    argument_pos: 0,
+                    rf: std::mem::replace(&mut reference.rf, label.clone()),
+                    subtrahend: None,
+                    addend: std::mem::take(&mut reference.addend)
+                };
+
+                if section.labels.contains_key(&label) {
+                    continue
+                }
+
+                let ptr = section.instructions.len() as u64;
+                if !section.origins.iter().any(|o| o.object == VENEER_OBJECT) {
+                    section.origins.push(SectionOrigin {
+                        object: VENEER_OBJECT.to_string(),
+                        section: sec_name.clone(),
+                        ptr
+                    });
+                }
+                section.labels.insert(label.clone(), ObjectLabelSymbol {
+                    name: label,
+                    ptr,
+                    binding: SymbolBinding::Local
+                });
+                section.instructions.push(InstructionData {
+                    opcode: 9, // jmp opcode
+                    references: vec![target],
+                    constants: Vec::new()
+                });
+            }
+        }
+    }
+
+    /**
+     * Evaluates the link script's assertions over the final layout
+     */
+    fn check_assertions(&self) -> Result<(), String> {
+        for assertion in self.link_structure.assertions.iter() {
+            let value = assertion.condition.evaluate(&self.link_structure, &|n| self.symbol_value(n))?;
+
+            if value == 0 {
+                return Err(match &assertion.message {
+                    Some(message) => format!("Link script assertion '{}' failed: {}", assertion.condition, message),
+                    None => format!("Link script assertion '{}' failed", assertion.condition)
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Checks that the sections placed in every memory region fit in it
+     */
+    fn check_regions(&self) -> Result<(), String> {
+        for section in self.link_structure.sections.iter() {
+            let size = match self.section_symbols.get(&section.name) {
+                Some(s) => s.get_binary_size() as u64,
+                None => continue
+            };
+
+            let mut placements = Vec::<(&String, u64)>::new();
+            if let Some(region) = &section.region {
+                placements.push((region, self.get_section_offset(&section.name)?));
+            }
+            if let (Some(region), Some(address)) = (&section.load_region, self.load_addresses.get(&section.name)) {
+                placements.push((region, *address));
+            }
+
+            for (region, offset) in placements {
+                // Unwrap because link structures are validated when loaded
+                let region = self.link_structure.get_region(region).unwrap();
+                let region_end = region.origin + region.length;
+
+                if offset < region.origin {
+                    return Err(format!("Section '{}' is placed at {:#06x}, before the start of memory region '{}' ({:#06x})",
+                        section.name, offset, region.name, region.origin))
+                }
+
+                if offset + size > region_end {
+                    return Err(format!("Section '{}' ({:#06x}..{:#06x}) overflows memory region '{}' \
+                    ({:#06x}..{:#06x}) by {} bytes", section.name, offset, offset + size,
+                    region.name, region.origin, region_end, offset + size - region_end))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Checks that no two output sections, noload ones included, share an
+     * address, and that no two sections are stored at the same place in the image.
+     * Sections of different banks can share addresses.
+     */
+    fn check_overlaps(&self) -> Result<(), String> {
+        let layout = self.section_layout()?;
+        let ranges: Vec<(u64, u64, &str)> = layout.iter()
+            .map(|(name, offset, size)| (*offset, offset + *size as u64, name.as_str()))
+            .collect();
+        let mut overlaps = overlapping_ranges(ranges, "overlap");
+
+        // Sections stored where they run were checked above
+        if !self.load_addresses.is_empty() {
+            let mut load_ranges = Vec::<(u64, u64, &str)>::new();
+            for (name, _, size) in layout.iter().filter(|(name, _, _)| !self.section_symbols[name].is_nobits()) {
+                let start = self.get_section_load_address(name)?;
+                load_ranges.push((start, start + *size as u64, name.as_str()));
+            }
+            overlaps.extend(overlapping_ranges(load_ranges, "are stored overlapping").into_iter()
+                .filter(|(_, first, second)| [first, second].iter().any(|n| self.load_addresses.contains_key(**n))));
+        }
+
+        // Sections of different banks are never switched in together
+        let bank = |name: &str| self.link_structure.get_section(name).unwrap().bank;
+        overlaps.retain(|(_, first, second)| match (bank(first), bank(second)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true
+        });
+
+        match overlaps.is_empty() {
+            true => Ok(()),
+            false => Err(overlaps.into_iter().map(|(message, _, _)| message).collect::<Vec<String>>().join("\n"))
+        }
+    }
+
+    /**
+     * Returns (name, offset, size) of every output section, in link order.
+     * Requires the link structure to be loaded.
+     */
+    pub fn section_layout(&self) -> Result<Vec<(String, u64, usize)>, String> {
+        let mut layout = Vec::new();
+
+        for section in self.link_structure.sections.iter() {
+            let size = match self.section_symbols.get(&section.name) {
+                Some(s) => s.get_binary_size(),
+                None => {
+                    return Err(format!("No section '{}' found!", section.name))
+                }
+            };
+            layout.push((section.name.clone(), self.get_section_offset(&section.name)?, size));
+        }
+
+        Ok(layout)
+    }
+
+    /**
+     * Describes the linked image: memory regions, output sections with the
+     * parts of objects they are made of, and the address of every symbol.
+     * Requires the binary to be generated.
+     */
+    pub fn link_map(&self) -> Result<String, String> {
+        let mut result = String::new();
+
+        if !self.link_structure.memory.is_empty() {
+            result += "Memory regions:\n";
+            for region in self.link_structure.memory.iter() {
+                result += &format!("\t{:<16} {:#010x} - {:#010x} ({} bytes)\n",
+                    region.name, region.origin, region.origin + region.length, region.length);
+            }
+            result += "\n";
+        }
+
+        result += "Output sections:\n";
+        for (name, offset, size) in self.section_layout()? {
+            let section = &self.section_symbols[&name];
+            let link_section = self.link_structure.get_section(&name).unwrap();
+            let mut region = match &link_section.region {
+                Some(r) => format!(" > {}", r),
+                None => String::new()
+            };
+            if let (Some(r), Some(address)) = (&link_section.load_region, self.load_addresses.get(&name)) {
+                region += &format!(" AT> {} ({:#010x})", r, address);
+            }
+            if let Some(bank) = link_section.bank {
+                region += &format!(" BANK({})", bank);
+            }
+            result += &format!("\t{:<16} {:#010x} {:>8} bytes{}\n", name, offset, size, region);
+
+            for (idx, origin) in section.origins.iter().enumerate() {
+                let start = section.get_binary_position(origin.ptr);
+                let end = match section.origins.get(idx + 1) {
+                    Some(next) => section.get_binary_position(next.ptr),
+                    None => size as u64
+                };
+                if start == end {
+                    continue
+                }
+                result += &format!("\t\t{:#010x} {:>8} bytes  {}({})\n",
+                    offset + start, end - start, origin.object, origin.section);
+            }
+        }
+
+        let mut symbols = Vec::<(u64, String, &str)>::new();
+        for (sec_name, section) in self.section_symbols.iter() {
+            if self.link_structure.get_section(sec_name).is_none() {
+                continue
+            }
+            for name in section.labels.keys() {
+                symbols.push((self.symbol_value(name)?, name.clone(), sec_name));
+            }
+        }
+        for symbol in self.link_structure.symbols.iter() {
+            if self.find_section_with_label(&symbol.name).is_none() {
+                symbols.push((self.symbol_value(&symbol.name)?, symbol.name.clone(), "*ABS*"));
+            }
+        }
+        symbols.sort();
+
+        result += "\nSymbols:\n";
+        for (value, name, sec_name) in symbols {
+            result += &format!("\t{:#010x} {:<16} {}\n", value, sec_name, name);
+        }
+
+        Ok(result)
+    }
+
+    /**
+     * Bytes each memory region and output section takes up.
+     * Requires the binary to be generated.
+     */
+    pub fn memory_usage(&self) -> Result<MemoryUsage, String> {
+        let layout = self.section_layout()?;
+        let mut usage = MemoryUsage { regions: Vec::new(), sections: Vec::new() };
+
+        for region in self.link_structure.memory.iter() {
+            // Regions are used up to the end of their last section, gaps included
+            let mut end = layout.iter()
+                .filter(|(name, _, _)| self.link_structure.get_section(name).unwrap().region.as_ref() == Some(&region.name))
+                .map(|(_, offset, size)| offset + *size as u64)
+                .max();
+            // Sections stored in the region use it too
+            for (name, _, size) in layout.iter() {
+                if self.link_structure.get_section(name).unwrap().load_region.as_ref() != Some(&region.name) {
+                    continue
+                }
+                if let Some(address) = self.load_addresses.get(name) {
+                    end = end.max(Some(address + *size as u64));
+                }
+            }
+            usage.regions.push(RegionUsage {
+                name: region.name.clone(),
+                origin: region.origin,
+                used: end.map_or(0, |e| e.saturating_sub(region.origin)),
+                total: region.length
+            });
+        }
+
+        for (name, offset, size) in layout {
+            let region = self.link_structure.get_section(&name).unwrap().region.clone();
+            usage.sections.push(SectionUsage { name, region, address: offset, size: size as u64 });
+        }
+
+        Ok(usage)
+    }
+
+    /**
+     * Header put before the image with `image_header`, so a loader knows
+     * where to put the image and where to start it. Fields are in the
+     * byte order of the target. Requires the binary to be generated.
+     *
+     * 0 - 4: magic ("SAIM")
+     * 4 - 6: version
+     * 6 - 8: section count
+     * 8 - 12: entry address, the load address if there's no entry point
+     * 12 - 16: load address of the image
+     * 16 - 20: image size
+     * sections: address (u32), size (u32), flags (u8), name length (u8), name
+     */
+    pub fn image_header(&self, image_size: usize) -> Result<Vec<u8>, String> {
+        let layout = self.section_layout()?;
+        let base = self.image_base()?;
+        let entry = match &self.entry {
+            Some(e) => self.symbol_value(e)?,
+            None => base
+        };
+
+        let address = |what: &str, value: u64| -> Result<u64, String> {
+            match value > u32::MAX as u64 {
+                true => Err(format!("Image header: {} {:#x} does not fit in 32 bits", what, value)),
+                false => Ok(value)
+            }
+        };
+
+        let endianness = self.endianness();
+        let mut header = Vec::<u8>::new();
+        endianness.write(&mut header, ConstantSize::DoubleWord, IMAGE_HEADER_MAGIC as u64);
+        endianness.write(&mut header, ConstantSize::Word, IMAGE_HEADER_VERSION as u64);
+        endianness.write(&mut header, ConstantSize::Word, layout.len() as u64);
+        endianness.write(&mut header, ConstantSize::DoubleWord, address("entry point", entry)?);
+        endianness.write(&mut header, ConstantSize::DoubleWord, address("load address", base)?);
+        endianness.write(&mut header, ConstantSize::DoubleWord, address("image size", image_size as u64)?);
+
+        for (name, offset, size) in layout {
+            if name.len() > u8::MAX as usize {
+                return Err(format!("Image header: section name '{}' is longer than 255 bytes", name))
+            }
+            endianness.write(&mut header, ConstantSize::DoubleWord, address("section address", offset)?);
+            endianness.write(&mut header, ConstantSize::DoubleWord, address("section size", size as u64)?);
+            header.push(self.section_symbols[&name].flags.0);
+            header.push(name.len() as u8);
+            header.extend_from_slice(name.as_bytes());
+        }
+
+        Ok(header)
+    }
+
+    /**
+     * Address the image starts at: where the lowest loadable section is stored.
+     * Requires the link structure to be loaded.
+     */
+    pub fn image_base(&self) -> Result<u64, String> {
+        self.bank_base(None)
+    }
+
+    /**
+     * Address the image of a bank, or the main image with None, starts at
+     */
+    fn bank_base(&self, bank: Option<u64>) -> Result<u64, String> {
+        let layout = self.section_layout()?;
+        let mut base: Option<u64> = None;
+        for (name, _, _) in layout.iter() {
+            if self.section_symbols[name].is_nobits() || self.link_structure.get_section(name).unwrap().bank != bank {
+                continue
+            }
+            let address = self.get_section_load_address(name)?;
+            base = Some(base.map_or(address, |b| b.min(address)));
+        }
+        Ok(base.unwrap_or(0))
+    }
+
+    /**
+     * Describes the image as ELF sections and symbols.
+     * Requires the binary to be generated.
+     */
+    pub fn elf_image(&self, image: &[u8]) -> Result<ElfImage, String> {
+        let layout = self.section_layout()?;
+        let base = self.image_base()?;
+
+        let mut sections = Vec::<ElfSection>::new();
+        for (name, offset, size) in layout.iter() {
+            let section = &self.section_symbols[name];
+            let load_address = self.get_section_load_address(name)?;
+            let data = match section.is_nobits() {
+                true => Vec::new(),
+                false => {
+                    let start = (load_address - base) as usize;
+                    image[start..start + size].to_vec()
+                }
+            };
+            sections.push(ElfSection {
+                name: name.clone(),
+                address: *offset,
+                load_address,
+                size: *size as u64,
+                data,
+                flags: section.flags
+            });
+        }
+
+        let mut symbols = Vec::<ElfSymbol>::new();
+        for (idx, (sec_name, _, _)) in layout.iter().enumerate() {
+            let section = &self.section_symbols[sec_name];
+            for label in section.labels.values() {
+                symbols.push(ElfSymbol {
+                    name: label.name.clone(),
+                    value: self.symbol_value(&label.name)?,
+                    size: section.get_label_size(&label.name).unwrap_or(0),
+                    section: Some(idx),
+                    binding: label.binding
+                });
+            }
+        }
+        for symbol in self.link_structure.symbols.iter() {
+            if self.find_section_with_label(&symbol.name).is_none() {
+                symbols.push(ElfSymbol {
+                    name: symbol.name.clone(),
+                    value: self.symbol_value(&symbol.name)?,
+                    size: 0,
+                    section: None,
+                    binding: SymbolBinding::Global
+                });
+            }
+        }
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let entry = match &self.entry {
+            Some(e) => self.symbol_value(e)?,
+            None => base
+        };
+
+        Ok(ElfImage { endianness: self.endianness(), entry, sections, symbols })
+    }
+
+    /**
+     * Writes an image loaded at `base` in the output format
+     */
+    fn encode_image(&self, bin: Vec<u8>, base: u64, entry: Option<u64>, path: &str) -> Result<Vec<u8>, String> {
+        Ok(match self.output_format {
+            OutputFormat::Binary => bin,
+            OutputFormat::IntelHex => {
+                hexfile::intel_hex(&bin, base, self.record_size, entry)?.into_bytes()
+            }
+            OutputFormat::Elf => self.elf_image(&bin)?.to_bytes()?,
+            OutputFormat::SRecord(address_size) => {
+                let name = std::path::Path::new(path).file_name()
+                    .map_or(String::new(), |n| n.to_string_lossy().to_string());
+                hexfile::srecords(&bin, base, address_size, self.record_size, entry, &name)?
+                    .into_bytes()
+            }
+            OutputFormat::ReadMemH => {
+                hexfile::readmemh(&bin, base, self.memh_width, self.memh_depth,
+                    self.link_structure.fill.unwrap_or(0), self.endianness())?.into_bytes()
+            }
+            OutputFormat::CArray => {
+                let source_path = std::path::Path::new(path);
+                let header_path = source_path.with_extension("h");
+                if header_path == source_path {
+                    return Err(format!("Output '{}' would be overwritten by its own header", path))
+                }
+                let name = source_path.file_stem()
+                    .map_or("image".to_string(), |n| hexfile::c_identifier(&n.to_string_lossy()));
+                let header_name = header_path.file_name().unwrap().to_string_lossy().to_string();
+
+                let (source, header) = hexfile::c_array(&bin, base, entry, &name, &header_name);
+                write_output(&header_path.to_string_lossy(), header.as_bytes())?;
+                source.into_bytes()
+            }
+        })
+    }
+
+    pub fn save_binary(&mut self, path: &str, ls_path: Option<&str>) -> Result<(), String> {
+        let mut bin = self.generate_binary(ls_path)?;
+
+        if self.image_header {
+            if self.output_format != OutputFormat::Binary {
+                return Err(format!("The image header can only be written to binary output"))
+            }
+            if !self.bank_images.is_empty() {
+                return Err(format!("The image header can't describe banked output"))
+            }
+            let mut header = self.image_header(bin.len())?;
+            header.append(&mut bin);
+            bin = header;
+        }
+        if self.output_format == OutputFormat::Elf && !self.bank_images.is_empty() {
+            return Err(format!("Banked output can't be written as ELF"))
+        }
+
+        let entry = match &self.entry {
+            Some(e) => Some(self.symbol_value(e)?),
+            None => None
+        };
+
+        self.write_image(path, bin, self.image_base()?, entry)?;
+
+        // Every bank goes next to the image, 'game.bin' has 'game.bank1.bin' and 'game.bank1.sym'
+        for (bank, image) in self.bank_images.iter() {
+            let bank_path = output_file_name(path, &format!("bank{}", bank), None);
+            self.write_image(&bank_path, image.clone(), self.bank_base(Some(*bank))?, None)?;
+            write_output(&output_file_name(path, &format!("bank{}", bank), Some("sym")),
+                self.bank_symbols(*bank)?.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Writes an image in the output format, split into 'name.lane0.ext'
+     * and so on with `split_lanes`
+     */
+    fn write_image(&self, path: &str, bin: Vec<u8>, base: u64, entry: Option<u64>) -> Result<(), String> {
+        let lanes = match self.split_lanes {
+            Some(l) => l,
+            None => return write_output(path, &self.encode_image(bin, base, entry, path)?)
+        };
+        if self.output_format == OutputFormat::Elf {
+            return Err(format!("Split output can't be written as ELF"))
+        }
+
+        let (images, lane_base) = hexfile::split_lanes(&bin, base, lanes, self.split_stride.unwrap_or(1),
+            self.link_structure.fill.unwrap_or(0))?;
+        for (lane, image) in images.into_iter().enumerate() {
+            let lane_path = output_file_name(path, &format!("lane{}", lane), None);
+            write_output(&lane_path, &self.encode_image(image, lane_base, None, &lane_path)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionUsage {
+    pub name: String,
+    pub origin: u64,
+    pub used: u64,
+    pub total: u64
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SectionUsage {
+    pub name: String,
+    pub region: Option<String>,
+    pub address: u64,
+    pub size: u64
+}
+
+/**
+ * Memory usage report printed after linking, like the one of GNU ld
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryUsage {
+    pub regions: Vec<RegionUsage>,
+    pub sections: Vec<SectionUsage>
+}
+
+/**
+ * Size in bytes, or in KB and MB when it is a whole number of them
+ */
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 && b % (1 << 20) == 0 => format!("{} MB", b >> 20),
+        b if b >= 1 << 10 && b % (1 << 10) == 0 => format!("{} KB", b >> 10),
+        b => format!("{} B", b)
+    }
+}
+
+impl MemoryUsage {
+    /**
+     * Table of every region with the sections placed in it, then the
+     * sections outside of any region
+     */
+    pub fn table(&self) -> String {
+        let row = |name: &str, used: u64, total: Option<u64>| {
+            let (total, percent) = match total {
+                Some(0) => (human_size(0), "-".to_string()),
+                Some(t) => (human_size(t), format!("{:.2}%", used as f64 * 100.0 / t as f64)),
+                None => ("-".to_string(), "-".to_string())
+            };
+            format!("{:<24} {:>10} {:>10} {:>9}\n", name, human_size(used), total, percent)
+        };
+
+        let mut result = format!("{:<24} {:>10} {:>10} {:>9}\n", "Region / section", "Used", "Total", "Used %");
+
+        for region in self.regions.iter() {
+            result += &row(&format!("{}:", region.name), region.used, Some(region.total));
+            for section in self.sections.iter().filter(|s| s.region.as_ref() == Some(&region.name)) {
+                result += &row(&format!("  {}", section.name), section.size, Some(region.total));
+            }
+        }
+        for section in self.sections.iter().filter(|s| s.region.is_none()) {
+            result += &row(&section.name, section.size, None);
+        }
+
+        result
+    }
+
+    pub fn to_json(&self) -> String {
+        // Unwrap because these structs always serialize
+        serde_json::to_string_pretty(self).unwrap()
     }
 }
@@ -0,0 +1,1032 @@
+use std::{fs, io::Read};
+
+use serde::{Serialize, Deserialize};
+use crate::objgen::crc32;
+
+/**
+ * Named range of the address space that sections are packed into
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub origin: u64,
+    pub length: u64
+}
+
+/**
+ * Pattern of input section names gathered into an output section,
+ * '*' matches any run of characters and '?' any single one
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputPattern {
+    pub pattern: String,
+    // Kept even if nothing references it
+    #[serde(default)]
+    pub keep: bool
+}
+
+impl InputPattern {
+    pub fn matches(&self, name: &str) -> bool {
+        glob_match(self.pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+/**
+ * Integer in hex (0x), binary (0b) or decimal, optionally scaled by a K or M suffix
+ */
+pub fn parse_number(word: &str) -> Option<u64> {
+    let (digits, scale) = match word.chars().last() {
+        Some('K') | Some('k') => (&word[..word.len() - 1], 1024),
+        Some('M') | Some('m') => (&word[..word.len() - 1], 1024 * 1024),
+        _ => (word, 1)
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2)
+    } else {
+        digits.parse::<u64>()
+    };
+
+    value.ok().and_then(|v| v.checked_mul(scale))
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkStructureSection {
+    pub name: String,
+    #[serde(default = "default_alignment")]
+    pub alignment: u64,
+    // Byte the section is padded to its alignment with, 0 if not given
+    #[serde(default)]
+    pub fill: Option<u8>,
+    // Memory region the section is placed in. Sections without one are packed from address 0
+    #[serde(default)]
+    pub region: Option<String>,
+    // Fixed address of the section, the sections after it are packed from its end
+    #[serde(default)]
+    pub address: Option<u64>,
+    // Memory region the section's bytes are stored in, when it runs from another one
+    #[serde(default)]
+    pub load_region: Option<String>,
+    // Bank the section is switched in with. Banks share addresses and are output as images of their own
+    #[serde(default)]
+    pub bank: Option<u64>,
+    // Input sections gathered into this one. Without any, it is made of the input section with its name
+    #[serde(default)]
+    pub inputs: Vec<InputPattern>
+}
+
+impl LinkStructureSection {
+    /**
+     * Whether the script keeps this section even if nothing references it
+     */
+    pub fn keeps(&self) -> bool {
+        self.inputs.iter().any(|i| i.keep)
+    }
+}
+
+fn default_alignment() -> u64 {
+    1
+}
+
+/**
+ * Integer expression of a link script, evaluated once sections are laid out
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptExpression {
+    Number(u64),
+    Symbol(String),
+    Origin(String),
+    Length(String),
+    Negate(Box<ScriptExpression>),
+    Binary(&'static str, Box<ScriptExpression>, Box<ScriptExpression>)
+}
+
+impl std::fmt::Display for ScriptExpression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{:#x}", n),
+            Self::Symbol(name) => write!(f, "{}", name),
+            Self::Origin(name) => write!(f, "ORIGIN({})", name),
+            Self::Length(name) => write!(f, "LENGTH({})", name),
+            Self::Negate(expr) => write!(f, "-{}", expr),
+            Self::Binary(op, left, right) => {
+                for (idx, side) in [left, right].iter().enumerate() {
+                    match side.as_ref() {
+                        Self::Binary(..) => write!(f, "({})", side)?,
+                        _ => write!(f, "{}", side)?
+                    }
+                    if idx == 0 {
+                        write!(f, " {} ", op)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ScriptExpression {
+    /**
+     * Evaluates the expression, looking up symbols with `symbol_value`
+     */
+    pub fn evaluate(&self, structure: &LinkStructure,
+        symbol_value: &dyn Fn(&str) -> Result<u64, String>) -> Result<u64, String>
+    {
+        let region = |name: &str| match structure.get_region(name) {
+            Some(r) => Ok(r),
+            None => Err(format!("Undefined memory region '{}' in link script", name))
+        };
+
+        match self {
+            Self::Number(n) => Ok(*n),
+            Self::Symbol(name) => symbol_value(name),
+            Self::Origin(name) => Ok(region(name)?.origin),
+            Self::Length(name) => Ok(region(name)?.length),
+            Self::Negate(expr) => Ok(expr.evaluate(structure, symbol_value)?.wrapping_neg()),
+            Self::Binary(op, left, right) => {
+                let left = left.evaluate(structure, symbol_value)?;
+                let right = right.evaluate(structure, symbol_value)?;
+
+                match *op {
+                    "+" => Ok(left.wrapping_add(right)),
+                    "-" => Ok(left.wrapping_sub(right)),
+                    "*" => Ok(left.wrapping_mul(right)),
+                    "/" => match left.checked_div(right) {
+                        Some(v) => Ok(v),
+                        None => Err(format!("Division by zero in link script"))
+                    },
+                    "&" => Ok(left & right),
+                    "|" => Ok(left | right),
+                    // Comparisons are unsigned and give 1 or 0
+                    "<" => Ok((left < right) as u64),
+                    ">" => Ok((left > right) as u64),
+                    "<=" => Ok((left <= right) as u64),
+                    ">=" => Ok((left >= right) as u64),
+                    "==" => Ok((left == right) as u64),
+                    "!=" => Ok((left != right) as u64),
+                    _ => Err(format!("Unknown operator '{}' in link script", op))
+                }
+            }
+        }
+    }
+
+    /**
+     * Names of the symbols the expression uses
+     */
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Self::Symbol(name) => vec![name.as_str()],
+            Self::Number(_) | Self::Origin(_) | Self::Length(_) => Vec::new(),
+            Self::Negate(expr) => expr.symbols(),
+            Self::Binary(_, left, right) => {
+                let mut symbols = left.symbols();
+                symbols.extend(right.symbols());
+                symbols
+            }
+        }
+    }
+}
+
+/**
+ * 'ASSERT(condition [, "message"])', fails the link if the condition is 0
+ */
+#[derive(Debug, Clone)]
+pub struct ScriptAssertion {
+    pub condition: ScriptExpression,
+    pub message: Option<String>
+}
+
+/**
+ * Checksum the linker computes over the image
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Crc32,
+    // Sum of the bytes, cut to 1, 2 or 4 bytes
+    Sum8, Sum16, Sum32
+}
+
+impl ChecksumAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "CRC32" => Some(Self::Crc32),
+            "SUM8" => Some(Self::Sum8),
+            "SUM16" => Some(Self::Sum16),
+            "SUM32" => Some(Self::Sum32),
+            _ => None
+        }
+    }
+
+    /**
+     * Bytes the checksum is written in
+     */
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Sum8 => 1,
+            Self::Sum16 => 2,
+            Self::Crc32 | Self::Sum32 => 4
+        }
+    }
+
+    pub fn compute(&self, data: &[u8]) -> u64 {
+        match self {
+            Self::Crc32 => crc32(data) as u64,
+            _ => {
+                let sum = data.iter().fold(0u64, |sum, b| sum.wrapping_add(*b as u64));
+                sum & (u64::MAX >> (64 - 8 * self.size()))
+            }
+        }
+    }
+}
+
+/**
+ * 'CHECKSUM(algorithm, start, end, location)', writes the checksum of the
+ * image bytes from start up to end at location once the image is made
+ */
+#[derive(Debug, Clone)]
+pub struct ScriptChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub start: ScriptExpression,
+    pub end: ScriptExpression,
+    pub location: ScriptExpression
+}
+
+/**
+ * Symbol defined by the link script: 'name = expression'
+ */
+#[derive(Debug, Clone)]
+pub struct SymbolAssignment {
+    pub name: String,
+    pub value: ScriptExpression,
+    // 'PROVIDE(name = expression)', only used if no object defines the symbol
+    pub provide: bool
+}
+
+impl SymbolAssignment {
+    /**
+     * Parses a lone 'name = expression', like the ones '--defsym' takes
+     */
+    pub fn parse(txt: &str) -> Result<Self, String> {
+        let mut parser = ScriptParser::new(txt);
+        let mut structure = LinkStructure {
+            memory: Vec::new(),
+            sections: Vec::new(),
+            fill: None,
+            symbols: Vec::new(),
+            assertions: Vec::new(),
+            checksums: Vec::new()
+        };
+
+        if !parser.assignment(&mut structure)? || parser.peek().is_some() {
+            return Err(format!("Expected 'name = expression', got '{}'", txt))
+        }
+
+        // Unwrap because the assignment was just parsed
+        Ok(structure.symbols.pop().unwrap())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinkStructure {
+    #[serde(default)]
+    pub memory: Vec<MemoryRegion>,
+    pub sections: Vec<LinkStructureSection>,
+    // Byte gaps and sections without a fill of their own are padded with, 0 if not given
+    #[serde(default)]
+    pub fill: Option<u8>,
+    // Only the script language can define symbols, assertions and checksums
+    #[serde(skip)]
+    pub symbols: Vec<SymbolAssignment>,
+    #[serde(skip)]
+    pub assertions: Vec<ScriptAssertion>,
+    #[serde(skip)]
+    pub checksums: Vec<ScriptChecksum>
+}
+
+impl LinkStructure {
+    /**
+     * Creates a default link structure
+     *
+     * Default structure includes sections: text, data, rodata (ordered)
+     * All sections by default are aligned to 0x100 bytes in hex
+     */
+    pub fn new() -> Self {
+        Self {
+            memory: Vec::new(),
+            symbols: Vec::new(),
+            assertions: Vec::new(),
+            checksums: Vec::new(),
+            fill: None,
+            sections: vec![
+                LinkStructureSection {
+                    name: "text".to_string(),
+                    alignment: 0x100,
+                    fill: None,
+                    region: None,
+                    address: None,
+                    load_region: None,
+                    bank: None,
+                    inputs: Vec::new()
+                },
+                LinkStructureSection {
+                    name: "data".to_string(),
+                    alignment: 0x100,
+                    fill: None,
+                    region: None,
+                    address: None,
+                    load_region: None,
+                    bank: None,
+                    inputs: Vec::new()
+                },
+                LinkStructureSection {
+                    name: "rodata".to_string(),
+                    alignment: 0x100,
+                    fill: None,
+                    region: None,
+                    address: None,
+                    load_region: None,
+                    bank: None,
+                    inputs: Vec::new()
+                },
+            ]
+        }
+    }
+
+    /**
+     * Byte a section is padded with
+     */
+    pub fn fill_for(&self, section: &LinkStructureSection) -> u8 {
+        section.fill.or(self.fill).unwrap_or(0)
+    }
+
+    pub fn get_section(&self, name: &str) -> Option<&LinkStructureSection> {
+        let mut sec_iter = self.sections.iter();
+
+        sec_iter.find(|x| x.name == name)
+    }
+
+    pub fn get_section_index(&self, name: &str) -> Option<usize> {
+        for (idx, sec) in self.sections.iter().enumerate() {
+            if sec.name == name {
+                return Some(idx)
+            }
+        }
+        None
+    }
+
+    pub fn get_region(&self, name: &str) -> Option<&MemoryRegion> {
+        self.memory.iter().find(|r| r.name == name)
+    }
+
+    pub fn get_symbol(&self, name: &str) -> Option<&SymbolAssignment> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let mut file = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(format!("Failed to open file '{}' for reading!\n{}", path, e))
+            }
+        };
+
+        let mut txt = String::new();
+
+        match file.read_to_string(&mut txt) {
+            Ok(l) => l,
+            Err(e) => {
+                return Err(format!("Error reading file '{}': {}", path, e))
+            }
+        };
+
+        Self::from_text(txt)
+    }
+
+    /**
+     * Parses a link script. Scripts starting with '{' are read as the
+     * older JSON section list, anything else as the script language.
+     */
+    pub fn from_text(txt: String) -> Result<Self, String> {
+        let link_struct = if txt.trim_start().starts_with('{') {
+            match serde_json::from_str::<LinkStructure>(&txt) {
+                Ok(l) => l,
+                Err(e) => {
+                    return Err(format!("Error occured while parsing JSON: {e}"))
+                }
+            }
+        } else {
+            ScriptParser::new(&txt).parse()?
+        };
+
+        link_struct.validate()?;
+
+        Ok(link_struct)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (idx, region) in self.memory.iter().enumerate() {
+            if self.memory[..idx].iter().any(|r| r.name == region.name) {
+                return Err(format!("Memory region '{}' is defined more than once", region.name))
+            }
+            if region.origin.checked_add(region.length).is_none() {
+                return Err(format!("Memory region '{}' extends past the end of the address space", region.name))
+            }
+        }
+
+        for (idx, symbol) in self.symbols.iter().enumerate() {
+            if self.symbols[..idx].iter().any(|s| s.name == symbol.name) {
+                return Err(format!("Symbol '{}' is assigned more than once", symbol.name))
+            }
+        }
+
+        for (idx, section) in self.sections.iter().enumerate() {
+            if self.sections[..idx].iter().any(|s| s.name == section.name) {
+                return Err(format!("Section '{}' is placed more than once", section.name))
+            }
+            if section.alignment == 0 {
+                return Err(format!("Section '{}' has zero alignment", section.name))
+            }
+            if let Some(address) = section.address {
+                if address % section.alignment != 0 {
+                    return Err(format!("Section '{}' is placed at {:#x}, which is not aligned to {:#x} bytes",
+                        section.name, address, section.alignment))
+                }
+            }
+            if let Some(region) = &section.region {
+                if self.get_region(region).is_none() {
+                    return Err(format!("Section '{}' is placed in undefined memory region '{}'",
+                        section.name, region))
+                }
+            }
+            if let Some(region) = &section.load_region {
+                if self.get_region(region).is_none() {
+                    return Err(format!("Section '{}' is loaded into undefined memory region '{}'",
+                        section.name, region))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for LinkStructure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ScriptToken {
+    Word(String),
+    Symbol(char),
+    // Two character operators like '<='
+    Operator(&'static str),
+    String(String)
+}
+
+impl std::fmt::Display for ScriptToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Word(w) => write!(f, "{}", w),
+            Self::Symbol(c) => write!(f, "{}", c),
+            Self::Operator(op) => write!(f, "{}", op),
+            Self::String(s) => write!(f, "\"{}\"", s)
+        }
+    }
+}
+
+/**
+ * Line a link script token is on, and whether it continues the previous token
+ */
+#[derive(Debug, Clone, Copy)]
+struct TokenPosition {
+    line: usize,
+    // Whether the token directly follows the previous one, without whitespace
+    glued: bool
+}
+
+/**
+ * Parser of the link script language:
+ *
+ * MEMORY {
+ *     ROM : ORIGIN = 0x0000, LENGTH = 32K
+ *     RAM : ORIGIN = 0x8000, LENGTH = 16K
+ *     BANKED : ORIGIN = 0xC000, LENGTH = 16K
+ * }
+ * __stack_top = ORIGIN(RAM) + LENGTH(RAM)
+ * SECTIONS {
+ *     vectors at 0x0000 > ROM
+ *     text : { *.text* KEEP(isr*) } ALIGN(0x100) FILL(0xFF) > ROM
+ *     rodata > ROM
+ *     data > RAM AT> ROM
+ *     level1 BANK(1) > BANKED
+ *     level2 BANK(2) > BANKED
+ * }
+ *
+ * '#' starts a comment until the end of the line. Sections without ALIGN
+ * are aligned to 1 byte and padded with zeroes unless FILL gives another byte,
+ * sections without a region are packed from address 0.
+ * Symbols can be assigned at the top level or between sections, their values
+ * are expressions over numbers, other symbols, ORIGIN(region) and LENGTH(region)
+ * with + - * / & | and parentheses, each optionally ended with ';'.
+ * 'PROVIDE(name = expression)' defines a default that objects can override.
+ * 'ASSERT(condition [, "message"])' fails the link when the condition,
+ * which can compare with < > <= >= == !=, is 0 after layout.
+ * 'CHECKSUM(CRC32, start, end, location)' writes the CRC-32 of the image
+ * bytes from start up to end at location, in the byte order of the target.
+ * SUM8, SUM16 and SUM32 write the sum of the bytes instead.
+ * Sections placed 'at' an address start there and the next ones follow them.
+ * 'AT> REGION' stores a section's bytes in another region than the one it
+ * runs from, after the sections already there; its labels keep their
+ * runtime addresses and startup code copies it over using the
+ * '__load_start_' and '__load_end_' symbols of the section.
+ * Sections in a 'BANK(n)' are packed separately from the other banks, so
+ * banks share their addresses, and every bank is output as its own image.
+ * An output section can gather input sections whose names match patterns
+ * in braces, each input going to the first section matching it. Patterns
+ * in KEEP(...) mark the section as used even if nothing references it.
+ */
+struct ScriptParser {
+    tokens: Vec<(ScriptToken, TokenPosition)>,
+    position: usize
+}
+
+impl ScriptParser {
+    fn new(txt: &str) -> Self {
+        let mut tokens = Vec::new();
+
+        for (line_idx, line) in txt.lines().enumerate() {
+            let mut word = String::new();
+            let mut word_glued = false;
+            // Whether the last character was part of a token
+            let mut after_token = false;
+            let mut chars = line.chars().peekable();
+            let position = |glued| TokenPosition { line: line_idx + 1, glued };
+
+            while let Some(c) = chars.next() {
+                if c.is_alphanumeric() || "_.$".contains(c) {
+                    if word.is_empty() {
+                        word_glued = after_token;
+                    }
+                    word.push(c);
+                    after_token = true;
+                    continue
+                }
+                if !word.is_empty() {
+                    tokens.push((ScriptToken::Word(std::mem::take(&mut word)), position(word_glued)));
+                }
+                if c == '#' {
+                    break
+                }
+                if c.is_whitespace() {
+                    after_token = false;
+                    continue
+                }
+                let glued = after_token;
+                after_token = true;
+
+                if c == '"' {
+                    let string: String = chars.by_ref().take_while(|c| *c != '"').collect();
+                    tokens.push((ScriptToken::String(string), position(glued)));
+                    continue
+                }
+                let pair = chars.peek().map(|next| format!("{}{}", c, next));
+                if let Some(op) = ["<=", ">=", "==", "!="].into_iter().find(|op| Some(op.to_string()) == pair) {
+                    chars.next();
+                    tokens.push((ScriptToken::Operator(op), position(glued)));
+                    continue
+                }
+                tokens.push((ScriptToken::Symbol(c), position(glued)));
+            }
+            if !word.is_empty() {
+                tokens.push((ScriptToken::Word(word), position(word_glued)));
+            }
+        }
+
+        Self { tokens, position: 0 }
+    }
+
+    fn peek(&self) -> Option<&ScriptToken> {
+        self.tokens.get(self.position).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Result<ScriptToken, String> {
+        match self.tokens.get(self.position) {
+            Some((t, _)) => {
+                self.position += 1;
+                Ok(t.clone())
+            }
+            None => Err(format!("Unexpected end of link script"))
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, String> {
+        let line = match self.tokens.get(self.position.saturating_sub(1)) {
+            Some((_, position)) => position.line,
+            None => 0
+        };
+        Err(format!("Link script line {}: {}", line, message))
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), String> {
+        match self.next()? {
+            ScriptToken::Symbol(c) if c == symbol => Ok(()),
+            t => self.error(format!("Expected '{}', found '{}'", symbol, t))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.next()? {
+            ScriptToken::Word(w) if w.eq_ignore_ascii_case(keyword) => Ok(()),
+            t => self.error(format!("Expected '{}', found '{}'", keyword, t))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next()? {
+            ScriptToken::Word(w) => Ok(w),
+            t => self.error(format!("Expected a name, found '{}'", t))
+        }
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        let word = match self.next()? {
+            ScriptToken::Word(w) => w,
+            t => return self.error(format!("Expected a number, found '{}'", t))
+        };
+
+        match parse_number(&word) {
+            Some(v) => Ok(v),
+            None => self.error(format!("Invalid number '{}'", word))
+        }
+    }
+
+    /**
+     * Returns which of `operators` the next token is, without consuming it
+     */
+    fn peek_operator(&self, operators: &[&'static str]) -> Option<&'static str> {
+        let text = match self.peek() {
+            Some(ScriptToken::Symbol(c)) => c.to_string(),
+            Some(ScriptToken::Operator(op)) => op.to_string(),
+            _ => return None
+        };
+        operators.iter().find(|op| **op == text).copied()
+    }
+
+    /**
+     * Left associative chain of `operators` between operands parsed by `operand`
+     */
+    fn binary_chain(&mut self, operators: &[&'static str],
+        operand: fn(&mut Self) -> Result<ScriptExpression, String>) -> Result<ScriptExpression, String>
+    {
+        let mut result = operand(self)?;
+
+        while let Some(op) = self.peek_operator(operators) {
+            self.position += 1;
+            result = ScriptExpression::Binary(op, Box::new(result), Box::new(operand(self)?));
+        }
+
+        Ok(result)
+    }
+
+    // Operators bind like in C: bitwise and/or, then comparisons, sums and products
+    fn expression(&mut self) -> Result<ScriptExpression, String> {
+        self.binary_chain(&["&", "|"], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<ScriptExpression, String> {
+        self.binary_chain(&["<", ">", "<=", ">=", "==", "!="], Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<ScriptExpression, String> {
+        self.binary_chain(&["+", "-"], Self::term)
+    }
+
+    fn term(&mut self) -> Result<ScriptExpression, String> {
+        self.binary_chain(&["*", "/"], Self::factor)
+    }
+
+    fn factor(&mut self) -> Result<ScriptExpression, String> {
+        match self.next()? {
+            ScriptToken::Symbol('(') => {
+                let result = self.expression()?;
+                self.expect_symbol(')')?;
+                Ok(result)
+            }
+            ScriptToken::Symbol('-') => Ok(ScriptExpression::Negate(Box::new(self.factor()?))),
+            ScriptToken::Word(w) if w.starts_with(|c: char| c.is_ascii_digit()) => {
+                match parse_number(&w) {
+                    Some(v) => Ok(ScriptExpression::Number(v)),
+                    None => self.error(format!("Invalid number '{}'", w))
+                }
+            }
+            ScriptToken::Word(w) if self.peek() == Some(&ScriptToken::Symbol('(')) => {
+                self.position += 1;
+                let region = self.name()?;
+                self.expect_symbol(')')?;
+
+                match w.to_uppercase().as_str() {
+                    "ORIGIN" => Ok(ScriptExpression::Origin(region)),
+                    "LENGTH" => Ok(ScriptExpression::Length(region)),
+                    _ => self.error(format!("Unknown function '{}'", w))
+                }
+            }
+            ScriptToken::Word(w) => Ok(ScriptExpression::Symbol(w)),
+            t => self.error(format!("Expected an expression, found '{}'", t))
+        }
+    }
+
+    /**
+     * Parses 'ASSERT(condition [, "message"]) [;]' if the next tokens are an assertion
+     */
+    fn assertion(&mut self, structure: &mut LinkStructure) -> Result<bool, String> {
+        match (self.tokens.get(self.position), self.tokens.get(self.position + 1)) {
+            (Some((ScriptToken::Word(w), _)), Some((ScriptToken::Symbol('('), _)))
+                if w.eq_ignore_ascii_case("ASSERT") => {},
+            _ => return Ok(false)
+        }
+        self.position += 2;
+
+        let condition = self.expression()?;
+        let mut message = None;
+        if self.peek() == Some(&ScriptToken::Symbol(',')) {
+            self.position += 1;
+            message = match self.next()? {
+                ScriptToken::String(s) => Some(s),
+                t => return self.error(format!("Expected a message string, found '{}'", t))
+            };
+        }
+        self.expect_symbol(')')?;
+        if self.peek() == Some(&ScriptToken::Symbol(';')) {
+            self.position += 1;
+        }
+
+        structure.assertions.push(ScriptAssertion { condition, message });
+
+        Ok(true)
+    }
+
+    /**
+     * Parses 'name = expression [;]' or 'PROVIDE(name = expression) [;]'
+     * if the next tokens are an assignment
+     */
+    fn assignment(&mut self, structure: &mut LinkStructure) -> Result<bool, String> {
+        let provide = match (self.tokens.get(self.position), self.tokens.get(self.position + 1)) {
+            (Some((ScriptToken::Word(w), _)), Some((ScriptToken::Symbol('('), _)))
+                if w.eq_ignore_ascii_case("PROVIDE") => true,
+            (Some((ScriptToken::Word(_), _)), Some((ScriptToken::Symbol('='), _))) => false,
+            _ => return Ok(false)
+        };
+        if provide {
+            self.position += 2;
+        }
+
+        let name = self.name()?;
+        self.expect_symbol('=')?;
+        let value = self.expression()?;
+        if provide {
+            self.expect_symbol(')')?;
+        }
+        if self.peek() == Some(&ScriptToken::Symbol(';')) {
+            self.position += 1;
+        }
+
+        structure.symbols.push(SymbolAssignment { name, value, provide });
+
+        Ok(true)
+    }
+
+    // 'CHECKSUM(algorithm, start, end, location)'
+    fn checksum(&mut self, structure: &mut LinkStructure) -> Result<bool, String> {
+        match (self.tokens.get(self.position), self.tokens.get(self.position + 1)) {
+            (Some((ScriptToken::Word(w), _)), Some((ScriptToken::Symbol('('), _)))
+                if w.eq_ignore_ascii_case("CHECKSUM") => {},
+            _ => return Ok(false)
+        }
+        self.position += 2;
+
+        let name = self.name()?;
+        let algorithm = match ChecksumAlgorithm::from_name(&name) {
+            Some(a) => a,
+            None => return self.error(format!("Unknown checksum '{}', expected CRC32, SUM8, SUM16 or SUM32", name))
+        };
+        self.expect_symbol(',')?;
+        let start = self.expression()?;
+        self.expect_symbol(',')?;
+        let end = self.expression()?;
+        self.expect_symbol(',')?;
+        let location = self.expression()?;
+        self.expect_symbol(')')?;
+        if self.peek() == Some(&ScriptToken::Symbol(';')) {
+            self.position += 1;
+        }
+
+        structure.checksums.push(ScriptChecksum { algorithm, start, end, location });
+
+        Ok(true)
+    }
+
+    fn parse(mut self) -> Result<LinkStructure, String> {
+        let mut structure = LinkStructure {
+            memory: Vec::new(),
+            sections: Vec::new(),
+            fill: None,
+            symbols: Vec::new(),
+            assertions: Vec::new(),
+            checksums: Vec::new()
+        };
+
+        while self.peek().is_some() {
+            if self.assignment(&mut structure)? || self.assertion(&mut structure)? || self.checksum(&mut structure)? {
+                continue
+            }
+            if matches!(self.peek(), Some(ScriptToken::Word(w)) if w.eq_ignore_ascii_case("FILL")) {
+                self.position += 1;
+                structure.fill = Some(self.fill_byte()?);
+                continue
+            }
+
+            let block = self.name()?;
+            self.expect_symbol('{')?;
+
+            match block.to_uppercase().as_str() {
+                "MEMORY" => self.parse_memory(&mut structure)?,
+                "SECTIONS" => self.parse_sections(&mut structure)?,
+                _ => return self.error(format!("Unknown block '{}', expected MEMORY or SECTIONS", block))
+            }
+        }
+
+        Ok(structure)
+    }
+
+    // '(byte)' after FILL
+    fn fill_byte(&mut self) -> Result<u8, String> {
+        self.expect_symbol('(')?;
+        let fill = self.number()?;
+        let fill = match u8::try_from(fill) {
+            Ok(b) => b,
+            Err(_) => return self.error(format!("Fill byte {:#x} doesn't fit in a byte", fill))
+        };
+        self.expect_symbol(')')?;
+
+        Ok(fill)
+    }
+
+    // 'NAME : ORIGIN = origin, LENGTH = length' until '}'
+    fn parse_memory(&mut self, structure: &mut LinkStructure) -> Result<(), String> {
+        loop {
+            if self.peek() == Some(&ScriptToken::Symbol('}')) {
+                self.position += 1;
+                return Ok(())
+            }
+
+            let name = self.name()?;
+            self.expect_symbol(':')?;
+            self.expect_keyword("ORIGIN")?;
+            self.expect_symbol('=')?;
+            let origin = self.number()?;
+            if self.peek() == Some(&ScriptToken::Symbol(',')) {
+                self.position += 1;
+            }
+            self.expect_keyword("LENGTH")?;
+            self.expect_symbol('=')?;
+            let length = self.number()?;
+
+            structure.memory.push(MemoryRegion { name, origin, length });
+        }
+    }
+
+    // 'name [: { inputs }] [at address] [ALIGN(alignment)] [FILL(byte)] [BANK(bank)] [> REGION] [AT> REGION]' until '}'
+    fn parse_sections(&mut self, structure: &mut LinkStructure) -> Result<(), String> {
+        loop {
+            if self.peek() == Some(&ScriptToken::Symbol('}')) {
+                self.position += 1;
+                return Ok(())
+            }
+            if self.assignment(structure)? || self.assertion(structure)? {
+                continue
+            }
+
+            let mut section = LinkStructureSection {
+                name: self.name()?,
+                alignment: default_alignment(),
+                fill: None,
+                region: None,
+                address: None,
+                load_region: None,
+                bank: None,
+                inputs: Vec::new()
+            };
+
+            loop {
+                match self.peek() {
+                    Some(ScriptToken::Word(w)) if w.eq_ignore_ascii_case("ALIGN") => {
+                        self.position += 1;
+                        self.expect_symbol('(')?;
+                        section.alignment = self.number()?;
+                        self.expect_symbol(')')?;
+                    }
+                    Some(ScriptToken::Word(w)) if w.eq_ignore_ascii_case("BANK") => {
+                        self.position += 1;
+                        self.expect_symbol('(')?;
+                        section.bank = Some(self.number()?);
+                        self.expect_symbol(')')?;
+                    }
+                    Some(ScriptToken::Word(w)) if w.eq_ignore_ascii_case("FILL") => {
+                        self.position += 1;
+                        section.fill = Some(self.fill_byte()?);
+                    }
+                    Some(ScriptToken::Word(w)) if w.eq_ignore_ascii_case("at") => {
+                        self.position += 1;
+                        if self.peek() == Some(&ScriptToken::Symbol('>')) {
+                            self.position += 1;
+                            section.load_region = Some(self.name()?);
+                        } else {
+                            section.address = Some(self.number()?);
+                        }
+                    }
+                    Some(ScriptToken::Symbol('>')) => {
+                        self.position += 1;
+                        section.region = Some(self.name()?);
+                    }
+                    Some(ScriptToken::Symbol(':')) => {
+                        self.position += 1;
+                        self.expect_symbol('{')?;
+                        self.parse_inputs(&mut section)?;
+                    }
+                    Some(ScriptToken::Symbol('{')) => {
+                        self.position += 1;
+                        self.parse_inputs(&mut section)?;
+                    }
+                    _ => break
+                }
+            }
+
+            structure.sections.push(section);
+        }
+    }
+
+    // 'pattern' or 'KEEP(pattern...)' until '}'
+    fn parse_inputs(&mut self, section: &mut LinkStructureSection) -> Result<(), String> {
+        loop {
+            match (self.tokens.get(self.position), self.tokens.get(self.position + 1)) {
+                (Some((ScriptToken::Symbol('}'), _)), _) => {
+                    self.position += 1;
+                    return Ok(())
+                }
+                (Some((ScriptToken::Word(w), _)), Some((ScriptToken::Symbol('('), _)))
+                    if w.eq_ignore_ascii_case("KEEP") => {
+                    self.position += 2;
+                    while self.peek() != Some(&ScriptToken::Symbol(')')) {
+                        let pattern = self.pattern()?;
+                        section.inputs.push(InputPattern { pattern, keep: true });
+                    }
+                    self.position += 1;
+                }
+                _ => {
+                    let pattern = self.pattern()?;
+                    section.inputs.push(InputPattern { pattern, keep: false });
+                }
+            }
+        }
+    }
+
+    /**
+     * Section name pattern, made of words and wildcards without whitespace between them
+     */
+    fn pattern(&mut self) -> Result<String, String> {
+        let mut pattern = String::new();
+
+        loop {
+            let part = match self.tokens.get(self.position) {
+                Some((ScriptToken::Word(w), position)) if pattern.is_empty() || position.glued => w.clone(),
+                Some((ScriptToken::Symbol(c), position)) if "*?".contains(*c) && (pattern.is_empty() || position.glued) => c.to_string(),
+                _ => break
+            };
+            pattern += &part;
+            self.position += 1;
+        }
+
+        if pattern.is_empty() {
+            let found = match self.peek() {
+                Some(t) => t.to_string(),
+                None => format!("end of file")
+            };
+            return self.error(format!("Expected a section name pattern, found '{}'", found))
+        }
+
+        Ok(pattern)
+    }
+}
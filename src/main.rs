@@ -1,20 +1,26 @@
 pub mod lexer;
+pub mod preprocessor;
 pub mod parser;
 pub mod symbols;
 pub mod objgen;
+pub mod linkscript;
 pub mod linker;
+pub mod archive;
+pub mod hexfile;
+pub mod elf;
 pub mod objdump;
 
 pub mod tests;
 
-use lexer::{AsmLexer, LexerToken};
+use lexer::{AsmLexer, LexerToken, OwnedToken};
 use objdump::Objdump;
 use parser::{Parser, ParserNode};
+use preprocessor::Preprocessor;
 use regex_lexer::Token;
 
-use crate::{objgen::ObjectFormat, linker::Linker};
+use crate::{objgen::{ObjectFormat, ObjectSink, Endianness}, linker::Linker, archive::Archive, hexfile::OutputFormat, linkscript::{SymbolAssignment, parse_number}};
 
-use std::{fs, env::args, process::ExitCode};
+use std::{fs, io::{self, Write}, env, env::args, path::PathBuf, process::ExitCode, collections::HashSet};
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION", "No crate version is defined in environment variables.");
 const GITHUB: &'static str = "https://github.com/pi4erd/sarch_asm";
@@ -25,17 +31,51 @@ fn print_version() {
 
 // TODO: Update with every argument
 fn print_usage(program: &str) {
-    eprintln!("\nUsage: {} <input_file>\n", program);
+    eprintln!("\nUsage: {} <input_file>", program);
+    eprintln!("       {} ar <c|t|x> <archive> [objects...]\n", program);
     eprintln!("\t-b | --oblect\t\t\tCompile to object without linking");
     eprintln!("\t-c | --link-script <filename>\tSpecify linker script");
+    eprintln!("\t-D | --define <name[=value]>\tDefine a preprocessor symbol");
+    eprintln!("\t-E | --preprocess\t\tOnly run the preprocessor and print its output");
     eprintln!("\t-d | --disassemble\t\tToggle disassembly for an object file");
+    eprintln!("\t-g | --debug\t\t\tRecord source lines in the object file");
     eprintln!("\t-h | --help\t\t\tPrint this menu");
+    eprintln!("\t-I | --include-dir <dir>\tAdd a directory searched by '%include'");
     eprintln!("\t-k | --keep-object\t\tKeep an object file after linking");
     eprintln!("\t-o | --output <filename>\tSpecify output file");
+    eprintln!("\t-r | --relocatable\t\tLink into an object instead of a binary");
     eprintln!("\t-v | --version\t\t\tPrint current version");
-    eprintln!("\t-l | --link-object\t\tAdds object file to a linker");
+    eprintln!("\t-l | --link-object <name>\tLink an object or library, searching -L directories");
+    eprintln!("\t-L | --library-path <dir>\tAdd a directory searched by '-l'");
+    eprintln!("\t-y | --trace-symbol <symbol>\tPrint where a symbol is defined, referenced and resolved");
     eprintln!("\t     --entrypoint\t\tSpecify entrypoint of a program");
+    eprintln!("\t     --image-header\t\tWrite a header with the entry point and sections before the image");
+    eprintln!("\t     --big-endian\t\tTarget a big endian SArch32");
+    eprintln!("\t     --little-endian\t\tTarget a little endian SArch32 (default)");
+    eprintln!("\t     --max-macro-depth <n>\tLimit nesting of macro expansions");
+    eprintln!("\t     --wrap <symbol>\t\tSend references to a symbol to '__wrap_<symbol>'");
+    eprintln!("\t     --defsym <name=value>\tDefine an absolute symbol for the link");
+    eprintln!("\t     --output-format <format>\tWrite the image as binary (default), elf, ihex, srec/s19/s28/s37, c or memh");
+    eprintln!("\t     --record-size <bytes>\tData bytes per record of text output formats");
+    eprintln!("\t     --memh-width <bits>\tBits per word of memh output (default 8)");
+    eprintln!("\t     --memh-depth <words>\tFill memh output up to a number of words");
+    eprintln!("\t     --split-rom <n[:stride]>\tSplit the image by byte lane into n files, stride bytes each (default 1)");
+    eprintln!("\t     --fill <[section=]byte>\tPad gaps, or just one section, with a byte instead of zeros");
+    eprintln!("\t     --pad-to <size[:byte]>\tPad the image to a size, with the fill or the given byte");
+    eprintln!("\t     --dry-run\t\t\tResolve and lay out everything, but write no image or object");
     eprintln!("\t     --link\t\t\tTreat input file as SAO and link it");
+    eprintln!("\t     --literal-pools\t\tLoad large immediates from literal pools");
+    eprintln!("\t     --map <filename>\t\tWrite a link map of sections and symbols");
+    eprintln!("\t     --widen-db\t\tLet '.db' widen values that don't fit in a byte");
+    eprintln!("\t     --merge-constants\t\tMerge identical labeled constants in read-only sections");
+    eprintln!("\t     --gc-sections\t\tDrop sections unreachable from the image start");
+    eprintln!("\t     --strip-local\t\tLeave local symbols and debug lines out of saved objects");
+    eprintln!("\t     --retain-symbols-file <file>\tStrip local symbols except the ones listed, one per line");
+    eprintln!("\t     --print-memory-usage\t\tPrint how much of each region and section is used");
+    eprintln!("\t     --print-memory-usage-json\tPrint the memory usage as JSON");
+    eprintln!("\t     --print-gc-sections\t\tList sections unreachable from the image start");
+    eprintln!("\t     --warn-misaligned\t\tWarn about unaligned absolute memory accesses");
+    eprintln!("\t     --why-live <symbol>\t\tExplain which references keep a symbol or section");
 }
 
 pub fn lex(code: &str, print_tokens: bool) -> Vec<Token<'_, LexerToken>> {
@@ -51,6 +91,13 @@ pub fn lex(code: &str, print_tokens: bool) -> Vec<Token<'_, LexerToken>> {
     tokens
 }
 
+pub fn preprocess(tokens: Vec<Token<'_, LexerToken>>, preprocessor: &mut Preprocessor) -> Result<Vec<OwnedToken>, String> {
+    match preprocessor.preprocess(&tokens) {
+        Ok(t) => Ok(t),
+        Err(err) => Err(format!("Error occured while preprocessing:\n{}", err))
+    }
+}
+
 pub fn parse(tokens: Vec<Token<'_, LexerToken>>, print_ast: bool) -> Result<ParserNode, String> {
     let mut parser = Parser::new();
     match parser.parse(&tokens) {
@@ -67,14 +114,119 @@ pub fn parse(tokens: Vec<Token<'_, LexerToken>>, print_ast: bool) -> Result<Pars
     Ok(parser.root)
 }
 
+/**
+ * Resolves '-l name': an existing path is used as is, otherwise every
+ * library directory is searched for 'libname.sal', then 'name.sao'
+ */
+fn find_library(name: &str, library_paths: &[PathBuf]) -> Result<String, String> {
+    if PathBuf::from(name).is_file() {
+        return Ok(name.to_string())
+    }
+
+    let candidates = [format!("lib{}.sal", name), format!("{}.sao", name)];
+
+    for dir in library_paths {
+        for candidate in candidates.iter() {
+            let path = dir.join(candidate);
+            if path.is_file() {
+                return Ok(path.to_string_lossy().to_string())
+            }
+        }
+    }
+
+    let searched: Vec<String> = library_paths.iter().map(|d| d.display().to_string()).collect();
+    Err(format!("Cannot find library '{}': looked for '{}' and '{}' in [{}]",
+        name, candidates[0], candidates[1], searched.join(", ")))
+}
+
+/**
+ * Manages static libraries: 'c' creates an archive from objects,
+ * 't' lists its members and 'x' extracts them, or just the named ones
+ */
+fn librarian(program: &str, args: Vec<String>) -> ExitCode {
+    let (operation, archive_path, files) = match args.as_slice() {
+        [op, archive, files @ ..] => (op.as_str(), archive.as_str(), files),
+        _ => {
+            eprintln!("Expected an operation and an archive after 'ar'");
+            print_usage(program);
+            return ExitCode::FAILURE
+        }
+    };
+
+    match operation {
+        "c" => {
+            let mut archive = Archive::new();
+            for file in files {
+                let object = match ObjectFormat::from_file(file) {
+                    Ok(o) => o,
+                    Err(e) => {
+                        eprintln!("Error occured while reading object '{}': {}", file, e);
+                        return ExitCode::FAILURE
+                    }
+                };
+                let name = PathBuf::from(file).file_name()
+                    .map_or(file.clone(), |n| n.to_string_lossy().to_string());
+                if let Err(e) = archive.add_object(&name, &object) {
+                    eprintln!("Error occured while adding '{}' to archive: {}", file, e);
+                    return ExitCode::FAILURE
+                }
+            }
+            if let Err(e) = archive.save(archive_path) {
+                eprintln!("Error occured while saving archive: {e}");
+                return ExitCode::FAILURE
+            }
+        }
+        "t" | "x" => {
+            let archive = match Archive::from_file(archive_path) {
+                Ok(a) => a,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::FAILURE
+                }
+            };
+            if let Some(name) = files.iter().find(|f| !archive.members.iter().any(|m| m.name == **f)) {
+                eprintln!("No member '{}' in '{}'", name, archive_path);
+                return ExitCode::FAILURE
+            }
+
+            let selected = archive.members.iter()
+                .filter(|m| files.is_empty() || files.contains(&m.name));
+            for member in selected {
+                if operation == "t" {
+                    println!("{}", member.name);
+                } else if let Err(e) = fs::write(&member.name, &member.data) {
+                    eprintln!("Failed to write '{}': {}", member.name, e);
+                    return ExitCode::FAILURE
+                }
+            }
+        }
+        _ => {
+            eprintln!("Unknown archive operation '{}'", operation);
+            print_usage(program);
+            return ExitCode::FAILURE
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
 fn main() -> ExitCode {
+    run(args().collect())
+}
+
+/**
+ * Runs the assembler with command line `args`, the program name first
+ */
+pub fn run(args: Vec<String>) -> ExitCode {
     // Debug stuff #
     let print_tokens = false;
     let print_ast = false;
     let print_object_tree = false;
     // ############
 
-    let mut args: std::env::Args = args();
+    // Recorded in objects, so they can tell how they were built
+    let build_flags = args[1..].join(" ");
+    let mut args = args.into_iter();
 
     // Inputs #####
     let mut input_files: Vec<String> = Vec::new();
@@ -85,13 +237,49 @@ fn main() -> ExitCode {
     let mut link_object = true;
     let mut input_is_object = false;
     let mut keep_object = false;
+    let mut relocatable = false;
+    let mut image_header = false;
+    let mut pad_to: Option<(u64, Option<u8>)> = None;
+    let mut split_rom: Option<(usize, Option<usize>)> = None;
+    let mut fill: Option<u8> = None;
+    let mut output_format = OutputFormat::Binary;
+    let mut record_size: Option<usize> = None;
+    let mut memh_width: Option<usize> = None;
+    let mut trace_symbols = Vec::<String>::new();
+    let mut wrap_symbols = Vec::<String>::new();
+    // Some(true) prints it as JSON
+    let mut print_memory_usage: Option<bool> = None;
+    let mut memh_depth: Option<u64> = None;
+    let mut section_fills: Vec<(String, u8)> = Vec::new();
     let mut disassemble = false;
     let mut entrypoint: Option<String> = None;
+    let mut defines: Vec<(String, String)> = Vec::new();
+    let mut gc_sections = false;
+    let mut strip_local = false;
+    let mut retain_symbols = HashSet::<String>::new();
+    let mut print_gc_sections = false;
+    let mut why_live: Vec<String> = Vec::new();
+    let mut warn_misaligned = false;
+    let mut dry_run = false;
+    let mut max_macro_depth: Option<usize> = None;
+    let mut include_paths: Vec<PathBuf> = Vec::new();
+    let mut library_paths: Vec<PathBuf> = Vec::new();
+    let mut preprocess_only = false;
+    let mut literal_pools = false;
+    let mut merge_constants = false;
+    let mut widen_db = false;
+    let mut debug_lines = false;
+    let mut endianness = Endianness::Little;
+    let mut map_file: Option<String> = None;
+    let mut defsyms: Vec<SymbolAssignment> = Vec::new();
     // ############
 
     let mut linker_script_filename: String;
 
     let program = args.next().unwrap();
+    if args.as_slice().first().map(|a| a.as_str()) == Some("ar") {
+        return librarian(&program, args.skip(1).collect())
+    }
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
@@ -124,6 +312,10 @@ fn main() -> ExitCode {
                 keep_object = true;
                 link_object = true;
             }
+            "-r" | "--relocatable" => {
+                relocatable = true;
+                link_object = true;
+            }
             "-b" | "--object" => {
                 keep_object = true;
                 link_object = false;
@@ -144,6 +336,38 @@ fn main() -> ExitCode {
                 };
                 linker_script = Some(&linker_script_filename);
             }
+            "-D" | "--define" => {
+                let definition = match args.next() {
+                    Some(d) => d,
+                    None => {
+                        eprintln!("Expected symbol name after '{}'", arg);
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                // NAME defines to 1, NAME=VALUE to VALUE
+                match definition.split_once('=') {
+                    Some((name, value)) => defines.push((name.to_string(), value.to_string())),
+                    None => defines.push((definition, "1".to_string()))
+                }
+            }
+            "-I" | "--include-dir" => {
+                let dir = match args.next() {
+                    Some(d) => d,
+                    None => {
+                        eprintln!("Expected directory after '{}'", arg);
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                include_paths.push(PathBuf::from(dir));
+            }
+            "-E" | "--preprocess" => {
+                preprocess_only = true;
+            }
+            "-g" | "--debug" => {
+                debug_lines = true;
+            }
             "-d" | "--disassemble" => {
                 disassemble = true;
                 input_is_object = true;
@@ -162,6 +386,69 @@ fn main() -> ExitCode {
                 };
                 lib_files.push(filename);
             }
+            "--defsym" => {
+                let definition = match args.next() {
+                    Some(d) => d,
+                    None => {
+                        eprintln!("Expected 'name=value' after '{}'", arg);
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                match SymbolAssignment::parse(&definition) {
+                    Ok(s) => defsyms.push(s),
+                    Err(e) => {
+                        eprintln!("Invalid '--defsym {}': {}", definition, e);
+                        return ExitCode::FAILURE
+                    }
+                }
+            }
+            "-L" | "--library-path" => {
+                let dir = match args.next() {
+                    Some(d) => d,
+                    None => {
+                        eprintln!("Expected directory after '{}'", arg);
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                library_paths.push(PathBuf::from(dir));
+            }
+            "-y" | "--trace-symbol" => {
+                let name = match args.next() {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("Expected symbol after '{}'", arg);
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                trace_symbols.push(name);
+            }
+            "--wrap" => {
+                let name = match args.next() {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("Expected symbol after '{}'", arg);
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                wrap_symbols.push(name);
+            }
+            glued if glued.starts_with("--wrap=") => {
+                wrap_symbols.push(glued["--wrap=".len()..].to_string());
+            }
+            glued if glued.starts_with("--trace-symbol=") => {
+                trace_symbols.push(glued["--trace-symbol=".len()..].to_string());
+            }
+            // '-lutil' and '-Llibs' like in GNU tools
+            glued if glued.len() > 2 && glued.starts_with("-l") => {
+                lib_files.push(glued[2..].to_string());
+            }
+            glued if glued.len() > 2 && glued.starts_with("-L") => {
+                library_paths.push(PathBuf::from(&glued[2..]));
+            }
             "--link" => {
                 // Links input file as object file without compiling it
                 // May be useful trying to compile multiple object files
@@ -179,6 +466,255 @@ fn main() -> ExitCode {
                 };
                 entrypoint = Some(labelname)
             }
+            "--big-endian" => {
+                endianness = Endianness::Big;
+            }
+            "--little-endian" => {
+                endianness = Endianness::Little;
+            }
+            "--image-header" => {
+                image_header = true;
+            }
+            "--print-memory-usage" => {
+                print_memory_usage = Some(false);
+            }
+            "--print-memory-usage-json" => {
+                print_memory_usage = Some(true);
+            }
+            "--gc-sections" => {
+                gc_sections = true;
+            }
+            "--strip" | "--strip-local" => {
+                strip_local = true;
+            }
+            "--print-gc-sections" => {
+                print_gc_sections = true;
+            }
+            "--max-macro-depth" => {
+                let depth = match args.next() {
+                    Some(d) => d,
+                    None => {
+                        eprintln!("Expected number after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                max_macro_depth = match depth.parse::<usize>() {
+                    Ok(d) => Some(d),
+                    Err(e) => {
+                        eprintln!("Invalid macro depth '{depth}': {e}");
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--output-format" => {
+                let format = match args.next() {
+                    Some(f) => f,
+                    None => {
+                        eprintln!("Expected format after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                output_format = match OutputFormat::from_name(&format) {
+                    Some(f) => f,
+                    None => {
+                        eprintln!("Unknown output format '{format}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--record-size" => {
+                let size = match args.next() {
+                    Some(s) => s,
+                    None => {
+                        eprintln!("Expected number after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                record_size = match size.parse::<usize>() {
+                    Ok(s) => Some(s),
+                    Err(e) => {
+                        eprintln!("Invalid record size '{size}': {e}");
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--memh-width" => {
+                let width = match args.next() {
+                    Some(w) => w,
+                    None => {
+                        eprintln!("Expected number after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                memh_width = match width.parse::<usize>() {
+                    Ok(w) => Some(w),
+                    Err(e) => {
+                        eprintln!("Invalid word width '{width}': {e}");
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--memh-depth" => {
+                let depth = match args.next() {
+                    Some(d) => d,
+                    None => {
+                        eprintln!("Expected number after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                memh_depth = match parse_number(&depth) {
+                    Some(d) => Some(d),
+                    None => {
+                        eprintln!("Invalid memory depth '{depth}'");
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--fill" => {
+                let value = match args.next() {
+                    Some(v) => v,
+                    None => {
+                        eprintln!("Expected byte after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                let (section, byte) = match value.split_once('=') {
+                    Some((section, byte)) => (Some(section), byte),
+                    None => (None, value.as_str())
+                };
+                let byte = match parse_number(byte).and_then(|b| u8::try_from(b).ok()) {
+                    Some(b) => b,
+                    None => {
+                        eprintln!("Invalid fill byte in '{value}'");
+                        return ExitCode::FAILURE
+                    }
+                };
+                match section {
+                    Some(section) => section_fills.push((section.to_string(), byte)),
+                    None => fill = Some(byte)
+                }
+            }
+            "--pad-to" => {
+                let value = match args.next() {
+                    Some(v) => v,
+                    None => {
+                        eprintln!("Expected size after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                let (size, fill) = match value.split_once(':') {
+                    Some((size, fill)) => (size, Some(fill)),
+                    None => (value.as_str(), None)
+                };
+                let fill = match fill.map(|f| parse_number(f).and_then(|f| u8::try_from(f).ok())) {
+                    Some(Some(f)) => Some(f),
+                    Some(None) => {
+                        eprintln!("Invalid fill byte in '{value}'");
+                        return ExitCode::FAILURE
+                    }
+                    None => None
+                };
+                pad_to = match parse_number(size) {
+                    Some(s) => Some((s, fill)),
+                    None => {
+                        eprintln!("Invalid size in '{value}'");
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--split-rom" => {
+                let value = match args.next() {
+                    Some(v) => v,
+                    None => {
+                        eprintln!("Expected lane count after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                let (lanes, stride) = match value.split_once(':') {
+                    Some((lanes, stride)) => (lanes, Some(stride)),
+                    None => (value.as_str(), None)
+                };
+                let stride = match stride.map(|s| s.parse::<usize>()) {
+                    Some(Ok(s)) => Some(s),
+                    Some(Err(e)) => {
+                        eprintln!("Invalid stride in '{value}': {e}");
+                        return ExitCode::FAILURE
+                    }
+                    None => None
+                };
+                split_rom = match lanes.parse::<usize>() {
+                    Ok(l) => Some((l, stride)),
+                    Err(e) => {
+                        eprintln!("Invalid lane count in '{value}': {e}");
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--dry-run" => {
+                dry_run = true;
+            }
+            "--warn-misaligned" => {
+                warn_misaligned = true;
+            }
+            "--literal-pools" => {
+                literal_pools = true;
+            }
+            "--merge-constants" => {
+                merge_constants = true;
+            }
+            "--widen-db" => {
+                widen_db = true;
+            }
+            "--retain-symbols-file" => {
+                let file = match args.next() {
+                    Some(f) => f,
+                    None => {
+                        eprintln!("Expected filename after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                let list = match fs::read_to_string(&file) {
+                    Ok(l) => l,
+                    Err(e) => {
+                        eprintln!("Failed to read '{}': {}", file, e);
+                        return ExitCode::FAILURE
+                    }
+                };
+                // One symbol per line, everything else is stripped
+                retain_symbols.extend(list.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).map(|l| l.to_string()));
+                strip_local = true;
+            }
+            "--map" => {
+                map_file = match args.next() {
+                    Some(f) => Some(f),
+                    None => {
+                        eprintln!("Expected filename after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+            }
+            "--why-live" => {
+                let name = match args.next() {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("Expected symbol name after '{arg}'");
+                        print_usage(&program);
+                        return ExitCode::FAILURE
+                    }
+                };
+                why_live.push(name);
+            }
             _ => {
                 input_files.push(arg);
             }
@@ -191,6 +727,33 @@ fn main() -> ExitCode {
     }
     let mut objects: Vec<ObjectFormat> = Vec::new();
 
+    // Standard library directories are searched after the ones given with -I
+    if let Some(dirs) = env::var_os("SARCH_INCLUDE") {
+        include_paths.extend(env::split_paths(&dirs));
+    }
+
+    // Same for -L
+    if let Some(dirs) = env::var_os("SARCH_LIBRARY_PATH") {
+        library_paths.extend(env::split_paths(&dirs));
+    }
+
+    // Output of -E, all inputs concatenated and written as they're preprocessed
+    let mut source_writer = None;
+    if preprocess_only {
+        let out: Box<dyn Write> = if output_file_specified {
+            match fs::File::create(&output_file) {
+                Ok(f) => Box::new(io::BufWriter::new(f)),
+                Err(e) => {
+                    eprintln!("Failed to write '{}': {}", output_file, e);
+                    return ExitCode::FAILURE
+                }
+            }
+        } else {
+            Box::new(io::stdout().lock())
+        };
+        source_writer = Some(preprocessor::SourceWriter::new(out));
+    }
+
     if !input_is_object {
         for filepath in input_files.iter() {
 
@@ -204,21 +767,52 @@ fn main() -> ExitCode {
             
             let tokens = lex(&code, print_tokens);
 
-            let node = match parse(tokens, print_ast) {
-                Ok(n) => n,
-                Err(e) => {
-                    eprintln!("{}", e);
+            let mut preprocessor = Preprocessor::new();
+            for (name, value) in defines.iter() {
+                preprocessor.define(name, value);
+            }
+            if let Some(depth) = max_macro_depth {
+                preprocessor.max_macro_depth = depth;
+            }
+            preprocessor.set_source(filepath, &code);
+            preprocessor.include_paths = include_paths.clone();
+            preprocessor.line_markers = debug_lines;
+
+            if let Some(writer) = source_writer.as_mut() {
+                writer.comment(&format!("line 1 \"{}\"", filepath));
+                if let Err(e) = preprocessor.preprocess_into(&tokens, writer) {
+                    eprintln!("Error occured while preprocessing:\n{}", e);
+                    if output_file_specified {
+                        let _ = fs::remove_file(&output_file);
+                    }
                     return ExitCode::FAILURE
                 }
-            };
+                continue
+            }
 
             let mut object = ObjectFormat::new();
-            match object.load_parser_node(&node) {
-                Ok(()) => {},
-                Err(err) => {
-                    eprintln!("Error occured while generating object file:\n{}", err);
-                    return ExitCode::FAILURE
-                }
+            object.literal_pools = literal_pools;
+            object.widen_db = widen_db;
+            object.header.endianness = endianness;
+            object.set_metadata("source", filepath);
+            object.set_metadata("assembler", &format!("Sarch32 ASM {}", VERSION));
+            object.set_metadata("flags", &build_flags);
+
+            // Statements are assembled as soon as the preprocessor produces them
+            let mut sink = ObjectSink::new(&mut object);
+            if print_ast {
+                sink.tree = Some(ParserNode::new());
+            }
+            if let Err(e) = preprocessor.preprocess_into(&tokens, &mut sink) {
+                eprintln!("Error occured while preprocessing:\n{}", e);
+                return ExitCode::FAILURE
+            }
+            if let Some(tree) = sink.tree.take() {
+                println!("Parser tree: {:#?}", &tree);
+            }
+            if let Err(e) = sink.finish() {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE
             }
             if print_object_tree {
                 println!("Object tree: {:#?}", object);
@@ -240,6 +834,16 @@ fn main() -> ExitCode {
         }
     }
 
+    if let Some(writer) = source_writer {
+        return match writer.finish().and_then(|mut out| out.flush()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Failed to write '{}': {}", output_file, e);
+                ExitCode::FAILURE
+            }
+        }
+    }
+
     if disassemble {
         if objects.len() > 1 {
             eprintln!("Cannot disassemble multiple files!");
@@ -274,7 +878,13 @@ fn main() -> ExitCode {
             print_usage(&program);
             return ExitCode::FAILURE
         }
-        let object = &objects[0];
+        if dry_run {
+            return ExitCode::SUCCESS
+        }
+        let object = &mut objects[0];
+        if strip_local {
+            object.strip_local_symbols(&retain_symbols);
+        }
         match object.save_object(&output_file) {
             Ok(()) => {},
             Err(e) => {
@@ -287,18 +897,26 @@ fn main() -> ExitCode {
 
     if link_object {
         let mut linker = Linker::new();
-
-        if let Some(entry_label) = entrypoint {
-            let first_object = ObjectFormat::create_jumper(entry_label);
-            match linker.load_symbols(first_object) {
-                Ok(_) => {},
-                Err(e) => {
-                    // this error shouldn't happen. if it does happen,
-                    // then please fix this in objgen.rs/ObjectFormat::create_jumper()
-                    eprintln!("Compiler error occured (you're lucky): {e}");
-                    return ExitCode::FAILURE
-                }
-            };
+        linker.warn_misaligned = warn_misaligned;
+        linker.merge_constants = merge_constants;
+        linker.gc_sections = gc_sections;
+        linker.strip_local = strip_local;
+        linker.retain_symbols = retain_symbols;
+        linker.entry = entrypoint;
+        linker.image_header = image_header;
+        linker.pad_to = pad_to;
+        linker.fill = fill;
+        linker.section_fills = section_fills;
+        linker.output_format = output_format;
+        linker.record_size = record_size;
+        linker.memh_width = memh_width;
+        linker.memh_depth = memh_depth;
+        linker.split_lanes = split_rom.map(|(lanes, _)| lanes);
+        linker.split_stride = split_rom.and_then(|(_, stride)| stride);
+        linker.trace_symbols = trace_symbols;
+        linker.wrap_symbols = wrap_symbols;
+        for symbol in defsyms {
+            linker.define_symbol(symbol);
         }
     
         for object in objects {
@@ -311,7 +929,28 @@ fn main() -> ExitCode {
             };
         }
         
+        // Members are only pulled in once every plain object is loaded
+        let mut archives = Vec::<(String, Archive)>::new();
+
         for lib in lib_files {
+            let lib = match find_library(&lib, &library_paths) {
+                Ok(l) => l,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::FAILURE
+                }
+            };
+            if Archive::is_archive(&lib) {
+                let archive = match Archive::from_file(&lib) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        eprintln!("Error occured while reading library archive: {e}");
+                        return ExitCode::FAILURE
+                    }
+                };
+                archives.push((lib, archive));
+                continue
+            }
             let lib_fmt = match ObjectFormat::from_file(&lib) {
                 Ok(l) => l,
                 Err(e) => {
@@ -328,25 +967,128 @@ fn main() -> ExitCode {
             };
         }
 
-        if keep_object {
-            let filename = output_file.clone() + ".sao";
+        if let Err(e) = linker.load_archives(&archives) {
+            eprintln!("Error occured while loading a library in linker: {e}");
+            return ExitCode::FAILURE
+        }
 
-            match linker.save_object(&filename) {
+        if relocatable {
+            if dry_run {
+                return ExitCode::SUCCESS
+            }
+            if !output_file_specified {
+                output_file = "output.sao".to_string();
+            }
+            match linker.save_object(&output_file) {
                 Ok(()) => {},
                 Err(e) => {
                     eprintln!("Error occured while saving linker object: {e}");
                     return ExitCode::FAILURE
                 }
             }
+            return ExitCode::SUCCESS
         }
 
-        match linker.save_binary(&output_file, linker_script) {
-            Ok(_) => {},
-            Err(e) => {
-                eprintln!("Error occured while linking: {e}");
+        if print_gc_sections || why_live.len() != 0 {
+            match linker.load_link_structure(linker_script) {
+                Ok(()) => {},
+                Err(e) => {
+                    eprintln!("Error occured while loading link script: {e}");
+                    return ExitCode::FAILURE
+                }
+            }
+        }
+
+        if print_gc_sections && gc_sections {
+            for section in linker.removed_sections() {
+                println!("Removed unused section '{}'", section);
+            }
+        } else if print_gc_sections {
+            for section in linker.garbage_sections() {
+                println!("Unreferenced section '{}'", section);
+            }
+        }
+
+        for name in why_live.iter() {
+            match linker.why_live(name) {
+                Ok(s) => print!("{}", s),
+                Err(e) => {
+                    eprintln!("Error occured while tracing '{name}': {e}");
+                    return ExitCode::FAILURE
+                }
+            }
+        }
+
+        // A dry run links and reports like any other, it only writes no image or object
+        if dry_run {
+            let image = match linker.generate_binary(linker_script) {
+                Ok(b) => b,
+                Err(e) => {
+                    eprintln!("Error occured while linking: {e}");
+                    return ExitCode::FAILURE
+                }
+            };
+            let layout = match linker.section_layout() {
+                Ok(l) => l,
+                Err(e) => {
+                    eprintln!("Error occured while laying out sections: {e}");
+                    return ExitCode::FAILURE
+                }
+            };
+            for (name, offset, size) in layout {
+                println!("Section '{}': offset {:#06x}, {} bytes", name, offset, size);
+            }
+            println!("Image size: {} bytes", image.len());
+        } else {
+            if keep_object {
+                let filename = output_file.clone() + ".sao";
+
+                match linker.save_object(&filename) {
+                    Ok(()) => {},
+                    Err(e) => {
+                        eprintln!("Error occured while saving linker object: {e}");
+                        return ExitCode::FAILURE
+                    }
+                }
+            }
+
+            if !output_file_specified {
+                output_file = format!("output.{}", output_format.extension());
+            }
+
+            match linker.save_binary(&output_file, linker_script) {
+                Ok(_) => {},
+                Err(e) => {
+                    eprintln!("Error occured while linking: {e}");
+                    return ExitCode::FAILURE
+                }
+            };
+        }
+
+        if let Some(json) = print_memory_usage {
+            match linker.memory_usage() {
+                Ok(u) if json => println!("{}", u.to_json()),
+                Ok(u) => print!("{}", u.table()),
+                Err(e) => {
+                    eprintln!("Error occured while measuring memory usage: {e}");
+                    return ExitCode::FAILURE
+                }
+            }
+        }
+
+        if let Some(map_file) = map_file {
+            let map = match linker.link_map() {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("Error occured while generating link map: {e}");
+                    return ExitCode::FAILURE
+                }
+            };
+            if let Err(e) = fs::write(&map_file, map) {
+                eprintln!("Failed to write '{}': {}", map_file, e);
                 return ExitCode::FAILURE
             }
-        };
+        }
     }
     
     return ExitCode::SUCCESS
@@ -1,4 +1,6 @@
-use crate::{objgen::ObjectFormat, symbols::Instructions};
+use std::{collections::HashMap, fs};
+
+use crate::{objgen::{ObjectFormat, SymbolBinding, SymbolType}, symbols::Instructions};
 
 pub struct Objdump {
     object: ObjectFormat
@@ -11,14 +13,16 @@ impl Objdump {
     pub fn get_disassembly(&self) -> Result<String, String> {
         let instructions = Instructions::new();
 
-        let mut result = String::new();
+        let mut result = self.get_metadata();
+        // Lines of source files named by line information, None if unreadable
+        let mut sources = HashMap::<String, Option<Vec<String>>>::new();
 
         for (sec_name, sec) in self.object.sections.iter() {
             if sec.binary_section || sec.instructions.len() == 0 {
                 continue;
             }
 
-            result += &format!("Section '{}':\n", sec_name);
+            result += &format!("Section '{}' [{}]:\n", sec_name, sec.flags);
 
             let mut offset = 0;
 
@@ -29,12 +33,43 @@ impl Objdump {
                     }
                     None => {}
                 };
+                if let Some((alignment, fill)) = instruction.get_alignment() {
+                    let padding = instruction.get_binary_size(offset as u64);
+                    result += &format!("\t{:#06x}: .align_code {} ({} bytes of {:#04x})\n",
+                        offset, alignment, padding, fill);
+                    offset += padding;
+                    continue
+                }
+                if let Some((opcode, _)) = instruction.get_raw() {
+                    let size = instruction.get_binary_size(offset as u64);
+                    result += &match opcode {
+                        Some(opcode) => format!("\t{:#06x}: .insn {:#04x} {}\n", offset, opcode, instruction.get_args()),
+                        None => format!("\t{:#06x}: .word {}\n", offset, instruction.get_args())
+                    };
+                    offset += size;
+                    continue
+                }
                 let sym = match instructions.get_instruction(instruction.opcode) {
                     Some(s) => s,
                     None => {
                         return Err(format!("No instruction with opcode '{}' exists!", instruction.opcode))
                     }
                 };
+                let line_info = self.object.debug_lines.iter()
+                    .find(|l| l.section == *sec_name && l.ptr == i as u64);
+                if let Some(info) = line_info {
+                    let lines = sources.entry(info.file.clone()).or_insert_with(|| {
+                        fs::read_to_string(&info.file).ok().map(|s| s.lines().map(str::to_string).collect())
+                    });
+                    let text = match lines {
+                        Some(lines) => (info.line as usize).checked_sub(1)
+                            .and_then(|i| lines.get(i))
+                            .map(|l| l.trim())
+                            .unwrap_or(""),
+                        None => ""
+                    };
+                    result += &format!("\t; {}:{}: {}\n", info.file, info.line, text);
+                }
                 result += &format!("\t{:#06x} ({:#04x}): {} ", offset, instruction.opcode, sym.name);
 
                 result += &instruction.get_args();
@@ -48,6 +83,45 @@ impl Objdump {
             }
         }
 
+        result += &self.get_symbol_table();
+
         Ok(result)
     }
+    pub fn get_metadata(&self) -> String {
+        if self.object.metadata.is_empty() {
+            return String::new()
+        }
+        let mut result = String::from("Metadata:\n");
+
+        for (key, value) in self.object.metadata.iter() {
+            result += &format!("\t{}: {}\n", key, value);
+        }
+
+        result + "\n"
+    }
+    pub fn get_symbol_table(&self) -> String {
+        let mut result = String::from("\nSymbol table:\n");
+
+        for symbol in self.object.symbol_table() {
+            let binding = match symbol.binding {
+                SymbolBinding::Default => "default",
+                SymbolBinding::Global => "global",
+                SymbolBinding::Local => "local",
+                SymbolBinding::Weak => "weak"
+            };
+            let symbol_type = match symbol.symbol_type {
+                SymbolType::Func => "func",
+                SymbolType::Object => "object"
+            };
+            result += &format!("\t{:#06x} {:<7} {:<6} {:<12} {}\n",
+                symbol.offset, binding, symbol_type, symbol.section, symbol.name);
+        }
+        // Common symbols have no section yet, their value is the alignment
+        for common in self.object.commons.iter() {
+            result += &format!("\t{:#06x} {:<7} {:<6} {:<12} {} ({} bytes)\n",
+                common.alignment, "global", "object", "*COM*", common.name, common.size);
+        }
+
+        result
+    }
 }
\ No newline at end of file
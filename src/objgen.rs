@@ -4,12 +4,18 @@
  * Generates object files for SArch32 ASM. Default extension: .sao
  */
 
-use std::collections::HashMap;
-use std::io::{Error, Write};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::io::{BufReader, BufWriter, Error, Read, Seek, SeekFrom, Write};
 use std::{fs, io, str};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-
-use crate::parser::{ParserNode, NodeType, Registers};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::lexer::{LexerToken, OwnedToken};
+use crate::parser::{Parser, ParserNode, NodeType, Registers};
+use crate::preprocessor::TokenSink;
 use crate::symbols::{Instructions, ArgumentTypes, Conditions};
 
 macro_rules! unexpected_node {
@@ -33,56 +39,396 @@ macro_rules! unexpected_eof {
     };
 }
 
+/**
+ * How string literals are turned into bytes, set by '.encoding'
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StringEncoding {
+    Utf8, Latin1, Utf16Le
+}
+
+impl StringEncoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "utf8" | "utf-8" => Some(Self::Utf8),
+            "latin1" | "iso-8859-1" => Some(Self::Latin1),
+            "utf16le" | "utf-16le" => Some(Self::Utf16Le),
+            _ => None
+        }
+    }
+    /**
+     * Appends one character. `raw` characters come from '\xNN' escapes and
+     * are written as a single code unit in any encoding.
+     */
+    fn push_char(&self, bytes: &mut Vec<u8>, c: char, raw: bool) -> Result<(), String> {
+        match self {
+            Self::Utf8 if raw => bytes.push(c as u8),
+            Self::Utf8 => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Self::Latin1 => {
+                if c as u32 > 0xFF {
+                    return Err(format!("Character '{}' cannot be encoded in latin1", c))
+                }
+                bytes.push(c as u8)
+            }
+            Self::Utf16Le => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    bytes.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+    /**
+     * Size of a code unit, and so of the string terminator
+     */
+    fn unit_size(&self) -> usize {
+        match self {
+            Self::Utf16Le => 2,
+            _ => 1
+        }
+    }
+}
+
+/**
+ * Encodes a string literal, converting escape sequences
+ * (\n, \t, \r, \0, \\, \", \', \xNN) if `unescape` is set
+ */
+fn encode_string(text: &str, encoding: StringEncoding, unescape: bool) -> Result<Vec<u8>, String> {
+    let mut result = Vec::<u8>::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' || !unescape {
+            encoding.push_char(&mut result, c, false)?;
+            continue
+        }
+        let escaped = match chars.next() {
+            Some(e) => e,
+            None => {
+                return Err(format!("Unterminated escape sequence in \"{}\"", text))
+            }
+        };
+        let (c, raw) = match escaped {
+            'n' => ('\n', false),
+            't' => ('\t', false),
+            'r' => ('\r', false),
+            '0' => ('\0', false),
+            '\\' | '"' | '\'' => (escaped, false),
+            'x' => {
+                let digits: String = chars.by_ref().take(2).collect();
+                let value = Some(digits.as_str())
+                    .filter(|d| d.len() == 2)
+                    .and_then(|d| u8::from_str_radix(d, 16).ok());
+                match value {
+                    Some(v) => (v as char, true),
+                    None => {
+                        return Err(format!("Invalid '\\x' escape in \"{}\": two hex digits expected", text))
+                    }
+                }
+            }
+            _ => {
+                return Err(format!("Unknown escape sequence '\\{}' in \"{}\"", escaped, text))
+            }
+        };
+        encoding.push_char(&mut result, c, raw)?;
+    }
+
+    Ok(result)
+}
+
 const MAGIC_FORMAT_NUMBER: u64 = 0x3A6863FC6173371B;
-const CURRENT_FORMAT_VERSION: u32 = 4;
+const CURRENT_FORMAT_VERSION: u32 = 18;
+// Oldest object format that can still be read
+const OLDEST_FORMAT_VERSION: u32 = 4;
+// Versions that changed the layout of existing structures
+const LABEL_BINDING_VERSION: u32 = 5;
+const SUBTRAHEND_VERSION: u32 = 6;
+const SECTION_FLAGS_VERSION: u32 = 7;
+const NOBITS_VERSION: u32 = 8;
+const ADDEND_VERSION: u32 = 10;
+const SYMBOL_TABLE_VERSION: u32 = 11;
+const STRING_TABLE_VERSION: u32 = 12;
+const LINE_TABLE_VERSION: u32 = 13;
+const CHECKSUM_VERSION: u32 = 14;
+const COMPRESSION_VERSION: u32 = 15;
+const ENDIANNESS_VERSION: u32 = 16;
+const METADATA_VERSION: u32 = 17;
+const COMMON_VERSION: u32 = 18;
+// Limits for fields read from untrusted object files
+const MAX_NAME_LENGTH: usize = 4096;
+const MAX_SECTION_SIZE: u64 = 1 << 32;
+
+/**
+ * Pseudo-opcode of a code alignment unit created by '.align_code'.
+ * Constant 0 holds the alignment, constant 1 the opcode used as padding.
+ * The padding is materialized by the linker once the unit's position is known.
+ */
+pub const ALIGN_CODE_OPCODE: u16 = 0xFFFF;
+
+/**
+ * Pseudo-opcode of raw code created by '.insn' and '.word' in code sections.
+ * Constant 0 holds the operand layout, 4 bits per operand starting from the lowest:
+ * bit 3 marks the operand present, bit 2 makes it relative to the start of the unit
+ * and bits 0-1 are log2 of its size in bytes. Constant 1, if present, is the opcode,
+ * encoded like a real one. Operands are arguments 2 and up.
+ */
+pub const RAW_CODE_OPCODE: u16 = 0xFFFE;
+// Operand layout of raw code is a single quad word
+pub const MAX_RAW_OPERANDS: usize = 16;
+
+/**
+ * Operand types of '.insn': keyword, size and whether it's relative
+ */
+const RAW_OPERAND_TYPES: [(&str, ConstantSize, bool); 5] = [
+    ("byte", ConstantSize::Byte, false),
+    ("word", ConstantSize::Word, false),
+    ("dword", ConstantSize::DoubleWord, false),
+    ("qword", ConstantSize::QuadWord, false),
+    ("rel", ConstantSize::DoubleWord, true)
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawOperand {
+    pub size: ConstantSize,
+    pub relative: bool
+}
+
+impl RawOperand {
+    fn type_name(&self) -> &'static str {
+        match RAW_OPERAND_TYPES.iter().find(|(_, size, relative)| *size == self.size && *relative == self.relative) {
+            Some((name, _, _)) => name,
+            None => "(UTYPE)"
+        }
+    }
+}
+
+/**
+ * Size in bytes of a raw opcode, encoded like `Instruction::extended_opcode`
+ */
+pub fn raw_opcode_size(opcode: u16) -> usize {
+    if opcode & 0x80 != 0 { 2 } else { 1 }
+}
+
+/**
+ * References to `<label>_size` that aren't defined anywhere resolve to the
+ * distance from `<label>` to the next label (or the end of its section).
+ */
+pub const SIZE_SYMBOL_SUFFIX: &str = "_size";
+
+pub fn size_symbol_label(name: &str) -> Option<&str> {
+    name.strip_suffix(SIZE_SYMBOL_SUFFIX).filter(|label| !label.is_empty())
+}
+
+/**
+ * Reference as it would be written in source, e.g. `(end - start + 4)`.
+ */
+fn reference_expression(rf: &str, subtrahend: Option<&str>, addend: i64) -> String {
+    let mut result = rf.to_string();
+    if let Some(sub) = subtrahend {
+        result += &format!(" - {}", sub);
+    }
+    match addend {
+        0 => {}
+        n if n < 0 => result += &format!(" - {}", n.unsigned_abs()),
+        n => result += &format!(" + {}", n)
+    }
+    if result.len() == rf.len() { result } else { format!("({})", result) }
+}
 
 /**
  * 0 - 1: argument position
- * 1 - <>: reference name
+ * 1 - 5: reference name (string index)
+ * 5 - 9: subtracted reference name (string index, empty if none)
+ * 9 - 17: signed addend
  */
 #[derive(Debug, Clone)]
 pub struct Reference {
     pub argument_pos: u8,
-    pub rf: String
+    pub rf: String,
+    pub subtrahend: Option<String>,
+    pub addend: i64
 }
 
 impl Reference {
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
-        let mut me = Self {
-            argument_pos: 0,
-            rf: String::new()
-        };
+    fn from_bytes(binary: &mut dyn Read, names: &dyn NameSource, version: u32) -> Result<Self, Error> {
+        let argument_pos = binary.read_u8()?;
+        let rf = names.read_name(binary)?;
+        let subtrahend = if version >= SUBTRAHEND_VERSION { names.read_name(binary)? } else { String::new() };
+        let addend = if version >= ADDEND_VERSION { binary.read_i64::<LittleEndian>()? } else { 0 };
 
-        me.argument_pos = binary.read_u8()?;
+        Ok(Self {
+            argument_pos,
+            rf,
+            subtrahend: if subtrahend.is_empty() { None } else { Some(subtrahend) },
+            addend
+        })
+    }
+    fn write_bytes(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
+        binary.write_u8(self.argument_pos)?;
+
+        strings.write(binary, &self.rf)?;
+        strings.write(binary, self.subtrahend.as_deref().unwrap_or(""))?;
+        binary.write_i64::<LittleEndian>(self.addend)?;
 
-        let mut char_vec = Vec::<u8>::new();
+        Ok(())
+    }
+    /**
+     * Every symbol name the reference depends on.
+     */
+    pub fn symbols(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.rf).chain(self.subtrahend.iter())
+    }
+    pub fn expression(&self) -> String {
+        reference_expression(&self.rf, self.subtrahend.as_deref(), self.addend)
+    }
+}
+
+/**
+ * Values may be given either signed or unsigned, e.g. `.db -1` and `.db 255`
+ */
+fn check_data_range(instruction: &str, value: i64, size: ConstantSize) -> Result<(), String> {
+    let range = match size {
+        ConstantSize::Byte => -0x80..=0xFF,
+        ConstantSize::Word => -0x8000..=0xFFFF,
+        ConstantSize::DoubleWord => -0x8000_0000..=0xFFFF_FFFF,
+        ConstantSize::QuadWord => return Ok(())
+    };
+    if !range.contains(&value) {
+        return Err(format!("Value {} ({:#x}) does not fit in '.{}' ({} bytes)",
+            value, value, instruction, size.get_size()))
+    }
+    Ok(())
+}
+
+pub fn read_cstring(binary: &mut dyn Read) -> Result<String, Error> {
+    let mut char_vec = Vec::<u8>::new();
 
-        let mut c = binary.read_u8()?;
+    let mut c = binary.read_u8()?;
 
-        while c != 0 {
-            char_vec.push(c);
-            c = binary.read_u8()?;
+    while c != 0 {
+        if char_vec.len() == MAX_NAME_LENGTH {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("String is longer than {} bytes", MAX_NAME_LENGTH)))
         }
+        char_vec.push(c);
+        c = binary.read_u8()?;
+    }
 
-        me.rf = String::from_utf8(char_vec).unwrap();
+    match String::from_utf8(char_vec) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
 
-        Ok(me)
+pub fn write_cstring(binary: &mut dyn Write, s: &str) -> Result<(), Error> {
+    for c in s.bytes() {
+        binary.write_u8(c)?;
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
-        binary.write_u8(self.argument_pos)?;
+    binary.write_u8(0)
+}
+
+/**
+ * Where names are read from: the string table, or inline
+ * C strings in objects older than the string table.
+ */
+trait NameSource {
+    fn read_name(&self, binary: &mut dyn Read) -> Result<String, Error>;
+}
+
+struct InlineNames;
+
+impl NameSource for InlineNames {
+    fn read_name(&self, binary: &mut dyn Read) -> Result<String, Error> {
+        read_cstring(binary)
+    }
+}
+
+impl NameSource for StringTable {
+    fn read_name(&self, binary: &mut dyn Read) -> Result<String, Error> {
+        let index = binary.read_u32::<LittleEndian>()?;
+        match self.get(index) {
+            Some(s) => Ok(s.to_string()),
+            None => Err(Error::new(io::ErrorKind::InvalidData,
+                format!("String index {} is out of the string table ({} strings)", index, self.len())))
+        }
+    }
+}
+
+/**
+ * Names used by an object, each stored once. Sections, references
+ * and symbols refer to them by index. Index 0 is always the empty string.
+ *
+ * 0 - 4: string count
+ * 4 - <>: null terminated strings
+ */
+#[derive(Debug, Clone)]
+pub struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u32>
+}
 
-        for c in self.rf.bytes() {
-            binary.write_u8(c)?;
+impl StringTable {
+    pub fn new() -> Self {
+        let mut me = Self { strings: Vec::new(), indices: HashMap::new() };
+        me.intern("");
+        me
+    }
+    pub fn intern(&mut self, s: &str) -> u32 {
+        if let Some(index) = self.indices.get(s) {
+            return *index
+        }
+        let index = self.strings.len() as u32;
+        self.strings.push(s.to_string());
+        self.indices.insert(s.to_string(), index);
+        index
+    }
+    pub fn get(&self, index: u32) -> Option<&str> {
+        self.strings.get(index as usize).map(|s| s.as_str())
+    }
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+    fn from_bytes(binary: &mut dyn Read) -> Result<Self, Error> {
+        let mut me = Self { strings: Vec::new(), indices: HashMap::new() };
+
+        let count = binary.read_u32::<LittleEndian>()?;
+        for _ in 0..count {
+            let s = read_cstring(binary)?;
+            me.indices.insert(s.clone(), me.strings.len() as u32);
+            me.strings.push(s);
         }
-        binary.write_u8(0)?;
 
+        if me.get(0) != Some("") {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("String table must start with an empty string")))
+        }
+        Ok(me)
+    }
+    fn write_bytes(&self, binary: &mut dyn Write) -> Result<(), Error> {
+        binary.write_u32::<LittleEndian>(self.strings.len() as u32)?;
+        for s in self.strings.iter() {
+            write_cstring(binary, s)?;
+        }
         Ok(())
     }
+    fn write(&mut self, binary: &mut dyn Write, s: &str) -> Result<(), Error> {
+        let index = self.intern(s);
+        binary.write_u32::<LittleEndian>(index)
+    }
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConstantSize {
-    Byte, Word, DoubleWord
+    Byte, Word, DoubleWord, QuadWord
 }
 
 impl ConstantSize {
@@ -91,6 +437,7 @@ impl ConstantSize {
             1 => Some(ConstantSize::Byte),
             2 => Some(ConstantSize::Word),
             4 => Some(ConstantSize::DoubleWord),
+            8 => Some(ConstantSize::QuadWord),
             _ => None
         }
     }
@@ -98,7 +445,8 @@ impl ConstantSize {
         match self {
             Self::Byte => 1,
             Self::Word => 2,
-            Self::DoubleWord => 4
+            Self::DoubleWord => 4,
+            Self::QuadWord => 8
         }
     }
     pub fn get_size(&self) -> usize {
@@ -119,7 +467,7 @@ pub struct Constant {
 }
 
 impl Constant {
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
+    fn from_bytes(binary: &mut dyn Read) -> Result<Self, Error> {
         let mut me = Self {
             argument_pos: 0,
             size: ConstantSize::Byte,
@@ -140,18 +488,20 @@ impl Constant {
             ConstantSize::Byte => binary.read_i8()? as i64,
             ConstantSize::Word => binary.read_i16::<LittleEndian>()? as i64,
             ConstantSize::DoubleWord => binary.read_i32::<LittleEndian>()? as i64,
+            ConstantSize::QuadWord => binary.read_i64::<LittleEndian>()?,
         };
 
         Ok(me)
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
+    fn write_bytes(&self, binary: &mut dyn Write) -> Result<(), Error> {
         binary.write_u8(self.argument_pos)?;
         binary.write_u8(self.size.to_u8())?;
 
         match self.size {
             ConstantSize::Byte => binary.write_i8(self.value as i8),
             ConstantSize::Word => binary.write_i16::<LittleEndian>(self.value as i16),
-            ConstantSize::DoubleWord => binary.write_i32::<LittleEndian>(self.value as i32)
+            ConstantSize::DoubleWord => binary.write_i32::<LittleEndian>(self.value as i32),
+            ConstantSize::QuadWord => binary.write_i64::<LittleEndian>(self.value)
         }?;
 
         Ok(())
@@ -174,7 +524,7 @@ pub struct InstructionData {
 }
 
 impl InstructionData {
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
+    fn from_bytes(binary: &mut dyn Read, names: &dyn NameSource, version: u32) -> Result<Self, Error> {
         let mut me = Self {
             opcode: 0xFFFF,
             references: Vec::new(),
@@ -186,7 +536,7 @@ impl InstructionData {
         let const_count = binary.read_u8()?;
 
         for _ in 0..ref_count {
-            let reference = Reference::from_bytes(binary)?;
+            let reference = Reference::from_bytes(binary, names, version)?;
             me.references.push(reference);
         }
 
@@ -206,15 +556,119 @@ impl InstructionData {
             }
         }
 
+        me.validate()?;
+
         Ok(me)
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
+
+    /**
+     * Checks that the linker can encode this instruction: a known opcode
+     * with every argument given exactly once, in the size it is encoded in
+     */
+    fn validate(&self) -> Result<(), Error> {
+        if self.is_alignment() {
+            return match self.get_alignment() {
+                Some((alignment, _)) if alignment.is_power_of_two() && alignment <= MAX_SECTION_SIZE => Ok(()),
+                _ => Err(Error::new(io::ErrorKind::InvalidData,
+                    format!("Invalid code alignment in instruction!")))
+            }
+        }
+
+        if self.is_raw() {
+            return self.validate_raw()
+        }
+
+        let instructions = Instructions::new();
+        let instr_symbol = match instructions.get_instruction(self.opcode) {
+            Some(i) => i,
+            None => {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                    format!("Unknown opcode {:#x} in instruction!", self.opcode)))
+            }
+        };
+
+        let mut given = vec![false; instr_symbol.args.len()];
+        let positions = self.references.iter().map(|r| (r.argument_pos, None))
+            .chain(self.constants.iter().map(|c| (c.argument_pos, Some(c.size))));
+
+        for (pos, size) in positions {
+            let arg = match instr_symbol.args.get(pos as usize) {
+                Some(a) => a,
+                None => {
+                    return Err(Error::new(io::ErrorKind::InvalidData,
+                        format!("Argument {} is out of range for '{}'", pos, instr_symbol.name)))
+                }
+            };
+            if given[pos as usize] {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                    format!("Argument {} of '{}' is given more than once", pos, instr_symbol.name)))
+            }
+            if let Some(size) = size {
+                if size.get_size() != arg.get_size() {
+                    return Err(Error::new(io::ErrorKind::InvalidData,
+                        format!("Argument {} of '{}' has size {}, {} expected",
+                        pos, instr_symbol.name, size.get_size(), arg.get_size())))
+                }
+            }
+            given[pos as usize] = true;
+        }
+
+        if given.contains(&false) {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Instruction '{}' is missing arguments", instr_symbol.name)))
+        }
+
+        Ok(())
+    }
+    fn validate_raw(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::new(io::ErrorKind::InvalidData, message));
+
+        let (opcode, operands) = match self.get_raw() {
+            Some(r) => r,
+            None => return invalid(format!("Raw code is missing its operand layout!"))
+        };
+        if let Some(opcode) = opcode {
+            if opcode > 0x7F && opcode & 0x80 == 0 {
+                return invalid(format!("Raw opcode {:#x} cannot be encoded!", opcode))
+            }
+        }
+
+        let mut given = vec![false; operands.len()];
+        let positions = self.references.iter().map(|r| (r.argument_pos, None))
+            .chain(self.constants.iter().map(|c| (c.argument_pos, Some(c.size))))
+            .filter(|(pos, size)| *pos >= 2 || size.is_none());
+
+        for (pos, size) in positions {
+            let operand = match (pos as usize).checked_sub(2).and_then(|i| operands.get(i)) {
+                Some(o) => o,
+                None => return invalid(format!("Raw code operand {} is out of range", pos))
+            };
+            let i = pos as usize - 2;
+            if given[i] {
+                return invalid(format!("Raw code operand {} is given more than once", pos))
+            }
+            if let Some(size) = size {
+                if size != operand.size {
+                    return invalid(format!("Raw code operand {} has size {}, {} expected",
+                        pos, size.get_size(), operand.size.get_size()))
+                }
+            }
+            given[i] = true;
+        }
+
+        if given.contains(&false) {
+            return invalid(format!("Raw code is missing operands"))
+        }
+
+        Ok(())
+    }
+    fn write_bytes(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
         binary.write_u16::<LittleEndian>(self.opcode)?;
         binary.write_u8(self.references.len() as u8)?;
         binary.write_u8(self.constants.len() as u8)?;
         
         for rf in self.references.iter() {
-            rf.write_bytes(binary)?;
+            rf.write_bytes(binary, strings)?;
         }
 
         for cst in self.constants.iter() {
@@ -223,7 +677,154 @@ impl InstructionData {
 
         Ok(())
     }
+    pub fn is_alignment(&self) -> bool {
+        self.opcode == ALIGN_CODE_OPCODE
+    }
+
+    /**
+     * Code alignment unit, padded with `fill` bytes
+     */
+    pub fn alignment(alignment: i64, fill: u16) -> Self {
+        Self {
+            opcode: ALIGN_CODE_OPCODE,
+            references: Vec::new(),
+            constants: vec![
+                Constant {
+                    argument_pos: 0,
+                    size: ConstantSize::DoubleWord,
+                    value: alignment
+                },
+                Constant {
+                    argument_pos: 1,
+                    size: ConstantSize::Word,
+                    value: fill as i64
+                }
+            ]
+        }
+    }
+
+    /**
+     * Returns (alignment, fill opcode) of a code alignment unit
+     */
+    pub fn get_alignment(&self) -> Option<(u64, u16)> {
+        if !self.is_alignment() {
+            return None
+        }
+        let alignment = self.constants.iter().find(|c| c.argument_pos == 0)?.value as u64;
+        let fill = self.constants.iter().find(|c| c.argument_pos == 1)?.value as u16;
+
+        Some((alignment, fill))
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.opcode == RAW_CODE_OPCODE
+    }
+
+    /**
+     * Raw code unit with an optional opcode, operands have to be added
+     * as arguments 2 and up
+     */
+    pub fn raw(opcode: Option<u16>, operands: &[RawOperand]) -> Self {
+        let mut layout = 0u64;
+        for (i, operand) in operands.iter().enumerate() {
+            let bits = 0b1000 | (operand.relative as u64) << 2 | operand.size.get_size().trailing_zeros() as u64;
+            layout |= bits << (i * 4);
+        }
+
+        let mut constants = vec![
+            Constant {
+                argument_pos: 0,
+                size: ConstantSize::QuadWord,
+                value: layout as i64
+            }
+        ];
+        if let Some(opcode) = opcode {
+            constants.push(Constant {
+                argument_pos: 1,
+                size: ConstantSize::Word,
+                value: opcode as i64
+            });
+        }
+
+        Self {
+            opcode: RAW_CODE_OPCODE,
+            references: Vec::new(),
+            constants
+        }
+    }
+
+    /**
+     * Returns (opcode, operands) of a raw code unit
+     */
+    pub fn get_raw(&self) -> Option<(Option<u16>, Vec<RawOperand>)> {
+        if !self.is_raw() {
+            return None
+        }
+        let layout = self.constants.iter().find(|c| c.argument_pos == 0)?.value as u64;
+        let opcode = self.constants.iter().find(|c| c.argument_pos == 1).map(|c| c.value as u16);
+
+        let mut operands = Vec::new();
+        for i in 0..MAX_RAW_OPERANDS {
+            let bits = (layout >> (i * 4)) & 0xF;
+            if bits & 0b1000 == 0 {
+                break
+            }
+            operands.push(RawOperand {
+                // Unwrap, because 2 bits are always a valid size
+                size: ConstantSize::from_u8(1 << (bits & 0b11)).unwrap(),
+                relative: bits & 0b100 != 0
+            });
+        }
+
+        Some((opcode, operands))
+    }
+
+    /**
+     * Size in bytes of this instruction when placed `position` bytes into its section
+     */
+    pub fn get_binary_size(&self, position: u64) -> usize {
+        let instructions = Instructions::new();
+
+        if let Some((alignment, _)) = self.get_alignment() {
+            return ((alignment - position % alignment) % alignment) as usize
+        }
+        if let Some((opcode, operands)) = self.get_raw() {
+            return opcode.map_or(0, raw_opcode_size) + operands.iter().map(|o| o.size.get_size()).sum::<usize>()
+        }
+
+        // Unwrap because we assume valid section data from object files
+        instructions.get_instruction(self.opcode).unwrap().get_size()
+    }
+
+    /**
+     * Arguments of a raw code unit as written in '.insn' (or '.word' without an opcode)
+     */
+    fn get_raw_args(&self, opcode: Option<u16>, operands: &[RawOperand]) -> String {
+        let mut result = String::new();
+
+        for (i, operand) in operands.iter().enumerate() {
+            let pos = (i + 2) as u8;
+            if opcode.is_some() || operand.size != ConstantSize::Word || operand.relative {
+                result += &format!("{} ", operand.type_name());
+            }
+            match self.references.iter().find(|r| r.argument_pos == pos) {
+                Some(r) => result += &format!("{} ", r.expression()),
+                None => match self.constants.iter().find(|c| c.argument_pos == pos) {
+                    // Constants are read back sign extended
+                    Some(c) => result += &format!("{:#04x} ", c.value as u64 & (u64::MAX >> (64 - 8 * c.size.get_size()))),
+                    None => result += "(MISSING) "
+                }
+            }
+        }
+
+        result
+    }
+
     pub fn get_args(&self) -> String {
+        if let Some((opcode, operands)) = self.get_raw() {
+            return self.get_raw_args(opcode, &operands)
+        }
+
         let instructions = Instructions::new();
         let registers = Registers::new();
 
@@ -240,7 +841,7 @@ impl InstructionData {
         for i in 0..argc {
             match refs.find(|r| r.argument_pos == (i as u8)) {
                 Some(r) => {
-                    result += &format!("{} ", r.rf);
+                    result += &format!("{} ", r.expression());
                     continue
                 },
                 None => {}
@@ -284,62 +885,247 @@ impl InstructionData {
 }
 
 /**
- * 0 - 8: ptr
- * 8 - <>: name
+ * Visibility of a label to other objects
  */
-#[derive(Debug, Clone)]
-pub struct ObjectLabelSymbol {
-    name: String,
-    pub ptr: u64,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolBinding {
+    // Visible to every object. Used by files that don't use '.global'
+    Default,
+    // Exported with '.global'
+    Global,
+    // Only visible inside its own object
+    Local,
+    // Exported with '.weak', gives way to a non-weak label of the same name
+    Weak
 }
 
-impl ObjectLabelSymbol {
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
-        let mut me = Self {
-            name: String::new(),
-            ptr: 0,
-        };
-
-        me.ptr = binary.read_u64::<LittleEndian>()?;
-
-        let mut char_vec = Vec::<u8>::new();
-
-        let mut c = binary.read_u8()?;
-
-        while c != 0 {
-            char_vec.push(c);
-            c = binary.read_u8()?;
+impl SymbolBinding {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(SymbolBinding::Default),
+            1 => Some(SymbolBinding::Global),
+            2 => Some(SymbolBinding::Local),
+            3 => Some(SymbolBinding::Weak),
+            _ => None
         }
-
-        me.name = String::from_utf8(char_vec).unwrap();
-
-        Ok(me)
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
-        binary.write_u64::<LittleEndian>(self.ptr)?;
-
-        for b in self.name.bytes() {
-            binary.write_u8(b)?;
+    fn to_u8(&self) -> u8 {
+        match self {
+            Self::Default => 0,
+            Self::Global => 1,
+            Self::Local => 2,
+            Self::Weak => 3
         }
-        binary.write_u8(0)?;
-
-        Ok(())
     }
 }
 
 /**
- * Binary reference structure:
- * 0 - 1: size
- * 1 - <>: name
+ * Kind of thing a symbol names
  */
-#[derive(Debug, Clone)]
-pub struct BinaryReference {
-    pub rf: String,
-    pub size: ConstantSize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolType {
+    // Label in a code section
+    Func,
+    // Label in a data or noload section
+    Object
+}
+
+impl SymbolType {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(SymbolType::Func),
+            1 => Some(SymbolType::Object),
+            _ => None
+        }
+    }
+    fn to_u8(&self) -> u8 {
+        match self {
+            Self::Func => 0,
+            Self::Object => 1
+        }
+    }
+}
+
+/**
+ * Label as it is kept in memory, in the labels map of its section
+ */
+#[derive(Debug, Clone)]
+pub struct ObjectLabelSymbol {
+    pub name: String,
+    pub ptr: u64,
+    pub binding: SymbolBinding
+}
+
+/**
+ * Symbol table entry. The table is written after all sections:
+ * 0 - 4: section index, in the order sections are written
+ * 4 - 12: ptr (instruction or data unit index, bytes in noload sections)
+ * 12 - 20: byte offset in the section
+ * 20 - 21: binding
+ * 21 - 22: type
+ * 22 - 26: name (string index)
+ */
+#[derive(Debug, Clone)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: String,
+    pub ptr: u64,
+    pub offset: u64,
+    pub binding: SymbolBinding,
+    pub symbol_type: SymbolType
+}
+
+/**
+ * Source line of the instruction or data unit at `ptr` in `section`.
+ * The line table is written after the symbol table, and is empty unless
+ * the source was preprocessed with line markers:
+ * 0 - 4: section index
+ * 4 - 12: ptr
+ * 12 - 16: file name (string index)
+ * 16 - 20: line
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineInfo {
+    pub section: String,
+    pub ptr: u64,
+    pub file: String,
+    pub line: u32
+}
+
+impl LineInfo {
+    fn from_bytes(binary: &mut dyn Read, sections: &[String], names: &dyn NameSource) -> Result<Self, Error> {
+        let index = binary.read_u32::<LittleEndian>()?;
+        let section = match sections.get(index as usize) {
+            Some(s) => s.clone(),
+            None => {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Line information refers to section {} out of {}", index, sections.len())))
+            }
+        };
+        let ptr = binary.read_u64::<LittleEndian>()?;
+        let file = names.read_name(binary)?;
+        let line = binary.read_u32::<LittleEndian>()?;
+
+        Ok(Self { section, ptr, file, line })
+    }
+    fn write_bytes(&self, binary: &mut dyn Write, section_index: u32, strings: &mut StringTable) -> Result<(), Error> {
+        binary.write_u32::<LittleEndian>(section_index)?;
+        binary.write_u64::<LittleEndian>(self.ptr)?;
+        strings.write(binary, &self.file)?;
+        binary.write_u32::<LittleEndian>(self.line)?;
+
+        Ok(())
+    }
+}
+
+impl ObjectSymbol {
+    fn from_bytes(binary: &mut dyn Read, sections: &[String], names: &dyn NameSource) -> Result<Self, Error> {
+        let index = binary.read_u32::<LittleEndian>()?;
+        let section = match sections.get(index as usize) {
+            Some(s) => s.clone(),
+            None => {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Symbol refers to section {} out of {}", index, sections.len())))
+            }
+        };
+        let ptr = binary.read_u64::<LittleEndian>()?;
+        let offset = binary.read_u64::<LittleEndian>()?;
+        let binding = match SymbolBinding::from_u8(binary.read_u8()?) {
+            Some(b) => b,
+            None => {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Invalid symbol binding for label!")))
+            }
+        };
+        let symbol_type = match SymbolType::from_u8(binary.read_u8()?) {
+            Some(t) => t,
+            None => {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Invalid symbol type for label!")))
+            }
+        };
+        let name = names.read_name(binary)?;
+
+        Ok(Self { name, section, ptr, offset, binding, symbol_type })
+    }
+    fn write_bytes(&self, binary: &mut dyn Write, section_index: u32, strings: &mut StringTable) -> Result<(), Error> {
+        binary.write_u32::<LittleEndian>(section_index)?;
+        binary.write_u64::<LittleEndian>(self.ptr)?;
+        binary.write_u64::<LittleEndian>(self.offset)?;
+        binary.write_u8(self.binding.to_u8())?;
+        binary.write_u8(self.symbol_type.to_u8())?;
+        strings.write(binary, &self.name)?;
+
+        Ok(())
+    }
+}
+
+/**
+ * Tentative definition made with '.comm'. The linker gives every common
+ * symbol of a name one allocation in "bss", as big and as aligned as the
+ * largest of them, unless some object defines the name as a label.
+ * Written after the metadata:
+ * 0 - 4: name (string index)
+ * 4 - 12: size
+ * 12 - 20: alignment
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommonSymbol {
+    pub name: String,
+    pub size: u64,
+    pub alignment: u64
+}
+
+impl CommonSymbol {
+    /**
+     * Combines two tentative definitions of the same name
+     */
+    pub fn merge(&mut self, other: &CommonSymbol) {
+        self.size = self.size.max(other.size);
+        self.alignment = self.alignment.max(other.alignment);
+    }
+    fn from_bytes(binary: &mut dyn Read, names: &dyn NameSource) -> Result<Self, Error> {
+        let name = names.read_name(binary)?;
+        let size = binary.read_u64::<LittleEndian>()?;
+        let alignment = binary.read_u64::<LittleEndian>()?;
+
+        if size > MAX_SECTION_SIZE {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Common symbol '{}' is larger than the address space", name)))
+        }
+        if !alignment.is_power_of_two() || alignment > MAX_SECTION_SIZE {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Invalid alignment {} of common symbol '{}'", alignment, name)))
+        }
+
+        Ok(Self { name, size, alignment })
+    }
+    fn write_bytes(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
+        strings.write(binary, &self.name)?;
+        binary.write_u64::<LittleEndian>(self.size)?;
+        binary.write_u64::<LittleEndian>(self.alignment)?;
+
+        Ok(())
+    }
+}
+
+/**
+ * Binary reference structure:
+ * 0 - 1: size
+ * 1 - 5: name (string index)
+ * 5 - 9: subtracted name (string index, empty if none)
+ * 9 - 17: signed addend
+ */
+#[derive(Debug, Clone)]
+pub struct BinaryReference {
+    pub rf: String,
+    pub subtrahend: Option<String>,
+    pub addend: i64,
+    pub size: ConstantSize
 }
 
 impl BinaryReference {
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
+    fn from_bytes(binary: &mut dyn Read, names: &dyn NameSource, version: u32) -> Result<Self, Error> {
         let size = match ConstantSize::from_u8(binary.read_u8()?) {
             Some(s) => s,
             None => {
@@ -348,30 +1134,35 @@ impl BinaryReference {
             }
         };
 
-        let mut char_vec = Vec::<u8>::new();
-
-        let mut c = binary.read_u8()?;
-
-        while c != 0 {
-            char_vec.push(c);
-            c = binary.read_u8()?;
-        }
+        let rf = names.read_name(binary)?;
+        let subtrahend = if version >= SUBTRAHEND_VERSION { names.read_name(binary)? } else { String::new() };
+        let addend = if version >= ADDEND_VERSION { binary.read_i64::<LittleEndian>()? } else { 0 };
 
         Ok(Self {
             size,
-            rf: String::from_utf8(char_vec).unwrap()
+            rf,
+            subtrahend: if subtrahend.is_empty() { None } else { Some(subtrahend) },
+            addend
         })
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
+    fn write_bytes(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
         binary.write_u8(self.size.to_u8())?;
 
-        for b in self.rf.bytes() {
-            binary.write_u8(b)?;
-        }
-        binary.write_u8(0)?;
+        strings.write(binary, &self.rf)?;
+        strings.write(binary, self.subtrahend.as_deref().unwrap_or(""))?;
+        binary.write_i64::<LittleEndian>(self.addend)?;
 
         Ok(())
     }
+    /**
+     * Every symbol name the reference depends on.
+     */
+    pub fn symbols(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.rf).chain(self.subtrahend.iter())
+    }
+    pub fn expression(&self) -> String {
+        reference_expression(&self.rf, self.subtrahend.as_deref(), self.addend)
+    }
 }
 
 /**
@@ -386,7 +1177,7 @@ pub struct BinaryConstant {
 }
 
 impl BinaryConstant {
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
+    fn from_bytes(binary: &mut dyn Read) -> Result<Self, Error> {
         let size = binary.read_u8()?;
         let value = binary.read_i64::<LittleEndian>()?;
 
@@ -401,7 +1192,7 @@ impl BinaryConstant {
             value
         })
     }
-    fn write_binary(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
+    fn write_binary(&self, binary: &mut dyn Write) -> Result<(), Error> {
         binary.write_u8(self.size.to_u8())?;
         binary.write_i64::<LittleEndian>(self.value)?;
 
@@ -409,31 +1200,73 @@ impl BinaryConstant {
     }
 }
 
+/**
+ * Binary alignment structure, created by '.balign':
+ * 0 - 8: alignment
+ * 8 - 9: fill byte
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryAlignment {
+    pub alignment: u64,
+    pub fill: u8
+}
+
+impl BinaryAlignment {
+    fn from_bytes(binary: &mut dyn Read) -> Result<Self, Error> {
+        let alignment = binary.read_u64::<LittleEndian>()?;
+        let fill = binary.read_u8()?;
+
+        if alignment == 0 || alignment & (alignment - 1) != 0 {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Error occured loading BinaryAlignment: {} is not a power of two", alignment)))
+        }
+        if alignment > MAX_SECTION_SIZE {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Error occured loading BinaryAlignment: {} is too large", alignment)))
+        }
+
+        Ok(Self { alignment, fill })
+    }
+    fn write_bytes(&self, binary: &mut dyn Write) -> Result<(), Error> {
+        binary.write_u64::<LittleEndian>(self.alignment)?;
+        binary.write_u8(self.fill)?;
+
+        Ok(())
+    }
+}
+
 /**
  * Binary unit structure description
- * 0 - 1: Type (0 is const, 1 is ref)
+ * 0 - 1: Type (0 is const, 1 is ref, 2 is alignment)
  * <data>
  */
 #[derive(Debug, Clone)]
 pub struct BinaryUnit {
     pub reference: Option<BinaryReference>,
-    pub constant: Option<BinaryConstant>
+    pub constant: Option<BinaryConstant>,
+    pub alignment: Option<BinaryAlignment>
 }
 
 impl BinaryUnit {
-    pub fn get_size(&self) -> Option<usize> {
+    /**
+     * Size in bytes of this unit when placed `position` bytes into its section
+     */
+    pub fn get_size(&self, position: u64) -> Option<usize> {
         if let Some(cst) = &self.constant {
             Some(cst.size.get_size())
         } else if let Some(reference) = &self.reference {
             Some(reference.size.get_size())
+        } else if let Some(align) = &self.alignment {
+            Some(((align.alignment - position % align.alignment) % align.alignment) as usize)
         } else {
             None
         }
     }
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
+    fn from_bytes(binary: &mut dyn Read, names: &dyn NameSource, version: u32) -> Result<Self, Error> {
         let mut me = Self {
             reference: None,
-            constant: None
+            constant: None,
+            alignment: None
         };
         
         let typ = binary.read_u8()?;
@@ -443,7 +1276,10 @@ impl BinaryUnit {
                 me.constant = Some(BinaryConstant::from_bytes(binary)?)
             },
             1 => {
-                me.reference = Some(BinaryReference::from_bytes(binary)?)
+                me.reference = Some(BinaryReference::from_bytes(binary, names, version)?)
+            },
+            2 => {
+                me.alignment = Some(BinaryAlignment::from_bytes(binary)?)
             },
             _ => {
                 return Err(Error::new(io::ErrorKind::InvalidData, 
@@ -453,13 +1289,16 @@ impl BinaryUnit {
 
         Ok(me)
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
+    fn write_bytes(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
         if let Some(cst) = &self.constant {
             binary.write_u8(0)?;
             cst.write_binary(binary)?;
         } else if let Some(reference) = &self.reference {
             binary.write_u8(1)?;
-            reference.write_bytes(binary)?;
+            reference.write_bytes(binary, strings)?;
+        } else if let Some(align) = &self.alignment {
+            binary.write_u8(2)?;
+            align.write_bytes(binary)?;
         } else {
             return Err(Error::new(io::ErrorKind::InvalidData, 
                 format!("BinaryUnit without information!")))
@@ -468,41 +1307,220 @@ impl BinaryUnit {
     }
 }
 
+/**
+ * Section attributes set with '.section "name" "flags"'.
+ * Flags are written as letters: r(ead), w(rite), (e)x(ecute), n(oload),
+ * c(ompressed).
+ * 
+ * Noload sections hold no data, only a size (like BSS), so they may only
+ * contain reservations and labels.
+ *
+ * Compressed sections are deflated in the object file. This doesn't
+ * change how they are linked.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionFlags(pub u8);
+
+impl SectionFlags {
+    pub const READ: u8 = 1;
+    pub const WRITE: u8 = 2;
+    pub const EXEC: u8 = 4;
+    pub const NOLOAD: u8 = 8;
+    pub const COMPRESSED: u8 = 16;
+
+    const LETTERS: [(char, u8); 5] = [
+        ('r', Self::READ), ('w', Self::WRITE), ('x', Self::EXEC), ('n', Self::NOLOAD),
+        ('c', Self::COMPRESSED)
+    ];
+
+    pub fn parse(flags: &str) -> Result<Self, String> {
+        let mut result = 0u8;
+
+        for c in flags.chars() {
+            match Self::LETTERS.iter().find(|(l, _)| *l == c) {
+                Some((_, bit)) => result |= bit,
+                None => {
+                    return Err(format!("Unknown section flag '{}' in \"{}\". Expected any of \"rwxnc\"", c, flags))
+                }
+            }
+        }
+
+        Ok(Self(result))
+    }
+
+    /**
+     * Flags of sections that don't specify any.
+     * Subsections like "text.init" get the flags of their parent.
+     */
+    pub fn default_for(section_name: &str) -> Self {
+        match section_name.split('.').next().unwrap_or(section_name) {
+            "text" => Self(Self::READ | Self::EXEC),
+            "rodata" => Self(Self::READ),
+            "bss" => Self(Self::READ | Self::WRITE | Self::NOLOAD),
+            _ => Self(Self::READ | Self::WRITE)
+        }
+    }
+
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+
+    /**
+     * Flags that matter once the section is linked
+     */
+    pub fn link_flags(&self) -> Self {
+        Self(self.0 & !Self::COMPRESSED)
+    }
+}
+
+impl std::fmt::Display for SectionFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (letter, bit) in Self::LETTERS {
+            if self.contains(bit) {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 /**
  * Section structure description:
  * 0 - 8: instruction count
- * 8 - 16: label count
- * 16 - 24: binary size
- * 24 - <>: section name
- * <> - <>: Labels
- * <> - <>: Instructions
+ * 8 - 16: binary size
+ * 16 - 20: section name
+ * 20 - 21: section flags
+ * 21 - 29: reserved size of a noload section
+ * 29 - <>: Instructions
  * <> - <>: Binary
+ *
+ * In compressed sections, instructions and binary are deflated
+ * and preceded by their compressed size (8 bytes).
  */
 #[derive(Debug, Clone)]
 pub struct SectionData {
-    name: String,
+    pub name: String,
     pub instructions: Vec<InstructionData>,
-    pub labels: HashMap<String, ObjectLabelSymbol>,
+    // Sorted, so objects are written the same way every time
+    pub labels: BTreeMap<String, ObjectLabelSymbol>,
 //    pub binary_data: Vec<u8>,
     pub binary_data: Vec<BinaryUnit>,
-    pub binary_section: bool
+    pub binary_section: bool,
+    pub flags: SectionFlags,
+    // Size in bytes of a noload section. Its labels point to byte offsets
+    pub bss_size: u64,
+    // Where the parts of a linked section came from, objects don't store it
+    pub origins: Vec<SectionOrigin>
+}
+
+/**
+ * Part of a linked section that came from one input section of an object
+ */
+#[derive(Debug, Clone)]
+pub struct SectionOrigin {
+    pub object: String,
+    pub section: String,
+    // Unit (or byte of a noload section) the part starts at
+    pub ptr: u64
 }
 
 impl SectionData {
+    /**
+     * Empty noload section with the default flags of `name`
+     */
+    pub fn nobits(name: &str) -> Self {
+        let mut me = Self::new();
+        me.name = name.to_string();
+        me.flags = SectionFlags(SectionFlags::default_for(name).0 | SectionFlags::NOLOAD);
+        me
+    }
+    /**
+     * Empty section with the given flags
+     */
+    pub fn with_flags(name: &str, flags: SectionFlags) -> Self {
+        let mut me = Self::new();
+        me.name = name.to_string();
+        me.flags = flags;
+        me
+    }
+    /**
+     * Code section holding just 'jpr entry'
+     */
+    pub fn boot_stub(entry: &str) -> Self {
+        let mut me = Self::new();
+        me.instructions.push(InstructionData {
+            opcode: 12, // jpr opcode
+            references: vec![Reference {
+                argument_pos: 0,
+                rf: entry.to_string(),
+                subtrahend: None,
+                addend: 0
+            }],
+            constants: Vec::new()
+        });
+        me
+    }
+    /**
+     * Empty section of the same kind and with the same flags as this one
+     */
+    pub fn empty_like(&self) -> Self {
+        let mut me = Self::new();
+        me.name = self.name.clone();
+        me.flags = self.flags;
+        me.binary_section = self.binary_section;
+        me
+    }
     fn new() -> Self {
         Self {
             name: "text".to_string(),
             instructions: Vec::new(),
-            labels: HashMap::new(),
+            labels: BTreeMap::new(),
             binary_data: Vec::new(),
-            binary_section: false
+            binary_section: false,
+            flags: SectionFlags::default_for("text"),
+            bss_size: 0,
+            origins: Vec::new()
+        }
+    }
+    pub fn is_nobits(&self) -> bool {
+        self.flags.contains(SectionFlags::NOLOAD)
+    }
+    /**
+     * Number of units label pointers index: bytes of noload sections,
+     * binary units or instructions
+     */
+    pub fn get_unit_count(&self) -> u64 {
+        if self.is_nobits() {
+            self.bss_size
+        } else if self.binary_section {
+            self.binary_data.len() as u64
+        } else {
+            self.instructions.len() as u64
         }
     }
     pub fn append_other(&mut self, mut other: SectionData) -> Result<(), String> {
         if self.binary_section != other.binary_section {
             return Err(format!("Cannot merge binary section with non-binary one"))
         }
-        if self.binary_section {
+        if self.flags.link_flags() != other.flags.link_flags() {
+            return Err(format!("Cannot merge section '{}' with flags \"{}\" into one with flags \"{}\"",
+                other.name, other.flags, self.flags))
+        }
+        let old_length = self.get_unit_count();
+        self.origins.extend(other.origins.drain(..).map(|mut origin| {
+            origin.ptr += old_length;
+            origin
+        }));
+        if self.is_nobits() {
+            for (label_name, mut label) in other.labels {
+                if self.labels.contains_key(&label_name) {
+                    return Err(format!("Cannot merge two binary sections with similar labels!"))
+                }
+                label.ptr += self.bss_size;
+                self.labels.insert(label_name, label);
+            }
+            self.bss_size += other.bss_size;
+        } else if self.binary_section {
             let old_bin_length = self.binary_data.len() as u64;
             self.binary_data.append(&mut other.binary_data);
             
@@ -529,135 +1547,242 @@ impl SectionData {
         Ok(())
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty() && self.binary_data.is_empty() && self.labels.is_empty()
+    }
+
+    /**
+     * Whether a label can point at `ptr`: any entry of the section, or its end
+     */
+    fn contains_position(&self, ptr: u64) -> bool {
+        if self.is_nobits() {
+            ptr <= self.bss_size
+        } else if self.binary_section {
+            ptr <= self.binary_data.len() as u64
+        } else {
+            ptr <= self.instructions.len() as u64
+        }
+    }
+
     pub fn get_binary_size(&self) -> usize {
+        if self.is_nobits() {
+            return self.bss_size as usize
+        }
         if self.binary_section {
             let mut binary_len = 0;
 
             for unit in self.binary_data.iter() {
                 // unwrap because we assume this is valid from object file
-                binary_len += unit.get_size().unwrap();
+                binary_len += unit.get_size(binary_len as u64).unwrap();
             }
 
             return binary_len
         }
 
-        let instructions = Instructions::new();
-
         let mut binary_len = 0usize;
 
         for instr in self.instructions.iter() {
-            // Unwrap, because we assume a section is valid from object file
-            binary_len += instructions.get_instruction(instr.opcode).unwrap().get_size();
+            binary_len += instr.get_binary_size(binary_len as u64);
         }
 
         binary_len
     }
 
+    /**
+     * Largest alignment requested by '.align_code' or '.balign' in this section
+     */
+    pub fn get_code_alignment(&self) -> u64 {
+        let code = self.instructions.iter()
+            .filter_map(|i| i.get_alignment())
+            .map(|(alignment, _)| alignment);
+        let binary = self.binary_data.iter()
+            .filter_map(|u| u.alignment.as_ref())
+            .map(|a| a.alignment);
+
+        code.chain(binary).max().unwrap_or(1)
+    }
+
     pub fn get_binary_position(&self, index: u64) -> u64 {
+        if self.is_nobits() {
+            return index
+        }
         if self.binary_section {
             let mut binary_index = 0;
 
             for (i, unit) in self.binary_data.iter().enumerate() {
                 if i as u64 == index { break }
                 // unwrap because we assume this is valid from object file
-                binary_index += unit.get_size().unwrap();
+                binary_index += unit.get_size(binary_index as u64).unwrap();
             }
 
             return binary_index as u64
         }
 
-        let instructions = Instructions::new();
-
         let mut binary_index = 0u64;
 
         for (idx, instr) in self.instructions.iter().enumerate() {
             if idx as u64 == index { break }
-            // I won't explain why I'm adding unwraps anymore
-            binary_index += instructions.get_instruction(instr.opcode).unwrap().get_size() as u64;
+            binary_index += instr.get_binary_size(binary_index) as u64;
         }
 
         binary_index
     }
 
+    /**
+     * Distance from a label to the next label, or to the end of the section
+     */
+    pub fn get_label_size(&self, label_name: &str) -> Option<u64> {
+        let start = self.get_label_binary_offset(label_name)?;
+
+        let end = self.labels.values()
+            .map(|l| self.get_binary_position(l.ptr))
+            .filter(|offset| *offset > start)
+            .min()
+            .unwrap_or(self.get_binary_size() as u64);
+
+        Some(end - start)
+    }
+
+    /**
+     * Type given to labels of this section in the symbol table
+     */
+    pub fn symbol_type(&self) -> SymbolType {
+        if self.binary_section || self.is_nobits() {
+            SymbolType::Object
+        } else {
+            SymbolType::Func
+        }
+    }
+
     pub fn get_label_binary_offset(&self, label_name: &str) -> Option<u64> {
         let label = self.labels.get(label_name)?;
 
-        if self.binary_section { return Some(label.ptr) }
-
+        // Label pointers index units or instructions, which may be larger than a byte
         Some(self.get_binary_position(label.ptr))
     }
 
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
+    fn from_bytes(binary: &mut dyn Read, names: &dyn NameSource, version: u32) -> Result<Self, Error> {
         let mut me = Self::new();
 
         let instruction_count = binary.read_u64::<LittleEndian>()?;
-        let label_count = binary.read_u64::<LittleEndian>()?;
+        // Labels moved to the symbol table
+        let label_count = if version < SYMBOL_TABLE_VERSION { binary.read_u64::<LittleEndian>()? } else { 0 };
         let binary_count = binary.read_u64::<LittleEndian>()?;
 
-        let mut char_vec = Vec::<u8>::new();
-
-        let mut c = binary.read_u8()?;
+        me.name = names.read_name(binary)?;
+        me.flags = if version >= SECTION_FLAGS_VERSION {
+            SectionFlags(binary.read_u8()?)
+        } else {
+            SectionFlags::default_for(&me.name)
+        };
+        me.bss_size = if version >= NOBITS_VERSION { binary.read_u64::<LittleEndian>()? } else { 0 };
 
-        while c != 0 {
-            char_vec.push(c);
-            c = binary.read_u8()?;
+        if me.bss_size > MAX_SECTION_SIZE {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Section '{}' reserves {} bytes, more than the address space", me.name, me.bss_size)))
         }
 
-        me.name = String::from_utf8(char_vec).unwrap();
-
         for _ in 0..label_count {
-            let label = ObjectLabelSymbol::from_bytes(binary)?;
-
-            let name = label.name.clone();
+            let ptr = binary.read_u64::<LittleEndian>()?;
+            let binding = if version >= LABEL_BINDING_VERSION {
+                match SymbolBinding::from_u8(binary.read_u8()?) {
+                    Some(b) => b,
+                    None => {
+                        return Err(Error::new(io::ErrorKind::InvalidData,
+                        format!("Invalid symbol binding for label!")))
+                    }
+                }
+            } else {
+                SymbolBinding::Default
+            };
+            let name = read_cstring(binary)?;
 
             if me.labels.contains_key(&name) {
                 return Err(Error::new(io::ErrorKind::InvalidData,
                 format!("Invalid label information for section '{}'! Label '{}' already exists!",
                 me.name, name)))
             }
+            me.labels.insert(name.clone(), ObjectLabelSymbol { name, ptr, binding });
+        }
+
+        if version >= COMPRESSION_VERSION && me.flags.contains(SectionFlags::COMPRESSED) {
+            let compressed_size = binary.read_u64::<LittleEndian>()?;
+            let mut decoder = DeflateDecoder::new(binary.take(compressed_size));
+
+            me.read_contents(&mut decoder, names, version, instruction_count, binary_count)?;
+
+            // The decoder may stop before the end of the compressed data
+            let mut rest = decoder.into_inner();
+            io::copy(&mut rest, &mut io::sink())?;
+            if rest.limit() != 0 {
+                return Err(Error::new(io::ErrorKind::UnexpectedEof,
+                    format!("Compressed data of section '{}' is truncated", me.name)))
+            }
+        } else {
+            me.read_contents(binary, names, version, instruction_count, binary_count)?;
+        }
 
-            me.labels.insert(label.name.clone(), label);
+        if me.binary_data.len() != 0 && me.instructions.len() != 0 {
+            return Err(Error::new(io::ErrorKind::InvalidData,
+                format!("Section '{}' has both binary and instructions!", me.name)))
+        }
+        me.binary_section = me.binary_data.len() != 0;
+
+        for label in me.labels.values() {
+            if !me.contains_position(label.ptr) {
+                return Err(Error::new(io::ErrorKind::InvalidData,
+                    format!("Label '{}' points outside of section '{}'", label.name, me.name)))
+            }
         }
 
+        Ok(me)
+    }
+    fn read_contents(&mut self, binary: &mut dyn Read, names: &dyn NameSource, version: u32,
+        instruction_count: u64, binary_count: u64) -> Result<(), Error>
+    {
         for _ in 0..instruction_count {
-            let instruction = InstructionData::from_bytes(binary)?;
-            me.instructions.push(instruction);
+            let instruction = InstructionData::from_bytes(binary, names, version)?;
+            self.instructions.push(instruction);
         }
 
         for _ in 0..binary_count {
-            let bin = BinaryUnit::from_bytes(binary)?;
-            me.binary_data.push(bin);
+            let bin = BinaryUnit::from_bytes(binary, names, version)?;
+            self.binary_data.push(bin);
         }
 
-        me.binary_section = me.binary_data.len() != 0;
-
-        Ok(me)
+        Ok(())
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
+    fn write_bytes(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
         if self.binary_data.len() != 0 && self.instructions.len() != 0 {
             return Err(Error::new(io::ErrorKind::InvalidInput,
                 format!("Binary and instructions cannot coexist in a single section!")))
         }
 
         binary.write_u64::<LittleEndian>(self.instructions.len() as u64)?;
-        binary.write_u64::<LittleEndian>(self.labels.len() as u64)?;
         binary.write_u64::<LittleEndian>(self.binary_data.len() as u64)?;
 
-        for b in self.name.bytes() {
-            binary.write_u8(b)?;
-        }
-        binary.write_u8(0)?;
+        strings.write(binary, &self.name)?;
+        binary.write_u8(self.flags.0)?;
+        binary.write_u64::<LittleEndian>(self.bss_size)?;
+
+        if self.flags.contains(SectionFlags::COMPRESSED) {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            self.write_contents(&mut encoder, strings)?;
+            let compressed = encoder.finish()?;
 
-        for (_, lbl) in self.labels.iter() {
-            lbl.write_bytes(binary)?;
+            binary.write_u64::<LittleEndian>(compressed.len() as u64)?;
+            return binary.write_all(&compressed)
         }
 
+        self.write_contents(binary, strings)
+    }
+    fn write_contents(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
         for instr in self.instructions.iter() {
-            instr.write_bytes(binary)?;
+            instr.write_bytes(binary, strings)?;
         }
 
         for byt in self.binary_data.iter() {
-            byt.write_bytes(binary)?;
+            byt.write_bytes(binary, strings)?;
             //binary.write_u8(*byt)?;
         }
 
@@ -670,15 +1795,93 @@ impl SectionData {
  * 0 - 8:   Magic
  * 8 - 16: length of sections
  * 16 - 20: version number
+ * 20 - 21: target endianness
+ * 21 - 25: CRC32 of everything after the header
+ *
+ * It is followed by the string table, the sections, the symbol table,
+ * line information, metadata and common symbols.
+ * The object file itself is always little endian.
+ */
+
+pub const HEADER_SIZE: u64 = 8 * 2 + 4 * 2 + 1;
+
+/**
+ * Byte order of multi-byte values in the linked image
  */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little, Big
+}
+
+impl Endianness {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Little),
+            1 => Some(Self::Big),
+            _ => None
+        }
+    }
+    fn to_u8(&self) -> u8 {
+        match self {
+            Self::Little => 0,
+            Self::Big => 1
+        }
+    }
+
+    /**
+     * Appends the low `size` bytes of `value` in this byte order
+     */
+    pub fn write(&self, binary: &mut Vec<u8>, size: ConstantSize, value: u64) {
+        match self {
+            Self::Little => Self::write_ordered::<LittleEndian>(binary, size, value),
+            Self::Big => Self::write_ordered::<BigEndian>(binary, size, value)
+        }
+    }
+    /**
+     * Reads a `size` byte value in this byte order from the start of `bytes`,
+     * which has to be long enough
+     */
+    pub fn read(&self, bytes: &[u8], size: ConstantSize) -> u64 {
+        match self {
+            Self::Little => Self::read_ordered::<LittleEndian>(bytes, size),
+            Self::Big => Self::read_ordered::<BigEndian>(bytes, size)
+        }
+    }
+    fn read_ordered<E: ByteOrder>(bytes: &[u8], size: ConstantSize) -> u64 {
+        match size {
+            ConstantSize::Byte => bytes[0] as u64,
+            ConstantSize::Word => E::read_u16(bytes) as u64,
+            ConstantSize::DoubleWord => E::read_u32(bytes) as u64,
+            ConstantSize::QuadWord => E::read_u64(bytes)
+        }
+    }
+    fn write_ordered<E: ByteOrder>(binary: &mut Vec<u8>, size: ConstantSize, value: u64) {
+        // Unwraps because writing to a Vec doesn't fail
+        match size {
+            ConstantSize::Byte => binary.write_u8(value as u8).unwrap(),
+            ConstantSize::Word => binary.write_u16::<E>(value as u16).unwrap(),
+            ConstantSize::DoubleWord => binary.write_u32::<E>(value as u32).unwrap(),
+            ConstantSize::QuadWord => binary.write_u64::<E>(value).unwrap()
+        }
+    }
+}
 
-pub const HEADER_SIZE: u64 = 8 * 2 + 4;
+impl std::fmt::Display for Endianness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Little => write!(f, "little"),
+            Self::Big => write!(f, "big")
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ObjectFormatHeader {
     magic: u64,
     pub sections_length: u64, // sections count
     version: u32,
+    pub endianness: Endianness,
+    checksum: u32
 }
 
 impl ObjectFormatHeader {
@@ -686,10 +1889,12 @@ impl ObjectFormatHeader {
         Self {
             magic: MAGIC_FORMAT_NUMBER,
             sections_length: 0,
-            version: CURRENT_FORMAT_VERSION
+            version: CURRENT_FORMAT_VERSION,
+            endianness: Endianness::Little,
+            checksum: 0
         }
     }
-    fn from_bytes(binary: &mut &[u8]) -> Result<Self, Error> {
+    fn from_bytes(binary: &mut dyn Read) -> Result<Self, Error> {
         let mut me = ObjectFormatHeader::new();
 
         me.magic = binary.read_u64::<LittleEndian>()?;
@@ -701,21 +1906,222 @@ impl ObjectFormatHeader {
 
         me.sections_length = binary.read_u64::<LittleEndian>()?;
         me.version = binary.read_u32::<LittleEndian>()?;
-
+        if me.version >= ENDIANNESS_VERSION {
+            me.endianness = match Endianness::from_u8(binary.read_u8()?) {
+                Some(e) => e,
+                None => {
+                    return Err(Error::new(io::ErrorKind::InvalidData,
+                        format!("Invalid target endianness!")))
+                }
+            };
+        }
+        if me.version >= CHECKSUM_VERSION {
+            me.checksum = binary.read_u32::<LittleEndian>()?;
+        }
+
         Ok(me)
     }
-    fn write_bytes(&self, binary: &mut Vec<u8>) -> Result<(), Error> {
+    fn write_bytes(&self, binary: &mut dyn Write) -> Result<(), Error> {
         binary.write_u64::<LittleEndian>(self.magic)?;
         binary.write_u64::<LittleEndian>(self.sections_length)?;
         binary.write_u32::<LittleEndian>(self.version)?;
+        binary.write_u8(self.endianness.to_u8())?;
+        binary.write_u32::<LittleEndian>(self.checksum)?;
 
         Ok(())
     }
 }
 
+/**
+ * CRC-32 (IEEE 802.3, as used by zip and PNG)
+ */
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/**
+ * Running CRC-32, for data that is streamed instead of held in memory
+ */
+struct Crc32 {
+    crc: u32
+}
+
+impl Crc32 {
+    fn new() -> Self {
+        Self { crc: 0xFFFFFFFF }
+    }
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (self.crc & 1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (0xEDB88320 & mask);
+            }
+        }
+    }
+    fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/**
+ * Reader that checksums everything read through it
+ */
+struct ChecksumReader<'a> {
+    inner: &'a mut dyn Read,
+    crc: Crc32
+}
+
+impl Read for ChecksumReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/**
+ * Writer that checksums everything written through it
+ */
+struct ChecksumWriter<'a> {
+    inner: &'a mut dyn Write,
+    crc: Crc32
+}
+
+impl Write for ChecksumWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/**
+ * Value of a link-time expression: the `plus` labels minus the
+ * `minus` labels plus a constant.
+ */
+#[derive(Debug, Default)]
+struct SymbolicValue {
+    plus: Vec<String>,
+    minus: Vec<String>,
+    constant: i64
+}
+
+impl SymbolicValue {
+    fn constant(value: i64) -> Self {
+        Self { constant: value, ..Default::default() }
+    }
+    fn label(name: String) -> Self {
+        Self { plus: vec![name], ..Default::default() }
+    }
+    fn has_labels(&self) -> bool {
+        !self.plus.is_empty() || !self.minus.is_empty()
+    }
+    fn negate(self) -> Self {
+        Self { plus: self.minus, minus: self.plus, constant: self.constant.wrapping_neg() }
+    }
+    // Labels added and subtracted again cancel out
+    fn add(mut self, other: Self) -> Self {
+        for name in other.plus {
+            match self.minus.iter().position(|n| *n == name) {
+                Some(i) => { self.minus.remove(i); }
+                None => self.plus.push(name)
+            }
+        }
+        for name in other.minus {
+            match self.plus.iter().position(|n| *n == name) {
+                Some(i) => { self.plus.remove(i); }
+                None => self.minus.push(name)
+            }
+        }
+        self.constant = self.constant.wrapping_add(other.constant);
+        self
+    }
+
+    /**
+     * Splits an expression into labels and a constant. `identifier`
+     * gives the value of a name, other operators only apply to constants.
+     */
+    fn parse(node: &ParserNode, depth: i32,
+        identifier: &dyn Fn(&str, i32) -> Result<SymbolicValue, String>) -> Result<Self, String>
+    {
+        if depth > 100 {
+            return Err(format!("Define nesting is too deep"))
+        }
+        let child = |index: usize| match node.children.get(index) {
+            Some(n) => Self::parse(n, depth + 1, identifier),
+            None => Err(format!("Malformed {:?} node", node.node_type))
+        };
+        match &node.node_type {
+            NodeType::ConstInteger(n) => Ok(Self::constant(*n)),
+            NodeType::Identifier(name) => identifier(name, depth),
+            NodeType::Expression => child(0),
+            NodeType::Negate => Ok(child(0)?.negate()),
+            NodeType::Addition => Ok(child(0)?.add(child(1)?)),
+            NodeType::Subtraction => Ok(child(0)?.add(child(1)?.negate())),
+            _ => match node.evaluate(&|name| match identifier(name, depth) {
+                Ok(value) if !value.has_labels() => Some(value.constant),
+                _ => None
+            }) {
+                Ok(value) => Ok(Self::constant(value)),
+                // Labels are only known at link time, where they can only be added up
+                Err(_) if node.children.iter().any(|c| Self::parse(c, depth + 1, identifier).is_ok_and(|v| v.has_labels())) => {
+                    Err(format!("Label differences must be of the form (a - b), plus or minus constants: \
+                        {:?} can't be applied to labels", node.node_type))
+                }
+                Err(e) => Err(e)
+            }
+        }
+    }
+
+    /**
+     * Label, optional subtrahend and addend the linker can resolve.
+     */
+    fn reference(&self) -> Result<(String, Option<String>, i64), String> {
+        match (self.plus.as_slice(), self.minus.as_slice()) {
+            ([rf], []) => Ok((rf.clone(), None, self.constant)),
+            ([rf], [sub]) => Ok((rf.clone(), Some(sub.clone()), self.constant)),
+            _ => Err(format!("Link-time expressions must be a label or a difference of two labels \
+                of the form (a - b), got +{:?} -{:?}", self.plus, self.minus))
+        }
+    }
+
+    fn to_node(&self) -> ParserNode {
+        let leaf = |node_type| ParserNode { node_type, children: Vec::new() };
+
+        let mut terms: Vec<(bool, ParserNode)> = Vec::new();
+        terms.extend(self.plus.iter().map(|n| (false, leaf(NodeType::Identifier(n.clone())))));
+        terms.extend(self.minus.iter().map(|n| (true, leaf(NodeType::Identifier(n.clone())))));
+        if self.constant != 0 || terms.is_empty() {
+            terms.push((false, leaf(NodeType::ConstInteger(self.constant))));
+        }
+
+        let mut iter = terms.into_iter();
+        let mut node = match iter.next() {
+            Some((true, n)) => ParserNode { node_type: NodeType::Negate, children: vec![n] },
+            Some((false, n)) => n,
+            None => unreachable!()
+        };
+        for (negative, term) in iter {
+            node = ParserNode {
+                node_type: if negative { NodeType::Subtraction } else { NodeType::Addition },
+                children: vec![node, term]
+            };
+        }
+        ParserNode { node_type: NodeType::Expression, children: vec![node] }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Define {
-    node: ParserNode
+    node: ParserNode,
+    // Set by '.equ', which cannot be reassigned
+    constant: bool
 }
 
 /**
@@ -730,16 +2136,153 @@ struct Define {
 pub struct ObjectFormat {
     pub header: ObjectFormatHeader,
     defines: HashMap<String, Define>,
-    pub sections: HashMap<String, SectionData>,
+    pub sections: BTreeMap<String, SectionData>,
     compiler_instructions: HashMap<String, fn(&mut Self, &Vec<ParserNode>) -> Result<(), String>>,
-    current_section: String
+    current_section: String,
+    // Symbols named by '.global', '.extern', '.weak' and '.local'
+    globals: HashSet<String>,
+    externs: HashSet<String>,
+    weaks: HashSet<String>,
+    locals: HashSet<String>,
+    // Spill large immediates into literal pools
+    pub literal_pools: bool,
+    // Let '.db' widen values that don't fit in a byte, like it used to
+    pub widen_db: bool,
+    string_encoding: StringEncoding,
+    // (value, label) of literals not yet placed by '.ltorg'
+    pending_literals: Vec<(i64, String)>,
+    literal_count: usize,
+    // Anonymous ':' labels defined so far
+    anonymous_labels: usize,
+    // Set by LineMarker nodes
+    source_line: Option<(String, u32)>,
+    pub debug_lines: Vec<LineInfo>,
+    // (key, value) pairs describing what produced the object
+    pub metadata: Vec<(String, String)>,
+    // Declared with '.comm'
+    pub commons: Vec<CommonSymbol>,
+    // File the object was read from, not saved in objects
+    pub file: Option<String>
 }
 
 const DEFAULT_SECTION_NAME: &str = "text";
+const ANONYMOUS_LABEL_PREFIX: &str = "__anon_";
+// Literals that no '.ltorg' placed end up here, at the front of "rodata"
+const LITERAL_POOL_SECTION: &str = "rodata.literals";
+// Instructions taking a 32-bit immediate, and their memory operand forms
+const LITERAL_LOADS: [(&str, &str); 3] = [("loadid", "loadmd"), ("iadd", "madd"), ("isub", "msub")];
+
+/**
+ * Deserializer for a range of object format versions. Versions that only
+ * add fields share a reader, which checks the version for each of them.
+ */
+trait ObjectReader {
+    fn versions(&self) -> RangeInclusive<u32>;
+    // Reads everything after the header
+    fn read(&self, object: &mut ObjectFormat, version: u32, binary: &mut dyn Read) -> Result<(), String>;
+}
+
+// Names stored inline as C strings
+struct InlineNamesReader;
+
+impl ObjectReader for InlineNamesReader {
+    fn versions(&self) -> RangeInclusive<u32> {
+        OLDEST_FORMAT_VERSION..=STRING_TABLE_VERSION - 1
+    }
+    fn read(&self, object: &mut ObjectFormat, version: u32, binary: &mut dyn Read) -> Result<(), String> {
+        object.read_body(version, binary, &InlineNames)
+    }
+}
+
+// Names stored once in a string table in front of the sections
+struct StringTableReader;
+
+impl ObjectReader for StringTableReader {
+    fn versions(&self) -> RangeInclusive<u32> {
+        STRING_TABLE_VERSION..=CURRENT_FORMAT_VERSION
+    }
+    fn read(&self, object: &mut ObjectFormat, version: u32, binary: &mut dyn Read) -> Result<(), String> {
+        let strings = match StringTable::from_bytes(binary) {
+            Ok(strings) => strings,
+            Err(e) => {
+                return Err(format!("Error occured while parsing string table: {}", e))
+            }
+        };
+        object.read_body(version, binary, &strings)
+    }
+}
+
+const OBJECT_READERS: [&dyn ObjectReader; 2] = [&InlineNamesReader, &StringTableReader];
 
 impl ObjectFormat {
-    fn evaluate_expression(&self, _expr: &ParserNode) -> Result<ParserNode, String> {
-        todo!()
+    /**
+     * Value of an integer define, following defines that name other defines
+     */
+    fn define_value(&self, name: &str, depth: i32) -> Option<i64> {
+        if depth > 100 {
+            return None
+        }
+        match &self.defines.get(name)?.node.node_type {
+            NodeType::ConstInteger(n) => Some(*n),
+            NodeType::Identifier(other) => self.define_value(other, depth + 1),
+            _ => None
+        }
+    }
+
+    /**
+     * Folds a constant expression into a single ConstInteger node.
+     * Identifiers are looked up in defines.
+     */
+    fn evaluate_expression(&self, expr: &ParserNode) -> Result<ParserNode, String> {
+        // Negative float literals are the only float expression
+        if let (NodeType::Negate, Some(ParserNode { node_type: NodeType::ConstFloat(n), .. }))
+            = (&expr.node_type, expr.children.get(0))
+        {
+            return Ok(ParserNode { node_type: NodeType::ConstFloat(-n), children: Vec::new() })
+        }
+
+        let value = expr.evaluate(&|name| self.define_value(name, 0))?;
+
+        Ok(ParserNode {
+            node_type: NodeType::ConstInteger(value),
+            children: Vec::new()
+        })
+    }
+
+    /**
+     * Defines are followed, any other name is taken to be a label.
+     */
+    fn symbolic_value(&self, node: &ParserNode, depth: i32) -> Result<SymbolicValue, String> {
+        SymbolicValue::parse(node, depth, &|name, depth| match self.defines.get(name) {
+            Some(define) => self.symbolic_value(&define.node, depth + 1),
+            None => Ok(SymbolicValue::label(self.anonymous_reference(name)?))
+        })
+    }
+
+    /**
+     * Value of an expression that involves labels, or `None` if it
+     * is not one and should be folded as a constant instead.
+     */
+    fn link_value(&self, node: &ParserNode) -> Result<Option<SymbolicValue>, String> {
+        match self.symbolic_value(node, 0) {
+            Ok(value) if value.has_labels() => {
+                value.reference()?;
+                Ok(Some(value))
+            }
+            Ok(_) => Ok(None),
+            // Say why labels can't be used, unless it folds anyway
+            Err(e) => match self.evaluate_expression(node) {
+                Ok(_) => Ok(None),
+                Err(_) => Err(e)
+            }
+        }
+    }
+
+    /**
+     * Reference of a folded data argument, where every name is a label.
+     */
+    fn data_reference(expr: &ParserNode) -> Result<(String, Option<String>, i64), String> {
+        SymbolicValue::parse(expr, 0, &|name, _| Ok(SymbolicValue::label(name.to_string())))?.reference()
     }
 
     // Compiler instructions
@@ -750,17 +2293,33 @@ impl ObjectFormat {
                 return Err(format!("Expected argument for 'section'"))
             }
         };
+        let flags = match children.get(1) {
+            Some(ParserNode { node_type: NodeType::String(flags), .. }) => Some(SectionFlags::parse(flags)?),
+            Some(other) => wrong_argument!(other, NodeType::String("".to_string())),
+            None => None
+        };
         match &child.node_type {
             NodeType::String(name) => {
+                self.current_section = name.clone();
+
+                if let Some(sec) = self.sections.get_mut(name) {
+                    // Reopening a section keeps its flags unless new ones are given
+                    match flags {
+                        Some(flags) if flags != sec.flags && !sec.is_empty() => {
+                            return Err(format!("Section '{}' was already opened with flags \"{}\"", name, sec.flags))
+                        }
+                        Some(flags) => sec.flags = flags,
+                        None => {}
+                    }
+                    return Ok(())
+                }
+
                 let mut sec = SectionData::new();
                 sec.name = name.clone();
+                sec.flags = flags.unwrap_or(SectionFlags::default_for(name));
 
-                self.current_section = sec.name.clone();
-
-                if !self.sections.contains_key(&sec.name) {
-                    self.sections.insert(sec.name.clone(), sec);
-                    self.header.sections_length += 1;
-                }
+                self.sections.insert(sec.name.clone(), sec);
+                self.header.sections_length += 1;
 
                 Ok(())
             }
@@ -784,20 +2343,64 @@ impl ObjectFormat {
             NodeType::Identifier(name) => name,
             _ => wrong_argument!(name_node, NodeType::String(String::new()))
         };
+        self.check_reassignable(name)?;
         match &data.node_type {
-            NodeType::Expression => {
-                let n = self.evaluate_expression(data)?;
+            // Kept symbolic for the linker when it involves labels
+            NodeType::Expression | NodeType::Negate => {
+                let n = match self.symbolic_value(data, 0) {
+                    Ok(value) if value.has_labels() => value.to_node(),
+                    _ => self.evaluate_expression(data)?
+                };
                 self.defines.insert(name.clone(), Define {
-                    node: n
+                    node: n,
+                    constant: false
                 });
             }
             _ => {
-                self.defines.insert(name.clone(), Define { node: data.clone() });
+                self.defines.insert(name.clone(), Define { node: data.clone(), constant: false });
+            }
+        }
+        Ok(())
+    }
+    fn check_reassignable(&self, name: &str) -> Result<(), String> {
+        match self.defines.get(name) {
+            Some(d) if d.constant => Err(format!("Cannot reassign '{}': it was defined with '.equ'", name)),
+            _ => Ok(())
+        }
+    }
+    // Both are evaluated immediately, so '.set' may refer to its own previous value
+    fn assign_symbol(&mut self, instruction: &str, children: &Vec<ParserNode>, constant: bool) -> Result<(), String> {
+        let (name, value) = match children.as_slice() {
+            [ParserNode { node_type: NodeType::Identifier(name), .. }, value] => (name, value),
+            [other, _] => wrong_argument!(other, NodeType::Identifier(String::new())),
+            _ => {
+                return Err(format!("'{}' takes a name and a value, {} arguments provided", instruction, children.len()))
             }
+        };
+
+        if constant && self.defines.contains_key(name) {
+            return Err(format!("Symbol '{}' is already defined", name))
         }
+        self.check_reassignable(name)?;
+
+        let node = match &value.node_type {
+            NodeType::ConstFloat(_) => value.clone(),
+            _ => self.evaluate_expression(value)?
+        };
+        self.defines.insert(name.clone(), Define { node, constant });
+
         Ok(())
     }
+    fn _equ_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.assign_symbol("equ", children, true)
+    }
+    fn _set_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.assign_symbol("set", children, false)
+    }
     fn _db_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let widen = self.widen_db;
+        let encoding = self.string_encoding;
+
         let sec = match self.sections.get_mut(&self.current_section) {
             Some(s) => s,
             None => {
@@ -822,10 +2425,38 @@ impl ObjectFormat {
                         constant: None,
                         reference: Some(BinaryReference {
                             size: ConstantSize::Byte,
-                            rf: sym_name.clone()
-                        })
+                            rf: sym_name.clone(),
+                            subtrahend: None,
+                            addend: 0
+                        }),
+                        alignment: None
+                    });
+                }
+                NodeType::Expression => {
+                    let (rf, sub, addend) = Self::data_reference(child)?;
+                    sec.binary_data.push(BinaryUnit {
+                        constant: None,
+                        reference: Some(BinaryReference {
+                            size: ConstantSize::Byte,
+                            rf,
+                            subtrahend: sub,
+                            addend
+                        }),
+                        alignment: None
+                    });
+                }
+                NodeType::ConstInteger(num) if !widen => {
+                    check_data_range("db", *num, ConstantSize::Byte)?;
+                    sec.binary_data.push(BinaryUnit {
+                        constant: Some(BinaryConstant {
+                            size: ConstantSize::Byte,
+                            value: *num
+                        }),
+                        reference: None,
+                        alignment: None
                     });
                 }
+                // Legacy behaviour: values that don't fit are silently widened
                 NodeType::ConstInteger(num) => {
                     if *num < 256 {
                         sec.binary_data.push(BinaryUnit {
@@ -833,7 +2464,8 @@ impl ObjectFormat {
                                 size: ConstantSize::Byte,
                                 value: *num
                             }),
-                            reference: None
+                            reference: None,
+                            alignment: None
                         });
                     } else if *num < 65536 {
                         sec.binary_data.push(BinaryUnit {
@@ -841,7 +2473,8 @@ impl ObjectFormat {
                                 size: ConstantSize::Word,
                                 value: *num
                             }),
-                            reference: None
+                            reference: None,
+                            alignment: None
                         });
                     } else {
                         sec.binary_data.push(BinaryUnit {
@@ -849,21 +2482,20 @@ impl ObjectFormat {
                                 size: ConstantSize::DoubleWord,
                                 value: *num
                             }),
-                            reference: None
+                            reference: None,
+                            alignment: None
                         });
                     }
                 }
-                NodeType::Negate | NodeType::Expression => {
-                    todo!()
-                }
                 NodeType::String(some_str) => {
-                    for b in some_str.bytes() {
+                    for b in encode_string(some_str, encoding, false)? {
                         sec.binary_data.push(BinaryUnit {
                             constant: Some(BinaryConstant {
                                 size: ConstantSize::Byte,
                                 value: b as i64
                             }),
-                            reference: None
+                            reference: None,
+                            alignment: None
                         });
                     }
                 }
@@ -873,42 +2505,27 @@ impl ObjectFormat {
 
         Ok(())
     }
-    fn _resb_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
-        let sec = match self.sections.get_mut(&self.current_section) {
-            Some(s) => s,
-            None => {
-                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
-            }
-        };
-
-        if sec.instructions.len() != 0 {
-            return Err(format!("Trying to add binary into section with instructions!"))
-        }
-
-        sec.binary_section = true;
-
-        let mut binary = Vec::<BinaryUnit>::new();
-
-        let child_node = match children.get(0) { 
+    // Reserves zeroed elements of a given size
+    fn reserve(&mut self, instruction: &str, children: &Vec<ParserNode>, size: ConstantSize) -> Result<(), String> {
+        let child_node = match children.get(0) {
             Some(c) => c,
-            None => unexpected_eof!("RESB instruction requires 1 argument, 0 provided")
+            None => unexpected_eof!(format!("{} instruction requires 1 argument, 0 provided",
+                instruction.to_uppercase()))
         };
 
-        if let NodeType::ConstInteger(n) = child_node.node_type {
-            for _ in 0..n {
-                binary.push(BinaryUnit {
-                    reference: None,
-                    constant: Some(BinaryConstant {
-                        size: ConstantSize::Byte,
-                        value: 0
-                    })
-                });
-            }
+        match child_node.node_type {
+            NodeType::ConstInteger(n) if n >= 0 => self.push_fill(n as usize, size, 0),
+            _ => wrong_argument!(child_node, NodeType::ConstInteger(0))
         }
-
-        sec.binary_data.append(&mut binary);
-
-        Ok(())
+    }
+    fn _resb_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.reserve("resb", children, ConstantSize::Byte)
+    }
+    fn _resw_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.reserve("resw", children, ConstantSize::Word)
+    }
+    fn _resd_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.reserve("resd", children, ConstantSize::DoubleWord)
     }
     // Reads binary data from file and inserts it as binary data into section
     fn _data_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
@@ -919,7 +2536,7 @@ impl ObjectFormat {
             }
         };
 
-        if !sec.binary_section || sec.instructions.len() != 0 {
+        if sec.instructions.len() != 0 {
             return Err(format!("Trying to add binary into section with instructions!"))
         }
 
@@ -928,6 +2545,8 @@ impl ObjectFormat {
             None => unexpected_eof!("DATA instruction requires 1 argument, 0 provided")
         };
 
+        sec.binary_section = true;
+
         if let NodeType::String(path) = &child_node.node_type {
             let data = match fs::read(path) {
                 Ok(d) => d,
@@ -935,13 +2554,41 @@ impl ObjectFormat {
                     return Err(format!("Error occured while reading file: {e}"))
                 }
             };
-            for b in data {
+
+            // Optional '.data "file" offset [length]'
+            let mut args = Vec::<usize>::new();
+            for child in &children[1..] {
+                match &child.node_type {
+                    NodeType::ConstInteger(n) if *n >= 0 => args.push(*n as usize),
+                    NodeType::ConstInteger(n) => {
+                        return Err(format!("DATA offset and length cannot be negative, {} provided", n))
+                    }
+                    _ => wrong_argument!(child, NodeType::ConstInteger(0))
+                }
+            }
+            let (offset, length) = match args.as_slice() {
+                [] => (0, data.len()),
+                [offset] => (*offset, data.len().saturating_sub(*offset)),
+                [offset, length] => (*offset, *length),
+                _ => {
+                    return Err(format!("DATA instruction takes at most 3 arguments, {} provided", children.len()))
+                }
+            };
+            let data = match offset.checked_add(length).and_then(|end| data.get(offset..end)) {
+                Some(d) => d,
+                None => {
+                    return Err(format!("DATA range {}..{} is out of bounds for '{}' ({} bytes)",
+                        offset, offset.saturating_add(length), path, data.len()))
+                }
+            };
+            for &b in data {
                 sec.binary_data.push(BinaryUnit {
                     reference: None,
                     constant: Some(BinaryConstant {
                         size: ConstantSize::Byte,
                         value: b as i64
-                    })
+                    }),
+                    alignment: None
                 })
             }
         } else {
@@ -950,8 +2597,10 @@ impl ObjectFormat {
 
         Ok(())
     }
-    // Define double word, same as db but for dw
-    fn _dd_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+    // Same as db, but every value takes `size` bytes
+    fn push_data(&mut self, instruction: &str, children: &Vec<ParserNode>, size: ConstantSize) -> Result<(), String> {
+        let encoding = self.string_encoding;
+
         let sec = match self.sections.get_mut(&self.current_section) {
             Some(s) => s,
             None => {
@@ -964,7 +2613,7 @@ impl ObjectFormat {
         }
 
         if children.len() == 0 {
-            return Err(format!("Arguments expected for compiler instruction 'db'"))
+            return Err(format!("Arguments expected for compiler instruction '{}'", instruction))
         }
 
         sec.binary_section = true;
@@ -975,31 +2624,47 @@ impl ObjectFormat {
                     sec.binary_data.push(BinaryUnit {
                         constant: None,
                         reference: Some(BinaryReference {
-                            size: ConstantSize::DoubleWord,
-                            rf: sym_name.clone()
-                        })
+                            size,
+                            rf: sym_name.clone(),
+                            subtrahend: None,
+                            addend: 0
+                        }),
+                        alignment: None
+                    });
+                }
+                NodeType::Expression => {
+                    let (rf, sub, addend) = Self::data_reference(child)?;
+                    sec.binary_data.push(BinaryUnit {
+                        constant: None,
+                        reference: Some(BinaryReference {
+                            size,
+                            rf,
+                            subtrahend: sub,
+                            addend
+                        }),
+                        alignment: None
                     });
                 }
                 NodeType::ConstInteger(num) => {
+                    check_data_range(instruction, *num, size)?;
                     sec.binary_data.push(BinaryUnit {
                         reference: None,
                         constant: Some(BinaryConstant {
-                            size: ConstantSize::DoubleWord,
+                            size,
                             value: *num
-                        })
+                        }),
+                        alignment: None
                     });
                 }
-                NodeType::Negate | NodeType::Expression => {
-                    todo!()
-                }
                 NodeType::String(some_str) => {
-                    for b in some_str.bytes() {
+                    for b in encode_string(some_str, encoding, false)? {
                         sec.binary_data.push(BinaryUnit {
                             reference: None,
                             constant: Some(BinaryConstant {
-                                size: ConstantSize::DoubleWord,
+                                size,
                                 value: b as i64
-                            })
+                            }),
+                            alignment: None
                         });
                     }
                 }
@@ -1011,89 +2676,673 @@ impl ObjectFormat {
     }
     // Define word, same as db but for w
     fn _dw_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
-        let sec = match self.sections.get_mut(&self.current_section) {
+        self.push_data("dw", children, ConstantSize::Word)
+    }
+    // Define double word, same as db but for dw
+    fn _dd_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.push_data("dd", children, ConstantSize::DoubleWord)
+    }
+    // Define quad word, for 64-bit values
+    fn _dq_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.push_data("dq", children, ConstantSize::QuadWord)
+    }
+    // Raw code for opcodes the assembler doesn't know: '.insn opcode [[type] operand]...'
+    fn _insn_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let opcode = match children.get(0) {
+            Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) if (0..=0xFFFF).contains(n) => *n as u16,
+            Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+            None => unexpected_eof!("INSN instruction requires at least 1 argument, 0 provided")
+        };
+        if opcode > 0x7F && opcode & 0x80 == 0 {
+            return Err(format!("Opcode {:#x} cannot be encoded: opcodes above 0x7f need bit 7 set", opcode))
+        }
+
+        let mut operands = Vec::new();
+        let mut args = children[1..].iter();
+
+        while let Some(child) = args.next() {
+            let operand_type = match &child.node_type {
+                NodeType::Identifier(name) => RAW_OPERAND_TYPES.iter().find(|(n, _, _)| n == name),
+                _ => None
+            };
+            match operand_type {
+                Some((name, size, relative)) => {
+                    let value = match args.next() {
+                        Some(v) => v,
+                        None => unexpected_eof!(format!("Operand expected after '{}'", name))
+                    };
+                    operands.push((RawOperand { size: *size, relative: *relative }, value));
+                }
+                None => operands.push((RawOperand { size: ConstantSize::DoubleWord, relative: false }, child))
+            }
+        }
+
+        self.push_raw_code("insn", Some(opcode), operands)
+    }
+    // Same as dw in data, raw 16-bit words in code sections
+    fn _word_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let sec = match self.sections.get(&self.current_section) {
             Some(s) => s,
             None => {
                 return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
             }
         };
 
-        if sec.instructions.len() != 0 {
-            return Err(format!("Trying to add binary into section with instructions!"))
+        if sec.instructions.len() != 0 || (!sec.binary_section && sec.flags.contains(SectionFlags::EXEC)) {
+            let operands = children.iter()
+                .map(|c| (RawOperand { size: ConstantSize::Word, relative: false }, c))
+                .collect();
+            self.push_raw_code("word", None, operands)
+        } else {
+            self.push_data("word", children, ConstantSize::Word)
         }
-
-        if children.len() == 0 {
-            return Err(format!("Arguments expected for compiler instruction 'db'"))
+    }
+    fn push_raw_code(&mut self, instruction: &str, opcode: Option<u16>, operands: Vec<(RawOperand, &ParserNode)>) -> Result<(), String> {
+        if opcode.is_none() && operands.len() == 0 {
+            return Err(format!("Arguments expected for compiler instruction '{}'", instruction))
+        }
+        if operands.len() > MAX_RAW_OPERANDS {
+            return Err(format!("Too many operands for '{}': {} provided, at most {} allowed",
+                instruction, operands.len(), MAX_RAW_OPERANDS))
         }
 
-        sec.binary_section = true;
+        let layout: Vec<RawOperand> = operands.iter().map(|(o, _)| *o).collect();
+        let mut instr = InstructionData::raw(opcode, &layout);
 
-        for child in children {
-            match &child.node_type {
-                NodeType::Identifier(sym_name) => {
-                    sec.binary_data.push(BinaryUnit {
-                        constant: None,
-                        reference: Some(BinaryReference {
-                            size: ConstantSize::Word,
-                            rf: sym_name.clone()
-                        })
+        for (i, (operand, node)) in operands.iter().enumerate() {
+            let argument_pos = (i + 2) as u8;
+            match &node.node_type {
+                NodeType::ConstInteger(num) => {
+                    check_data_range(instruction, *num, operand.size)?;
+                    instr.constants.push(Constant {
+                        argument_pos,
+                        size: operand.size,
+                        value: *num
                     });
                 }
-                NodeType::ConstInteger(num) => {
-                    sec.binary_data.push(BinaryUnit {
-                        reference: None,
-                        constant: Some(BinaryConstant {
-                            size: ConstantSize::Word,
-                            value: *num
-                        })
+                NodeType::Identifier(sym_name) => {
+                    instr.references.push(Reference {
+                        argument_pos,
+                        rf: sym_name.clone(),
+                        subtrahend: None,
+                        addend: 0
                     });
                 }
-                NodeType::Negate | NodeType::Expression => {
-                    todo!()
+                NodeType::Expression => {
+                    let (rf, subtrahend, addend) = Self::data_reference(node)?;
+                    instr.references.push(Reference {
+                        argument_pos,
+                        rf,
+                        subtrahend,
+                        addend
+                    });
                 }
-                NodeType::String(some_str) => {
-                    for b in some_str.bytes() {
-                        sec.binary_data.push(BinaryUnit {
-                            reference: None,
-                            constant: Some(BinaryConstant {
-                                size: ConstantSize::Word,
-                                value: b as i64
-                            })
-                        });
+                _ => unexpected_node!(node)
+            }
+        }
+
+        let sec = match self.sections.get_mut(&self.current_section) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
+            }
+        };
+
+        if sec.binary_section || sec.is_nobits() {
+            return Err(format!("Trying to add raw code into binary section!"))
+        }
+
+        sec.instructions.push(instr);
+
+        Ok(())
+    }
+    fn float_value(node: &ParserNode) -> Result<f64, String> {
+        match &node.node_type {
+            NodeType::ConstFloat(n) => Ok(*n),
+            NodeType::ConstInteger(n) => Ok(*n as f64),
+            NodeType::Negate => match node.children.get(0) {
+                Some(child) => Ok(-ObjectFormat::float_value(child)?),
+                None => unexpected_node!(node)
+            },
+            _ => wrong_argument!(node, NodeType::ConstFloat(0.0))
+        }
+    }
+    // Emits IEEE-754 single (4 bytes) or double (8 bytes) precision values
+    fn push_floats(&mut self, instruction: &str, children: &Vec<ParserNode>, size: ConstantSize) -> Result<(), String> {
+        if children.len() == 0 {
+            return Err(format!("Arguments expected for compiler instruction '{}'", instruction))
+        }
+
+        let mut units = Vec::<BinaryUnit>::new();
+        for child in children {
+            let value = ObjectFormat::float_value(child)?;
+            units.push(BinaryUnit {
+                reference: None,
+                constant: Some(BinaryConstant {
+                    size,
+                    value: match size {
+                        ConstantSize::QuadWord => value.to_bits() as i64,
+                        _ => (value as f32).to_bits() as i64
+                    }
+                }),
+                alignment: None
+            });
+        }
+
+        let sec = match self.sections.get_mut(&self.current_section) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
+            }
+        };
+
+        if sec.instructions.len() != 0 {
+            return Err(format!("Trying to add binary into section with instructions!"))
+        }
+
+        sec.binary_section = true;
+        sec.binary_data.append(&mut units);
+
+        Ok(())
+    }
+    fn _float_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.push_floats("float", children, ConstantSize::DoubleWord)
+    }
+    fn _double_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.push_floats("double", children, ConstantSize::QuadWord)
+    }
+    // Pads code with a filler instruction up to an alignment boundary
+    fn _align_code_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let instructions = Instructions::new();
+
+        let sec = match self.sections.get_mut(&self.current_section) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
+            }
+        };
+
+        if sec.binary_section {
+            return Err(format!("Trying to add code alignment into binary section!"))
+        }
+
+        let alignment = match children.get(0) {
+            Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) => *n,
+            Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+            None => unexpected_eof!("ALIGN_CODE instruction requires at least 1 argument, 0 provided")
+        };
+
+        if alignment <= 0 || alignment & (alignment - 1) != 0 {
+            return Err(format!("Code alignment must be a power of two, {} provided", alignment))
+        }
+
+        let fill = match children.get(1) {
+            Some(ParserNode { node_type: NodeType::Identifier(name), .. }) => name.as_str(),
+            Some(c) => wrong_argument!(c, NodeType::Identifier(String::new())),
+            None => "nop"
+        };
+
+        let fill_opcode = match instructions.get_opcode(fill) {
+            Some(opc) => opc,
+            None => {
+                return Err(format!("Invalid instruction '{}'!", fill))
+            }
+        };
+        if instructions.get_instruction(fill_opcode).unwrap().get_size() != 1 {
+            return Err(format!("Instruction '{}' cannot be used as padding: \
+            only single byte instructions are allowed", fill))
+        }
+
+        sec.instructions.push(InstructionData::alignment(alignment, fill_opcode));
+
+        Ok(())
+    }
+    // Aligns the location counter in any section: '.balign alignment [fill byte]'
+    fn _balign_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let sec = match self.sections.get_mut(&self.current_section) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
+            }
+        };
+
+        let alignment = match children.get(0) {
+            Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) => *n,
+            Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+            None => unexpected_eof!("BALIGN instruction requires at least 1 argument, 0 provided")
+        };
+        if alignment <= 0 || alignment & (alignment - 1) != 0 {
+            return Err(format!("Alignment must be a power of two, {} provided", alignment))
+        }
+
+        let fill = match children.get(1) {
+            Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) if (0..256).contains(n) => *n as u8,
+            Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+            None => 0
+        };
+
+        if sec.is_nobits() {
+            if fill != 0 {
+                return Err(format!("Noload section '{}' can only be filled with zeroes", sec.name))
+            }
+            sec.bss_size = sec.bss_size.next_multiple_of(alignment as u64);
+        } else if sec.instructions.len() != 0 || (!sec.binary_section && sec.flags.contains(SectionFlags::EXEC)) {
+            // Code keeps using code alignment units, padded with the raw byte
+            sec.instructions.push(InstructionData::alignment(alignment, fill as u16));
+        } else {
+            sec.binary_section = true;
+            sec.binary_data.push(BinaryUnit {
+                reference: None,
+                constant: None,
+                alignment: Some(BinaryAlignment { alignment: alignment as u64, fill })
+            });
+        }
+
+        Ok(())
+    }
+    // Moves the location counter forward to an offset from the start of the section
+    fn _org_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let instructions = Instructions::new();
+
+        let sec = match self.sections.get_mut(&self.current_section) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
+            }
+        };
+
+        let offset = match children.get(0) {
+            Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) if *n >= 0 => *n as usize,
+            Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+            None => unexpected_eof!("ORG instruction requires at least 1 argument, 0 provided")
+        };
+
+        let position = sec.get_binary_size();
+        if offset < position {
+            return Err(format!("Cannot move location counter backwards: '.org {:#x}' in section '{}' \
+            which is already {:#x} bytes long", offset, sec.name, position))
+        }
+
+        // Code is padded with a single byte instruction, binary with a byte value
+        match children.get(1) {
+            Some(ParserNode { node_type: NodeType::Identifier(fill), .. }) => {
+                if sec.binary_section {
+                    return Err(format!("Cannot pad binary section '{}' with instruction '{}'", sec.name, fill))
+                }
+                let fill_opcode = match instructions.get_opcode(fill) {
+                    Some(opc) => opc,
+                    None => {
+                        return Err(format!("Invalid instruction '{}'!", fill))
                     }
+                };
+                if instructions.get_instruction(fill_opcode).unwrap().get_size() != 1 {
+                    return Err(format!("Instruction '{}' cannot be used as padding: \
+                    only single byte instructions are allowed", fill))
+                }
+                for _ in position..offset {
+                    sec.instructions.push(InstructionData {
+                        opcode: fill_opcode,
+                        references: Vec::new(),
+                        constants: Vec::new()
+                    });
+                }
+            }
+            fill => {
+                let value = match fill {
+                    Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) if (0..256).contains(n) => *n,
+                    Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+                    None => 0
+                };
+                if sec.is_nobits() && value == 0 {
+                    sec.bss_size = offset as u64;
+                    return Ok(())
+                }
+                if sec.instructions.len() != 0 {
+                    return Err(format!("Section '{}' contains code: specify a padding instruction \
+                    like '.org {:#x} nop'", sec.name, offset))
+                }
+                sec.binary_section = true;
+                for _ in position..offset {
+                    sec.binary_data.push(BinaryUnit {
+                        reference: None,
+                        constant: Some(BinaryConstant {
+                            size: ConstantSize::Byte,
+                            value
+                        }),
+                        alignment: None
+                    });
                 }
-                _ => unexpected_node!(child)
             }
         }
 
         Ok(())
     }
+    fn symbol_names(instruction: &str, children: &Vec<ParserNode>) -> Result<Vec<String>, String> {
+        if children.len() == 0 {
+            return Err(format!("Arguments expected for compiler instruction '{}'", instruction))
+        }
+        let mut names = Vec::<String>::new();
+        for child in children {
+            match &child.node_type {
+                NodeType::Identifier(name) => names.push(name.clone()),
+                _ => wrong_argument!(child, NodeType::Identifier(String::new()))
+            }
+        }
+        Ok(names)
+    }
+    // Exports labels to other objects
+    fn _global_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.globals.extend(ObjectFormat::symbol_names("global", children)?);
+        Ok(())
+    }
+    // Declares symbols that are defined in other objects
+    fn _extern_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.externs.extend(ObjectFormat::symbol_names("extern", children)?);
+        Ok(())
+    }
+    // Exports labels that other objects may override
+    fn _weak_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.weaks.extend(ObjectFormat::symbol_names("weak", children)?);
+        Ok(())
+    }
+    // Tentative definition shared between objects: '.comm name size [alignment]'
+    fn _comm_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let name = match children.get(0) {
+            Some(ParserNode { node_type: NodeType::Identifier(name), .. }) => name.clone(),
+            Some(c) => wrong_argument!(c, NodeType::Identifier(String::new())),
+            None => unexpected_eof!("COMM instruction requires 2 arguments, 0 provided")
+        };
+        let size = match children.get(1) {
+            Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) if *n >= 0 => *n as u64,
+            Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+            None => unexpected_eof!("COMM instruction requires 2 arguments, 1 provided")
+        };
+        // Naturally aligned up to a word by default
+        let alignment = match children.get(2) {
+            Some(ParserNode { node_type: NodeType::ConstInteger(n), .. }) => *n,
+            Some(c) => wrong_argument!(c, NodeType::ConstInteger(0)),
+            None => size.clamp(1, 4).next_power_of_two() as i64
+        };
+        if alignment <= 0 || alignment & (alignment - 1) != 0 {
+            return Err(format!("Alignment must be a power of two, {} provided", alignment))
+        }
+
+        self.add_common(CommonSymbol { name, size, alignment: alignment as u64 });
+        Ok(())
+    }
+    fn add_common(&mut self, common: CommonSymbol) {
+        match self.commons.iter_mut().find(|c| c.name == common.name) {
+            Some(c) => c.merge(&common),
+            None => self.commons.push(common)
+        }
+    }
+    // Hides labels from other objects
+    fn _local_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.locals.extend(ObjectFormat::symbol_names("local", children)?);
+        Ok(())
+    }
+    // Writes string literals as bytes, optionally followed by a NUL terminator
+    fn push_strings(&mut self, instruction: &str, children: &Vec<ParserNode>, terminate: bool) -> Result<(), String> {
+        let encoding = self.string_encoding;
+
+        let sec = match self.sections.get_mut(&self.current_section) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
+            }
+        };
+
+        if sec.instructions.len() != 0 {
+            return Err(format!("Trying to add binary into section with instructions!"))
+        }
+
+        if children.len() == 0 {
+            return Err(format!("Arguments expected for compiler instruction '{}'", instruction))
+        }
+
+        sec.binary_section = true;
+
+        for child in children {
+            let text = match &child.node_type {
+                NodeType::String(text) => text,
+                _ => wrong_argument!(child, NodeType::String(String::new()))
+            };
+            let mut bytes = encode_string(text, encoding, true)?;
+            if terminate {
+                bytes.extend(std::iter::repeat_n(0, encoding.unit_size()));
+            }
+            for b in bytes {
+                sec.binary_data.push(BinaryUnit {
+                    reference: None,
+                    constant: Some(BinaryConstant {
+                        size: ConstantSize::Byte,
+                        value: b as i64
+                    }),
+                    alignment: None
+                });
+            }
+        }
+
+        Ok(())
+    }
+    // Sets how following string literals are encoded: '.encoding "utf16le"'
+    fn _encoding_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let name = match children.as_slice() {
+            [ParserNode { node_type: NodeType::String(name), .. }] => name,
+            [other, ..] => wrong_argument!(other, NodeType::String(String::new())),
+            [] => unexpected_eof!("ENCODING instruction requires 1 argument, 0 provided")
+        };
+
+        self.string_encoding = match StringEncoding::from_name(name) {
+            Some(e) => e,
+            None => {
+                return Err(format!("Unknown string encoding '{}'. Expected utf8, latin1 or utf16le", name))
+            }
+        };
+
+        Ok(())
+    }
+    fn _ascii_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.push_strings("ascii", children, false)
+    }
+    fn _asciz_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        self.push_strings("asciz", children, true)
+    }
+    // Repeats a value of a given size: '.fill count [size [value]]'
+    fn _fill_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let mut args = Vec::<i64>::new();
+        for child in children {
+            match &child.node_type {
+                NodeType::ConstInteger(n) => args.push(*n),
+                _ => wrong_argument!(child, NodeType::ConstInteger(0))
+            }
+        }
+
+        let (count, size, value) = match args.as_slice() {
+            [count] => (*count, 1, 0),
+            [count, size] => (*count, *size, 0),
+            [count, size, value] => (*count, *size, *value),
+            [] => unexpected_eof!("FILL instruction requires at least 1 argument, 0 provided"),
+            _ => {
+                return Err(format!("FILL instruction takes at most 3 arguments, {} provided", args.len()))
+            }
+        };
+
+        if count < 0 {
+            return Err(format!("Fill count cannot be negative, {} provided", count))
+        }
+        let size = match ConstantSize::from_u8(size as u8) {
+            Some(s) if (1..=4).contains(&size) => s,
+            _ => {
+                return Err(format!("Fill size must be 1, 2 or 4 bytes, {} provided", size))
+            }
+        };
+
+        self.push_fill(count as usize, size, value)
+    }
+    // Reserves bytes set to a value: '.space count [value]'
+    fn _space_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        let (count, value) = match children.as_slice() {
+            [ParserNode { node_type: NodeType::ConstInteger(count), .. }] => (*count, 0),
+            [ParserNode { node_type: NodeType::ConstInteger(count), .. },
+                ParserNode { node_type: NodeType::ConstInteger(value), .. }] => (*count, *value),
+            [] => unexpected_eof!("SPACE instruction requires at least 1 argument, 0 provided"),
+            _ => {
+                return Err(format!("SPACE instruction takes a count and an optional byte value"))
+            }
+        };
+
+        if count < 0 {
+            return Err(format!("Space size cannot be negative, {} provided", count))
+        }
+
+        self.push_fill(count as usize, ConstantSize::Byte, value)
+    }
+    fn push_fill(&mut self, count: usize, size: ConstantSize, value: i64) -> Result<(), String> {
+        let sec = match self.sections.get_mut(&self.current_section) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", self.current_section))
+            }
+        };
+
+        if sec.is_nobits() {
+            if value != 0 {
+                return Err(format!("Noload section '{}' can only be filled with zeroes", sec.name))
+            }
+            sec.bss_size += (count * size.get_size()) as u64;
+            return Ok(())
+        }
+
+        if sec.instructions.len() != 0 {
+            return Err(format!("Trying to add binary into section with instructions!"))
+        }
+
+        sec.binary_section = true;
+
+        for _ in 0..count {
+            sec.binary_data.push(BinaryUnit {
+                reference: None,
+                constant: Some(BinaryConstant {
+                    size,
+                    value
+                }),
+                alignment: None
+            });
+        }
+
+        Ok(())
+    }
+    // Places pending literals at this point of the current section
+    fn _ltorg_ci(&mut self, children: &Vec<ParserNode>) -> Result<(), String> {
+        if let Some(c) = children.get(0) {
+            unexpected_node!(c)
+        }
+        let section = self.current_section.clone();
+        self.flush_literals(&section)
+    }
     // End compiler instructions
 
-    pub fn create_jumper(entrypoint: String) -> Self {
-        let mut me = Self::new();
+    /**
+     * Once a file uses '.global' or '.extern', only its '.global' labels are
+     * visible to other objects, and every symbol it references must either
+     * be defined in it or declared with '.extern'.
+     */
+    fn apply_visibility(&mut self) -> Result<(), String> {
+        let explicit = !self.globals.is_empty() || !self.externs.is_empty();
+        let mut defined = HashSet::<String>::new();
+
+        for sec in self.sections.values_mut() {
+            for (name, label) in sec.labels.iter_mut() {
+                label.binding = if self.locals.contains(name) {
+                    SymbolBinding::Local
+                } else if self.weaks.contains(name) {
+                    SymbolBinding::Weak
+                } else if self.globals.contains(name) {
+                    SymbolBinding::Global
+                } else if explicit {
+                    SymbolBinding::Local
+                } else {
+                    SymbolBinding::Default
+                };
+                defined.insert(name.clone());
+            }
+        }
 
-        let mut section = SectionData::new();
-        section.instructions.push(InstructionData {
-            opcode: 12, // jpr opcode
-            references: vec![Reference {
-                argument_pos: 0,
-                rf: entrypoint
-            }],
-            constants: Vec::new()
-        });
-        me.sections.insert(section.name.clone(), section);
+        for name in self.weaks.iter() {
+            if !defined.contains(name) {
+                return Err(format!("Symbol '{}' is declared '.weak' but never defined", name))
+            }
+        }
+        for common in self.commons.iter() {
+            if defined.contains(&common.name) {
+                return Err(format!("Symbol '{}' is declared '.comm' but defined in this file", common.name))
+            }
+        }
+        for name in self.locals.iter() {
+            if !defined.contains(name) {
+                return Err(format!("Symbol '{}' is declared '.local' but never defined", name))
+            }
+            if self.globals.contains(name) || self.weaks.contains(name) {
+                return Err(format!("Symbol '{}' cannot be both '.local' and exported", name))
+            }
+        }
+        if !explicit {
+            return Ok(())
+        }
 
-        me
+        for name in self.globals.iter() {
+            if !defined.contains(name) {
+                return Err(format!("Symbol '{}' is declared '.global' but never defined", name))
+            }
+        }
+        for name in self.externs.iter() {
+            if defined.contains(name) {
+                return Err(format!("Symbol '{}' is declared '.extern' but defined in this file", name))
+            }
+        }
+
+        for sec in self.sections.values() {
+            let instruction_refs = sec.instructions.iter()
+                .flat_map(|i| i.references.iter().flat_map(|r| r.symbols()));
+            let binary_refs = sec.binary_data.iter()
+                .filter_map(|u| u.reference.as_ref())
+                .flat_map(|r| r.symbols());
+
+            for name in instruction_refs.chain(binary_refs) {
+                let known = |n: &str| defined.contains(n) || self.externs.contains(n)
+                    || self.commons.iter().any(|c| c.name == n);
+                if !known(name) && !size_symbol_label(name).is_some_and(known) {
+                    return Err(format!("Undefined symbol '{}': define it or declare it with '.extern'", name))
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn new() -> Self {
         let mut me = Self {
             header: ObjectFormatHeader::new(),
             defines: HashMap::new(),
-            sections: HashMap::new(),
+            sections: BTreeMap::new(),
             compiler_instructions: HashMap::new(),
             current_section: DEFAULT_SECTION_NAME.to_string(),
+            globals: HashSet::new(),
+            externs: HashSet::new(),
+            weaks: HashSet::new(),
+            locals: HashSet::new(),
+            literal_pools: false,
+            widen_db: false,
+            string_encoding: StringEncoding::Utf8,
+            pending_literals: Vec::new(),
+            literal_count: 0,
+            anonymous_labels: 0,
+            source_line: None,
+            debug_lines: Vec::new(),
+            metadata: Vec::new(),
+            commons: Vec::new(),
+            file: None
         };
 
         let default_section = SectionData::new();
@@ -1104,49 +3353,306 @@ impl ObjectFormat {
 
         me.compiler_instructions.insert("section".to_string(), ObjectFormat::_section_ci);
         me.compiler_instructions.insert("define".to_string(), ObjectFormat::_define_ci);
+        me.compiler_instructions.insert("equ".to_string(), ObjectFormat::_equ_ci);
+        me.compiler_instructions.insert("set".to_string(), ObjectFormat::_set_ci);
         me.compiler_instructions.insert("db".to_string(), ObjectFormat::_db_ci);
         me.compiler_instructions.insert("resb".to_string(), ObjectFormat::_resb_ci);
+        me.compiler_instructions.insert("resw".to_string(), ObjectFormat::_resw_ci);
+        me.compiler_instructions.insert("resd".to_string(), ObjectFormat::_resd_ci);
         me.compiler_instructions.insert("data".to_string(), ObjectFormat::_data_ci);
         me.compiler_instructions.insert("dd".to_string(), ObjectFormat::_dd_ci);
         me.compiler_instructions.insert("dw".to_string(), ObjectFormat::_dw_ci);
+        me.compiler_instructions.insert("word".to_string(), ObjectFormat::_word_ci);
+        me.compiler_instructions.insert("insn".to_string(), ObjectFormat::_insn_ci);
+        me.compiler_instructions.insert("dq".to_string(), ObjectFormat::_dq_ci);
+        me.compiler_instructions.insert("float".to_string(), ObjectFormat::_float_ci);
+        me.compiler_instructions.insert("double".to_string(), ObjectFormat::_double_ci);
+        me.compiler_instructions.insert("align_code".to_string(), ObjectFormat::_align_code_ci);
+        me.compiler_instructions.insert("balign".to_string(), ObjectFormat::_balign_ci);
+        me.compiler_instructions.insert("ltorg".to_string(), ObjectFormat::_ltorg_ci);
+        me.compiler_instructions.insert("org".to_string(), ObjectFormat::_org_ci);
+        me.compiler_instructions.insert("global".to_string(), ObjectFormat::_global_ci);
+        me.compiler_instructions.insert("extern".to_string(), ObjectFormat::_extern_ci);
+        me.compiler_instructions.insert("weak".to_string(), ObjectFormat::_weak_ci);
+        me.compiler_instructions.insert("local".to_string(), ObjectFormat::_local_ci);
+        me.compiler_instructions.insert("comm".to_string(), ObjectFormat::_comm_ci);
+        me.compiler_instructions.insert("ascii".to_string(), ObjectFormat::_ascii_ci);
+        me.compiler_instructions.insert("asciz".to_string(), ObjectFormat::_asciz_ci);
+        me.compiler_instructions.insert("encoding".to_string(), ObjectFormat::_encoding_ci);
+        me.compiler_instructions.insert("fill".to_string(), ObjectFormat::_fill_ci);
+        me.compiler_instructions.insert("space".to_string(), ObjectFormat::_space_ci);
 
         me
     }
 
-    fn generate_binary(&self) -> Result<Vec<u8>, String> {
-        let mut binary = Vec::<u8>::new();
+    /**
+     * Writes sections, the symbol table and the line table, interning names in `strings`
+     */
+    fn write_body(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), String> {
+        let mut section_names = Vec::<&String>::new();
+
+        for (sec_name, sec) in self.sections.iter() {
+            match sec.write_bytes(binary, strings) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Error occured while generating \
+                    binary for section '{}': {}", sec_name, e))
+                }
+            }
+            section_names.push(sec_name);
+        }
+
+        let symbols = self.symbol_table();
+        match binary.write_u64::<LittleEndian>(symbols.len() as u64) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(format!("Error occured while generating symbol table: {}", e))
+            }
+        }
+
+        for symbol in symbols.iter() {
+            // Unwrap because every symbol comes from one of the sections
+            let index = section_names.iter().position(|n| **n == symbol.section).unwrap();
+
+            match symbol.write_bytes(binary, index as u32, strings) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Error occured while generating \
+                    symbol '{}': {}", symbol.name, e))
+                }
+            }
+        }
+
+        match binary.write_u64::<LittleEndian>(self.debug_lines.len() as u64) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(format!("Error occured while generating line information: {}", e))
+            }
+        }
+
+        for info in self.debug_lines.iter() {
+            let index = match section_names.iter().position(|n| **n == info.section) {
+                Some(i) => i,
+                None => {
+                    return Err(format!("Line information refers to missing section '{}'", info.section))
+                }
+            };
+            match info.write_bytes(binary, index as u32, strings) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Error occured while generating line information: {}", e))
+                }
+            }
+        }
+
+        match self.write_metadata(binary, strings) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(format!("Error occured while generating metadata: {}", e))
+            }
+        }
+
+        match binary.write_u32::<LittleEndian>(self.commons.len() as u32) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(format!("Error occured while generating common symbols: {}", e))
+            }
+        }
+
+        for common in self.commons.iter() {
+            match common.write_bytes(binary, strings) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Error occured while generating \
+                    common symbol '{}': {}", common.name, e))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Metadata block:
+     * 0 - 4: entry count
+     * 4 - <>: key and value string indices
+     */
+    fn write_metadata(&self, binary: &mut dyn Write, strings: &mut StringTable) -> Result<(), Error> {
+        binary.write_u32::<LittleEndian>(self.metadata.len() as u32)?;
+        for (key, value) in self.metadata.iter() {
+            strings.write(binary, key)?;
+            strings.write(binary, value)?;
+        }
+        Ok(())
+    }
+    fn read_metadata(&mut self, binary: &mut dyn Read, names: &dyn NameSource) -> Result<(), Error> {
+        let count = binary.read_u32::<LittleEndian>()?;
+        for _ in 0..count {
+            let key = names.read_name(binary)?;
+            let value = names.read_name(binary)?;
+            self.set_metadata(&key, &value);
+        }
+        Ok(())
+    }
+
+    /**
+     * Sets a metadata entry, replacing an earlier value of `key`
+     */
+    pub fn set_metadata(&mut self, key: &str, value: &str) {
+        match self.metadata.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => self.metadata.push((key.to_string(), value.to_string()))
+        }
+    }
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /**
+     * Streams the object to `binary`. The checksum is patched into
+     * the header once the payload has been written.
+     */
+    pub fn write_object<W: Write + Seek>(&self, binary: &mut W) -> Result<(), String> {
+        // The string table goes before the body, so a first pass only collects names
+        let mut strings = StringTable::new();
+        self.write_body(&mut io::sink(), &mut strings)?;
+
+        let start = match binary.stream_position() {
+            Ok(p) => p,
+            Err(e) => {
+                return Err(format!("Error occured while generating binary header: {}", e))
+            }
+        };
+        match self.header.write_bytes(binary) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(format!("Error occured while generating binary header: {}", e))
+            }
+        }
+
+        let mut payload = ChecksumWriter { inner: binary, crc: Crc32::new() };
+        match strings.write_bytes(&mut payload) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(format!("Error occured while generating string table: {}", e))
+            }
+        }
+        self.write_body(&mut payload, &mut strings)?;
+        let checksum = payload.crc.finish();
+
+        // Checksum is the last field of the header
+        let patch = binary.stream_position().and_then(|end| {
+            binary.seek(SeekFrom::Start(start + HEADER_SIZE - 4))?;
+            binary.write_u32::<LittleEndian>(checksum)?;
+            binary.seek(SeekFrom::Start(end))
+        });
+        match patch {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error occured while writing checksum: {}", e))
+        }
+    }
+
+    /**
+     * Every label of the object, ordered by section and offset
+     */
+    pub fn symbol_table(&self) -> Vec<ObjectSymbol> {
+        let mut symbols: Vec<ObjectSymbol> = self.sections.iter()
+            .flat_map(|(sec_name, sec)| sec.labels.values().map(move |label| ObjectSymbol {
+                name: label.name.clone(),
+                section: sec_name.clone(),
+                ptr: label.ptr,
+                offset: sec.get_binary_position(label.ptr),
+                binding: label.binding,
+                symbol_type: sec.symbol_type()
+            }))
+            .collect();
+
+        symbols.sort_by(|a, b| (&a.section, a.offset, &a.name).cmp(&(&b.section, b.offset, &b.name)));
+        symbols
+    }
 
-        match self.header.write_bytes(&mut binary) {
-            Ok(_) => {},
-            Err(e) => {
-                return Err(format!("Error occured while generating binary header: {}", e))
+    /**
+     * Drops local labels and debug lines, for objects that are handed out.
+     * References to dropped labels point at the start of the label's
+     * section instead, which gets a local '#section' label when needed.
+     * Labels in `retain` and labels whose size is referenced are kept.
+     */
+    pub fn strip_local_symbols(&mut self, retain: &HashSet<String>) {
+        self.debug_lines.clear();
+
+        let sized: HashSet<String> = self.sections.values()
+            .flat_map(|s| s.instructions.iter().flat_map(|i| i.references.iter().flat_map(|r| r.symbols()))
+                .chain(s.binary_data.iter().flat_map(|u| u.reference.iter().flat_map(|r| r.symbols()))))
+            .filter_map(|name| size_symbol_label(name))
+            .map(|label| label.to_string())
+            .collect();
+
+        // Dropped label -> (section start label, offset from the start)
+        let mut dropped = HashMap::<String, (String, i64)>::new();
+        for (sec_name, sec) in self.sections.iter() {
+            for label in sec.labels.values() {
+                // Labels localized by a link are still known by their name before the '#'
+                let source_name = label.name.split('#').next().unwrap_or_default();
+                if label.binding == SymbolBinding::Local && !sized.contains(&label.name) && !retain.contains(source_name) {
+                    // Unwrap because the label is in this section
+                    let offset = sec.get_label_binary_offset(&label.name).unwrap() as i64;
+                    dropped.insert(label.name.clone(), (format!("#{}", sec_name), offset));
+                }
             }
         }
+        if dropped.is_empty() {
+            return
+        }
 
-        for (sec_name, sec) in self.sections.iter() {
-            match sec.write_bytes(&mut binary) {
-                Ok(_) => {},
-                Err(e) => {
-                    return Err(format!("Error occured while generating \
-                    binary for section '{}': {}", sec_name, e))
+        let mut anchored = HashSet::<String>::new();
+        let mut redirect = |rf: &mut String, subtrahend: &mut Option<String>, addend: &mut i64| {
+            if let Some((start, offset)) = dropped.get(rf.as_str()) {
+                *addend = addend.wrapping_add(*offset);
+                anchored.insert(start.clone());
+                *rf = start.clone();
+            }
+            if let Some((start, offset)) = subtrahend.as_ref().and_then(|s| dropped.get(s.as_str())) {
+                *addend = addend.wrapping_sub(*offset);
+                anchored.insert(start.clone());
+                *subtrahend = Some(start.clone());
+            }
+        };
+
+        for sec in self.sections.values_mut() {
+            sec.labels.retain(|name, _| !dropped.contains_key(name));
+
+            for instruction in sec.instructions.iter_mut() {
+                for r in instruction.references.iter_mut() {
+                    redirect(&mut r.rf, &mut r.subtrahend, &mut r.addend);
+                }
+            }
+            for unit in sec.binary_data.iter_mut() {
+                if let Some(r) = &mut unit.reference {
+                    redirect(&mut r.rf, &mut r.subtrahend, &mut r.addend);
                 }
             }
         }
 
-        Ok(binary)
+        for (sec_name, sec) in self.sections.iter_mut() {
+            let name = format!("#{}", sec_name);
+            if anchored.contains(&name) {
+                sec.labels.insert(name.clone(), ObjectLabelSymbol { name, ptr: 0, binding: SymbolBinding::Local });
+            }
+        }
     }
 
     pub fn save_object(&self, path: &str) -> Result<(), String> {
-        let binary = self.generate_binary()?;
-
-        let mut file = match fs::File::create(path) {
+        let file = match fs::File::create(path) {
             Ok(f) => f,
             Err(e) => {
                 return Err(format!("Failed to open file to write: {e}"))
             }
         };
-        
-        match file.write_all(binary.as_slice()) {
+        let mut writer = BufWriter::new(file);
+
+        self.write_object(&mut writer)?;
+
+        match writer.flush() {
             Ok(_) => (),
             Err(e) =>
                 return Err(format!("Failed to write binary to file: {}", e))
@@ -1156,12 +3662,18 @@ impl ObjectFormat {
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, String> {
-        let mut me = Self::new();
+        Self::from_reader(&mut bytes.as_slice())
+    }
 
-        let mut binary_slice = bytes.as_slice();
+    /**
+     * Reads an object from a stream. The payload is checksummed while
+     * it is parsed, so it never has to be held in memory twice.
+     */
+    pub fn from_reader(binary: &mut dyn Read) -> Result<Self, String> {
+        let mut me = Self::new();
 
         let header_parse_result = 
-            ObjectFormatHeader::from_bytes(&mut binary_slice);
+            ObjectFormatHeader::from_bytes(binary);
         
         me.header = match header_parse_result {
             Ok(header) => header,
@@ -1170,42 +3682,261 @@ impl ObjectFormat {
             }
         };
 
-        if me.header.version != CURRENT_FORMAT_VERSION {
-            println!("Warning: File version does not match with latest format \
-version! It may not be compatible!");
+        let version = me.header.version;
+        let reader = match OBJECT_READERS.iter().find(|r| r.versions().contains(&version)) {
+            Some(r) => r,
+            None => {
+                return Err(format!("Object format version {} is not supported, versions {} to {} can be read",
+                    version, OLDEST_FORMAT_VERSION, CURRENT_FORMAT_VERSION))
+            }
+        };
+
+        if version < CHECKSUM_VERSION {
+            reader.read(&mut me, version, binary)?;
+        } else {
+            let mut payload = ChecksumReader { inner: binary, crc: Crc32::new() };
+
+            match reader.read(&mut me, version, &mut payload) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Object file is truncated or corrupted: {}", e))
+                }
+            }
+            // Anything after the tables is covered by the checksum too
+            match io::copy(&mut payload, &mut io::sink()) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Error occured while reading object file: {}", e))
+                }
+            }
+
+            let checksum = payload.crc.finish();
+            if checksum != me.header.checksum {
+                return Err(format!("Object file is truncated or corrupted: checksum is {:#010x}, \
+                    header says {:#010x}", checksum, me.header.checksum))
+            }
         }
 
-        for _ in 0..me.header.sections_length {
+        // Saved again in the current layout
+        me.header.version = CURRENT_FORMAT_VERSION;
+
+        Ok(me)
+    }
+
+    /**
+     * Reads sections, then the symbol table, line table, metadata and
+     * common symbols of versions that have them
+     */
+    fn read_body(&mut self, version: u32, binary: &mut dyn Read, names: &dyn NameSource) -> Result<(), String> {
+        let mut section_names = Vec::<String>::new();
+
+        for _ in 0..self.header.sections_length {
             let section =
-            match SectionData::from_bytes(&mut binary_slice) {
+            match SectionData::from_bytes(binary, names, version) {
                 Ok(section) => section,
                 Err(e) => {
                     return Err(format!("Error occured while parsing section: {}", e))
                 }
             };
-            me.sections.insert(section.name.clone(), section);
+            if section_names.contains(&section.name) {
+                return Err(format!("Section '{}' appears more than once", section.name))
+            }
+            section_names.push(section.name.clone());
+            self.sections.insert(section.name.clone(), section);
         }
 
-        Ok(me)
+        if version >= SYMBOL_TABLE_VERSION {
+            let symbol_count = match binary.read_u64::<LittleEndian>() {
+                Ok(n) => n,
+                Err(e) => {
+                    return Err(format!("Error occured while parsing symbol table: {}", e))
+                }
+            };
+
+            for _ in 0..symbol_count {
+                let symbol = match ObjectSymbol::from_bytes(binary, &section_names, names) {
+                    Ok(symbol) => symbol,
+                    Err(e) => {
+                        return Err(format!("Error occured while parsing symbol table: {}", e))
+                    }
+                };
+                // Unwrap because the section was read above
+                let sec = self.sections.get_mut(&symbol.section).unwrap();
+
+                if !sec.contains_position(symbol.ptr) {
+                    return Err(format!("Symbol '{}' points outside of section '{}'", symbol.name, symbol.section))
+                }
+
+                if sec.labels.contains_key(&symbol.name) {
+                    return Err(format!("Invalid label information for section '{}'! Label '{}' already exists!",
+                        symbol.section, symbol.name))
+                }
+                sec.labels.insert(symbol.name.clone(), ObjectLabelSymbol {
+                    name: symbol.name,
+                    ptr: symbol.ptr,
+                    binding: symbol.binding
+                });
+            }
+        }
+
+        if version >= LINE_TABLE_VERSION {
+            let line_count = match binary.read_u64::<LittleEndian>() {
+                Ok(n) => n,
+                Err(e) => {
+                    return Err(format!("Error occured while parsing line information: {}", e))
+                }
+            };
+
+            for _ in 0..line_count {
+                match LineInfo::from_bytes(binary, &section_names, names) {
+                    Ok(info) => self.debug_lines.push(info),
+                    Err(e) => {
+                        return Err(format!("Error occured while parsing line information: {}", e))
+                    }
+                }
+            }
+        }
+
+        if version >= METADATA_VERSION {
+            match self.read_metadata(binary, names) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Error occured while parsing metadata: {}", e))
+                }
+            }
+        }
+
+        if version >= COMMON_VERSION {
+            let common_count = match binary.read_u32::<LittleEndian>() {
+                Ok(n) => n,
+                Err(e) => {
+                    return Err(format!("Error occured while parsing common symbols: {}", e))
+                }
+            };
+
+            for _ in 0..common_count {
+                match CommonSymbol::from_bytes(binary, names) {
+                    Ok(common) => self.add_common(common),
+                    Err(e) => {
+                        return Err(format!("Error occured while parsing common symbols: {}", e))
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
+    /**
+     * Reads an object, SArch32 ELF relocatable objects are accepted too
+     */
     pub fn from_file(path: &str) -> Result<Self, String> {
-        let content = match fs::read(path) {
-            Ok(vc) => vc,
+        if crate::elf::is_elf(path) {
+            let bytes = match fs::read(path) {
+                Ok(b) => b,
+                Err(e) => {
+                    return Err(format!("Error occured while reading file:\n{}", e))
+                }
+            };
+            let mut me = crate::elf::read_relocatable(&bytes)?;
+            me.file = Some(path.to_string());
+            return Ok(me)
+        }
+
+        let file = match fs::File::open(path) {
+            Ok(f) => f,
             Err(e) => {
                 return Err(format!("Error occured while reading file:\n{}", e))
             }
         };
         
-        ObjectFormat::from_bytes(content)
+        let mut me = ObjectFormat::from_reader(&mut BufReader::new(file))?;
+        me.file = Some(path.to_string());
+
+        Ok(me)
     }
 
     fn do_compiler_instruction(&mut self, name: &str, children: &Vec<ParserNode>) -> Result<(), String> {
         let instr = match self.compiler_instructions.get(name) {
-            Some(i) => i,
+            Some(i) => *i,
             None => bad_compinstr!(name)
         };
-        instr(self, children)
+
+        // Compiler instructions only ever see folded constants.
+        // '.define', '.equ' and '.set' resolve their own arguments, since the first one is a name
+        let mut folded = Vec::<ParserNode>::with_capacity(children.len());
+        for child in children {
+            self.fold_argument(name, child, &mut folded)?;
+        }
+
+        instr(self, &folded)
+    }
+
+    fn fold_argument(&self, instruction: &str, child: &ParserNode, folded: &mut Vec<ParserNode>) -> Result<(), String> {
+        let assignment = matches!(instruction, "define" | "equ" | "set");
+        match &child.node_type {
+            NodeType::Expression | NodeType::Negate if assignment => folded.push(child.clone()),
+            NodeType::Expression | NodeType::Negate => match self.link_value(child)? {
+                Some(value) => folded.push(value.to_node()),
+                None => folded.push(self.evaluate_expression(child)?)
+            },
+            NodeType::Identifier(iden) if !assignment => match self.define_value(iden, 0) {
+                Some(n) => folded.push(ParserNode {
+                    node_type: NodeType::ConstInteger(n),
+                    children: Vec::new()
+                }),
+                None if self.defines.contains_key(iden) => match self.link_value(child)? {
+                    Some(value) => folded.push(value.to_node()),
+                    None => folded.push(child.clone())
+                },
+                None => folded.push(ParserNode {
+                    node_type: NodeType::Identifier(self.anonymous_reference(iden)?),
+                    children: Vec::new()
+                })
+            },
+            // Expanded into `count` copies of the value
+            NodeType::Duplicate => {
+                let count = child.children[1].evaluate(&|name| self.define_value(name, 0))?;
+                if count < 0 {
+                    return Err(format!("Duplicate count cannot be negative, {} provided", count))
+                }
+
+                let mut value = Vec::<ParserNode>::new();
+                self.fold_argument(instruction, &child.children[0], &mut value)?;
+
+                for _ in 0..count {
+                    folded.extend(value.iter().cloned());
+                }
+            }
+            _ => folded.push(child.clone())
+        }
+        Ok(())
+    }
+
+    // '.times count <instruction or compiler instruction>'
+    fn do_times(&mut self, children: &Vec<ParserNode>, current_label: &str) -> Result<(), String> {
+        let (count, repeated) = match children.as_slice() {
+            [count, repeated] => (count, repeated),
+            _ => unexpected_eof!("TIMES instruction requires a count and an instruction")
+        };
+
+        let count = count.evaluate(&|name| self.define_value(name, 0))?;
+        if count < 0 {
+            return Err(format!("Repeat count cannot be negative, {} provided", count))
+        }
+
+        for _ in 0..count {
+            match &repeated.node_type {
+                NodeType::CompilerInstruction(instr) if instr == "times" => {
+                    self.do_times(&repeated.children, current_label)?
+                }
+                NodeType::CompilerInstruction(instr) => self.do_compiler_instruction(instr, &repeated.children)?,
+                NodeType::Instruction(instr) => self.process_instruction(instr, &repeated.children, current_label)?,
+                _ => unexpected_node!(repeated)
+            }
+        }
+
+        Ok(())
     }
 
     fn resolve_define(&self, arg: usize, instr: &mut InstructionData, expected_argument: &ArgumentTypes, define_symbol: &Define, depth: i32)
@@ -1289,7 +4020,33 @@ version! It may not be compatible!");
         let conditions = Conditions::new();
         let registers = Registers::new();
 
-        match &arg.node_type { // TODO: Implement expressions
+        let symbolic = match &arg.node_type {
+            NodeType::Expression | NodeType::Negate => true,
+            NodeType::Identifier(name) => self.defines.contains_key(name)
+                && !matches!(expected_argument, ArgumentTypes::Condition),
+            _ => false
+        };
+        if symbolic {
+            if let Some(value) = self.link_value(arg)? {
+                let (rf, sub, addend) = value.reference()?;
+                let local_name = |name: String| {
+                    if name.starts_with('@') { current_label.to_string() + &name } else { name }
+                };
+                instr.references.push(Reference {
+                    argument_pos: index as u8,
+                    rf: local_name(rf),
+                    subtrahend: sub.map(local_name),
+                    addend
+                });
+                return Ok(())
+            }
+        }
+
+        match &arg.node_type {
+            NodeType::Expression | NodeType::Negate => {
+                let folded = self.evaluate_expression(arg)?;
+                self.resolve_instruction(&folded, instr, expected_argument, index, current_label)?;
+            }
             NodeType::Identifier(identifier_name) => {
                 if self.defines.contains_key(identifier_name) {
                     let define_symbol = &self.defines[identifier_name];
@@ -1309,7 +4066,7 @@ version! It may not be compatible!");
                             });
                         }
                         _ => {
-                            let mut identifier = identifier_name.clone();
+                            let mut identifier = self.anonymous_reference(identifier_name)?;
                             if identifier.starts_with('@') {
                                 identifier = current_label.to_string() + &identifier;
                             } else if identifier == "@" {
@@ -1317,7 +4074,9 @@ version! It may not be compatible!");
                             }
                             instr.references.push(Reference {
                                 argument_pos: index as u8,
-                                rf: identifier
+                                rf: identifier,
+                                subtrahend: None,
+                                addend: 0
                             })
                         }
                     }
@@ -1442,6 +4201,10 @@ version! It may not be compatible!");
             self.resolve_instruction(arg, &mut instr, &expected_argument, i, current_label)?;
         }
 
+        if self.literal_pools {
+            self.spill_literal(&mut instr);
+        }
+
         match self.sections.get_mut(&self.current_section) {
             Some(s) => s,
             None => {
@@ -1452,6 +4215,162 @@ version! It may not be compatible!");
         Ok(())
     }
 
+    /**
+     * Rewrites an instruction with an immediate that doesn't fit in 16 bits
+     * into its memory operand form, loading the value from a literal pool.
+     */
+    fn spill_literal(&mut self, instr: &mut InstructionData) {
+        let instructions = Instructions::new();
+
+        let memory_opcode = match LITERAL_LOADS.iter()
+            .find(|(imm, _)| instructions.get_opcode(imm) == Some(instr.opcode))
+        {
+            // Unwrap because the table only names existing instructions
+            Some((_, mem)) => instructions.get_opcode(mem).unwrap(),
+            None => return
+        };
+
+        let index = match instr.constants.iter()
+            .position(|c| c.argument_pos == 0 && !(-0x8000..=0xFFFF).contains(&c.value))
+        {
+            Some(i) => i,
+            None => return
+        };
+        let value = instr.constants.remove(index).value;
+
+        let label = match self.pending_literals.iter().find(|(v, _)| *v == value) {
+            Some((_, label)) => label.clone(),
+            None => {
+                let label = format!("__literal_{}", self.literal_count);
+                self.literal_count += 1;
+                self.locals.insert(label.clone());
+                self.pending_literals.push((value, label.clone()));
+                label
+            }
+        };
+
+        instr.opcode = memory_opcode;
+        instr.references.push(Reference {
+            argument_pos: 0,
+            rf: label,
+            subtrahend: None,
+            addend: 0
+        });
+    }
+
+    /**
+     * Places every pending literal into a section as aligned double words
+     */
+    fn flush_literals(&mut self, section_name: &str) -> Result<(), String> {
+        let sec = match self.sections.get_mut(section_name) {
+            Some(s) => s,
+            None => {
+                return Err(format!("Section '{}' not found! Maybe compiler bug?", section_name))
+            }
+        };
+
+        if sec.instructions.len() != 0 || sec.is_nobits() {
+            return Err(format!("Cannot place a literal pool into section '{}': \
+            it must hold binary data", section_name))
+        }
+        if self.pending_literals.is_empty() {
+            return Ok(())
+        }
+
+        sec.binary_section = true;
+        sec.binary_data.push(BinaryUnit {
+            reference: None,
+            constant: None,
+            alignment: Some(BinaryAlignment { alignment: 4, fill: 0 })
+        });
+
+        for (value, label) in self.pending_literals.drain(..) {
+            if sec.labels.contains_key(&label) {
+                return Err(format!("Label '{}' is redefined!", label))
+            }
+            sec.labels.insert(label.clone(), ObjectLabelSymbol {
+                name: label,
+                ptr: sec.binary_data.len() as u64,
+                binding: SymbolBinding::Default
+            });
+            sec.binary_data.push(BinaryUnit {
+                reference: None,
+                constant: Some(BinaryConstant {
+                    size: ConstantSize::DoubleWord,
+                    value
+                }),
+                alignment: None
+            });
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Names the next anonymous label. They are local to their object.
+     */
+    fn define_anonymous_label(&mut self) -> String {
+        let name = format!("{}{}", ANONYMOUS_LABEL_PREFIX, self.anonymous_labels);
+        self.anonymous_labels += 1;
+        self.locals.insert(name.clone());
+        name
+    }
+
+    /**
+     * Resolves ':+' (next anonymous label), ':-' (previous one), ':++', ':--', ...
+     * Other names are returned unchanged.
+     */
+    fn anonymous_reference(&self, name: &str) -> Result<String, String> {
+        let steps = match name.strip_prefix(':') {
+            Some(s) => s,
+            None => return Ok(name.to_string())
+        };
+        let distance = steps.len();
+
+        let index = if steps.chars().all(|c| c == '+') {
+            self.anonymous_labels + distance - 1
+        } else if steps.chars().all(|c| c == '-') {
+            match self.anonymous_labels.checked_sub(distance) {
+                Some(i) => i,
+                None => {
+                    return Err(format!("'{}' refers to an anonymous label before the first one", name))
+                }
+            }
+        } else {
+            return Err(format!("Invalid anonymous label reference '{}'", name))
+        };
+
+        Ok(format!("{}{}", ANONYMOUS_LABEL_PREFIX, index))
+    }
+
+    // Section and index the next instruction or data unit goes to
+    fn content_position(&self) -> Option<(String, u64)> {
+        let sec = self.sections.get(&self.current_section)?;
+        if sec.is_nobits() {
+            return None
+        }
+        Some((self.current_section.clone(), sec.instructions.len().max(sec.binary_data.len()) as u64))
+    }
+
+    // Maps what a statement starting at `start` emitted to the current source line
+    fn record_line(&mut self, start: Option<(String, u64)>) {
+        let (file, line) = match &self.source_line {
+            Some(l) => l.clone(),
+            None => return
+        };
+        let (section, ptr) = match start {
+            Some(s) => s,
+            None => return
+        };
+        // Statements that emitted nothing, or switched sections, have no line of their own
+        match self.content_position() {
+            Some((current, end)) if current == section && end > ptr => {
+                self.debug_lines.push(LineInfo { section, ptr, file, line });
+            }
+            _ => {}
+        }
+    }
+
     pub fn load_parser_node(&mut self, node: &ParserNode) -> Result<(), String> {
         //let instructions = Instructions::new();
 
@@ -1462,58 +4381,195 @@ version! It may not be compatible!");
         let mut current_label = String::new();
 
         for child in node.children.iter() {
-            match &child.node_type {
-                NodeType::CompilerInstruction(instr) => {
-                    match self.do_compiler_instruction(instr, &child.children) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            return Err(format!("Error while executing compiler instruction: {}", e))
-                        }
+            self.load_statement(child, &mut current_label)?;
+        }
+
+        self.finish_loading()
+    }
+
+    /**
+     * Assembles one top level statement of a Program node. `current_label`
+     * is the last non-local label, which '@' labels belong to.
+     */
+    fn load_statement(&mut self, child: &ParserNode, current_label: &mut String) -> Result<(), String> {
+        match &child.node_type {
+            // Repeats an instruction, so it needs the current label unlike other compiler instructions
+            NodeType::CompilerInstruction(instr) if instr == "times" => {
+                let start = self.content_position();
+                match self.do_times(&child.children, current_label) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        return Err(format!("Error while executing compiler instruction: {}", e))
                     }
                 }
-                NodeType::Instruction(instr) => {
-                    match self.process_instruction(instr, &child.children, &current_label) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            return Err(format!("Error while processing instruction: {}", e))
-                        }
+                self.record_line(start);
+            }
+            NodeType::CompilerInstruction(instr) => {
+                let start = self.content_position();
+                match self.do_compiler_instruction(instr, &child.children) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        return Err(format!("Error while executing compiler instruction: {}", e))
                     }
                 }
-                NodeType::Label(name) => {
-                    let current_section = match self.sections.get_mut(&self.current_section) {
-                        Some(s) => s,
-                        None => {
-                            return Err(format!("Section '{}' does not exist! Maybe compiler bug?", self.current_section))
-                        }
-                    };
-                    let pointer: usize;
-
-                    if current_section.binary_data.len() == 0 {
-                        pointer = current_section.instructions.len();
-                    } else {
-                        pointer = current_section.binary_data.len();
+                self.record_line(start);
+            }
+            NodeType::Instruction(instr) => {
+                let start = self.content_position();
+                match self.process_instruction(instr, &child.children, current_label) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        return Err(format!("Error while processing instruction: {}", e))
                     }
-
-                    if current_section.labels.contains_key(name) {
-                        return Err(format!("Label '{}' is redefined!", name))
+                }
+                self.record_line(start);
+            }
+            NodeType::LineMarker(file, line) => {
+                self.source_line = Some((file.clone(), *line));
+            }
+            NodeType::Label(name) => {
+                let name = &if name == ":" {
+                    self.define_anonymous_label()
+                } else {
+                    name.clone()
+                };
+                let current_section = match self.sections.get_mut(&self.current_section) {
+                    Some(s) => s,
+                    None => {
+                        return Err(format!("Section '{}' does not exist! Maybe compiler bug?", self.current_section))
                     }
+                };
+                let pointer: usize;
 
-                    let label = ObjectLabelSymbol {
-                        name: name.clone(),
-                        ptr: pointer as u64,
-                    };
-                    
-                    current_section.labels.insert(name.clone(), label);
-                    
-                    if !name.contains('@') {
-                        // FIXME: This is the easiest fix i can think about now
-                        current_label = name.clone();
-                    }
+                if current_section.is_nobits() {
+                    pointer = current_section.bss_size as usize;
+                } else if current_section.binary_data.len() == 0 {
+                    pointer = current_section.instructions.len();
+                } else {
+                    pointer = current_section.binary_data.len();
                 }
-                _ => unexpected_node!(child)
+
+                if current_section.labels.contains_key(name) {
+                    return Err(format!("Label '{}' is redefined!", name))
+                }
+
+                let label = ObjectLabelSymbol {
+                    name: name.clone(),
+                    ptr: pointer as u64,
+                    binding: SymbolBinding::Default
+                };
+                
+                current_section.labels.insert(name.clone(), label);
+                
+                if !name.contains('@') && !name.starts_with(ANONYMOUS_LABEL_PREFIX) {
+                    // FIXME: This is the easiest fix i can think about now
+                    *current_label = name.clone();
+                }
+            }
+            _ => unexpected_node!(child)
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Places pending literals and checks the sections once every statement is loaded
+     */
+    fn finish_loading(&mut self) -> Result<(), String> {
+        if !self.pending_literals.is_empty() {
+            if !self.sections.contains_key(LITERAL_POOL_SECTION) {
+                let mut sec = SectionData::new();
+                sec.name = LITERAL_POOL_SECTION.to_string();
+                sec.flags = SectionFlags::default_for(LITERAL_POOL_SECTION);
+                self.sections.insert(sec.name.clone(), sec);
+                self.header.sections_length += 1;
+            }
+            self.flush_literals(LITERAL_POOL_SECTION)?;
+        }
+
+        for sec in self.sections.values() {
+            if sec.is_nobits() && (!sec.instructions.is_empty() || !sec.binary_data.is_empty()) {
+                return Err(format!("Noload section '{}' can only contain reservations like '.resb'", sec.name))
+            }
+        }
+
+        self.apply_visibility()
+    }
+}
+
+/**
+ * Assembles preprocessed tokens into an object a line at a time, as the
+ * preprocessor produces them, so the whole token stream is never kept
+ */
+pub struct ObjectSink<'a> {
+    object: &'a mut ObjectFormat,
+    parser: Parser,
+    line: Vec<OwnedToken>,
+    current_label: String,
+    // Every parsed statement, only collected when it is going to be printed
+    pub tree: Option<ParserNode>,
+    // First error, reported by `finish`
+    error: Option<String>
+}
+
+impl<'a> ObjectSink<'a> {
+    pub fn new(object: &'a mut ObjectFormat) -> Self {
+        Self {
+            object,
+            parser: Parser::new(),
+            line: Vec::new(),
+            current_label: String::new(),
+            tree: None,
+            error: None
+        }
+    }
+
+    fn load_line(&mut self) -> Result<(), String> {
+        let tokens = self.line.iter().map(|t| t.as_token()).collect();
+        if let Err(e) = self.parser.parse(&tokens) {
+            return Err(format!("Error occured while parsing:\n{}", e))
+        }
+        self.line.clear();
+
+        let statements = std::mem::take(&mut self.parser.root.children);
+        for statement in statements.iter() {
+            if let Err(e) = self.object.load_statement(statement, &mut self.current_label) {
+                return Err(format!("Error occured while generating object file:\n{}", e))
             }
         }
+        if let Some(tree) = self.tree.as_mut() {
+            tree.children.extend(statements);
+        }
 
         Ok(())
     }
+
+    /**
+     * Assembles what's left of the last line and finishes the object
+     */
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(e) = self.error.take() {
+            return Err(e)
+        }
+        self.load_line()?;
+
+        match self.object.finish_loading() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Error occured while generating object file:\n{}", e))
+        }
+    }
+}
+
+impl TokenSink for ObjectSink<'_> {
+    fn push(&mut self, token: OwnedToken) {
+        if self.error.is_some() {
+            return
+        }
+        let line_end = token.kind == LexerToken::Newline || token.kind == LexerToken::Comment;
+        self.line.push(token);
+
+        if line_end {
+            self.error = self.load_line().err();
+        }
+    }
 }
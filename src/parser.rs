@@ -185,6 +185,18 @@ pub enum NodeType {
     Subtraction,
     Multiplication,
     Division,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    LogicalAnd,
+    LogicalOr,
+    // 'value dup(count)' in compiler instruction arguments
+    Duplicate,
+    // Source file and line of the statements that follow
+    LineMarker(String, u32),
     Program
 }
 
@@ -198,6 +210,58 @@ impl ParserNode {
     pub fn new() -> Self {
         Self { children: Vec::new(), node_type: NodeType::Program }
     }
+
+    fn evaluate_child(&self, idx: usize, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+        match self.children.get(idx) {
+            Some(c) => c.evaluate(resolve),
+            None => Err(format!("Missing operand {} in {:?}", idx, self.node_type))
+        }
+    }
+
+    /**
+     * Folds a constant expression into an integer.
+     * 
+     * Identifiers are looked up through `resolve`. Comparisons and logical
+     * operators evaluate to 1 (true) or 0 (false).
+     */
+    pub fn evaluate(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+        match &self.node_type {
+            NodeType::ConstInteger(n) => Ok(*n),
+            NodeType::Identifier(name) => {
+                match resolve(name) {
+                    Some(n) => Ok(n),
+                    None => Err(format!("Undefined symbol '{}' in constant expression", name))
+                }
+            }
+            NodeType::Expression => self.evaluate_child(0, resolve),
+            NodeType::Negate => Ok(self.evaluate_child(0, resolve)?.wrapping_neg()),
+            _ => {
+                let lhs = self.evaluate_child(0, resolve)?;
+                let rhs = self.evaluate_child(1, resolve)?;
+
+                match &self.node_type {
+                    NodeType::Addition => Ok(lhs.wrapping_add(rhs)),
+                    NodeType::Subtraction => Ok(lhs.wrapping_sub(rhs)),
+                    NodeType::Multiplication => Ok(lhs.wrapping_mul(rhs)),
+                    NodeType::Division => {
+                        if rhs == 0 {
+                            return Err(format!("Division by zero in constant expression"))
+                        }
+                        Ok(lhs.wrapping_div(rhs))
+                    }
+                    NodeType::Equal => Ok((lhs == rhs) as i64),
+                    NodeType::NotEqual => Ok((lhs != rhs) as i64),
+                    NodeType::Less => Ok((lhs < rhs) as i64),
+                    NodeType::Greater => Ok((lhs > rhs) as i64),
+                    NodeType::LessEqual => Ok((lhs <= rhs) as i64),
+                    NodeType::GreaterEqual => Ok((lhs >= rhs) as i64),
+                    NodeType::LogicalAnd => Ok((lhs != 0 && rhs != 0) as i64),
+                    NodeType::LogicalOr => Ok((lhs != 0 || rhs != 0) as i64),
+                    _ => Err(format!("Cannot evaluate {:?} as a constant expression", self.node_type))
+                }
+            }
+        }
+    }
 }
 
 pub struct Parser {
@@ -241,6 +305,27 @@ impl Parser {
 
                     self.root.children.push(node);
                 }
+                // Named by objgen, and doesn't start a new scope for '@' labels
+                LexerToken::AnonymousLabel => {
+                    self.root.children.push(ParserNode {
+                        node_type: NodeType::Label(":".to_string()),
+                        children: Vec::new()
+                    });
+                }
+                LexerToken::LineMarker => {
+                    let (line, file) = match token.text.split_once(':') {
+                        Some((line, file)) => (line.parse::<u32>().ok(), file),
+                        None => (None, "")
+                    };
+                    let line = match line {
+                        Some(l) => l,
+                        None => returnerr!(token)
+                    };
+                    self.root.children.push(ParserNode {
+                        node_type: NodeType::LineMarker(file.to_string(), line),
+                        children: Vec::new()
+                    });
+                }
                 LexerToken::Newline => {}
                 LexerToken::Comment => {}
                 _ => returnerr!(token)
@@ -250,6 +335,81 @@ impl Parser {
         Ok(&self.root)
     }
 
+    /**
+     * Parses a standalone expression spanning all of `tokens`.
+     * Used by the preprocessor to read `%if` conditions.
+     */
+    pub fn parse_constant_expression(tokens: &Vec<Token<LexerToken>>) -> Result<ParserNode, String> {
+        let mut iterator = tokens.iter();
+
+        let first = unwrap_from_option!(iterator.next());
+        let lhs = Parser::parse_expression(first, &mut iterator, false, false)?;
+        let node = Parser::parse_operator_chain(lhs, 0, &mut iterator)?;
+
+        if let Some(token) = iterator.next() {
+            returnerr!(token)
+        }
+
+        Ok(node)
+    }
+
+    fn binary_operator(kind: LexerToken) -> Option<(NodeType, u8)> {
+        match kind {
+            LexerToken::LogicalOr => Some((NodeType::LogicalOr, 1)),
+            LexerToken::LogicalAnd => Some((NodeType::LogicalAnd, 2)),
+            LexerToken::Equal => Some((NodeType::Equal, 3)),
+            LexerToken::NotEqual => Some((NodeType::NotEqual, 3)),
+            LexerToken::Less => Some((NodeType::Less, 4)),
+            LexerToken::Greater => Some((NodeType::Greater, 4)),
+            LexerToken::LessEqual => Some((NodeType::LessEqual, 4)),
+            LexerToken::GreaterEqual => Some((NodeType::GreaterEqual, 4)),
+            LexerToken::Plus => Some((NodeType::Addition, 5)),
+            LexerToken::Minus => Some((NodeType::Subtraction, 5)),
+            LexerToken::Multiply => Some((NodeType::Multiplication, 6)),
+            LexerToken::Divide => Some((NodeType::Division, 6)),
+            _ => None
+        }
+    }
+
+    /**
+     * Parses `lhs op rhs op ...` without parenthesis, honoring operator precedence.
+     * Operators bind to the left.
+     */
+    fn parse_operator_chain<'a>(mut lhs: ParserNode, min_precedence: u8,
+        tokens: &mut core::slice::Iter<'a, Token<'a, LexerToken>>)
+        -> Result<ParserNode, String>
+    {
+        while let Some(next) = tokens.as_slice().first() {
+            let (node_type, precedence) = match Parser::binary_operator(next.kind) {
+                Some(op) if op.1 >= min_precedence => op,
+                _ => break
+            };
+            tokens.next();
+
+            let operand = unwrap_from_option!(tokens.next());
+            let mut rhs = Parser::parse_expression(operand, tokens, false, false)?;
+
+            while let Some(next) = tokens.as_slice().first() {
+                match Parser::binary_operator(next.kind) {
+                    Some((_, p)) if p > precedence => {
+                        rhs = Parser::parse_operator_chain(rhs, p, tokens)?;
+                    }
+                    _ => break
+                }
+            }
+
+            lhs = ParserNode {
+                node_type: NodeType::Expression,
+                children: vec![ParserNode {
+                    node_type,
+                    children: vec![lhs, rhs]
+                }]
+            };
+        }
+
+        Ok(lhs)
+    }
+
     fn parse_instruction<'a>(current_token: &Token<'a, LexerToken>,
         tokens: &mut core::slice::Iter<'a, Token<'a, LexerToken>>)
         -> Result<ParserNode, String>
@@ -291,12 +451,51 @@ impl Parser {
 
         let mut token = unwrap_from_option!(tokens.next());
 
+        // '.times count <instruction>' wraps the rest of the line
+        if current_token.text == ".times" {
+            let count = Parser::parse_expression(token, tokens, false, false)?;
+            node.children.push(count);
+
+            token = unwrap_from_option!(tokens.next());
+            let repeated = match token.kind {
+                LexerToken::Identifier => Parser::parse_instruction(token, tokens)?,
+                LexerToken::CompilerInstruction => Parser::parse_compiler_instruction(token, tokens)?,
+                _ => returnerr!(token)
+            };
+            node.children.push(repeated);
+
+            return Ok(node)
+        }
+
         while token.kind != LexerToken::Newline && token.kind != LexerToken::Comment {
             let nd = Parser::parse_expression(token, tokens, false, true)?;
 
             node.children.push(nd);
 
             token = unwrap_from_option!(tokens.next());
+
+            // 'value dup(count)' repeats the previous argument
+            if token.kind == LexerToken::Identifier && token.text == "dup" {
+                let value = node.children.pop().unwrap();
+
+                token = unwrap_from_option!(tokens.next());
+                if token.kind != LexerToken::LParen {
+                    returnerr!(token)
+                }
+                let count = Parser::parse_expression(token, tokens, false, false)?;
+
+                node.children.push(ParserNode {
+                    node_type: NodeType::Duplicate,
+                    children: vec![value, count]
+                });
+
+                token = unwrap_from_option!(tokens.next());
+            }
+
+            // Arguments may optionally be separated by commas
+            if token.kind == LexerToken::Comma && node.children.len() > 0 {
+                token = unwrap_from_option!(tokens.next());
+            }
         }
 
         Ok(node)
@@ -314,12 +513,13 @@ impl Parser {
                 let mut numtxt = current_token.text;
                 let try_convert: Result<i64, std::num::ParseIntError>;
 
+                // Hex and binary literals may use all 64 bits
                 if numtxt.starts_with("0x") {
                     numtxt = numtxt.strip_prefix("0x").unwrap();
-                    try_convert = i64::from_str_radix(numtxt, 16);
+                    try_convert = u64::from_str_radix(numtxt, 16).map(|n| n as i64);
                 } else if numtxt.starts_with("0b") {
                     numtxt = numtxt.strip_prefix("0b").unwrap();
-                    try_convert = i64::from_str_radix(numtxt, 2);
+                    try_convert = u64::from_str_radix(numtxt, 2).map(|n| n as i64);
                 } else if numtxt.starts_with("0d") {
                     numtxt = numtxt.strip_prefix("0d").unwrap();
                     try_convert = i64::from_str_radix(numtxt, 10);
@@ -352,32 +552,13 @@ impl Parser {
                 };
                 Ok(node)
             }
-            // TODO: Add chaining expressions without adding more parenthesis
             LexerToken::LParen => { // Used for creating expressions
-                let mut next = unwrap_from_option!(tokens.next());
+                let next = unwrap_from_option!(tokens.next());
 
                 let lhs = Parser::parse_expression(next, tokens, use_registers, str_available)?;
-                next = unwrap_from_option!(tokens.next());
-                let operator = next.clone();
-                next = unwrap_from_option!(tokens.next());
-                let rhs = Parser::parse_expression(next, tokens, use_registers, str_available)?;
-
-                let node = ParserNode {
-                    node_type: match operator.kind {
-                        LexerToken::Plus => NodeType::Addition,
-                        LexerToken::Minus => NodeType::Subtraction,
-                        LexerToken::Multiply => NodeType::Multiplication,
-                        LexerToken::Divide => NodeType::Division,
-                        _ => returnerr!(operator)
-                    },
-                    children: vec![lhs, rhs]
-                };
-                let result = ParserNode {
-                    node_type: NodeType::Expression,
-                    children: vec![node]
-                };
+                let result = Parser::parse_operator_chain(lhs, 0, tokens)?;
 
-                next = unwrap_from_option!(tokens.next());
+                let next = unwrap_from_option!(tokens.next());
 
                 if next.kind != LexerToken::RParen {
                     returnerr!(next)
@@ -389,7 +570,7 @@ impl Parser {
                     return Err(format!("Using String where not allowed: {} at {}..{}",
                     current_token.text, current_token.span.start, current_token.span.end))
                 }
-                let _str = &current_token.text[1..current_token.text.chars().count() - 1];
+                let _str = &current_token.text[1..current_token.text.len() - 1];
                 let node = ParserNode {
                     node_type: NodeType::String(_str.to_string()),
                     children: Vec::new()
@@ -425,6 +606,13 @@ impl Parser {
                 let node = Parser::parse_expression(next, tokens, use_registers, str_available)?;
                 Ok(node)
             }
+            // ':+' and ':-', resolved by objgen
+            LexerToken::AnonymousReference => {
+                Ok(ParserNode {
+                    node_type: NodeType::Identifier(current_token.text.to_string()),
+                    children: Vec::new()
+                })
+            }
             LexerToken::Identifier => {
                 if rgs.has_key(current_token.text) {
                     if !use_registers {
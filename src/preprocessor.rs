@@ -0,0 +1,1188 @@
+/**
+ * preprocessor.rs
+ *
+ * Token-level preprocessor that runs between the lexer and the parser.
+ * Handles '%' directives: defines, macros, conditional assembly, repetition
+ * and file inclusion.
+ */
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use regex_lexer::Token;
+
+use crate::lexer::{AsmLexer, LexerToken, OwnedToken};
+use crate::parser::Parser;
+
+macro_rules! directive_error {
+    ($token:expr, $($arg:tt)*) => {
+        return Err(format!("{} at {}..{}", format!($($arg)*), $token.span.start, $token.span.end))
+    };
+}
+
+const MAX_EXPANSION_DEPTH: i32 = 100;
+const DEFAULT_MACRO_DEPTH: usize = 64;
+
+/**
+ * Rebuilds source text from tokens. Tokens that were adjacent in the
+ * source are joined directly, others are separated by a space.
+ */
+fn spell_tokens(tokens: &[OwnedToken]) -> String {
+    let mut text = String::new();
+
+    for (i, t) in tokens.iter().enumerate() {
+        if i != 0 && tokens[i - 1].span.end != t.span.start {
+            text.push(' ');
+        }
+        text += &t.text;
+    }
+
+    text
+}
+
+/**
+ * Identifies a source file regardless of how its path was spelled
+ */
+fn canonical_path(name: &str) -> PathBuf {
+    fs::canonicalize(name).unwrap_or_else(|_| PathBuf::from(name))
+}
+
+/**
+ * Comment telling a reader of preprocessed output where the next line came from
+ */
+fn line_marker(line: usize, file_name: &str, span: &Range<usize>) -> OwnedToken {
+    OwnedToken {
+        kind: LexerToken::Comment,
+        text: format!("; line {} \"{}\"\n", line, file_name),
+        span: span.clone()
+    }
+}
+
+/**
+ * Turns preprocessed tokens back into assembly source, one statement per line
+ */
+#[cfg(test)]
+pub fn format_tokens(tokens: &[OwnedToken]) -> String {
+    let mut writer = SourceWriter::new(Vec::<u8>::new());
+    for token in tokens {
+        writer.push(token.clone());
+    }
+    // Unwraps because writing to memory can't fail and tokens are UTF-8
+    String::from_utf8(writer.finish().unwrap()).unwrap()
+}
+
+/**
+ * Writes preprocessed tokens out as assembly source while they are being
+ * produced, holding only the line being written
+ */
+pub struct SourceWriter<W: Write> {
+    out: W,
+    line: Vec<OwnedToken>,
+    // First write error, reported by `finish`
+    error: Option<io::Error>
+}
+
+impl<W: Write> SourceWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, line: Vec::new(), error: None }
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.error.is_none() {
+            self.error = self.out.write_all(text.as_bytes()).err();
+        }
+    }
+
+    /**
+     * Writes `text` as a comment line of its own, after whatever line is pending
+     */
+    pub fn comment(&mut self, text: &str) {
+        let line = std::mem::take(&mut self.line);
+        if !line.is_empty() {
+            self.write(&spell_tokens(&line));
+            self.write("\n");
+        }
+        self.write(&format!("; {}\n", text));
+    }
+
+    /**
+     * Writes what's left of the last line and hands back the output
+     */
+    pub fn finish(mut self) -> io::Result<W> {
+        let rest = spell_tokens(&self.line);
+        self.write(&rest);
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.out)
+        }
+    }
+}
+
+impl<W: Write> TokenSink for SourceWriter<W> {
+    fn push(&mut self, token: OwnedToken) {
+        match token.kind {
+            LexerToken::LineMarker => {}
+            LexerToken::Newline | LexerToken::Comment => {
+                let line = std::mem::take(&mut self.line);
+                if let Some(first) = line.first() {
+                    if first.kind != LexerToken::Label && first.kind != LexerToken::AnonymousLabel {
+                        self.write("    ");
+                    }
+                    self.write(&spell_tokens(&line));
+                    if token.kind == LexerToken::Comment {
+                        self.write(" ");
+                    }
+                }
+                self.write(&token.text);
+            }
+            _ => self.line.push(token)
+        }
+    }
+}
+
+fn line_starts(code: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+
+    for (i, c) in code.bytes().enumerate() {
+        if c == b'\n' {
+            starts.push(i + 1);
+        }
+    }
+
+    starts
+}
+
+fn integer_token(value: i64, span: &Range<usize>) -> OwnedToken {
+    OwnedToken {
+        kind: LexerToken::Integer,
+        text: value.to_string(),
+        span: span.clone()
+    }
+}
+
+/**
+ * Receives preprocessed tokens as soon as they are produced, so callers
+ * don't have to wait for (or keep) the whole output.
+ */
+pub trait TokenSink {
+    fn push(&mut self, token: OwnedToken);
+}
+
+impl TokenSink for Vec<OwnedToken> {
+    fn push(&mut self, token: OwnedToken) {
+        Vec::push(self, token)
+    }
+}
+
+/**
+ * Text of an included file, read once and shared between all its inclusions.
+ * It is lexed again each time, as the tokens are needed.
+ */
+struct Source {
+    code: String,
+    line_starts: Rc<Vec<usize>>
+}
+
+/**
+ * State of a single %if/%ifdef/%ifndef block
+ */
+struct Conditional {
+    directive: String,
+    span: Range<usize>,
+    // Whether the block containing this one emits tokens
+    parent_active: bool,
+    // Whether the current branch emits tokens
+    active: bool,
+    // Whether any branch of this block was already taken
+    taken: bool,
+    has_else: bool
+}
+
+/**
+ * Symbols saved by '%push', restored by the matching '%pop'
+ */
+struct Context {
+    name: String,
+    span: Range<usize>,
+    defines: HashMap<String, Vec<OwnedToken>>,
+    macros: HashMap<String, Rc<Macro>>
+}
+
+/**
+ * Macro defined with '%macro name(a, b, ...)' ... '%endmacro'
+ */
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    // Trailing '...' collects any extra arguments for '%args'
+    variadic: bool,
+    body: Vec<OwnedToken>
+}
+
+pub struct Preprocessor {
+    defines: HashMap<String, Vec<OwnedToken>>,
+    macros: HashMap<String, Rc<Macro>>,
+    conditionals: Vec<Conditional>,
+    contexts: Vec<Context>,
+    // Names of the macros currently being expanded, outermost first
+    expansion_stack: Vec<String>,
+    pub max_macro_depth: usize,
+    // Number of macro expansions so far, used to make '%%' labels unique
+    invocation_count: u64,
+    // Current source file, for __FILE__ and __LINE__
+    file_name: String,
+    line_starts: Rc<Vec<usize>>,
+    // Next value of __COUNTER__
+    counter: Cell<i64>,
+    // Directories searched by '%include', in order
+    pub include_paths: Vec<PathBuf>,
+    // Files whose '%include' is being processed, outermost first
+    include_stack: Vec<String>,
+    // Files marked with '%once'
+    once_files: HashSet<PathBuf>,
+    // Included files, read once and keyed by canonical path
+    sources: HashMap<PathBuf, Rc<Source>>,
+    // Emit LineMarker tokens so objgen can record debug line information
+    pub line_markers: bool,
+    // File and line of the last LineMarker
+    last_location: Option<(String, usize)>,
+    lexer: Rc<AsmLexer>
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self {
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            conditionals: Vec::new(),
+            contexts: Vec::new(),
+            expansion_stack: Vec::new(),
+            max_macro_depth: DEFAULT_MACRO_DEPTH,
+            invocation_count: 0,
+            file_name: String::new(),
+            line_starts: Rc::new(vec![0]),
+            counter: Cell::new(0),
+            include_paths: Vec::new(),
+            include_stack: Vec::new(),
+            once_files: HashSet::new(),
+            sources: HashMap::new(),
+            line_markers: false,
+            last_location: None,
+            lexer: Rc::new(AsmLexer::new())
+        }
+    }
+
+    /**
+     * Sets the file the next tokens come from, used by __FILE__ and __LINE__
+     */
+    pub fn set_source(&mut self, file_name: &str, code: &str) {
+        self.file_name = file_name.to_string();
+        self.line_starts = Rc::new(line_starts(code));
+    }
+
+    /**
+     * Tells the parser which line the tokens from `token` on come from.
+     * Macro expansions carry the span of their call, so they map to the calling line.
+     */
+    fn mark_line(&mut self, token: &OwnedToken, result: &mut dyn TokenSink) {
+        let location = (self.file_name.clone(), self.line_of(token.span.start));
+        if self.last_location.as_ref() == Some(&location) {
+            return
+        }
+        result.push(OwnedToken {
+            kind: LexerToken::LineMarker,
+            text: format!("{}:{}", location.1, location.0),
+            span: token.span.clone()
+        });
+        self.last_location = Some(location);
+    }
+
+    fn line_of(&self, position: usize) -> usize {
+        match self.line_starts.binary_search(&position) {
+            Ok(i) => i + 1,
+            Err(i) => i
+        }
+    }
+
+    /**
+     * Value of a builtin symbol such as __FILE__, if `token` names one
+     */
+    fn builtin(&self, token: &OwnedToken) -> Option<OwnedToken> {
+        match token.text.as_str() {
+            "__FILE__" => Some(OwnedToken {
+                kind: LexerToken::String,
+                text: format!("\"{}\"", self.file_name),
+                span: token.span.clone()
+            }),
+            "__LINE__" => Some(integer_token(self.line_of(token.span.start) as i64, &token.span)),
+            "__COUNTER__" => {
+                let value = self.counter.get();
+                self.counter.set(value + 1);
+                Some(integer_token(value, &token.span))
+            }
+            _ => None
+        }
+    }
+
+    /**
+     * Defines a symbol from text, as with '-D NAME=VALUE' on the command line
+     */
+    pub fn define(&mut self, name: &str, value: &str) {
+        let tokens = self.lexer.tokenize(value).iter()
+            .filter(|t| t.kind != LexerToken::Newline && t.kind != LexerToken::Comment)
+            .map(OwnedToken::from_token)
+            .collect();
+
+        self.defines.insert(name.to_string(), tokens);
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.defines.contains_key(name) || matches!(name, "__FILE__" | "__LINE__" | "__COUNTER__")
+    }
+
+    fn is_active(&self) -> bool {
+        match self.conditionals.last() {
+            Some(c) => c.active,
+            None => true
+        }
+    }
+
+    fn expand_define(&self, token: &OwnedToken, result: &mut dyn TokenSink, depth: i32) -> Result<(), String> {
+        if token.kind != LexerToken::Identifier {
+            result.push(token.clone());
+            return Ok(())
+        }
+        if let Some(value) = self.builtin(token) {
+            result.push(value);
+            return Ok(())
+        }
+        let value = match self.defines.get(&token.text) {
+            Some(v) => v,
+            None => {
+                result.push(token.clone());
+                return Ok(())
+            }
+        };
+        if depth > MAX_EXPANSION_DEPTH {
+            directive_error!(token, "Looping defines detected while expanding '{}'", token.text)
+        }
+        for t in value.iter() {
+            // Keep the call-site span so errors point into the source
+            let mut t = t.clone();
+            t.span = token.span.clone();
+            self.expand_define(&t, result, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn evaluate_condition(&self, directive: &OwnedToken, args: &[OwnedToken]) -> Result<bool, String> {
+        Ok(self.evaluate_arguments(directive, args)? != 0)
+    }
+
+    fn evaluate_arguments(&self, directive: &OwnedToken, args: &[OwnedToken]) -> Result<i64, String> {
+        if args.len() == 0 {
+            directive_error!(directive, "Expected expression after '{}'", directive.text)
+        }
+
+        let mut expanded = Vec::<OwnedToken>::new();
+        for arg in args {
+            self.expand_define(arg, &mut expanded, 0)?;
+        }
+
+        let tokens: Vec<Token<LexerToken>> = expanded.iter().map(|t| t.as_token()).collect();
+
+        let node = match Parser::parse_constant_expression(&tokens) {
+            Ok(n) => n,
+            Err(e) => directive_error!(directive, "Invalid expression for '{}': {}", directive.text, e)
+        };
+
+        match node.evaluate(&|_| None) {
+            Ok(n) => Ok(n),
+            Err(e) => directive_error!(directive, "Failed to evaluate '{}': {}. \
+                Use '%ifdef' to test whether a symbol is defined", directive.text, e)
+        }
+    }
+
+    fn expect_name<'a>(directive: &OwnedToken, args: &'a [OwnedToken]) -> Result<&'a str, String> {
+        match args.get(0) {
+            Some(t) if t.kind == LexerToken::Identifier => Ok(&t.text),
+            Some(t) => directive_error!(t, "Expected identifier after '{}', found {:?} \"{}\"",
+                directive.text, t.kind, t.text),
+            None => directive_error!(directive, "Expected identifier after '{}'", directive.text)
+        }
+    }
+
+    fn open_conditional(&mut self, directive: &OwnedToken, args: &[OwnedToken]) -> Result<(), String> {
+        let parent_active = self.is_active();
+
+        // Conditions inside skipped blocks are never evaluated
+        let condition = if !parent_active {
+            false
+        } else {
+            match directive.text.as_str() {
+                "%ifdef" => self.is_defined(Preprocessor::expect_name(directive, args)?),
+                "%ifndef" => !self.is_defined(Preprocessor::expect_name(directive, args)?),
+                _ => self.evaluate_condition(directive, args)?
+            }
+        };
+
+        self.conditionals.push(Conditional {
+            directive: directive.text.clone(),
+            span: directive.span.clone(),
+            parent_active,
+            active: condition,
+            taken: condition,
+            has_else: false
+        });
+
+        Ok(())
+    }
+
+    /**
+     * Runs a directive other than the block and '%include' ones.
+     *
+     * A name is either a symbol or a macro, never both. '%define' won't change
+     * the value of a symbol while '%redef' and '%assign' replace it, whichever
+     * directive set it. Macros have no such form: they are '%undef'ined and
+     * defined again.
+     */
+    fn do_directive(&mut self, directive: &OwnedToken, args: &[OwnedToken]) -> Result<(), String> {
+        match directive.text.as_str() {
+            "%if" | "%ifdef" | "%ifndef" => self.open_conditional(directive, args),
+            "%elif" => {
+                let evaluate = match self.conditionals.last() {
+                    Some(c) if c.has_else => directive_error!(directive, "'%elif' after '%else'"),
+                    Some(c) => c.parent_active && !c.taken,
+                    None => directive_error!(directive, "'%elif' without matching '%if'")
+                };
+                let condition = evaluate && self.evaluate_condition(directive, args)?;
+
+                let cond = self.conditionals.last_mut().unwrap();
+                cond.active = condition;
+                cond.taken |= condition;
+                Ok(())
+            }
+            "%else" => {
+                let cond = match self.conditionals.last_mut() {
+                    Some(c) => c,
+                    None => directive_error!(directive, "'%else' without matching '%if'")
+                };
+                if cond.has_else {
+                    directive_error!(directive, "Duplicate '%else'")
+                }
+                cond.has_else = true;
+                cond.active = cond.parent_active && !cond.taken;
+                cond.taken = true;
+                Ok(())
+            }
+            "%endif" => {
+                match self.conditionals.pop() {
+                    Some(_) => Ok(()),
+                    None => directive_error!(directive, "'%endif' without matching '%if'")
+                }
+            }
+            // Everything below only runs in active blocks
+            _ if !self.is_active() => Ok(()),
+            "%endrep" => directive_error!(directive, "'%endrep' without matching '%rep'"),
+            "%endmacro" => directive_error!(directive, "'%endmacro' without matching '%macro'"),
+            "%args" | "%argc" | "%foreach" | "%endforeach" => {
+                directive_error!(directive, "'{}' can only be used inside a variadic macro", directive.text)
+            }
+            "%str" => directive_error!(directive, "'%str' can only be used inside a macro"),
+            "%define" | "%redef" => {
+                let name = Preprocessor::expect_name(directive, args)?.to_string();
+                let value = args[1..].to_vec();
+
+                if self.macros.contains_key(&name) {
+                    directive_error!(directive, "'{}' is already defined as a macro. Use '%undef' first", name)
+                }
+                if directive.text == "%define" {
+                    // Repeating an identical definition is harmless
+                    if let Some(old) = self.defines.get(&name) {
+                        let same = old.len() == value.len() && old.iter().zip(value.iter())
+                            .all(|(a, b)| a.kind == b.kind && a.text == b.text);
+                        if !same {
+                            directive_error!(directive, "'{}' is already defined with a different value. \
+                                Use '%redef' to change it or '%undef' it first", name)
+                        }
+                    }
+                }
+
+                self.defines.insert(name, value);
+                Ok(())
+            }
+            "%undef" => {
+                let name = Preprocessor::expect_name(directive, args)?;
+                if args.len() > 1 {
+                    directive_error!(args[1], "Unexpected {:?} \"{}\" after '%undef {}'",
+                        args[1].kind, args[1].text, name)
+                }
+                self.defines.remove(name);
+                self.macros.remove(name);
+                Ok(())
+            }
+            "%push" => {
+                let name = match args {
+                    [] => String::new(),
+                    [name] if name.kind == LexerToken::Identifier => name.text.clone(),
+                    _ => directive_error!(directive, "Expected an optional context name after '%push'")
+                };
+                self.contexts.push(Context {
+                    name,
+                    span: directive.span.clone(),
+                    defines: self.defines.clone(),
+                    macros: self.macros.clone()
+                });
+                Ok(())
+            }
+            "%pop" => {
+                let context = match self.contexts.pop() {
+                    Some(c) => c,
+                    None => directive_error!(directive, "'%pop' without matching '%push'")
+                };
+                match args {
+                    [] => {},
+                    [name] if name.text == context.name => {},
+                    [name] => directive_error!(name, "'%pop {}' does not match '%push {}' at {}..{}",
+                        name.text, context.name, context.span.start, context.span.end),
+                    _ => directive_error!(directive, "Expected an optional context name after '%pop'")
+                }
+                self.defines = context.defines;
+                self.macros = context.macros;
+                Ok(())
+            }
+            "%once" => {
+                if let Some(arg) = args.first() {
+                    directive_error!(arg, "Unexpected {:?} \"{}\" after '%once'", arg.kind, arg.text)
+                }
+                self.once_files.insert(canonical_path(&self.file_name));
+                Ok(())
+            }
+            "%assign" => {
+                let name = Preprocessor::expect_name(directive, args)?.to_string();
+                if self.macros.contains_key(&name) {
+                    directive_error!(directive, "'{}' is already defined as a macro. Use '%undef' first", name)
+                }
+                let value = self.evaluate_arguments(directive, &args[1..])?;
+                self.defines.insert(name, vec![integer_token(value, &directive.span)]);
+                Ok(())
+            }
+            _ => directive_error!(directive, "Invalid preprocessor directive '{}'", directive.text)
+        }
+    }
+
+    fn line_end(tokens: &[OwnedToken], mut idx: usize) -> usize {
+        while idx < tokens.len() && tokens[idx].kind != LexerToken::Newline
+            && tokens[idx].kind != LexerToken::Comment
+        {
+            idx += 1;
+        }
+        idx
+    }
+
+    /**
+     * Expands a '%rep count[, counter]' block. The optional counter symbol
+     * is defined to the current iteration index inside the body.
+     */
+    fn repeat(&mut self, directive: &OwnedToken, args: &[OwnedToken], body: &[OwnedToken],
+        result: &mut dyn TokenSink) -> Result<(), String>
+    {
+        let (count_args, counter) = match args.iter().position(|t| t.kind == LexerToken::Comma) {
+            Some(comma) => match &args[comma + 1..] {
+                [name] if name.kind == LexerToken::Identifier => (&args[..comma], Some(name.text.clone())),
+                _ => directive_error!(args[comma], "Expected a counter name after ',' in '%rep'")
+            },
+            None => (args, None)
+        };
+
+        let count = self.evaluate_arguments(directive, count_args)?;
+        if count < 0 {
+            directive_error!(directive, "Negative repetition count {} for '%rep'", count)
+        }
+
+        let previous = match &counter {
+            Some(name) => self.defines.get(name).cloned(),
+            None => None
+        };
+        let depth = self.conditionals.len();
+
+        for i in 0..count {
+            if let Some(name) = &counter {
+                self.defines.insert(name.clone(), vec![integer_token(i, &directive.span)]);
+            }
+
+            self.process(body, result)?;
+
+            if self.conditionals.len() != depth {
+                directive_error!(directive, "Conditional block is not closed inside '%rep' body")
+            }
+        }
+
+        if let Some(name) = counter {
+            match previous {
+                Some(p) => self.defines.insert(name, p),
+                None => self.defines.remove(&name)
+            };
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Finds the token closing a block whose body starts at `idx`, skipping nested blocks.
+     */
+    fn find_block_end(directive: &OwnedToken, tokens: &[OwnedToken], mut idx: usize,
+        open: &str, close: &str) -> Result<usize, String>
+    {
+        let mut depth = 0;
+
+        while idx < tokens.len() {
+            if tokens[idx].kind == LexerToken::PreprocessorInstruction {
+                if tokens[idx].text == open {
+                    depth += 1;
+                } else if tokens[idx].text == close {
+                    if depth == 0 {
+                        return Ok(idx)
+                    }
+                    depth -= 1;
+                }
+            }
+            idx += 1;
+        }
+
+        directive_error!(directive, "Unterminated '{}': '{}' expected", open, close)
+    }
+
+    /**
+     * Parses a '%macro name(a, b, ...)' header
+     */
+    fn define_macro(&mut self, directive: &OwnedToken, args: &[OwnedToken], body: &[OwnedToken]) -> Result<(), String> {
+        let name = Preprocessor::expect_name(directive, args)?.to_string();
+
+        let mut mac = Macro {
+            params: Vec::new(),
+            variadic: false,
+            body: body.to_vec()
+        };
+
+        let mut header = args[1..].iter();
+
+        match header.next() {
+            None => {}
+            Some(t) if t.kind == LexerToken::LParen => {
+                let mut expect_param = true;
+                loop {
+                    let token = match header.next() {
+                        Some(t) => t,
+                        None => directive_error!(directive, "Expected ')' in header of macro '{}'", name)
+                    };
+                    match token.kind {
+                        LexerToken::RParen if !expect_param || mac.params.len() == 0 => break,
+                        LexerToken::Identifier if expect_param && !mac.variadic => {
+                            if mac.params.contains(&token.text) {
+                                directive_error!(token, "Duplicate parameter '{}' in macro '{}'", token.text, name)
+                            }
+                            mac.params.push(token.text.clone());
+                        }
+                        LexerToken::Ellipsis if expect_param && !mac.variadic => mac.variadic = true,
+                        LexerToken::Comma if !expect_param && !mac.variadic => {}
+                        _ => directive_error!(token, "Unexpected {:?} \"{}\" in header of macro '{}'",
+                            token.kind, token.text, name)
+                    }
+                    expect_param = !expect_param;
+                }
+                if let Some(t) = header.next() {
+                    directive_error!(t, "Unexpected {:?} \"{}\" after header of macro '{}'", t.kind, t.text, name)
+                }
+            }
+            Some(t) => directive_error!(t, "Expected '(' after macro name '{}'", name)
+        }
+
+        if self.macros.contains_key(&name) {
+            directive_error!(directive, "Macro '{}' is already defined. Use '%undef' before redefining it", name)
+        }
+        if self.defines.contains_key(&name) {
+            directive_error!(directive, "'{}' is already defined as a symbol. Use '%undef' first", name)
+        }
+
+        self.macros.insert(name, Rc::new(mac));
+
+        Ok(())
+    }
+
+    /**
+     * Reads the comma separated arguments of a macro call starting at `idx`.
+     * Returns the arguments and the index of the first token after the call.
+     */
+    fn read_macro_arguments(call: &OwnedToken, tokens: &[OwnedToken], mut idx: usize)
+        -> Result<(Vec<Vec<OwnedToken>>, usize), String>
+    {
+        let mut args = Vec::<Vec<OwnedToken>>::new();
+
+        match tokens.get(idx) {
+            Some(t) if t.kind == LexerToken::LParen => idx += 1,
+            _ => return Ok((args, idx))
+        }
+
+        let mut current = Vec::<OwnedToken>::new();
+        let mut depth = 0;
+
+        loop {
+            let token = match tokens.get(idx) {
+                Some(t) => t,
+                None => directive_error!(call, "Unterminated argument list for macro '{}'", call.text)
+            };
+            idx += 1;
+
+            match token.kind {
+                LexerToken::Newline | LexerToken::Comment => {
+                    directive_error!(call, "Unterminated argument list for macro '{}'", call.text)
+                }
+                LexerToken::LParen => depth += 1,
+                LexerToken::RParen if depth == 0 => {
+                    if current.len() != 0 || args.len() != 0 {
+                        args.push(current);
+                    }
+                    return Ok((args, idx))
+                }
+                LexerToken::RParen => depth -= 1,
+                LexerToken::Comma if depth == 0 => {
+                    args.push(current);
+                    current = Vec::new();
+                    continue
+                }
+                _ => {}
+            }
+            current.push(token.clone());
+        }
+    }
+
+    /**
+     * Substitutes parameters, '%args', '%argc', '%foreach' blocks and
+     * '%%' local labels in a macro body
+     */
+    fn substitute(&self, call: &OwnedToken, body: &[OwnedToken], bindings: &HashMap<String, Vec<OwnedToken>>,
+        variadic: &[Vec<OwnedToken>], result: &mut Vec<OwnedToken>) -> Result<(), String>
+    {
+        let mut idx = 0;
+
+        while idx < body.len() {
+            let token = &body[idx];
+            idx += 1;
+
+            match (token.kind, token.text.as_str()) {
+                (LexerToken::Identifier, name) if bindings.contains_key(name) => {
+                    result.extend(bindings[name].iter().cloned());
+                }
+                // 'param:' defines a label named by the argument
+                (LexerToken::Label, name) if bindings.contains_key(&name[..name.len() - 1]) => {
+                    let arg = &bindings[&name[..name.len() - 1]];
+                    match arg.as_slice() {
+                        [t] if t.kind == LexerToken::Identifier => result.push(OwnedToken {
+                            kind: LexerToken::Label,
+                            text: format!("{}:", t.text),
+                            span: token.span.clone()
+                        }),
+                        _ => directive_error!(call, "Argument for label '{}' in macro '{}' \
+                            must be a single identifier", name, call.text)
+                    }
+                }
+                // '%%name' becomes a sublabel unique to this expansion. The '@' prefix
+                // keeps it from replacing the enclosing label for '@' sublabels.
+                (LexerToken::MacroLocal, name) => {
+                    result.push(OwnedToken {
+                        kind: LexerToken::Identifier,
+                        text: format!("@__{}_{}_{}", call.text, self.invocation_count, &name[2..]),
+                        span: token.span.clone()
+                    });
+                }
+                (LexerToken::MacroLocalLabel, name) => {
+                    result.push(OwnedToken {
+                        kind: LexerToken::Label,
+                        text: format!("@__{}_{}_{}", call.text, self.invocation_count, &name[2..]),
+                        span: token.span.clone()
+                    });
+                }
+                (LexerToken::PreprocessorInstruction, "%str") => {
+                    let (inner, next) = Preprocessor::read_macro_arguments(token, body, idx)?;
+                    if next == idx {
+                        directive_error!(call, "Expected '(' after '%str' in macro '{}'", call.text)
+                    }
+                    idx = next;
+
+                    let mut spelled = Vec::<String>::new();
+                    for arg in inner.iter() {
+                        let mut arg_tokens = Vec::<OwnedToken>::new();
+                        for t in arg {
+                            match bindings.get(&t.text) {
+                                Some(bound) if t.kind == LexerToken::Identifier => arg_tokens.extend(bound.iter().cloned()),
+                                _ => arg_tokens.push(t.clone())
+                            }
+                        }
+                        spelled.push(spell_tokens(&arg_tokens));
+                    }
+
+                    result.push(OwnedToken {
+                        kind: LexerToken::String,
+                        text: format!("\"{}\"", spelled.join(", ")),
+                        span: token.span.clone()
+                    });
+                }
+                (LexerToken::PreprocessorInstruction, "%args") => {
+                    for arg in variadic {
+                        result.extend(arg.iter().cloned());
+                    }
+                }
+                (LexerToken::PreprocessorInstruction, "%argc") => {
+                    result.push(integer_token(variadic.len() as i64, &call.span));
+                }
+                (LexerToken::PreprocessorInstruction, "%foreach") => {
+                    let var = match body.get(idx) {
+                        Some(t) if t.kind == LexerToken::Identifier => t.text.clone(),
+                        _ => directive_error!(call, "Expected identifier after '%foreach' in macro '{}'", call.text)
+                    };
+                    let start = Preprocessor::line_end(body, idx);
+                    let end = Preprocessor::find_block_end(token, body, start, "%foreach", "%endforeach")?;
+
+                    for arg in variadic {
+                        let mut inner = bindings.clone();
+                        inner.insert(var.clone(), arg.clone());
+                        self.substitute(call, &body[start..end], &inner, variadic, result)?;
+                    }
+
+                    idx = Preprocessor::line_end(body, end + 1);
+                }
+                _ => result.push(token.clone())
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Operands of '%+' that are builtins are replaced by their value before pasting,
+     * so 'label_ %+ __COUNTER__' gives a fresh label each time
+     */
+    fn paste_operand(&self, call: &OwnedToken, token: OwnedToken) -> OwnedToken {
+        // 'label_ %+ __COUNTER__:' lexes the builtin as a label
+        let (name, suffix) = match token.kind {
+            LexerToken::Identifier => (token.text.as_str(), ""),
+            LexerToken::Label => (&token.text[..token.text.len() - 1], ":"),
+            _ => return token
+        };
+        let at_call = OwnedToken {
+            kind: LexerToken::Identifier,
+            text: name.to_string(),
+            span: call.span.clone()
+        };
+
+        match self.builtin(&at_call) {
+            Some(value) => OwnedToken {
+                kind: value.kind,
+                text: value.text + suffix,
+                span: token.span
+            },
+            None => token
+        }
+    }
+
+    /**
+     * Joins the tokens around every '%+' into a single token
+     */
+    fn paste_tokens(&self, call: &OwnedToken, tokens: Vec<OwnedToken>) -> Result<Vec<OwnedToken>, String> {
+        let mut result = Vec::<OwnedToken>::with_capacity(tokens.len());
+        let mut iter = tokens.into_iter();
+
+        while let Some(token) = iter.next() {
+            if token.kind != LexerToken::Paste {
+                result.push(token);
+                continue
+            }
+            let lhs = match result.pop() {
+                Some(t) => t,
+                None => directive_error!(call, "'%+' has no left operand in macro '{}'", call.text)
+            };
+            let rhs = match iter.next() {
+                Some(t) if t.kind != LexerToken::Paste => t,
+                _ => directive_error!(call, "'%+' has no right operand in macro '{}'", call.text)
+            };
+
+            let lhs = self.paste_operand(call, lhs);
+            let rhs = self.paste_operand(call, rhs);
+
+            let text = lhs.text + &rhs.text;
+            let pasted = self.lexer.tokenize(&text);
+
+            match pasted.as_slice() {
+                [t] => result.push(OwnedToken {
+                    kind: t.kind,
+                    text: text.clone(),
+                    span: lhs.span
+                }),
+                _ => directive_error!(call, "Pasting in macro '{}' gives \"{}\", \
+                    which is not a single token", call.text, text)
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn expand_macro(&mut self, call: &OwnedToken, args: Vec<Vec<OwnedToken>>, result: &mut dyn TokenSink)
+        -> Result<(), String>
+    {
+        let mac = self.macros[&call.text].clone();
+
+        if self.expansion_stack.len() >= self.max_macro_depth {
+            if self.expansion_stack.contains(&call.text) {
+                directive_error!(call, "Recursive macro '{}' exceeded expansion depth limit of {}",
+                    call.text, self.max_macro_depth)
+            }
+            directive_error!(call, "Macro expansion depth limit of {} exceeded ({} -> {})",
+                self.max_macro_depth, self.expansion_stack.join(" -> "), call.text)
+        }
+
+        if args.len() < mac.params.len() || (!mac.variadic && args.len() > mac.params.len()) {
+            directive_error!(call, "Macro '{}' takes {}{} arguments, {} provided", call.text,
+                if mac.variadic { "at least " } else { "" }, mac.params.len(), args.len())
+        }
+
+        let mut bindings = HashMap::<String, Vec<OwnedToken>>::new();
+        for (param, arg) in mac.params.iter().zip(args.iter()) {
+            bindings.insert(param.clone(), arg.clone());
+        }
+
+        self.invocation_count += 1;
+
+        let mut expanded = Vec::<OwnedToken>::new();
+        self.substitute(call, &mac.body, &bindings, &args[mac.params.len()..], &mut expanded)?;
+        let mut expanded = self.paste_tokens(call, expanded)?;
+
+        // Errors inside the expansion point at the call site
+        for t in expanded.iter_mut() {
+            t.span = call.span.clone();
+        }
+
+        // Rescan the expansion so it can call other macros
+        self.expansion_stack.push(call.text.clone());
+        let processed = self.process(&expanded, result);
+        self.expansion_stack.pop();
+
+        processed
+    }
+
+    /**
+     * Finds the file named by '%include'. Quoted names are looked up next to
+     * the including file first, names in angle brackets only in include paths.
+     */
+    fn find_include(&self, name: &str, local: bool) -> Option<PathBuf> {
+        if local {
+            let dir = Path::new(&self.file_name).parent().unwrap_or(Path::new(""));
+            let path = dir.join(name);
+            if path.is_file() {
+                return Some(path)
+            }
+        }
+
+        self.include_paths.iter()
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+
+    /**
+     * Handles '%include "file"' and '%include <file>'
+     */
+    fn include(&mut self, directive: &OwnedToken, args: &[OwnedToken], result: &mut dyn TokenSink)
+        -> Result<(), String>
+    {
+        let (name, local) = match args {
+            [path] if path.kind == LexerToken::String => (path.text[1..path.text.len() - 1].to_string(), true),
+            [open, path @ .., close] if open.kind == LexerToken::Less && close.kind == LexerToken::Greater
+                && !path.is_empty() => (spell_tokens(path), false),
+            _ => directive_error!(directive, "Expected \"file\" or <file> after '%include'")
+        };
+
+        let path = match self.find_include(&name, local) {
+            Some(p) => p,
+            None => directive_error!(directive, "Included file '{}' not found", name)
+        };
+        let canonical = canonical_path(&path.to_string_lossy());
+
+        if self.once_files.contains(&canonical) {
+            return Ok(())
+        }
+        if self.include_stack.iter().chain(Some(&self.file_name)).any(|f| canonical_path(f) == canonical) {
+            let mut chain = self.include_stack.clone();
+            chain.push(self.file_name.clone());
+            chain.push(path.to_string_lossy().to_string());
+            directive_error!(directive, "Recursive include of '{}' ({})", name, chain.join(" -> "))
+        }
+        let source = match self.sources.get(&canonical) {
+            Some(s) => s.clone(),
+            None => {
+                let code = match fs::read_to_string(&path) {
+                    Ok(c) => c,
+                    Err(e) => directive_error!(directive, "Failed to read included file '{}': {}",
+                        path.display(), e)
+                };
+                let source = Rc::new(Source {
+                    line_starts: Rc::new(line_starts(&code)),
+                    code
+                });
+                self.sources.insert(canonical, source.clone());
+                source
+            }
+        };
+
+        // Spans of the included tokens refer to the included file
+        let depth = self.conditionals.len();
+        self.include_stack.push(std::mem::replace(&mut self.file_name, path.to_string_lossy().to_string()));
+        let line_starts = std::mem::replace(&mut self.line_starts, source.line_starts.clone());
+        result.push(line_marker(1, &self.file_name, &directive.span));
+
+        let lexer = self.lexer.clone();
+        let tokens = lexer.tokens(&source.code).map(|t| OwnedToken::from_token(&t));
+        let mut processed = self.process_lines(tokens, result);
+        if processed.is_ok() && self.conditionals.len() != depth {
+            let cond = self.conditionals.last().unwrap();
+            processed = Err(format!("Unterminated '{}' starting at {}..{}: '%endif' expected",
+                cond.directive, cond.span.start, cond.span.end));
+        }
+        let included = std::mem::replace(&mut self.file_name, self.include_stack.pop().unwrap());
+        self.line_starts = line_starts;
+        result.push(line_marker(self.line_of(directive.span.start), &self.file_name, &directive.span));
+
+        match processed {
+            Ok(()) => Ok(()),
+            Err(e) => directive_error!(directive, "In file '{}':\n{}\nIncluded", included, e)
+        }
+    }
+
+    fn process(&mut self, tokens: &[OwnedToken], result: &mut dyn TokenSink) -> Result<(), String> {
+        let mut idx = 0;
+
+        while idx < tokens.len() {
+            let token = &tokens[idx];
+            idx += 1;
+
+            if token.kind == LexerToken::PreprocessorInstruction {
+                let start = idx;
+                idx = Preprocessor::line_end(tokens, idx);
+
+                if token.text == "%rep" && self.is_active() {
+                    let end = Preprocessor::find_block_end(token, tokens, idx, "%rep", "%endrep")?;
+                    self.repeat(token, &tokens[start..idx], &tokens[idx..end], result)?;
+                    idx = Preprocessor::line_end(tokens, end + 1);
+                    continue
+                }
+                if token.text == "%macro" && self.is_active() {
+                    let end = Preprocessor::find_block_end(token, tokens, idx, "%macro", "%endmacro")?;
+                    self.define_macro(token, &tokens[start..idx], &tokens[idx..end])?;
+                    idx = Preprocessor::line_end(tokens, end + 1);
+                    continue
+                }
+                if token.text == "%include" && self.is_active() {
+                    self.include(token, &tokens[start..idx], result)?;
+                    continue
+                }
+
+                self.do_directive(token, &tokens[start..idx])?;
+                continue
+            }
+
+            if !self.is_active() {
+                // Keep line structure so the parser still sees instruction ends
+                if token.kind == LexerToken::Newline || token.kind == LexerToken::Comment {
+                    result.push(token.clone());
+                }
+                continue
+            }
+
+            if token.kind == LexerToken::MacroLocal || token.kind == LexerToken::MacroLocalLabel {
+                directive_error!(token, "Local label '{}' can only be used inside a macro", token.text)
+            }
+            if token.kind == LexerToken::Paste {
+                directive_error!(token, "'%+' can only be used inside a macro")
+            }
+            if self.line_markers && token.kind != LexerToken::Newline && token.kind != LexerToken::Comment {
+                self.mark_line(token, result);
+            }
+
+            if token.kind == LexerToken::Identifier && self.macros.contains_key(&token.text) {
+                let (args, next) = Preprocessor::read_macro_arguments(token, tokens, idx)?;
+                idx = next;
+                self.expand_macro(token, args, result)?;
+                continue
+            }
+
+            self.expand_define(token, result, 0)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn preprocess(&mut self, tokens: &Vec<Token<LexerToken>>) -> Result<Vec<OwnedToken>, String> {
+        let mut result = Vec::<OwnedToken>::new();
+        self.preprocess_into(tokens, &mut result)?;
+        Ok(result)
+    }
+
+    /**
+     * Like `preprocess`, but hands each output token to `sink` as soon as it's
+     * ready. '%rep' and '%macro' blocks are only expanded once complete.
+     */
+    pub fn preprocess_into(&mut self, tokens: &[Token<LexerToken>], sink: &mut dyn TokenSink) -> Result<(), String> {
+        self.process_lines(tokens.iter().map(OwnedToken::from_token), sink)?;
+
+        if let Some(cond) = self.conditionals.last() {
+            return Err(format!("Unterminated '{}' starting at {}..{}: '%endif' expected",
+                cond.directive, cond.span.start, cond.span.end))
+        }
+        if let Some(context) = self.contexts.last() {
+            return Err(format!("Unterminated '%push' starting at {}..{}: '%pop' expected",
+                context.span.start, context.span.end))
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Runs `process` on a line of `tokens` at a time, or a whole '%rep' or
+     * '%macro' block at a time, so only that much of the input is held
+     */
+    fn process_lines(&mut self, tokens: impl Iterator<Item = OwnedToken>, sink: &mut dyn TokenSink)
+        -> Result<(), String>
+    {
+        let mut chunk = Vec::<OwnedToken>::new();
+        // Blocks opened in the chunk and not closed yet
+        let mut depth = 0usize;
+
+        for token in tokens {
+            let line_end = token.kind == LexerToken::Newline || token.kind == LexerToken::Comment;
+
+            if token.kind == LexerToken::PreprocessorInstruction {
+                match token.text.as_str() {
+                    "%rep" | "%macro" => depth += 1,
+                    "%endrep" | "%endmacro" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            chunk.push(token);
+
+            if line_end && depth == 0 {
+                self.process(&chunk, sink)?;
+                chunk.clear();
+            }
+        }
+        self.process(&chunk, sink)
+    }
+}
+
+impl Default for Preprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -78,6 +78,25 @@ impl Instruction {
     pub fn extended_opcode(&self) -> bool {
         self.opcode & 0x80 != 0
     }
+    /**
+     * Width in bytes of the memory access done through an absolute pointer
+     * operand, taken from the register it is paired with.
+     * Returns None for instructions that don't access data memory (jumps, calls).
+     */
+    pub fn memory_access_size(&self) -> Option<usize> {
+        if !self.args.iter().any(|a| matches!(a, ArgumentTypes::AbsPointer)) {
+            return None
+        }
+        for arg in self.args.iter() {
+            match arg {
+                ArgumentTypes::Register32 => return Some(4),
+                ArgumentTypes::Register16 => return Some(2),
+                ArgumentTypes::Register8 => return Some(1),
+                _ => {}
+            }
+        }
+        None
+    }
     pub fn get_size(&self) -> usize {
         let mut size = if self.extended_opcode() { 2usize } else { 1usize };
 
@@ -1,8 +1,47 @@
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/**
+ * Assembles `code` into an object, without preprocessing it
+ */
+#[cfg(test)]
+fn compile(code: &str) -> Result<crate::objgen::ObjectFormat, String> {
+    let tokens = super::lex(code, false);
+    let node = super::parse(tokens, false)?;
+    let mut obj = crate::objgen::ObjectFormat::new();
+    obj.load_parser_node(&node)?;
+    Ok(obj)
+}
+
+/**
+ * Writes `script` to a temporary file of its own and returns the path
+ */
+#[cfg(test)]
+fn script_file(script: &str, extension: &str) -> String {
+    static SCRIPTS: AtomicUsize = AtomicUsize::new(0);
+
+    let name = format!("sarch_asm_script_{}_{}.{}", std::process::id(),
+        SCRIPTS.fetch_add(1, Ordering::Relaxed), extension);
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, script).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+/**
+ * Assembles `code` and links it on its own with the link script `script`
+ */
+#[cfg(test)]
+fn link_with_script(code: &str, script: &str) -> Result<Vec<u8>, String> {
+    let mut linker = crate::linker::Linker::new();
+    linker.load_symbols(compile(code)?)?;
+    linker.generate_binary(Some(&script_file(script, "ld")))
+}
+
 #[cfg(test)]
 
 #[test]
 fn recursive_define() {
-    use crate::objgen::{ObjectFormat, Constant};
+    use crate::objgen::Constant;
 
     let code = ".section \"text\"
     .define A 12
@@ -15,10 +54,7 @@ fn recursive_define() {
     .section \"data\"
     .section \"rodata\"
     ";
-    let tokens = super::lex(code, false);
-    let node = super::parse(tokens, false).unwrap();
-    let mut obj = ObjectFormat::new();
-    obj.load_parser_node(&node).unwrap();
+    let obj = compile(code).unwrap();
 
     let instr = &obj.sections["text"].instructions[0];
 
@@ -29,3 +65,3547 @@ fn recursive_define() {
         value: 12
     })
 }
+
+#[test]
+fn conditional_assembly() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%define VERSION 3
+    %if (VERSION >= 2)
+    %ifdef DEBUG
+    loadid 1 r0
+    %elif ((VERSION == 3) && (LEVEL > 1))
+    loadid 2 r0
+    %else
+    loadid 3 r0
+    %endif
+    %else
+    %if (UNDEFINED == 1)
+    %endif
+    loadid 4 r0
+    %endif
+    ";
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.define("LEVEL", "2");
+    let tokens = preprocessor.preprocess(&tokens).unwrap();
+
+    let numbers: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind == crate::lexer::LexerToken::Integer)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(numbers, vec!["2"]);
+
+    let tokens = super::lex("%if 1\nhalt\n", false);
+    assert!(Preprocessor::new().preprocess(&tokens).is_err());
+}
+
+#[test]
+fn repetition_blocks() {
+    use crate::preprocessor::Preprocessor;
+
+    let numbers = |code: &str| -> Result<Vec<String>, String> {
+        let tokens = super::lex(code, false);
+        Ok(Preprocessor::new().preprocess(&tokens)?.iter()
+            .filter(|t| t.kind == crate::lexer::LexerToken::Integer)
+            .map(|t| t.text.clone())
+            .collect())
+    };
+
+    assert_eq!(numbers("%rep 2, i
+    %rep (i + 1)
+    .db i
+    %endrep
+    %endrep
+    ").unwrap(), vec!["0", "1", "1"]);
+
+    // A count ending in a name is still just a count
+    assert_eq!(numbers("%define SIZE 2
+    %rep 2 * SIZE
+    .db 7
+    %endrep
+    ").unwrap(), vec!["7", "7", "7", "7"]);
+    assert_eq!(numbers("%define SIZE 1
+    %rep 2 * SIZE, n
+    .db n
+    %endrep
+    ").unwrap(), vec!["0", "1"]);
+
+    let err = numbers("%rep 2, (n)\n%endrep\n").unwrap_err();
+    assert!(err.contains("Expected a counter name after ',' in '%rep'"), "{}", err);
+}
+
+#[test]
+fn assign_variables() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%assign i 0
+    %rep 3
+    .db i
+    %assign i i+1*2
+    %endrep
+    %if i == 6 && 2 + 3 * 2 == 8
+    .db 99
+    %endif
+    ";
+    let tokens = super::lex(code, false);
+    let tokens = Preprocessor::new().preprocess(&tokens).unwrap();
+
+    let numbers: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind == crate::lexer::LexerToken::Integer)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(numbers, vec!["0", "2", "4", "99"]);
+}
+
+#[test]
+fn variadic_macros() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%macro save(first, ...)
+    push first
+    %foreach reg
+    push reg
+    %endforeach
+    .db %argc %args
+    %endmacro
+    start:
+    save(r0, r1, r2)
+    save(r3)
+    ";
+    let tokens = super::lex(code, false);
+    let tokens = Preprocessor::new().preprocess(&tokens).unwrap();
+
+    let text: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(text, vec![
+        "start:",
+        "push", "r0", "push", "r1", "push", "r2", ".db", "2", "r1", "r2",
+        "push", "r3", ".db", "0"
+    ]);
+
+    let tokens = super::lex("%macro two(a, b)\nnop\n%endmacro\ntwo(1)\n", false);
+    assert!(Preprocessor::new().preprocess(&tokens).is_err());
+}
+
+#[test]
+fn nested_macros() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%macro inner(x)
+    push x
+    %endmacro
+    %macro outer(a, b)
+    inner(a)
+    inner(b)
+    %endmacro
+    outer(r0, r1)
+    ";
+    let tokens = super::lex(code, false);
+    let tokens = Preprocessor::new().preprocess(&tokens).unwrap();
+    let text: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(text, vec!["push", "r0", "push", "r1"]);
+
+    let tokens = super::lex("%macro loop()\nloop()\n%endmacro\nloop()\n", false);
+    let err = Preprocessor::new().preprocess(&tokens).unwrap_err();
+    assert!(err.contains("Recursive macro 'loop'"));
+}
+
+#[test]
+fn token_pasting() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%macro vector(name)
+    .dd isr_ %+ name
+    %endmacro
+    %macro handler(name)
+    isr_ %+ name:
+    ret
+    %endmacro
+    vector(timer)
+    handler(timer)
+    ";
+    let tokens = super::lex(code, false);
+    let tokens = Preprocessor::new().preprocess(&tokens).unwrap();
+    let text: Vec<(crate::lexer::LexerToken, &str)> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline)
+        .map(|t| (t.kind, t.text.as_str()))
+        .collect();
+
+    use crate::lexer::LexerToken::*;
+    assert_eq!(text, vec![
+        (CompilerInstruction, ".dd"), (Identifier, "isr_timer"),
+        (Label, "isr_timer:"), (Identifier, "ret")
+    ]);
+}
+
+#[test]
+fn stringify_arguments() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%macro check(cond)
+    .db %str(cond) 0
+    %endmacro
+    check((r0 == 1))
+    ";
+    let tokens = super::lex(code, false);
+    let tokens = Preprocessor::new().preprocess(&tokens).unwrap();
+    let string = tokens.iter()
+        .find(|t| t.kind == crate::lexer::LexerToken::String)
+        .unwrap();
+    assert_eq!(string.text, "\"(r0 == 1)\"");
+}
+
+#[test]
+fn undef_and_redefinition() {
+    use crate::preprocessor::Preprocessor;
+
+    let preprocess = |code: &str| {
+        let tokens = super::lex(code, false);
+        Preprocessor::new().preprocess(&tokens)
+    };
+
+    assert!(preprocess("%define A 1\n%define A 1\n").is_ok());
+    assert!(preprocess("%define A 1\n%define A 2\n").is_err());
+    assert!(preprocess("%define A 1\n%redef A 2\n").is_ok());
+    assert!(preprocess("%define A 1\n%undef A\n%define A 2\n").is_ok());
+    assert!(preprocess("%macro m()\n%endmacro\n%macro m()\n%endmacro\n").is_err());
+    assert!(preprocess("%macro m()\n%endmacro\n%undef m\n%macro m()\n%endmacro\n").is_ok());
+
+    let tokens = preprocess("%define A 1\n%undef A\n%ifdef A\nnop\n%endif\n").unwrap();
+    assert!(tokens.iter().all(|t| t.text != "nop"));
+
+    // A name is a symbol or a macro, whichever way it would change
+    for code in [
+        "%macro M()\nhalt\n%endmacro\n%redef M halt\n",
+        "%macro M()\nhalt\n%endmacro\n%define M halt\n",
+        "%macro M()\nhalt\n%endmacro\n%assign M 5\n"
+    ] {
+        assert!(preprocess(code).unwrap_err().contains("'M' is already defined as a macro. Use '%undef' first"));
+    }
+    assert!(preprocess("%define M 1\n%macro M()\n%endmacro\n").unwrap_err()
+        .contains("'M' is already defined as a symbol. Use '%undef' first"));
+
+    // '%assign' replaces a symbol like '%redef'
+    let tokens = preprocess("%define Y 1\n%assign Y (Y + 4)\n.define Z Y\n").unwrap();
+    assert!(tokens.iter().any(|t| t.text == "5"));
+}
+
+#[test]
+fn file_and_line_builtins() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%macro where()
+    .db __FILE__ __LINE__
+    %endmacro
+    nop
+    where()
+    ";
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source("main.s", code);
+    let tokens = preprocessor.preprocess(&tokens).unwrap();
+
+    let text: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(text, vec!["nop", ".db", "\"main.s\"", "5"]);
+}
+
+#[test]
+fn counter_builtin() {
+    let code = "%macro unique()
+    lbl_ %+ __COUNTER__:
+    %endmacro
+    unique()
+    unique()
+    .dw __COUNTER__
+    ";
+    let tokens = super::lex(code, false);
+    let tokens = super::preprocess(tokens, &mut crate::preprocessor::Preprocessor::new()).unwrap();
+
+    let text: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(text, vec!["lbl_0:", "lbl_1:", ".dw", "2"]);
+}
+
+#[test]
+fn include_forms() {
+    use crate::preprocessor::Preprocessor;
+    use std::fs;
+
+    let root = std::env::temp_dir().join("sarch_asm_include_forms");
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::create_dir_all(root.join("lib")).unwrap();
+    fs::write(root.join("src/defs.s"), ".db 2\n").unwrap();
+    fs::write(root.join("lib/defs.s"), ".db 1\n").unwrap();
+
+    let code = "%include \"defs.s\"\n%include <defs.s>\n";
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source(&root.join("src/main.s").to_string_lossy(), code);
+    preprocessor.include_paths.push(root.join("lib"));
+    let tokens = preprocessor.preprocess(&tokens).unwrap();
+
+    let text: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline && t.kind != crate::lexer::LexerToken::Comment)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(text, vec![".db", "2", ".db", "1"]);
+
+    // Angle brackets never look next to the including file
+    let code = "%include <defs.s>\n";
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source(&root.join("src/main.s").to_string_lossy(), code);
+    assert!(preprocessor.preprocess(&tokens).is_err());
+}
+
+#[test]
+fn include_once_and_cycles() {
+    use crate::preprocessor::Preprocessor;
+    use std::fs;
+
+    let root = std::env::temp_dir().join("sarch_asm_include_once");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("once.s"), "%once\n.db 7\n").unwrap();
+    fs::write(root.join("a.s"), "%include \"b.s\"\n").unwrap();
+    fs::write(root.join("b.s"), "%include \"a.s\"\n").unwrap();
+
+    let code = "%include \"once.s\"\n%include \"once.s\"\n";
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source(&root.join("main.s").to_string_lossy(), code);
+    let tokens = preprocessor.preprocess(&tokens).unwrap();
+    let text: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline && t.kind != crate::lexer::LexerToken::Comment)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(text, vec![".db", "7"]);
+
+    let code = "%include \"a.s\"\n";
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source(&root.join("main.s").to_string_lossy(), code);
+    let err = preprocessor.preprocess(&tokens).unwrap_err();
+    assert!(err.contains("Recursive include"));
+}
+
+#[test]
+fn format_preprocessed_tokens() {
+    use crate::preprocessor::{format_tokens, Preprocessor};
+
+    let code = "%define COUNT 4
+%macro twice(x)
+    push x
+    push x
+%endmacro
+start: ; entry
+    twice(r0)
+    loadid COUNT, r1
+";
+    let tokens = super::lex(code, false);
+    let tokens = Preprocessor::new().preprocess(&tokens).unwrap();
+
+    assert_eq!(format_tokens(&tokens),
+        "\n\nstart: ; entry\n\n    push r0\n    push r0\n\n    loadid 4, r1\n");
+}
+
+#[test]
+fn stream_preprocessed_tokens() {
+    use crate::preprocessor::{format_tokens, Preprocessor, SourceWriter, TokenSink};
+    use crate::lexer::{LexerToken, OwnedToken};
+
+    struct LineCounter {
+        lines: usize,
+        tokens: Vec<OwnedToken>
+    }
+    impl TokenSink for LineCounter {
+        fn push(&mut self, token: OwnedToken) {
+            if token.kind == LexerToken::Newline {
+                self.lines += 1;
+            }
+            self.tokens.push(token);
+        }
+    }
+
+    let code = "%define COUNT 2
+%macro twice(x)
+    push x
+    push x
+%endmacro
+start: ; entry
+%rep COUNT
+    twice(r0)
+%endrep
+    loadid COUNT, r1
+";
+    let tokens = super::lex(code, false);
+    let expected = Preprocessor::new().preprocess(&tokens).unwrap();
+
+    let mut writer = SourceWriter::new(Vec::<u8>::new());
+    Preprocessor::new().preprocess_into(&tokens, &mut writer).unwrap();
+    let text = String::from_utf8(writer.finish().unwrap()).unwrap();
+    assert_eq!(text, format_tokens(&expected));
+    assert_eq!(text.matches("    push r0\n").count(), 4);
+    assert!(text.ends_with("    loadid 2, r1\n"));
+
+    let mut counter = LineCounter { lines: 0, tokens: Vec::new() };
+    Preprocessor::new().preprocess_into(&tokens, &mut counter).unwrap();
+    assert_eq!(counter.tokens, expected);
+
+    // Lines before an error have already reached the sink
+    let tokens = super::lex("nop\nnop\n%endrep\nnop\n", false);
+    let mut counter = LineCounter { lines: 0, tokens: Vec::new() };
+    let err = Preprocessor::new().preprocess_into(&tokens, &mut counter).unwrap_err();
+    assert!(err.contains("without matching"));
+    assert_eq!(counter.lines, 2);
+}
+
+#[test]
+fn assemble_while_preprocessing() {
+    use crate::objgen::{ObjectFormat, ObjectSink};
+    use crate::preprocessor::Preprocessor;
+    use std::fs;
+    use std::io::Cursor;
+
+    let root = std::env::temp_dir().join("sarch_asm_assemble_streamed");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("table.s"), "%rep 2, i\n.dw i\n%endrep\n").unwrap();
+
+    let code = "%define COUNT 2
+    .section \"text\"
+    start:
+    %rep COUNT
+    loadid 1 r0
+    %endrep
+    @loop:
+    jpr @loop
+    .section \"data\"
+    %include \"table.s\"
+    ";
+    let main = root.join("main.s").to_string_lossy().to_string();
+    let write = |obj: &ObjectFormat| {
+        let mut cursor = Cursor::new(Vec::new());
+        obj.write_object(&mut cursor).unwrap();
+        cursor.into_inner()
+    };
+
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source(&main, code);
+    let tokens = super::preprocess(tokens, &mut preprocessor).unwrap();
+    let node = super::parse(tokens.iter().map(|t| t.as_token()).collect(), false).unwrap();
+    let mut whole = ObjectFormat::new();
+    whole.load_parser_node(&node).unwrap();
+
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source(&main, code);
+    let mut obj = ObjectFormat::new();
+    let mut sink = ObjectSink::new(&mut obj);
+    sink.tree = Some(crate::parser::ParserNode::new());
+    preprocessor.preprocess_into(&tokens, &mut sink).unwrap();
+    let streamed = sink.tree.take();
+    sink.finish().unwrap();
+
+    assert_eq!(streamed.unwrap(), node);
+    assert_eq!(write(&obj), write(&whole));
+
+    // Errors name the stage they come from
+    let tokens = super::lex("nop\n.section\nnop\n", false);
+    let mut obj = ObjectFormat::new();
+    let mut sink = ObjectSink::new(&mut obj);
+    Preprocessor::new().preprocess_into(&tokens, &mut sink).unwrap();
+    let err = sink.finish().unwrap_err();
+    assert!(err.starts_with("Error occured while generating object file:"), "{}", err);
+}
+
+#[test]
+fn push_pop_contexts() {
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%define SIZE 1
+%push lib
+%redef SIZE 2
+%define TEMP 3
+%macro helper()
+    nop
+%endmacro
+.db SIZE TEMP
+%pop lib
+.db SIZE TEMP
+";
+    let tokens = super::lex(code, false);
+    let tokens = Preprocessor::new().preprocess(&tokens).unwrap();
+    let text: Vec<&str> = tokens.iter()
+        .filter(|t| t.kind != crate::lexer::LexerToken::Newline)
+        .map(|t| t.text.as_str())
+        .collect();
+    assert_eq!(text, vec![".db", "2", "3", ".db", "1", "TEMP"]);
+
+    let tokens = super::lex("%push a\n%pop b\n", false);
+    assert!(Preprocessor::new().preprocess(&tokens).is_err());
+    let tokens = super::lex("%push a\n", false);
+    assert!(Preprocessor::new().preprocess(&tokens).is_err());
+}
+
+#[test]
+fn org_directive() {
+    let code = ".section \"text\"
+    start:
+    halt
+    .org 4 nop
+    halt
+    .section \"data\"
+    .db 1
+    .org 0x10 0xFF
+    .db 2
+    ";
+    let obj = compile(code).unwrap();
+
+    assert_eq!(obj.sections["text"].get_binary_size(), 5);
+    assert_eq!(obj.sections["data"].get_binary_size(), 0x11);
+
+    let code = ".section \"data\"
+    .db 1 2 3
+    .org 2
+    ";
+    assert!(compile(code).is_err());
+}
+
+#[test]
+fn symbol_visibility() {
+    use crate::objgen::SymbolBinding;
+    use crate::linker::Linker;
+
+    let main = compile(".global start
+    .extern helper
+    .section \"text\"
+    start:
+    loop:
+    jpr helper
+    jpr loop
+    .section \"data\"
+    .section \"rodata\"
+    ").unwrap();
+    assert_eq!(main.sections["text"].labels["start"].binding, SymbolBinding::Global);
+    assert_eq!(main.sections["text"].labels["loop"].binding, SymbolBinding::Local);
+
+    let lib = compile(".global helper
+    .section \"text\"
+    helper:
+    loop:
+    jpr loop
+    ").unwrap();
+
+    // Both objects define a local 'loop'
+    let mut linker = Linker::new();
+    linker.load_symbols(main).unwrap();
+    linker.load_symbols(lib).unwrap();
+    assert!(linker.generate_binary(None).is_ok());
+
+    // Referencing an undeclared symbol is caught while assembling
+    assert!(compile(".global start\nstart:\njpr helper\n").is_err());
+
+    // Labels that aren't exported can't be reached from other objects
+    let user = compile(".extern loop\n.section \"data\"\n.dd loop\n.section \"rodata\"\n").unwrap();
+    let lib = compile(".global helper\nhelper:\nloop:\njpr loop\n").unwrap();
+    let mut linker = Linker::new();
+    linker.load_symbols(lib).unwrap();
+    linker.load_symbols(user).unwrap();
+    let err = linker.generate_binary(None).unwrap_err();
+    assert!(err.contains("'loop'"));
+}
+
+#[test]
+fn weak_symbols() {
+    use crate::linker::Linker;
+
+    let main = ".section \"text\"
+    start:
+    jpr handler
+    .section \"data\"
+    .section \"rodata\"
+    ";
+    let library = ".weak handler
+    .section \"text\"
+    handler:
+    halt
+    ";
+    let user = ".section \"text\"
+    handler:
+    nop
+    ";
+
+    // A single weak definition is used as is
+    let mut linker = Linker::new();
+    linker.load_symbols(compile(main).unwrap()).unwrap();
+    linker.load_symbols(compile(library).unwrap()).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    assert_eq!(&binary[0..6], &[0x0C, 5, 0, 0, 0, 0x01]);
+
+    // A strong definition overrides it, whichever is loaded first
+    for order in [[library, user], [user, library]] {
+        let mut linker = Linker::new();
+        linker.load_symbols(compile(main).unwrap()).unwrap();
+        for code in order {
+            linker.load_symbols(compile(code).unwrap()).unwrap();
+        }
+        let binary = linker.generate_binary(None).unwrap();
+        let target = binary[1] as usize;
+        assert_eq!(binary[target], 0x00);
+    }
+}
+
+#[test]
+fn local_labels_do_not_clash() {
+    use crate::objgen::SymbolBinding;
+    use crate::linker::Linker;
+
+    let first = compile(".local loop
+    .section \"text\"
+    start:
+    loop:
+    jpr loop
+    .section \"data\"
+    .section \"rodata\"
+    ").unwrap();
+    let second = compile(".local loop
+    .section \"text\"
+    other:
+    loop:
+    jpr loop
+    ").unwrap();
+    assert_eq!(first.sections["text"].labels["start"].binding, SymbolBinding::Default);
+    assert_eq!(first.sections["text"].labels["loop"].binding, SymbolBinding::Local);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(first).unwrap();
+    linker.load_symbols(second).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    // Each 'jpr loop' jumps to itself
+    assert_eq!(&binary[0..10], &[0x0C, 0, 0, 0, 0, 0x0C, 0, 0, 0, 0]);
+}
+
+#[test]
+fn ascii_directives() {
+    let code = ".section \"rodata\"
+    .ascii \"hi\\n\"
+    .asciz \"a\\\"b\" \"\\x41\"
+    ";
+    let obj = compile(code).unwrap();
+
+    let bytes: Vec<i64> = obj.sections["rodata"].binary_data.iter()
+        .map(|u| u.constant.as_ref().unwrap().value)
+        .collect();
+    assert_eq!(bytes, vec![
+        'h' as i64, 'i' as i64, '\n' as i64,
+        'a' as i64, '"' as i64, 'b' as i64, 0,
+        'A' as i64, 0
+    ]);
+}
+
+#[test]
+fn fill_directives() {
+    use crate::objgen::ConstantSize;
+
+    let code = ".section \"data\"
+    .fill 3, 2, 0xBEEF
+    .space 2 0xFF
+    .fill 1
+    ";
+    let obj = compile(code).unwrap();
+
+    let units: Vec<(ConstantSize, i64)> = obj.sections["data"].binary_data.iter()
+        .map(|u| u.constant.as_ref().unwrap())
+        .map(|c| (c.size, c.value))
+        .collect();
+    assert_eq!(units, vec![
+        (ConstantSize::Word, 0xBEEF), (ConstantSize::Word, 0xBEEF), (ConstantSize::Word, 0xBEEF),
+        (ConstantSize::Byte, 0xFF), (ConstantSize::Byte, 0xFF),
+        (ConstantSize::Byte, 0)
+    ]);
+    assert_eq!(obj.sections["data"].get_binary_size(), 9);
+}
+
+#[test]
+fn reserve_directives() {
+    let code = ".section \"data\"
+    buffer:
+    .resb 3
+    words:
+    .resw 2
+    dwords:
+    .resd 4
+    end:
+    ";
+    let obj = compile(code).unwrap();
+
+    let data = &obj.sections["data"];
+    assert_eq!(data.get_label_binary_offset("words"), Some(3));
+    assert_eq!(data.get_label_binary_offset("dwords"), Some(7));
+    assert_eq!(data.get_binary_size(), 23);
+}
+
+#[test]
+fn quad_word_data() {
+    use crate::linker::Linker;
+
+    let code = ".section \"text\"
+    halt
+    .section \"data\"
+    id:
+    .dq 0xFEDCBA9876543210 id
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+    assert_eq!(obj.sections["data"].get_binary_size(), 16);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    assert_eq!(&binary[0x100..0x110], &[
+        0x10, 0x32, 0x54, 0x76, 0x98, 0xBA, 0xDC, 0xFE,
+        0x00, 0x01, 0, 0, 0, 0, 0, 0
+    ]);
+}
+
+#[test]
+fn float_data() {
+    use crate::objgen::ConstantSize;
+
+    let code = ".section \"rodata\"
+    .float 1.5 -2.0 3
+    .double 0.1
+    ";
+    let obj = compile(code).unwrap();
+
+    let units: Vec<(ConstantSize, i64)> = obj.sections["rodata"].binary_data.iter()
+        .map(|u| u.constant.as_ref().unwrap())
+        .map(|c| (c.size, c.value))
+        .collect();
+    assert_eq!(units, vec![
+        (ConstantSize::DoubleWord, 1.5f32.to_bits() as i64),
+        (ConstantSize::DoubleWord, (-2.0f32).to_bits() as i64),
+        (ConstantSize::DoubleWord, 3.0f32.to_bits() as i64),
+        (ConstantSize::QuadWord, 0.1f64.to_bits() as i64)
+    ]);
+}
+
+#[test]
+fn constant_expressions() {
+    use crate::objgen::{Constant, ConstantSize};
+
+    let code = ".define BASE 0x10
+    .define SIZE (BASE * 2 + 1)
+    .define NEG -4
+    .section \"text\"
+    loadid (SIZE - 1) r0
+    loadid (NEG * 2) r1
+    .section \"data\"
+    .dw (BASE + 1) SIZE
+    .db -1
+    .resb (2 * 2)
+    ";
+    let obj = compile(code).unwrap();
+
+    let text = &obj.sections["text"];
+    assert_eq!(text.instructions[0].constants[0], Constant {
+        argument_pos: 0, size: ConstantSize::DoubleWord, value: 0x20
+    });
+    assert_eq!(text.instructions[1].constants[0], Constant {
+        argument_pos: 0, size: ConstantSize::DoubleWord, value: -8
+    });
+
+    let values: Vec<i64> = obj.sections["data"].binary_data.iter()
+        .map(|u| u.constant.as_ref().unwrap().value)
+        .collect();
+    assert_eq!(values, vec![0x11, 0x21, -1, 0, 0, 0, 0]);
+}
+
+#[test]
+fn label_differences() {
+    use crate::linker::Linker;
+
+    let code = ".section \"text\"
+    start:
+    loadid (msg_end - msg_start) r0
+    halt
+    .section \"data\"
+    msg_start:
+    .ascii \"hello\"
+    msg_end:
+    .dw (msg_end - msg_start)
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+
+    let reference = &obj.sections["text"].instructions[0].references[0];
+    assert_eq!(reference.rf, "msg_end");
+    assert_eq!(reference.subtrahend.as_deref(), Some("msg_start"));
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    // loadid 5 r0
+    assert_eq!(&binary[0..6], &[0x05, 5, 0, 0, 0, 0]);
+    assert_eq!(&binary[0x100..0x107], b"hello\x05\x00");
+
+    // Every object has its own local 'start' and 'end', in different sections
+    let object = |code: &str| compile(code).unwrap();
+    let mut linker = Linker::new();
+    linker.load_symbols(object(".local start end
+    .section \"text\"
+    start:
+    nop
+    nop
+    nop
+    .section \"rodata\"
+    end:
+    .db 0
+    .section \"data\"
+    .dd (end - start)
+    .dd (end - start - 1)
+    ")).unwrap();
+    linker.load_symbols(object(".local start end
+    .section \"text\"
+    start:
+    nop
+    .section \"rodata\"
+    end:
+    .db 0
+    .section \"data\"
+    .dd (end - start)
+    ")).unwrap();
+    let binary = linker.generate_binary(Some(&script_file("SECTIONS { text rodata data }", "ld"))).unwrap();
+    // The second object's 'start' is at 3 and its 'end' at 5
+    assert_eq!(&binary[6..], &[4, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0]);
+
+    let err = compile(".section \"data\"
+    start:
+    .db 1 2
+    end:
+    .dw ((end - start) / 2)
+    ").unwrap_err();
+    assert!(err.contains("Label differences must be of the form (a - b), plus or minus constants: \
+        Division can't be applied to labels"), "{}", err);
+}
+
+#[test]
+fn size_symbols() {
+    use crate::linker::Linker;
+
+    let code = ".global start
+    .section \"text\"
+    start:
+    loadid buffer_size r0
+    loadid table_size r1
+    .section \"data\"
+    buffer:
+    .resb 12
+    table:
+    .dw 1 2 3
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    // loadid 12 r0; loadid 6 r1
+    assert_eq!(&binary[0..12], &[0x05, 12, 0, 0, 0, 0, 0x05, 6, 0, 0, 0, 1]);
+}
+
+#[test]
+fn data_file_ranges() {
+    use std::fs;
+
+    let path = std::env::temp_dir().join("sarch_asm_data_ranges.bin");
+    fs::write(&path, [0u8, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+    let path = path.to_str().unwrap();
+
+    let values = |args: &str| -> Result<Vec<i64>, String> {
+        let code = format!(".section \"data\"\n.data \"{}\" {}\n", path, args);
+        let obj = compile(&code)?;
+        Ok(obj.sections["data"].binary_data.iter()
+            .map(|u| u.constant.as_ref().unwrap().value)
+            .collect())
+    };
+
+    assert_eq!(values("").unwrap(), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(values("5").unwrap(), vec![5, 6, 7]);
+    assert_eq!(values("2 3").unwrap(), vec![2, 3, 4]);
+    assert!(values("6 3").unwrap_err().contains("out of bounds"));
+}
+
+#[test]
+fn times_directive() {
+    let code = ".define N 3
+    .section \"text\"
+    .times N nop
+    .times 2 loadid (N + 1) r0
+    .section \"data\"
+    .times 4 .db 0xFF
+    .times 2 .times 2 .dw 7
+    ";
+    let obj = compile(code).unwrap();
+
+    let opcodes: Vec<u16> = obj.sections["text"].instructions.iter()
+        .map(|i| i.opcode)
+        .collect();
+    assert_eq!(opcodes, vec![0, 0, 0, 5, 5]);
+    assert_eq!(obj.sections["text"].instructions[4].constants[0].value, 4);
+
+    let values: Vec<i64> = obj.sections["data"].binary_data.iter()
+        .map(|u| u.constant.as_ref().unwrap().value)
+        .collect();
+    assert_eq!(values, vec![0xFF, 0xFF, 0xFF, 0xFF, 7, 7, 7, 7]);
+}
+
+#[test]
+fn dup_arguments() {
+    let code = ".define N 2
+    .section \"data\"
+    .db 0xAA dup(3) 1
+    .dw 7, 0 dup(N * 2)
+    ";
+    let obj = compile(code).unwrap();
+
+    let values: Vec<i64> = obj.sections["data"].binary_data.iter()
+        .map(|u| u.constant.as_ref().unwrap().value)
+        .collect();
+    assert_eq!(values, vec![0xAA, 0xAA, 0xAA, 1, 7, 0, 0, 0, 0]);
+}
+
+#[test]
+fn equ_and_set() {
+    let obj = compile(".equ WIDTH, 8
+    .set OFFSET, 0
+    .section \"data\"
+    .db OFFSET
+    .set OFFSET, (OFFSET + WIDTH)
+    .db OFFSET
+    .set OFFSET, (OFFSET + WIDTH)
+    .db OFFSET WIDTH
+    ").unwrap();
+    let values: Vec<i64> = obj.sections["data"].binary_data.iter()
+        .map(|u| u.constant.as_ref().unwrap().value)
+        .collect();
+    assert_eq!(values, vec![0, 8, 16, 8]);
+
+    let err = compile(".equ WIDTH, 8\n.set WIDTH, 9\n").err().unwrap();
+    assert!(err.contains("Cannot reassign 'WIDTH'"));
+    let err = compile(".set WIDTH, 8\n.equ WIDTH, 9\n").err().unwrap();
+    assert!(err.contains("already defined"));
+}
+
+#[test]
+fn section_flags() {
+    use crate::objgen::{ObjectFormat, SectionFlags};
+    use std::fs;
+
+    let code = ".section \"text\"
+    nop
+    .section \"vectors\" \"rx\"
+    nop
+    .section \"rodata\"
+    .db 1
+    .section \"scratch\", \"rwn\"
+    .resb 4
+    ";
+    let obj = compile(code).unwrap();
+
+    let path = std::env::temp_dir().join("sarch_asm_section_flags.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let obj = ObjectFormat::from_bytes(fs::read(&path).unwrap()).unwrap();
+
+    let flags = |name: &str| obj.sections[name].flags.to_string();
+    assert_eq!(flags("text"), "rx");
+    assert_eq!(flags("vectors"), "rx");
+    assert_eq!(flags("rodata"), "r");
+    assert_eq!(flags("scratch"), "rwn");
+    assert!(obj.sections["scratch"].flags.contains(SectionFlags::NOLOAD));
+
+    let err = compile(".section \"data\" \"rq\"\n").unwrap_err();
+    assert!(err.contains("Unknown section flag 'q'"));
+}
+
+#[test]
+fn nobits_sections() {
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"text\"
+    start:
+    loadid counter r0
+    loadid buffer r1
+    .section \"data\"
+    .db 1
+    .section \"rodata\"
+    .section \"bss\"
+    counter:
+    .resd 1
+    buffer:
+    .resb 4096
+    ").unwrap();
+    assert_eq!(obj.sections["bss"].get_binary_size(), 4100);
+    assert!(obj.sections["bss"].binary_data.is_empty());
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(Some(&script_file(r#"{"sections": [
+        {"name": "text", "alignment": 4},
+        {"name": "data", "alignment": 4},
+        {"name": "rodata", "alignment": 4},
+        {"name": "bss", "alignment": 4}
+    ]}"#, "json"))).unwrap();
+
+    // Image ends with data, bss lives right after it
+    assert_eq!(binary.len(), 16);
+    assert_eq!(&binary[0..12], &[0x05, 0x10, 0, 0, 0, 0, 0x05, 0x14, 0, 0, 0, 1]);
+
+    let err = compile(".section \"bss\"\n.db 1\n").err().unwrap();
+    assert!(err.contains("can only contain reservations"));
+}
+
+#[test]
+fn balign_directive() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+    use std::fs;
+
+    let code = ".section \"text\"
+    start:
+    nop
+    .balign 4 0xFF
+    loadid value r0
+    .section \"data\"
+    .db 1
+    .balign 8 0xAA
+    value:
+    .db 2
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+
+    // Alignment units survive a round trip through an object file
+    let path = std::env::temp_dir().join("sarch_asm_balign.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let obj = ObjectFormat::from_bytes(fs::read(&path).unwrap()).unwrap();
+    assert_eq!(obj.sections["data"].get_label_binary_offset("value"), Some(8));
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    assert_eq!(&binary[0..10], &[0x00, 0xFF, 0xFF, 0xFF, 0x05, 0x08, 0x01, 0, 0, 0]);
+    assert_eq!(&binary[0x100..0x109], &[1, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 2]);
+}
+
+#[test]
+fn subsections() {
+    use crate::linker::Linker;
+
+    let main = compile(".section \"text\"
+    main:
+    halt
+    .section \"text.1init\"
+    init:
+    jpr main
+    .section \"data\"
+    .section \"rodata\"
+    ").unwrap();
+    let vectors = compile(".section \"text.0vectors\"
+    vectors:
+    jpr init
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(main).unwrap();
+    linker.load_symbols(vectors).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    // vectors: jpr init; init: jpr main; main: halt
+    assert_eq!(&binary[0..11], &[0x0C, 5, 0, 0, 0, 0x0C, 5, 0, 0, 0, 0x01]);
+}
+
+#[test]
+fn literal_pools() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    let code = ".section \"text\"
+    start:
+    loadid 0x12345678 r0
+    loadid 5 r1
+    iadd 0x12345678 r2
+    .section \"data\"
+    .db 1
+    .ltorg
+    .section \"text\"
+    isub -0x10000 r3
+    .section \"rodata\"
+    ";
+    let tokens = super::lex(code, false);
+    let node = super::parse(tokens, false).unwrap();
+    let mut obj = ObjectFormat::new();
+    obj.literal_pools = true;
+    obj.load_parser_node(&node).unwrap();
+
+    let opcodes: Vec<u16> = obj.sections["text"].instructions.iter()
+        .map(|i| i.opcode)
+        .collect();
+    // loadmd, loadid, madd, msub
+    assert_eq!(opcodes, vec![4, 5, 6, 23]);
+    assert_eq!(obj.sections["data"].get_label_binary_offset("__literal_0"), Some(4));
+    assert!(obj.sections["rodata.literals"].labels.contains_key("__literal_1"));
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    // loadmd [0x104] r0
+    assert_eq!(&binary[0..6], &[0x04, 0x04, 0x01, 0, 0, 0]);
+    assert_eq!(&binary[0x100..0x108], &[1, 0, 0, 0, 0x78, 0x56, 0x34, 0x12]);
+}
+
+#[test]
+fn merge_constants() {
+    use crate::linker::Linker;
+
+    let first = compile(".section \"text\"
+    start:
+    loadid hello r0
+    loadid other_hello r1
+    loadid bye r2
+    .section \"data\"
+    .section \"rodata\"
+    hello:
+    .asciz \"hello\"
+    bye:
+    .asciz \"bye\"
+    ").unwrap();
+    let second = compile(".section \"rodata\"
+    other_hello:
+    .asciz \"hello\"
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.merge_constants = true;
+    linker.load_symbols(first).unwrap();
+    linker.load_symbols(second).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+
+    // Both messages point to the same bytes
+    assert_eq!(&binary[0..15], &[0x05, 0, 1, 0, 0, 0, 0x05, 0, 1, 0, 0, 1, 0x05, 6, 1]);
+    assert_eq!(&binary[0x100..0x10A], b"hello\0bye\0");
+    assert!(binary[0x10A..].iter().all(|b| *b == 0));
+}
+
+#[test]
+fn data_range_validation() {
+    use crate::objgen::ObjectFormat;
+
+    let compile = |code: &str, widen_db: bool| -> Result<ObjectFormat, String> {
+        let tokens = super::lex(code, false);
+        let node = super::parse(tokens, false).unwrap();
+        let mut obj = ObjectFormat::new();
+        obj.widen_db = widen_db;
+        obj.load_parser_node(&node)?;
+        Ok(obj)
+    };
+
+    assert!(compile(".section \"data\"\n.db -128 255\n.dw -1 0xFFFF\n.dd 0xFFFFFFFF\n", false).is_ok());
+
+    let err = compile(".section \"data\"\n.db 256\n", false).err().unwrap();
+    assert!(err.contains("does not fit in '.db'"));
+    let err = compile(".section \"data\"\n.dw 0x10000\n", false).err().unwrap();
+    assert!(err.contains("does not fit in '.dw'"));
+    let err = compile(".section \"data\"\n.dd -0x80000001\n", false).err().unwrap();
+    assert!(err.contains("does not fit in '.dd'"));
+
+    // The old widening behaviour is still available
+    let obj = compile(".section \"data\"\n.db 256\n", true).unwrap();
+    assert_eq!(obj.sections["data"].get_binary_size(), 2);
+}
+
+#[test]
+fn anonymous_labels() {
+    use crate::linker::Linker;
+
+    let first = compile(".section \"text\"
+    start:
+    :
+    jpr :+
+    jpr :-
+    :
+    jpr :--
+    .section \"data\"
+    .dd :-
+    .section \"rodata\"
+    ").unwrap();
+    // Anonymous labels don't clash between objects
+    let second = compile(".section \"text\"
+    :
+    jpr :-
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(first).unwrap();
+    linker.load_symbols(second).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    assert_eq!(&binary[0..20], &[
+        0x0C, 10, 0, 0, 0,
+        0x0C, 0xFB, 0xFF, 0xFF, 0xFF,
+        0x0C, 0xF6, 0xFF, 0xFF, 0xFF,
+        0x0C, 0, 0, 0, 0
+    ]);
+    assert_eq!(&binary[0x100..0x104], &[10, 0, 0, 0]);
+}
+
+#[test]
+fn string_encodings() {
+    let values = |code: &str| -> Result<Vec<i64>, String> {
+        let obj = compile(code)?;
+        Ok(obj.sections["data"].binary_data.iter()
+            .map(|u| u.constant.as_ref().unwrap().value)
+            .collect())
+    };
+
+    assert_eq!(values(".section \"data\"\n.asciz \"é\"\n").unwrap(), vec![0xC3, 0xA9, 0]);
+    assert_eq!(values(".section \"data\"\n.encoding \"latin1\"\n.db \"é\"\n.ascii \"\\xFF\"\n").unwrap(),
+        vec![0xE9, 0xFF]);
+    assert_eq!(values(".section \"data\"\n.encoding \"utf16le\"\n.asciz \"Aé\"\n").unwrap(),
+        vec![0x41, 0, 0xE9, 0, 0, 0]);
+    // Wider data takes one element per encoded byte
+    assert_eq!(values(".section \"data\"\n.encoding \"latin1\"\n.dw \"é\"\n.dd \"A\"\n").unwrap(),
+        vec![0xE9, 0x41]);
+    assert_eq!(values(".section \"data\"\n.dw \"é\"\n").unwrap(), vec![0xC3, 0xA9]);
+
+    let err = values(".section \"data\"\n.encoding \"latin1\"\n.ascii \"€\"\n").unwrap_err();
+    assert!(err.contains("cannot be encoded in latin1"));
+    let err = values(".encoding \"ebcdic\"\n").unwrap_err();
+    assert!(err.contains("Unknown string encoding"));
+}
+
+#[test]
+fn symbolic_defines() {
+    use crate::linker::Linker;
+
+    let code = ".define MSG_LEN (msg_end - msg_start)
+    .define LEN_ALIAS MSG_LEN
+    .define ENTRY (msg_end + 4 - 4)
+    .section \"text\"
+    start:
+    loadid LEN_ALIAS r0
+    loadid ENTRY r1
+    halt
+    .section \"data\"
+    msg_start:
+    .ascii \"hi\"
+    msg_end:
+    .dd ENTRY
+    .db MSG_LEN
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    // loadid 2 r0; loadid 0x102 r1
+    assert_eq!(&binary[0..12], &[0x05, 2, 0, 0, 0, 0, 0x05, 0x02, 0x01, 0, 0, 1]);
+    assert_eq!(&binary[0x100..0x107], b"hi\x02\x01\x00\x00\x02");
+}
+
+#[test]
+fn reference_addends() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+    use std::fs;
+
+    let code = ".define ISR_TABLE (vectors + 0x40)
+    .section \"text\"
+    start:
+    jpr (table + 8)
+    loadid ISR_TABLE r0
+    halt
+    .section \"data\"
+    table:
+    .dd 0 0 0
+    vectors:
+    .dd (table + 4) (vectors - table - 4)
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+
+    let path = std::env::temp_dir().join("sarch_asm_reference_addends.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let obj = ObjectFormat::from_bytes(fs::read(&path).unwrap()).unwrap();
+
+    let reference = &obj.sections["text"].instructions[1].references[0];
+    assert_eq!((reference.rf.as_str(), reference.addend), ("vectors", 0x40));
+    assert_eq!(obj.sections["text"].instructions[0].get_args(), "(table + 8) ");
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    // jpr 0x108; loadid 0x14c r0
+    assert_eq!(&binary[0..11], &[0x0C, 0x08, 0x01, 0, 0, 0x05, 0x4C, 0x01, 0, 0, 0]);
+    assert_eq!(&binary[0x10C..0x114], &[0x04, 0x01, 0, 0, 8, 0, 0, 0]);
+}
+
+#[test]
+fn symbol_table() {
+    use crate::objgen::{ObjectFormat, SymbolBinding, SymbolType};
+    use std::fs;
+
+    let code = ".global start
+    .section \"text\"
+    start:
+    loadid 1 r0
+    @loop:
+    jpr @loop
+    .section \"data\"
+    value:
+    .dw 1 2
+    after:
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+
+    let path = std::env::temp_dir().join("sarch_asm_symbol_table.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let obj = ObjectFormat::from_bytes(fs::read(&path).unwrap()).unwrap();
+
+    let symbols: Vec<_> = obj.symbol_table().into_iter()
+        .map(|s| (s.name, s.section, s.offset, s.binding, s.symbol_type))
+        .collect();
+    assert_eq!(symbols, vec![
+        ("value".to_string(), "data".to_string(), 0, SymbolBinding::Local, SymbolType::Object),
+        ("after".to_string(), "data".to_string(), 4, SymbolBinding::Local, SymbolType::Object),
+        ("start".to_string(), "text".to_string(), 0, SymbolBinding::Global, SymbolType::Func),
+        ("start@loop".to_string(), "text".to_string(), 6, SymbolBinding::Local, SymbolType::Func),
+    ]);
+    assert_eq!(obj.sections["text"].labels["start@loop"].ptr, 1);
+}
+
+#[test]
+fn string_table() {
+    use crate::objgen::ObjectFormat;
+    use std::fs;
+
+    let mut code = String::from(".section \"text\"\nstart:\n");
+    for _ in 0..50 {
+        code += "jpr a_rather_long_label_name\n";
+    }
+    code += "a_rather_long_label_name:\nhalt\n.section \"data\"\n.dd a_rather_long_label_name\n";
+
+    let obj = compile(&code).unwrap();
+
+    let path = std::env::temp_dir().join("sarch_asm_string_table.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let bytes = fs::read(&path).unwrap();
+
+    let name = b"a_rather_long_label_name";
+    let occurrences = bytes.windows(name.len()).filter(|w| w == name).count();
+    assert_eq!(occurrences, 1);
+
+    let obj = ObjectFormat::from_bytes(bytes).unwrap();
+    let text = &obj.sections["text"];
+    assert_eq!(text.instructions.len(), 51);
+    assert!(text.instructions[..50].iter().all(|i| i.references[0].rf == "a_rather_long_label_name"));
+    assert_eq!(text.labels["a_rather_long_label_name"].ptr, 50);
+    assert_eq!(obj.sections["data"].binary_data[0].reference.as_ref().unwrap().rf, "a_rather_long_label_name");
+}
+
+#[test]
+fn debug_line_info() {
+    use crate::objgen::{ObjectFormat, LineInfo};
+    use crate::objdump::Objdump;
+    use crate::preprocessor::Preprocessor;
+    use std::fs;
+
+    let code = "%macro twice()
+nop
+nop
+%endmacro
+.section \"text\"
+start:
+    loadid 1 r0
+    twice()
+
+    halt
+.section \"data\"
+    .dw 1 2
+";
+    let path = std::env::temp_dir().join("sarch_asm_debug_line_info.s");
+    let file = path.to_str().unwrap();
+    fs::write(&path, code).unwrap();
+
+    let tokens = super::lex(code, false);
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_source(file, code);
+    preprocessor.line_markers = true;
+    let tokens = super::preprocess(tokens, &mut preprocessor).unwrap();
+    let node = super::parse(tokens.iter().map(|t| t.as_token()).collect(), false).unwrap();
+    let mut obj = ObjectFormat::new();
+    obj.load_parser_node(&node).unwrap();
+
+    let object_path = std::env::temp_dir().join("sarch_asm_debug_line_info.sao");
+    obj.save_object(object_path.to_str().unwrap()).unwrap();
+    let obj = ObjectFormat::from_bytes(fs::read(&object_path).unwrap()).unwrap();
+
+    let line = |section: &str, ptr: u64, line: u32| LineInfo {
+        section: section.to_string(), ptr, file: file.to_string(), line
+    };
+    // Both instructions of the macro map to its call
+    assert_eq!(obj.debug_lines, vec![
+        line("text", 0, 7), line("text", 1, 8), line("text", 2, 8), line("text", 3, 10), line("data", 0, 12)
+    ]);
+
+    let disassembly = Objdump::new(obj).get_disassembly().unwrap();
+    assert!(disassembly.contains(&format!("; {}:10: halt\n", file)));
+}
+
+#[test]
+fn object_checksum() {
+    use crate::objgen::{ObjectFormat, crc32};
+    use std::fs;
+
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+    let code = ".section \"text\"\nstart:\nloadid 1 r0\nhalt\n";
+    let obj = compile(code).unwrap();
+
+    let path = std::env::temp_dir().join("sarch_asm_object_checksum.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let bytes = fs::read(&path).unwrap();
+    assert!(ObjectFormat::from_bytes(bytes.clone()).is_ok());
+
+    let truncated = bytes[..bytes.len() - 3].to_vec();
+    assert!(ObjectFormat::from_bytes(truncated).unwrap_err().contains("truncated or corrupted"));
+
+    let mut corrupted = bytes.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0x40;
+    assert!(ObjectFormat::from_bytes(corrupted).unwrap_err().contains("truncated or corrupted"));
+}
+#[test]
+fn older_object_versions() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    fn cstring(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+    }
+
+    // Version 4 object: labels inside sections, names as C strings
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&0x3A6863FC6173371Bu64.to_le_bytes());
+    bytes.extend_from_slice(&3u64.to_le_bytes());
+    bytes.extend_from_slice(&4u32.to_le_bytes());
+
+    // text: start: loadid 5 r0; jpr start
+    bytes.extend_from_slice(&2u64.to_le_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    cstring(&mut bytes, "text");
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    cstring(&mut bytes, "start");
+    bytes.extend_from_slice(&[0x05, 0x00, 0, 2, 0, 4, 5, 0, 0, 0, 1, 1, 0]);
+    bytes.extend_from_slice(&[0x0C, 0x00, 1, 0, 0]);
+    cstring(&mut bytes, "start");
+
+    // data: 32 bit reference to start
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    cstring(&mut bytes, "data");
+    bytes.extend_from_slice(&[1, 4]);
+    cstring(&mut bytes, "start");
+
+    bytes.extend_from_slice(&[0u8; 24]);
+    cstring(&mut bytes, "rodata");
+
+    let obj = ObjectFormat::from_bytes(bytes.clone()).unwrap();
+    assert_eq!(obj.sections["text"].labels["start"].ptr, 0);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    assert_eq!(&binary[0..6], &[0x05, 5, 0, 0, 0, 0]);
+    // jpr start, 6 bytes back
+    assert_eq!(&binary[6..11], &[0x0C, 0xFA, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(&binary[0x100..0x104], &[0, 0, 0, 0]);
+
+    bytes[16] = 3;
+    assert!(ObjectFormat::from_bytes(bytes).unwrap_err().contains("not supported"));
+}
+
+#[test]
+fn streamed_objects() {
+    use crate::objgen::ObjectFormat;
+    use std::io::Cursor;
+
+    let code = ".global start
+    .section \"text\"
+    start:
+    loadid table r0
+    .section \"data\"
+    table:
+    .resb 0x10000
+    .dd start
+    ";
+    let obj = compile(code).unwrap();
+
+    let mut cursor = Cursor::new(Vec::new());
+    obj.write_object(&mut cursor).unwrap();
+    let bytes = cursor.into_inner();
+
+    let path = std::env::temp_dir().join("sarch_asm_streamed_objects.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+    let loaded = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(loaded.sections["data"].get_binary_size(), obj.sections["data"].get_binary_size());
+    assert_eq!(loaded.sections["text"].labels["start"].ptr, 0);
+
+    let mut reader = bytes.as_slice();
+    assert!(ObjectFormat::from_reader(&mut reader).is_ok());
+}
+
+#[test]
+fn corrupt_objects() {
+    use crate::objgen::{ObjectFormat, crc32, HEADER_SIZE};
+    use crate::linker::Linker;
+    use std::io::Cursor;
+
+    let header = HEADER_SIZE as usize;
+
+    let code = ".global start
+    .section \"text\"
+    start:
+    loadid table r0
+    .align_code 4
+    jpr start
+    .section \"data\"
+    table:
+    .dd start 7
+    .balign 8
+    .section \"rodata\"
+    ";
+    let obj = compile(code).unwrap();
+
+    let mut cursor = Cursor::new(Vec::new());
+    obj.write_object(&mut cursor).unwrap();
+    let bytes = cursor.into_inner();
+
+    let mut mutations = Vec::new();
+    for len in 0..bytes.len() {
+        mutations.push(bytes[..len].to_vec());
+    }
+    for i in 0..bytes.len() {
+        for mask in [0x01, 0x80, 0xFF] {
+            let mut mutated = bytes.clone();
+            mutated[i] ^= mask;
+            mutations.push(mutated);
+        }
+    }
+
+    for mut mutated in mutations {
+        // Fix up the checksum, so corrupted fields get past it and into the linker
+        if mutated.len() >= header {
+            let checksum = crc32(&mutated[header..]);
+            mutated[header - 4..header].copy_from_slice(&checksum.to_le_bytes());
+        }
+        if let Ok(obj) = ObjectFormat::from_bytes(mutated) {
+            let mut linker = Linker::new();
+            if linker.load_symbols(obj).is_ok() {
+                let _ = linker.generate_binary(None);
+            }
+        }
+    }
+
+    // Names without a terminator are cut off instead of read to the end of memory
+    let mut long_name = bytes[..header].to_vec();
+    long_name.extend_from_slice(&[1, 0, 0, 0]);
+    long_name.extend(std::iter::repeat(b'a').take(100000));
+    assert!(ObjectFormat::from_bytes(long_name).unwrap_err().contains("longer than"));
+}
+
+#[test]
+fn compressed_sections() {
+    use crate::objgen::{ObjectFormat, SectionFlags};
+    use crate::linker::Linker;
+    use std::io::Cursor;
+
+    fn assemble(flags: &str) -> (ObjectFormat, Vec<u8>) {
+        let code = format!(".section \"text\"
+        loadid asset r0
+        .section \"data\" \"{}\"
+        asset:
+        .fill 4096 1 0x5A
+        .dd asset
+        .section \"rodata\"
+        ", flags);
+        let obj = compile(&code).unwrap();
+
+        let mut cursor = Cursor::new(Vec::new());
+        obj.write_object(&mut cursor).unwrap();
+        (obj, cursor.into_inner())
+    }
+
+    let (_, plain) = assemble("rw");
+    let (_, compressed) = assemble("rwc");
+    assert!(compressed.len() < plain.len() / 4);
+
+    let loaded = ObjectFormat::from_bytes(compressed.clone()).unwrap();
+    assert!(loaded.sections["data"].flags.contains(SectionFlags::COMPRESSED));
+    assert_eq!(loaded.sections["data"].get_binary_size(), 4100);
+
+    let link = |obj: ObjectFormat| {
+        let mut linker = Linker::new();
+        linker.load_symbols(obj).unwrap();
+        linker.generate_binary(None).unwrap()
+    };
+    assert_eq!(link(loaded), link(ObjectFormat::from_bytes(plain).unwrap()));
+
+    // Compressed data cut short is an error, not a short section
+    let mut truncated = compressed.clone();
+    truncated.truncate(compressed.len() - 40);
+    assert!(ObjectFormat::from_bytes(truncated).is_err());
+}
+
+#[test]
+fn big_endian_target() {
+    use crate::objgen::{ObjectFormat, Endianness};
+    use crate::linker::Linker;
+
+    let compile = |code: &str, endianness: Endianness| {
+        let tokens = super::lex(code, false);
+        let node = super::parse(tokens, false).unwrap();
+        let mut obj = ObjectFormat::new();
+        obj.header.endianness = endianness;
+        obj.load_parser_node(&node).unwrap();
+        obj
+    };
+    let code = ".section \"text\"
+    loadid 0x11223344 r0
+    .section \"data\"
+    value:
+    .dw 0xAABB
+    .dd value
+    .section \"rodata\"
+    ";
+
+    let path = std::env::temp_dir().join("sarch_asm_big_endian_target.sao");
+    compile(code, Endianness::Big).save_object(path.to_str().unwrap()).unwrap();
+    let obj = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(obj.header.endianness, Endianness::Big);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+    assert_eq!(&binary[0..6], &[0x05, 0x11, 0x22, 0x33, 0x44, 0]);
+    assert_eq!(&binary[0x100..0x106], &[0xAA, 0xBB, 0x00, 0x00, 0x01, 0x00]);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(compile(code, Endianness::Big)).unwrap();
+    let err = linker.load_symbols(compile(".section \"data\"\n", Endianness::Little)).unwrap_err();
+    assert!(err.contains("little endian"));
+}
+
+#[test]
+fn object_metadata() {
+    use crate::objgen::ObjectFormat;
+    use crate::objdump::Objdump;
+
+    let code = ".section \"text\"\nhalt\n";
+    let mut obj = compile(code).unwrap();
+    obj.set_metadata("source", "boot.s");
+    obj.set_metadata("flags", "-g");
+    obj.set_metadata("flags", "-g --big-endian");
+
+    let path = std::env::temp_dir().join("sarch_asm_object_metadata.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let loaded = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+
+    assert_eq!(loaded.get_metadata("source"), Some("boot.s"));
+    assert_eq!(loaded.get_metadata("flags"), Some("-g --big-endian"));
+    assert_eq!(loaded.get_metadata("assembler"), None);
+
+    let dump = Objdump::new(loaded).get_disassembly().unwrap();
+    assert!(dump.starts_with("Metadata:\n\tsource: boot.s\n\tflags: -g --big-endian\n"));
+}
+
+#[test]
+fn common_symbols() {
+    use crate::objgen::{ObjectFormat, CommonSymbol};
+    use crate::linker::Linker;
+
+    let main = compile(".comm buf 16
+    .comm flag 1
+    .comm shared 4
+    .section \"text\"
+    loadid buf r0
+    loadid flag r1
+    loadid shared r2
+    nop
+    nop
+    .section \"data\"
+    .db 1 0 0 0
+    .section \"rodata\"
+    ").unwrap();
+    assert_eq!(main.commons[0], CommonSymbol { name: "buf".to_string(), size: 16, alignment: 4 });
+    assert_eq!(main.commons[1].alignment, 1);
+
+    let other = compile(".comm buf 64 8
+    .section \"data\"
+    shared:
+    .dd 2
+    ").unwrap();
+
+    // Commons survive a round trip through an object file
+    let path = std::env::temp_dir().join("sarch_asm_common_symbols.sao");
+    other.save_object(path.to_str().unwrap()).unwrap();
+    let other = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(other.commons, vec![CommonSymbol { name: "buf".to_string(), size: 64, alignment: 8 }]);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(main).unwrap();
+    linker.load_symbols(other).unwrap();
+    let binary = linker.generate_binary(Some(&script_file(r#"{"sections": [
+        {"name": "text", "alignment": 4},
+        {"name": "data", "alignment": 4},
+        {"name": "rodata", "alignment": 4},
+        {"name": "bss", "alignment": 8}
+    ]}"#, "json"))).unwrap();
+
+    // One 64 byte 'buf' at the start of bss, then 'flag'. 'shared' is defined in data
+    assert_eq!(&binary[0..18], &[
+        0x05, 32, 0, 0, 0, 0,
+        0x05, 96, 0, 0, 0, 1,
+        0x05, 24, 0, 0, 0, 2
+    ]);
+    assert_eq!(binary.len(), 28);
+
+    let err = compile(".comm buf 4\n.section \"data\"\nbuf:\n.db 1\n").err().unwrap();
+    assert!(err.contains("declared '.comm' but defined"));
+}
+
+#[test]
+fn insn_directive() {
+    use crate::objgen::ObjectFormat;
+    use crate::objdump::Objdump;
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"text\"
+    start:
+    .insn 0x70 byte 3 rel target
+    .insn 0x1C0 word 0x1234
+    .word 0xBEEF start
+    target:
+    halt
+    .section \"data\"
+    .db 0
+    .section \"rodata\"
+    ").unwrap();
+
+    // Raw code survives a round trip through an object file
+    let path = std::env::temp_dir().join("sarch_asm_insn_directive.sao");
+    obj.save_object(path.to_str().unwrap()).unwrap();
+    let obj = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+
+    let disassembly = Objdump::new(obj.clone()).get_disassembly().unwrap();
+    assert!(disassembly.contains(".insn 0x70 byte 0x03 rel target"));
+    assert!(disassembly.contains(".word 0xbeef start"));
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+
+    assert_eq!(&binary[0..15], &[
+        0x70, 3, 14, 0, 0, 0,
+        0xC0, 0x01, 0x34, 0x12,
+        0xEF, 0xBE, 0, 0,
+        0x01
+    ]);
+
+    let err = compile(".insn 0x100\n").err().unwrap();
+    assert!(err.contains("cannot be encoded"));
+    let err = compile(".section \"data\"\n.db 1\n.insn 0x70\n").err().unwrap();
+    assert!(err.contains("binary section"));
+
+    // Outside of code '.word' is plain data
+    let obj = compile(".section \"data\"\n.word 1 2\n").unwrap();
+    assert_eq!(obj.sections["data"].get_binary_size(), 4);
+}
+
+#[test]
+fn memory_regions() {
+    let link = |script: &str| -> Result<Vec<u8>, String> {
+        link_with_script(".section \"text\"
+        loadid value r0
+        halt
+        .section \"data\"
+        value:
+        .dd 0x11223344
+        .section \"bss\" \"rwn\"
+        .resb 8
+        ", script)
+    };
+
+    let binary = link("MEMORY {
+        ROM : ORIGIN = 0x1000, LENGTH = 1K # code and constants
+        RAM : ORIGIN = 0x8000, LENGTH = 0x10
+    }
+    SECTIONS {
+        data > RAM
+        text ALIGN(0x10) > ROM
+        bss > RAM
+    }").unwrap();
+
+    // The image starts at ROM, 'value' lives at the start of RAM
+    assert_eq!(&binary[0..7], &[0x05, 0x00, 0x80, 0, 0, 0, 0x01]);
+    assert_eq!(binary.len(), 0x7000 + 4);
+    assert_eq!(&binary[0x7000..], &[0x44, 0x33, 0x22, 0x11]);
+
+    let err = link("MEMORY {
+        ROM : ORIGIN = 0, LENGTH = 0x100
+        RAM : ORIGIN = 0x8000, LENGTH = 8
+    }
+    SECTIONS {
+        text > ROM
+        data > RAM
+        bss > RAM
+    }").err().unwrap();
+    assert!(err.contains("Section 'bss' (0x8004..0x800c) overflows memory region 'RAM' (0x8000..0x8008) by 4 bytes"));
+
+    let err = link("SECTIONS { text > FLASH }").err().unwrap();
+    assert!(err.contains("undefined memory region 'FLASH'"));
+    let err = link("MEMORY { ROM : ORIGIN = 0 }").err().unwrap();
+    assert!(err.contains("line 1: Expected 'LENGTH'"));
+}
+
+#[test]
+fn fixed_section_addresses() {
+    let link = |script: &str| -> Result<Vec<u8>, String> {
+        link_with_script(".section \"vectors\"
+        .dd start
+        .section \"text\"
+        start:
+        jpr start
+        .section \"data\"
+        .db 0xAA
+        ", script)
+    };
+
+    let binary = link("SECTIONS {
+        vectors at 0x0000
+        data at 0x20
+        text at 0x10
+    }").unwrap();
+
+    assert_eq!(&binary[0..4], &[0x10, 0, 0, 0]);
+    assert_eq!(&binary[0x10..0x15], &[0x0C, 0, 0, 0, 0]);
+    assert_eq!(&binary[0x15..0x20], &[0; 11]);
+    assert_eq!(binary.len(), 0x21);
+    assert_eq!(binary[0x20], 0xAA);
+
+    // Sections after a fixed one follow it
+    let binary = link("SECTIONS {
+        text at 0x40
+        vectors ALIGN(4)
+        data
+    }").unwrap();
+    assert_eq!(&binary[0..5], &[0x0C, 0, 0, 0, 0]);
+    assert_eq!(&binary[8..12], &[0x40, 0, 0, 0]);
+
+    let err = link("SECTIONS {
+        vectors at 0
+        text at 2
+        data
+    }").err().unwrap();
+    assert_eq!(err, "Sections 'vectors' (0x0000..0x0004) and 'text' (0x0002..0x0007) overlap by 2 bytes");
+
+    let err = link("SECTIONS { vectors at 3 ALIGN(4) }").err().unwrap();
+    assert!(err.contains("not aligned"));
+}
+
+#[test]
+fn section_fill() {
+    use crate::linker::Linker;
+
+    let link = |script: &str, extension: &str| -> Result<Vec<u8>, String> {
+        let obj = compile(".section \"text\"
+        halt
+        .section \"data\"
+        .db 0xAA
+        .section \"rodata\"
+        .db 0xBB
+        ")?;
+
+        let mut linker = Linker::new();
+        linker.load_symbols(obj)?;
+        linker.generate_binary(Some(&script_file(script, extension)))
+    };
+
+    let binary = link("SECTIONS {
+        text ALIGN(4) FILL(0xFF)
+        rodata ALIGN(4) FILL(0xFF)
+        data ALIGN(4)
+    }", "ld").unwrap();
+    assert_eq!(binary, vec![0x01, 0xFF, 0xFF, 0xFF, 0xBB, 0xFF, 0xFF, 0xFF, 0xAA, 0, 0, 0]);
+
+    // JSON scripts take a fill byte too and default to no alignment
+    let binary = link(r#"{"sections": [
+        {"name": "text", "alignment": 2, "fill": 255},
+        {"name": "rodata"},
+        {"name": "data"}
+    ]}"#, "json").unwrap();
+    assert_eq!(binary, vec![0x01, 0xFF, 0xBB, 0xAA]);
+
+    let err = link("SECTIONS { text FILL(0x100) }", "ld").err().unwrap();
+    assert!(err.contains("doesn't fit in a byte"));
+
+    // A global fill pads every section that doesn't set its own
+    let binary = link("FILL(0xFF)
+    SECTIONS {
+        text ALIGN(4) FILL(0xEE)
+        rodata ALIGN(4)
+        data ALIGN(4)
+    }", "ld").unwrap();
+    assert_eq!(binary, vec![0x01, 0xEE, 0xEE, 0xEE, 0xBB, 0xFF, 0xFF, 0xFF, 0xAA, 0xFF, 0xFF, 0xFF]);
+
+    let binary = link(r#"{"fill": 255, "sections": [
+        {"name": "text", "alignment": 2},
+        {"name": "rodata"},
+        {"name": "data"}
+    ]}"#, "json").unwrap();
+    assert_eq!(binary, vec![0x01, 0xFF, 0xBB, 0xAA]);
+}
+
+#[test]
+fn command_line_fill() {
+    use crate::linker::Linker;
+
+    let link = |fill: Option<u8>, section_fills: Vec<(String, u8)>| -> Result<Vec<u8>, String> {
+        let obj = compile(".section \"text\"
+        halt
+        .section \"data\"
+        .db 0xAA
+        ")?;
+
+        let mut linker = Linker::new();
+        linker.fill = fill;
+        linker.section_fills = section_fills;
+        linker.load_symbols(obj)?;
+        linker.generate_binary(Some(&script_file("FILL(0x11)
+        SECTIONS {
+            text ALIGN(2) FILL(0x22)
+            data ALIGN(2)
+        }", "ld")))
+    };
+
+    assert_eq!(link(None, vec![]).unwrap(), vec![0x01, 0x22, 0xAA, 0x11]);
+    assert_eq!(link(Some(0xFF), vec![]).unwrap(), vec![0x01, 0x22, 0xAA, 0xFF]);
+    assert_eq!(link(None, vec![("text".to_string(), 0xFF)]).unwrap(), vec![0x01, 0xFF, 0xAA, 0x11]);
+
+    let err = link(None, vec![("bss".to_string(), 0xFF)]).unwrap_err();
+    assert_eq!(err, "Cannot set the fill of section 'bss': the link script doesn't place it");
+}
+
+#[test]
+fn script_symbols() {
+    let link = |script: &str| -> Result<Vec<u8>, String> {
+        link_with_script(".section \"text\"
+        loadid __stack_top r0
+        loadid __heap_end r1
+        .section \"data\"
+        .dd __vectors_size
+        ", script)
+    };
+
+    let binary = link("MEMORY {
+        RAM : ORIGIN = 0x8000, LENGTH = 4K
+    }
+    __stack_top = ORIGIN(RAM) + LENGTH(RAM);
+    SECTIONS {
+        text
+        __vectors_size = 4 * (2 + 1)
+        data
+    }
+    __heap_end = __stack_top - 0x100 & 0xFFFFF000
+    ").unwrap();
+
+    assert_eq!(binary, vec![
+        0x05, 0x00, 0x90, 0, 0, 0,
+        0x05, 0x00, 0x80, 0, 0, 1,
+        12, 0, 0, 0
+    ]);
+
+    let err = link("a = b\nb = a + 1\n__stack_top = a\n__heap_end = 0\n__vectors_size = 0\n\
+        SECTIONS { text data }").err().unwrap();
+    assert!(err.contains("defined in terms of itself"));
+    let err = link("__stack_top = (1 + \nSECTIONS { text data }").err().unwrap();
+    assert!(err.contains("line 2: Expected ')', found '{'"));
+}
+
+#[test]
+fn command_line_symbols() {
+    use crate::linker::Linker;
+    use crate::linkscript::SymbolAssignment;
+
+    let obj = compile(".section \"data\"
+    .dd build_id
+    .dd __flash
+    taken:
+    ").unwrap();
+
+    let script = script_file("__flash = 0x1000\nSECTIONS { data }", "ld");
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj.clone()).unwrap();
+    linker.define_symbol(SymbolAssignment::parse("build_id=0xC0FF00 + 0xEE").unwrap());
+    // Replaces the assignment in the script
+    linker.define_symbol(SymbolAssignment::parse("__flash = 0x2000").unwrap());
+    let binary = linker.generate_binary(Some(&script)).unwrap();
+    assert_eq!(binary, vec![0xEE, 0xFF, 0xC0, 0x00, 0x00, 0x20, 0x00, 0x00]);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    linker.define_symbol(SymbolAssignment::parse("build_id = 1").unwrap());
+    linker.define_symbol(SymbolAssignment::parse("taken = 2").unwrap());
+    let err = linker.generate_binary(Some(&script)).unwrap_err();
+    assert_eq!(err, "Symbol 'taken' is assigned with --defsym but also defined in section 'data'");
+
+    assert!(SymbolAssignment::parse("build_id").is_err());
+    assert!(SymbolAssignment::parse("a = 1 b = 2").is_err());
+}
+
+#[test]
+fn section_boundary_symbols() {
+    let link = |code: &str| -> Result<Vec<u8>, String> {
+        link_with_script(code, "SECTIONS { data rodata ALIGN(4) }")
+    };
+
+    let binary = link(".section \"data\"
+    .dd __start_data
+    .dd __end_data
+    .dd __start_rodata
+    .dd __size_rodata
+    .section \"rodata\"
+    .db 1, 2, 3
+    ").unwrap();
+    assert_eq!(binary, vec![
+        0x00, 0, 0, 0,
+        0x10, 0, 0, 0,
+        0x10, 0, 0, 0,
+        0x03, 0, 0, 0,
+        1, 2, 3, 0
+    ]);
+
+    let err = link(".section \"data\"
+    .dd __end_bss
+    .section \"rodata\"
+    ").unwrap_err();
+    assert!(err.contains("'__end_bss'"));
+}
+
+#[test]
+fn provided_symbols() {
+    let link = |code: &str| -> Result<Vec<u8>, String> {
+        link_with_script(code, "PROVIDE(__heap_size = 0x400);
+        SECTIONS {
+            text
+            PROVIDE(__heap_start = 0x8000)
+            data
+        }")
+    };
+
+    // Defaults from the script
+    let binary = link(".section \"text\"
+    .section \"data\"
+    .dd __heap_size __heap_start
+    ").unwrap();
+    assert_eq!(binary, vec![0x00, 0x04, 0, 0, 0x00, 0x80, 0, 0]);
+
+    // An object's definition wins over the script's
+    let binary = link(".section \"text\"
+    .section \"data\"
+    .dd __heap_size
+    __heap_size:
+    .dd 0
+    ").unwrap();
+    assert_eq!(binary, vec![4, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn script_assertions() {
+    let link = |script: &str| -> Result<Vec<u8>, String> {
+        link_with_script(".section \"text\"
+        nop
+        nop
+        text_end:
+        .section \"data\"
+        .dd 0
+        ", script)
+    };
+
+    link("SECTIONS { text data }
+    ASSERT(text_end <= 2, \"text is too big\");
+    ASSERT(text_end == 2 & (text_end != 3))
+    ").unwrap();
+
+    let err = link("SECTIONS { text data }
+    ASSERT(text_end < 0x2, \"text # is too big\")
+    ").err().unwrap();
+    assert_eq!(err, "Link script assertion 'text_end < 0x2' failed: text # is too big");
+
+    let err = link("SECTIONS { text data }
+    ASSERT((text_end + 4) * 2 >= 0x100)
+    ").err().unwrap();
+    assert_eq!(err, "Link script assertion '((text_end + 0x4) * 0x2) >= 0x100' failed");
+}
+
+#[test]
+fn input_section_patterns() {
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"text.init\" \"rx\"
+    nop
+    .section \"lib.text\" \"rx\"
+    halt
+    .section \"text\" \"rx\"
+    jpr start
+    .section \"vectors\" \"rx\"
+    start:
+    nop
+    .section \"isr_timer\" \"rx\"
+    halt
+    .section \"data\"
+    .db 0xAA
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(Some(&script_file("SECTIONS {
+        text : { vectors text* *.text }
+        isr { KEEP(isr_*) }
+        data
+    }", "ld"))).unwrap();
+
+    // vectors, then 'text' and 'text.init' by name, then 'lib.text'
+    assert_eq!(binary, vec![0x00, 0x0C, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x01, 0x01, 0xAA]);
+
+    // The image starts with 'vectors', which references nothing, but the script keeps 'isr_timer'
+    assert_eq!(linker.garbage_sections(), vec!["data", "lib.text", "text", "text.init"]);
+    assert!(linker.why_live("isr_timer").unwrap().contains("'isr_timer' is kept by the link script"));
+}
+
+#[test]
+fn gc_sections() {
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"text\"
+    jpr used
+    .section \"text.unused\" \"rx\"
+    unused:
+    halt
+    .section \"text.used\" \"rx\"
+    used:
+    nop
+    .section \"data\"
+    .db 0xAA
+    .section \"rodata\"
+    .db 0x55
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.gc_sections = true;
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(Some(&script_file("SECTIONS {
+        text : { text text.* }
+        data
+        rodata : { KEEP(rodata) }
+    }", "ld"))).unwrap();
+
+    // 'text.unused' and 'data' are gone, the script keeps 'rodata'
+    assert_eq!(binary, vec![0x0C, 0x05, 0x00, 0x00, 0x00, 0x00, 0x55]);
+    assert_eq!(linker.removed_sections(), ["data".to_string(), "text.unused".to_string()]);
+
+    // Traced from the input sections as they were before collection
+    assert_eq!(linker.garbage_sections(), linker.removed_sections());
+    assert_eq!(linker.why_live("used").unwrap(), "'used' is live:
+  'text' is the start of the image
+  'text' references 'used' defined in 'text.used'
+  'used' is defined in 'text.used'
+");
+    assert_eq!(linker.why_live("unused").unwrap(),
+        "'unused' is garbage: section 'text.unused' is not referenced from the start of the image.\n");
+    assert!(linker.why_live("rodata").unwrap().contains("'rodata' is kept by the link script"));
+
+    let source = std::env::temp_dir().join("sarch_asm_gc_sections.s");
+    std::fs::write(&source, ".section \"text\"
+    halt
+    .section \"data\"
+    unused:
+    .db 1
+    ").unwrap();
+    let script = script_file("SECTIONS { text data }", "ld");
+    let code = super::run(["sarch_asm", source.to_str().unwrap(), "--gc-sections", "--dry-run",
+        "--why-live", "unused", "--print-gc-sections", "-c", &script]
+        .iter().map(|a| a.to_string()).collect());
+    assert_eq!(code, std::process::ExitCode::SUCCESS);
+}
+
+#[test]
+fn relocatable_link() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    let first = ".section \"text\"
+    .local loop
+    loop:
+    jpr helper
+    .section \"data\"
+    .db 1
+    .section \"rodata\"
+    .db 2
+    ";
+    let second = ".section \"text\"
+    .local loop
+    loop:
+    helper:
+    jpr loop
+    .section \"data\"
+    .db 3
+    ";
+
+    let mut direct = Linker::new();
+    direct.load_symbols(compile(first).unwrap()).unwrap();
+    direct.load_symbols(compile(second).unwrap()).unwrap();
+    let expected = direct.generate_binary(None).unwrap();
+
+    let mut partial = Linker::new();
+    partial.load_symbols(compile(first).unwrap()).unwrap();
+    partial.load_symbols(compile(second).unwrap()).unwrap();
+    let path = std::env::temp_dir().join("sarch_asm_relocatable_link.sao");
+    partial.save_object(path.to_str().unwrap()).unwrap();
+
+    let merged = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(merged.sections["text"].instructions.len(), 2);
+
+    // Both 'loop' labels stay local and apart after another link
+    let mut linker = Linker::new();
+    linker.load_symbols(merged).unwrap();
+    assert_eq!(linker.generate_binary(None).unwrap(), expected);
+}
+
+#[test]
+fn library_archive() {
+    use crate::archive::Archive;
+
+    let mut archive = Archive::new();
+    archive.add_object("memcpy.sao", &compile(".section \"text\"
+    .global memcpy
+    memcpy:
+    .local copy_loop
+    copy_loop:
+    jpr copy_loop
+    ").unwrap()).unwrap();
+    archive.add_object("memset.sao", &compile(".section \"text\"
+    .weak memset
+    memset:
+    nop
+    ").unwrap()).unwrap();
+
+    let path = std::env::temp_dir().join("sarch_asm_library_archive.sal");
+    let path = path.to_str().unwrap();
+    archive.save(path).unwrap();
+    assert!(Archive::is_archive(path));
+
+    let archive = Archive::from_file(path).unwrap();
+    let members: Vec<&str> = archive.members.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(members, ["memcpy.sao", "memset.sao"]);
+
+    // Local labels stay out of the index
+    let symbols: Vec<(&str, &str)> = archive.symbols().into_iter()
+        .map(|(s, m)| (s, m.name.as_str()))
+        .collect();
+    assert_eq!(symbols, [("memcpy", "memcpy.sao"), ("memset", "memset.sao")]);
+    assert_eq!(archive.member_defining("copy_loop"), None);
+
+    let object = archive.members[1].object(path).unwrap();
+    assert!(object.sections["text"].labels.contains_key("memset"));
+    assert_eq!(object.file, Some(format!("{}(memset.sao)", path)));
+}
+
+#[test]
+fn lazy_archive_members() {
+    use crate::archive::Archive;
+    use crate::linker::Linker;
+
+    let mut archive = Archive::new();
+    archive.add_object("memcpy.sao", &compile(".section \"text\"
+    .global memcpy
+    .extern helper
+    memcpy:
+    jpr helper
+    ").unwrap()).unwrap();
+    archive.add_object("unused.sao", &compile(".section \"text\"
+    .global unused
+    unused:
+    halt
+    ").unwrap()).unwrap();
+    archive.add_object("helper.sao", &compile(".section \"text\"
+    .global helper
+    helper:
+    nop
+    ").unwrap()).unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(compile(".section \"text\"
+    .extern memcpy
+    jpr memcpy
+    .section \"data\"
+    .section \"rodata\"
+    ").unwrap()).unwrap();
+    linker.load_archives(&[("libc.sal".to_string(), archive)]).unwrap();
+    let binary = linker.generate_binary(None).unwrap();
+
+    // 'memcpy' pulls in 'helper', nothing wants 'unused', so padding follows
+    assert_eq!(binary[..12], [0x0C, 0x05, 0x00, 0x00, 0x00, 0x0C, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert!(!binary.contains(&0x01));
+}
+
+#[test]
+fn library_search() {
+    use std::path::PathBuf;
+
+    let root = std::env::temp_dir().join("sarch_asm_library_search");
+    let (first, second) = (root.join("first"), root.join("second"));
+    std::fs::create_dir_all(&first).unwrap();
+    std::fs::create_dir_all(&second).unwrap();
+    std::fs::write(first.join("crt0.sao"), []).unwrap();
+    std::fs::write(second.join("libcrt0.sal"), []).unwrap();
+    std::fs::write(second.join("util.sao"), []).unwrap();
+    let dirs = vec![first.clone(), second.clone()];
+
+    // Directories go in order, each is searched for the archive first
+    let found = PathBuf::from(super::find_library("crt0", &dirs).unwrap());
+    assert_eq!(found, first.join("crt0.sao"));
+    let found = PathBuf::from(super::find_library("util", &dirs).unwrap());
+    assert_eq!(found, second.join("util.sao"));
+
+    // Paths to existing files are used as they are
+    let path = second.join("util.sao");
+    assert_eq!(super::find_library(path.to_str().unwrap(), &[]).unwrap(), path.to_str().unwrap());
+
+    let err = super::find_library("missing", &dirs).unwrap_err();
+    assert!(err.contains("'libmissing.sal' and 'missing.sao'"));
+}
+
+#[test]
+fn entry_point() {
+    use crate::linker::Linker;
+
+    let linker = |image_header: bool| -> Linker {
+        let obj = compile(".section \"text.init\" \"rx\"
+        halt
+        .section \"text\"
+        start:
+        nop
+        .section \"data\"
+        .db 0xAA
+        ").unwrap();
+
+        let mut linker = Linker::new();
+        linker.entry = Some("start".to_string());
+        linker.image_header = image_header;
+        linker.load_symbols(obj).unwrap();
+        linker
+    };
+    let path = script_file("SECTIONS { text data }", "ld");
+    let script = Some(path.as_str());
+
+    // The stub goes before subsections folded into the first section
+    let mut stubbed = linker(false);
+    stubbed.load_link_structure(script).unwrap();
+    let binary = stubbed.generate_binary(script).unwrap();
+    assert_eq!(binary, vec![0x0C, 0x06, 0x00, 0x00, 0x00, 0x01, 0x00, 0xAA]);
+
+    // With a header there's no stub, the header says where to start
+    let mut headed = linker(true);
+    let binary = headed.generate_binary(script).unwrap();
+    assert_eq!(binary, vec![0x01, 0x00, 0xAA]);
+    assert_eq!(headed.image_header(binary.len()).unwrap(), vec![
+        b'S', b'A', b'I', b'M', 1, 0, 2, 0,
+        1, 0, 0, 0,
+        0, 0, 0, 0,
+        3, 0, 0, 0,
+        0, 0, 0, 0, 2, 0, 0, 0, 0b101, 4, b't', b'e', b'x', b't',
+        2, 0, 0, 0, 1, 0, 0, 0, 0b011, 4, b'd', b'a', b't', b'a'
+    ]);
+}
+
+#[test]
+fn relative_range() {
+    let link = |code: &str| -> Result<Vec<u8>, String> {
+        link_with_script(code, "far = 0x100000001\nnear = 0x80000000\nSECTIONS { text }")
+    };
+
+    assert_eq!(link(".section \"text\"\nnop\njpr near\n").unwrap(),
+        vec![0x00, 0x0C, 0xFF, 0xFF, 0xFF, 0x7F]);
+
+    let err = link(".section \"text\"\nnop\njpr far\n").unwrap_err();
+    assert_eq!(err, "Relative operand of 'jpr' at 0x0001 can't reach 'far' (0x100000001): \
+    distance 4294967296 does not fit in 4 bytes");
+    let err = link(".section \"text\"\n.insn 0x20 rel far\n").unwrap_err();
+    assert!(err.starts_with("Relative operand of '.insn' at 0x0000 can't reach 'far'"));
+}
+
+#[test]
+fn veneers() {
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"text\"
+    nop
+    jpr far
+    callr far
+    jpr near
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(Some(&script_file("far = 0x80000010\nnear = 0x10\nSECTIONS { text }", "ld"))).unwrap();
+
+    // Both far branches share one veneer after the code
+    assert_eq!(binary, vec![
+        0x00,
+        0x0C, 0x0F, 0x00, 0x00, 0x00,
+        0x0E, 0x0A, 0x00, 0x00, 0x00,
+        0x0C, 0x05, 0x00, 0x00, 0x00,
+        0x09, 0x10, 0x00, 0x00, 0x80
+    ]);
+    assert!(linker.link_map().unwrap().contains("<veneers>(text)"));
+}
+
+#[test]
+fn pad_to() {
+    use crate::linker::Linker;
+
+    let link = |pad_to: (u64, Option<u8>)| -> Result<Vec<u8>, String> {
+        let obj = compile(".section \"text\"
+        nop
+        halt
+        ")?;
+
+        let mut linker = Linker::new();
+        linker.pad_to = Some(pad_to);
+        linker.load_symbols(obj)?;
+        linker.generate_binary(Some(&script_file("SECTIONS { text }", "ld")))
+    };
+
+    assert_eq!(link((6, Some(0xFF))).unwrap(), vec![0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(link((4, None)).unwrap(), vec![0x00, 0x01, 0x00, 0x00]);
+    assert_eq!(link((2, Some(0xFF))).unwrap(), vec![0x00, 0x01]);
+    assert_eq!(link((1, Some(0xFF))).unwrap_err(), "Image is 2 bytes, 1 more than the 1 it is padded to");
+    assert_eq!(crate::linkscript::parse_number("32K"), Some(0x8000));
+}
+
+#[test]
+fn link_map() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    let object = |code: &str, source: &str| -> ObjectFormat {
+        let mut obj = compile(code).unwrap();
+        obj.set_metadata("source", source);
+        obj
+    };
+
+    let mut linker = Linker::new();
+    linker.load_symbols(object(".section \"text\"
+    start:
+    jpr helper
+    .section \"data\"
+    .dd 1
+    ", "main.s")).unwrap();
+    linker.load_symbols(object(".section \"text\"
+    helper:
+    halt
+    .section \"rodata\"
+    ", "lib.s")).unwrap();
+
+    linker.generate_binary(Some(&script_file("MEMORY { ROM : ORIGIN = 0x100, LENGTH = 1K }
+    stack = 0x9000
+    SECTIONS {
+        text > ROM
+        data ALIGN(4) > ROM
+        rodata > ROM
+    }", "ld"))).unwrap();
+    let map = linker.link_map().unwrap();
+
+    assert!(map.contains("\tROM              0x00000100 - 0x00000500 (1024 bytes)\n"));
+    assert!(map.contains("\ttext             0x00000100        6 bytes > ROM\n\
+        \t\t0x00000100        5 bytes  main.s(text)\n\
+        \t\t0x00000105        1 bytes  lib.s(text)\n"));
+    assert!(map.contains("\tdata             0x00000108        4 bytes > ROM\n\
+        \t\t0x00000108        4 bytes  main.s(data)\n"));
+    assert!(map.contains("\t0x00000100 text             start\n\
+        \t0x00000105 text             helper\n\
+        \t0x00009000 *ABS*            stack\n"));
+}
+
+#[test]
+fn undefined_references() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    let object = |code: &str, source: &str| -> ObjectFormat {
+        let mut obj = compile(code).unwrap();
+        obj.set_metadata("source", source);
+        obj
+    };
+
+    let mut linker = Linker::new();
+    linker.load_symbols(object(".section \"text\"
+    loadid counter r0
+    jpr missing
+    .section \"data\"
+    .dd table_size missing
+    .section \"rodata\"
+    ", "main.s")).unwrap();
+    linker.load_symbols(object(".section \"text\"
+    jpr missing
+    ", "lib.s")).unwrap();
+
+    let err = linker.generate_binary(None).err().unwrap();
+    assert_eq!(err, "3 undefined symbols:
+  'counter' referenced by:
+    instruction 'loadi dw' at text+0x0000 in main.s(text)
+  'missing' referenced by:
+    data unit 1 at data+0x0004 in main.s(data)
+    instruction 'jpr' at text+0x0006 in main.s(text)
+    instruction 'jpr' at text+0x000b in lib.s(text)
+  'table_size' referenced by:
+    data unit 0 at data+0x0000 in main.s(data)");
+}
+
+#[test]
+fn duplicate_symbols() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    let object = |code: &str, name: &str| -> ObjectFormat {
+        let obj = compile(code).unwrap();
+
+        let path = std::env::temp_dir().join(name);
+        obj.save_object(path.to_str().unwrap()).unwrap();
+        ObjectFormat::from_file(path.to_str().unwrap()).unwrap()
+    };
+
+    let a = object(".section \"text\"
+    .global start
+    .global table
+    start:
+    nop
+    table:
+    halt
+    ", "sarch_asm_duplicate_a.sao");
+    let b = object(".section \"text\"
+    nop
+    .global table
+    .global start
+    start:
+    halt
+    .section \"data\"
+    table:
+    .db 1
+    ", "sarch_asm_duplicate_b.sao");
+
+    let mut linker = Linker::new();
+    linker.load_symbols(a).unwrap();
+    let err = linker.load_symbols(b).err().unwrap();
+
+    let dir = std::env::temp_dir();
+    let name = |file: &str| dir.join(file).to_str().unwrap().to_string();
+    assert_eq!(err, format!("Duplicate symbol 'start' defined in {a}(text) and {b}(text)\n\
+        Duplicate symbol 'table' defined in {a}(text) and {b}(data)",
+        a = name("sarch_asm_duplicate_a.sao"), b = name("sarch_asm_duplicate_b.sao")));
+}
+
+#[test]
+fn intel_hex() {
+    use crate::hexfile::intel_hex;
+
+    assert_eq!(intel_hex(&[0x02, 0x33, 0x7A], 0x30, None, None).unwrap(),
+        ":0300300002337A1E\n:00000001FF\n");
+
+    // Records are split by size and never cross a 64K boundary
+    assert_eq!(intel_hex(&[0x01, 0x02, 0x03], 0, Some(2), None).unwrap(),
+        ":020000000102FB\n:0100020003FA\n:00000001FF\n");
+    assert_eq!(intel_hex(&[0xAA, 0xBB], 0xFFFF, None, Some(0x10000)).unwrap(),
+        ":01FFFF00AA57\n:020000040001F9\n:01000000BB44\n:0400000500010000F6\n:00000001FF\n");
+
+    assert_eq!(intel_hex(&[0x00], 0, Some(256), None).unwrap_err(),
+        "Records hold at most 255 bytes, 256 were asked for");
+    assert!(intel_hex(&[0x00, 0x00], 0xFFFFFFFF, None, None).is_err());
+}
+
+#[test]
+fn srecords() {
+    use crate::hexfile::srecords;
+
+    assert_eq!(srecords(&[0x01, 0x02], 0x1000, Some(2), None, Some(0x1000), "HDR").unwrap(),
+        "S00600004844521B\nS10510000102E7\nS5030001FB\nS9031000EC\n");
+
+    // The address size defaults to the fewest bytes that fit
+    assert_eq!(srecords(&[0xAA], 0x10000, None, None, None, "HDR").unwrap(),
+        "S00600004844521B\nS205010000AA4F\nS5030001FB\nS804000000FB\n");
+    assert_eq!(srecords(&[0x11], 0, Some(4), None, None, "").unwrap(),
+        "S0030000FC\nS3060000000011E8\nS5030001FB\nS70500000000FA\n");
+
+    assert_eq!(srecords(&[0xAA], 0x10000, Some(2), None, None, "").unwrap_err(),
+        "Address 0x10000 does not fit in the 2 bytes of S19 records");
+    assert_eq!(srecords(&[0x00], 0, Some(4), Some(251), None, "").unwrap_err(),
+        "Records hold at most 250 bytes, 251 were asked for");
+}
+
+#[test]
+fn elf_output() {
+    use crate::linker::Linker;
+    use crate::elf::EM_SARCH32;
+
+    let obj = compile(".section \"text\"
+    .global start
+    start:
+    nop
+    halt
+    .section \"data\"
+    value:
+    .db 0xAA
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let image = linker.generate_binary(Some(&script_file("SECTIONS {
+        text at 0x1000
+        data at 0x2000
+    }", "ld"))).unwrap();
+    let elf = linker.elf_image(&image).unwrap().to_bytes().unwrap();
+
+    let half = |at: usize| u16::from_le_bytes([elf[at], elf[at + 1]]) as usize;
+    let word = |at: usize| u32::from_le_bytes(elf[at..at + 4].try_into().unwrap()) as usize;
+
+    assert_eq!(&elf[0..7], &[0x7F, b'E', b'L', b'F', 1, 1, 1]);
+    assert_eq!(half(16), 2);
+    assert_eq!(half(18), EM_SARCH32 as usize);
+    assert_eq!(word(24), 0x1000);
+
+    // A loadable segment for each section
+    let phoff = word(28);
+    assert_eq!(half(44), 2);
+    let segment = |idx: usize| (0..8).map(|f| word(phoff + idx * 32 + f * 4)).collect::<Vec<usize>>();
+    let text = segment(0);
+    assert_eq!(text, vec![1, text[1], 0x1000, 0x1000, 2, 2, 5, 1]);
+    assert_eq!(&elf[text[1]..text[1] + 2], &[0x00, 0x01]);
+    let data = segment(1);
+    assert_eq!(data[2..7], [0x2000, 0x2000, 1, 1, 6]);
+    assert_eq!(elf[data[1]], 0xAA);
+
+    // Sections: null, text, data, .symtab, .strtab, .shstrtab
+    let shoff = word(32);
+    assert_eq!(half(48), 6);
+    assert_eq!(half(50), 5);
+    let symtab = shoff + 3 * 40;
+    assert_eq!(word(symtab + 4), 2);
+    let (symbols, strings) = (word(symtab + 16), word(shoff + 4 * 40 + 16));
+    let name = |at: usize| {
+        let end = elf[strings + at..].iter().position(|b| *b == 0).unwrap();
+        String::from_utf8(elf[strings + at..strings + at + end].to_vec()).unwrap()
+    };
+    // Null symbol, then the local 'value', then the global 'start'
+    assert_eq!(word(symtab + 20), 3 * 16);
+    assert!(name(word(symbols + 16)).starts_with("value"));
+    assert_eq!(word(symbols + 20), 0x2000);
+    assert_eq!(name(word(symbols + 32)), "start");
+    assert_eq!((word(symbols + 36), elf[symbols + 44], half(symbols + 46)), (0x1000, 0x12, 1));
+}
+
+#[test]
+fn elf_input() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+    use crate::elf::{EM_SARCH32, R_SARCH32_32, R_SARCH32_PC32};
+
+    // Relocatable object with 'jpr main; halt' in .text and a pointer to the halt in .data
+    let elf_object = |machine: u16| -> Vec<u8> {
+        let text = [0x0C, 0, 0, 0, 0, 0x01];
+        let data = [5, 0, 0, 0];
+        let rela_text = [1u32.to_le_bytes(), (3 << 8 | R_SARCH32_PC32).to_le_bytes(), 1u32.to_le_bytes()].concat();
+        let rel_data = [0u32.to_le_bytes(), (1 << 8 | R_SARCH32_32).to_le_bytes()].concat();
+        let strtab = b"\0elf_start\0main\0".to_vec();
+        let symbol = |name: u32, value: u32, info: u8, section: u16| [
+            &name.to_le_bytes()[..], &value.to_le_bytes(), &0u32.to_le_bytes(), &[info, 0], &section.to_le_bytes()
+        ].concat();
+        let symtab = [symbol(0, 0, 0, 0), symbol(0, 0, 0x03, 1), symbol(1, 0, 0x12, 1), symbol(11, 0, 0x10, 0)].concat();
+        let shstrtab = b"\0.text\0.data\0.rela.text\0.rel.data\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+
+        // (name, type, flags, contents, link, info, alignment)
+        let sections: [(u32, u32, u32, &[u8], u32, u32, u32); 7] = [
+            (1, 1, 6, &text, 0, 0, 1),
+            (7, 1, 3, &data, 0, 0, 4),
+            (13, 4, 0, &rela_text, 5, 1, 4),
+            (24, 9, 0, &rel_data, 5, 2, 4),
+            (34, 2, 0, &symtab, 6, 2, 4),
+            (42, 3, 0, &strtab, 0, 0, 1),
+            (50, 3, 0, &shstrtab, 0, 0, 1)
+        ];
+
+        let mut contents = Vec::<u8>::new();
+        let mut headers = vec![0u8; 40];
+        for (name, section_type, flags, bytes, link, info, alignment) in sections {
+            let offset = 52 + contents.len() as u32;
+            contents.extend_from_slice(bytes);
+            for field in [name, section_type, flags, 0, offset, bytes.len() as u32, link, info, alignment, 0] {
+                headers.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        let mut elf = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+        elf.resize(16, 0);
+        elf.extend_from_slice(&1u16.to_le_bytes());
+        elf.extend_from_slice(&machine.to_le_bytes());
+        for field in [1u32, 0, 0, 52 + contents.len() as u32, 0] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [52u16, 0, 0, 40, 8, 7] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.append(&mut contents);
+        elf.append(&mut headers);
+        elf
+    };
+
+    let path = std::env::temp_dir().join("sarch_asm_elf_input.o");
+    std::fs::write(&path, elf_object(EM_SARCH32)).unwrap();
+    let elf = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+
+    let obj = compile(".section \"text\"
+    .global main
+    .extern elf_start
+    main:
+    nop
+    halt
+    .section \"data\"
+    .dd elf_start
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    linker.load_symbols(elf).unwrap();
+    let binary = linker.generate_binary(Some(&script_file("SECTIONS { text data ALIGN(4) }", "ld"))).unwrap();
+
+    assert_eq!(binary, vec![
+        0x00, 0x01, 0x0C, 0xFE, 0xFF, 0xFF, 0xFF, 0x01,
+        0x02, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00
+    ]);
+
+    std::fs::write(&path, elf_object(0x3E)).unwrap();
+    let err = ObjectFormat::from_file(path.to_str().unwrap()).unwrap_err();
+    assert_eq!(err, "Error occured while reading ELF object: Object is for machine 0x3e, not SArch32 (0x5341)");
+}
+
+#[test]
+fn c_array() {
+    use crate::hexfile::{c_array, c_identifier};
+
+    let (source, header) = c_array(&(0..14).collect::<Vec<u8>>(), 0x100, Some(0x104), "rom", "rom.h");
+    assert_eq!(source, "#include \"rom.h\"\n\n\
+        const uint8_t rom[] = {\n    \
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,\n    \
+        0x0c, 0x0d,\n\
+        };\n\
+        const uint32_t rom_length = 14;\n\
+        const uint32_t rom_base = 0x00000100;\n\
+        const uint32_t rom_entry = 0x00000104;\n");
+    assert_eq!(header, "#ifndef ROM_H\n#define ROM_H\n\n#include <stdint.h>\n\n\
+        extern const uint8_t rom[];\n\
+        extern const uint32_t rom_length;\n\
+        extern const uint32_t rom_base;\n\
+        extern const uint32_t rom_entry;\n\n#endif\n");
+
+    assert_eq!(c_identifier("rom-v2.0"), "rom_v2_0");
+    assert_eq!(c_identifier("2nd"), "_2nd");
+}
+
+#[test]
+fn readmemh() {
+    use crate::hexfile::readmemh;
+    use crate::objgen::Endianness;
+
+    let image = [0x01, 0x02, 0x03, 0x04, 0x05];
+    assert_eq!(readmemh(&image, 0, None, None, 0, Endianness::Little).unwrap(), "01\n02\n03\n04\n05\n");
+
+    // Words are put together in the target byte order, the last one is filled
+    assert_eq!(readmemh(&image, 0, Some(32), None, 0xFF, Endianness::Little).unwrap(), "04030201\nffffff05\n");
+    assert_eq!(readmemh(&image, 0, Some(16), None, 0, Endianness::Big).unwrap(), "0102\n0304\n0500\n");
+
+    assert_eq!(readmemh(&image, 0x10, Some(32), Some(8), 0, Endianness::Little).unwrap(),
+        "@4\n04030201\n00000005\n00000000\n00000000\n");
+    assert_eq!(readmemh(&image, 0x10, Some(32), Some(5), 0, Endianness::Little).unwrap_err(),
+        "Image takes up words 0x4 to 0x5, past the 5 words of memory");
+    // An empty image only sets the address, as long as that's inside the memory
+    assert_eq!(readmemh(&[], 0x10, Some(32), Some(4), 0, Endianness::Little).unwrap(), "@4\n");
+    assert_eq!(readmemh(&[], 0x10, Some(32), Some(2), 0, Endianness::Little).unwrap_err(),
+        "Image starts at word 0x4, past the 2 words of memory");
+    assert_eq!(readmemh(&image, 0x2, Some(32), None, 0, Endianness::Little).unwrap_err(),
+        "Image starts at 0x2, which isn't a multiple of the 4 byte words");
+    assert!(readmemh(&image, 0, Some(24), None, 0, Endianness::Little).is_err());
+}
+
+#[test]
+fn memory_usage() {
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"text\"
+    nop
+    halt
+    .section \"data\"
+    .dd 1
+    .section \"rodata\"
+    .db 2
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    linker.generate_binary(Some(&script_file("MEMORY {
+        ROM : ORIGIN = 0, LENGTH = 1K
+        RAM : ORIGIN = 0x1000, LENGTH = 16
+    }
+    SECTIONS {
+        text > ROM
+        data ALIGN(4) > ROM
+        rodata at 0x1004 > RAM
+    }", "ld"))).unwrap();
+    let usage = linker.memory_usage().unwrap();
+
+    // Regions are used up to the end of their last section
+    assert_eq!(usage.table(), "Region / section               Used      Total    Used %\n\
+        ROM:                            8 B       1 KB     0.78%\n  \
+        text                          2 B       1 KB     0.20%\n  \
+        data                          4 B       1 KB     0.39%\n\
+        RAM:                            5 B       16 B    31.25%\n  \
+        rodata                        1 B       16 B     6.25%\n");
+
+    let json: serde_json::Value = serde_json::from_str(&usage.to_json()).unwrap();
+    assert_eq!(json["regions"][1], serde_json::json!({"name": "RAM", "origin": 0x1000, "used": 5, "total": 16}));
+    assert_eq!(json["sections"][2], serde_json::json!({"name": "rodata", "region": "RAM", "address": 0x1004, "size": 1}));
+}
+
+#[test]
+fn trace_symbol() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    let object = |code: &str, source: &str| -> ObjectFormat {
+        let mut obj = compile(code).unwrap();
+        obj.set_metadata("source", source);
+        obj
+    };
+
+    let mut linker = Linker::new();
+    linker.trace_symbols = vec!["handler".to_string(), "missing".to_string()];
+    linker.load_symbols(object(".section \"text\"
+    .extern handler
+    start:
+    jpr handler
+    ", "main.s")).unwrap();
+    linker.load_symbols(object(".section \"text\"
+    .weak handler
+    handler:
+    nop
+    ", "weak.s")).unwrap();
+    linker.load_symbols(object(".section \"text\"
+    .global handler
+    handler:
+    halt
+    ", "strong.s")).unwrap();
+
+    linker.generate_binary(Some(&script_file("SECTIONS { text }", "ld"))).unwrap();
+
+    assert_eq!(linker.traces(), &[
+        "main.s(text): reference to handler",
+        "weak.s(text): definition of handler (weak)",
+        "strong.s(text): definition of handler (replaces the earlier weak definition)",
+        "handler resolved to 0x00000006, defined in strong.s(text)",
+        "missing is not defined"
+    ]);
+}
+
+#[test]
+fn wrap_symbols() {
+    use crate::linker::Linker;
+
+    let mut linker = Linker::new();
+    linker.wrap_symbols = vec!["malloc".to_string()];
+    linker.load_symbols(compile(".section \"text\"
+    .extern malloc
+    start:
+    callr malloc
+    halt
+    ").unwrap()).unwrap();
+    linker.load_symbols(compile(".section \"text\"
+    .global __wrap_malloc
+    .extern __real_malloc
+    __wrap_malloc:
+    jpr __real_malloc
+    ").unwrap()).unwrap();
+    // References inside the object defining the symbol stay as they are
+    linker.load_symbols(compile(".section \"text\"
+    .global malloc
+    malloc:
+    jpr malloc
+    ").unwrap()).unwrap();
+
+    let binary = linker.generate_binary(Some(&script_file("SECTIONS { text }", "ld"))).unwrap();
+
+    assert_eq!(binary, vec![
+        0x0E, 0x06, 0x00, 0x00, 0x00, 0x01,
+        0x0C, 0x05, 0x00, 0x00, 0x00,
+        0x0C, 0x00, 0x00, 0x00, 0x00
+    ]);
+}
+
+#[test]
+fn reproducible_output() {
+    use std::io::Cursor;
+    use crate::linker::Linker;
+
+    let assemble = || compile(".section \"text\"
+    start: jpr main
+    main: callr helper
+    helper: halt
+    .section \"text.init\"
+    init: nop
+    .section \"text.fini\"
+    fini: nop
+    .section \"data\"
+    a: .dd start
+    b: .dd main
+    c: .dd helper
+    .section \"rodata\"
+    d: .db 1
+    e: .db 2
+    .comm buffer_a, 4
+    .comm buffer_b, 8
+    ").unwrap();
+    let object = || {
+        let mut bytes = Cursor::new(Vec::<u8>::new());
+        assemble().write_object(&mut bytes).unwrap();
+        bytes.into_inner()
+    };
+    let link = || {
+        let mut linker = Linker::new();
+        linker.load_symbols(assemble()).unwrap();
+        linker.generate_binary(None).unwrap()
+    };
+
+    // Each map gets a new random order, so a few runs catch output depending on it
+    let (first_object, first_binary) = (object(), link());
+    for _ in 0..8 {
+        assert_eq!(object(), first_object);
+        assert_eq!(link(), first_binary);
+    }
+}
+
+#[test]
+fn section_layout() {
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"first\"
+    .dd second
+    .dd third
+    .db 1
+    .section \"second\"
+    second:
+    .db 2, 2, 2, 2, 2, 2, 2, 2, 2
+    .section \"third\"
+    third:
+    .dd third
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj.clone()).unwrap();
+    let binary = linker.generate_binary(Some(&script_file("SECTIONS {
+        first ALIGN(4)
+        second ALIGN(4)
+        third ALIGN(8)
+    }", "ld"))).unwrap();
+
+    // Every section starts after the padding of the ones before it
+    assert_eq!(linker.section_layout().unwrap(), vec![
+        ("first".to_string(), 0x00, 9),
+        ("second".to_string(), 0x0C, 9),
+        ("third".to_string(), 0x18, 4)
+    ]);
+    assert_eq!(&binary[0..8], &[0x0C, 0, 0, 0, 0x18, 0, 0, 0]);
+    assert_eq!(&binary[0x18..0x1C], &[0x18, 0, 0, 0]);
+    assert_eq!(binary.len(), 0x20);
+
+    // A fixed section can't go into the padding of the one before it
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let err = linker.generate_binary(Some(&script_file("SECTIONS {
+        first ALIGN(16)
+        second at 0x0C
+        third
+    }", "ld"))).err().unwrap();
+    assert!(err.contains("Section 'second' is placed at 0x000c, but the image already extends to 0x0010"));
+}
+
+#[test]
+fn operand_patching() {
+    use crate::objgen::{ObjectFormat, ConstantSize};
+    use crate::linker::Linker;
+
+    let link = |edit: &dyn Fn(&mut ObjectFormat)| -> Result<Vec<u8>, String> {
+        let mut obj = compile(".section \"text\"
+        loadid value r2
+        halt
+        value:
+        ")?;
+        edit(&mut obj);
+
+        let mut linker = Linker::new();
+        linker.load_symbols(obj)?;
+        linker.generate_binary(Some(&script_file("SECTIONS { text }", "ld")))
+    };
+
+    // Operands are written in argument order, whatever order they are stored in
+    let binary = link(&|obj| {
+        let instruction = &mut obj.sections.get_mut("text").unwrap().instructions[0];
+        instruction.references.reverse();
+        instruction.constants.reverse();
+    }).unwrap();
+    assert_eq!(&binary[..], &[0x05, 0x07, 0, 0, 0, 0x02, 0x01]);
+
+    let err = link(&|obj| {
+        obj.sections.get_mut("text").unwrap().instructions[0].constants.clear();
+    }).err().unwrap();
+    assert_eq!(err, "Argument 1 of 'loadi dw' at 0x0000 is missing");
+
+    let err = link(&|obj| {
+        obj.sections.get_mut("text").unwrap().instructions[0].constants[0].size = ConstantSize::Word;
+    }).err().unwrap();
+    assert_eq!(err, "Argument 1 of 'loadi dw' at 0x0000 has size 2, 1 expected");
+
+    let err = link(&|obj| {
+        obj.sections.get_mut("text").unwrap().instructions[0].references[0].argument_pos = 2;
+    }).err().unwrap();
+    assert_eq!(err, "Reference to 'value' is argument 2, but 'loadi dw' takes 2");
+}
+
+#[test]
+fn load_addresses() {
+    use crate::linker::Linker;
+
+    let link = |script: &str| -> Result<(Vec<u8>, Linker), String> {
+        let obj = compile(".section \"text\"
+        loadid counter r0
+        .section \"rodata\"
+        .dd __load_start_data
+        .dd __start_data
+        .dd __end_data
+        .section \"data\"
+        counter:
+        .dd 0x11223344
+        .section \"bss\" \"rwn\"
+        .resb 4
+        ")?;
+
+        let mut linker = Linker::new();
+        linker.load_symbols(obj)?;
+        let binary = linker.generate_binary(Some(&script_file(script, "ld")))?;
+        Ok((binary, linker))
+    };
+
+    let (binary, linker) = link("MEMORY {
+        ROM : ORIGIN = 0x1000, LENGTH = 0x100
+        RAM : ORIGIN = 0x8000, LENGTH = 0x100
+    }
+    SECTIONS {
+        text > ROM
+        rodata > ROM
+        data ALIGN(4) > RAM AT> ROM
+        bss > RAM
+    }").unwrap();
+
+    // Code sees 'counter' in RAM, its initial value is stored in ROM after the code
+    assert_eq!(&binary[0..6], &[0x05, 0x00, 0x80, 0, 0, 0]);
+    assert_eq!(&binary[6..18], &[0x14, 0x10, 0, 0, 0x00, 0x80, 0, 0, 0x04, 0x80, 0, 0]);
+    assert_eq!(binary.len(), 0x18);
+    assert_eq!(&binary[0x14..], &[0x44, 0x33, 0x22, 0x11]);
+    assert_eq!(linker.image_base().unwrap(), 0x1000);
+
+    let map = linker.link_map().unwrap();
+    assert!(map.contains("data             0x00008000        4 bytes > RAM AT> ROM (0x00001014)"));
+    let usage = linker.memory_usage().unwrap();
+    assert_eq!(usage.regions[0].used, 0x18);
+    assert_eq!(usage.regions[1].used, 8);
+
+    let err = link("MEMORY {
+        ROM : ORIGIN = 0x1000, LENGTH = 0x14
+        RAM : ORIGIN = 0x8000, LENGTH = 0x100
+    }
+    SECTIONS {
+        text > ROM
+        rodata > ROM
+        data > RAM AT> ROM
+        bss > RAM
+    }").err().unwrap();
+    assert_eq!(err, "Section 'data' (0x1012..0x1016) overflows memory region 'ROM' (0x1000..0x1014) by 2 bytes");
+
+    let err = link("MEMORY { RAM : ORIGIN = 0x8000, LENGTH = 0x100 }
+    SECTIONS {
+        text
+        rodata
+        data > RAM AT> FLASH
+        bss > RAM
+    }").err().unwrap();
+    assert!(err.contains("Section 'data' is loaded into undefined memory region 'FLASH'"));
+}
+
+#[test]
+fn banks() {
+    use crate::linker::Linker;
+
+    let link = |script: &str, output: &str| -> Result<Linker, String> {
+        let obj = compile(".section \"text\"
+        callr level1
+        callr level2
+        .section \"level1\"
+        level1:
+        ret
+        .section \"level2\"
+        nop
+        level2:
+        ret
+        ")?;
+
+        let mut linker = Linker::new();
+        linker.load_symbols(obj)?;
+        linker.save_binary(output, Some(&script_file(script, "ld")))?;
+        Ok(linker)
+    };
+
+    let output = std::env::temp_dir().join("sarch_asm_banks.bin");
+    let output = output.to_str().unwrap();
+    let linker = link("MEMORY {
+        ROM : ORIGIN = 0, LENGTH = 0x100
+        BANKED : ORIGIN = 0x4000, LENGTH = 0x100
+    }
+    SECTIONS {
+        text > ROM
+        level1 BANK(1) > BANKED
+        level2 BANK(2) > BANKED
+    }", output).unwrap();
+
+    // Both banks start at the window, calls into them are relative to the fixed code
+    assert_eq!(std::fs::read(output).unwrap(), vec![0x0E, 0x00, 0x40, 0, 0, 0x0E, 0xFC, 0x3F, 0, 0]);
+    assert_eq!(linker.bank_images().keys().copied().collect::<Vec<u64>>(), vec![1, 2]);
+
+    let bank_path = |name: &str| std::env::temp_dir().join(name);
+    assert_eq!(std::fs::read(bank_path("sarch_asm_banks.bank1.bin")).unwrap(), linker.bank_images()[&1]);
+    assert_eq!(linker.bank_images()[&2], vec![0x00, 0x11]);
+    assert_eq!(std::fs::read_to_string(bank_path("sarch_asm_banks.bank2.sym")).unwrap(),
+        "0x00004001 level2\n");
+
+    let map = linker.link_map().unwrap();
+    assert!(map.contains("level2           0x00004000        2 bytes > BANKED BANK(2)"));
+
+    // Only sections of the same bank can't share addresses
+    let err = link("SECTIONS {
+        text
+        level1 at 0 BANK(1)
+        level2 BANK(2)
+    }", output).err().unwrap();
+    assert_eq!(err, "Sections 'level1' (0x0000..0x0001) and 'text' (0x0000..0x000a) overlap by 1 bytes\n\
+    Sections 'level2' (0x0000..0x0002) and 'text' (0x0000..0x000a) overlap by 2 bytes");
+}
+
+#[test]
+fn split_rom() {
+    use crate::hexfile::split_lanes;
+    use crate::linker::Linker;
+
+    let image: Vec<u8> = (0..10).collect();
+
+    let (lanes, base) = split_lanes(&image, 0x100, 2, 1, 0xFF).unwrap();
+    assert_eq!(lanes, vec![vec![0, 2, 4, 6, 8], vec![1, 3, 5, 7, 9]]);
+    assert_eq!(base, 0x80);
+
+    // Two 16-bit memories on a 32-bit bus, the last word is filled
+    let (lanes, base) = split_lanes(&image, 0, 2, 2, 0xFF).unwrap();
+    assert_eq!(lanes, vec![vec![0, 1, 4, 5, 8, 9], vec![2, 3, 6, 7, 0xFF, 0xFF]]);
+    assert_eq!(base, 0);
+
+    let err = split_lanes(&image, 2, 4, 1, 0).err().unwrap();
+    assert_eq!(err, "Image starts at 0x2, which isn't a multiple of the 4 byte bus");
+
+    let obj = compile(".section \"text\"
+    .db 1, 2, 3, 4, 5
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    linker.split_lanes = Some(2);
+    let output = std::env::temp_dir().join("sarch_asm_split_rom.bin");
+    linker.save_binary(output.to_str().unwrap(), Some(&script_file("SECTIONS { text }", "ld"))).unwrap();
+
+    let lane = |n: usize| std::fs::read(std::env::temp_dir().join(format!("sarch_asm_split_rom.lane{}.bin", n))).unwrap();
+    assert_eq!(lane(0), vec![1, 3, 5]);
+    assert_eq!(lane(1), vec![2, 4, 0]);
+}
+
+#[test]
+fn script_checksums() {
+    let link = |script: &str| -> Result<Vec<u8>, String> {
+        link_with_script(".section \"text\"
+        .db 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39
+        .section \"rodata\"
+        crc:
+        .dd 0
+        sum:
+        .dw 0
+        ", script)
+    };
+
+    // The sum covers the CRC written before it
+    let binary = link("SECTIONS { text rodata }
+    CHECKSUM(CRC32, __start_text, __end_text, crc)
+    CHECKSUM(SUM16, 0, sum, sum);").unwrap();
+    assert_eq!(&binary[9..13], &0xCBF43926u32.to_le_bytes());
+    let sum = binary[..13].iter().map(|b| *b as u16).sum::<u16>();
+    assert_eq!(&binary[13..15], &sum.to_le_bytes());
+
+    let err = link("SECTIONS { text rodata }
+    CHECKSUM(CRC32, 0, __end_rodata, crc)").err().unwrap();
+    assert_eq!(err, "Checksum location 0x0009 is inside the range 0x0000..0x000f it covers");
+    let err = link("SECTIONS { text rodata }
+    CHECKSUM(SUM8, 0, 0x100, crc)").err().unwrap();
+    assert_eq!(err, "Checksum range 0x0000..0x0100 is not inside the image (0x0000..0x000f)");
+    let err = link("CHECKSUM(MD5, 0, 1, 2)").err().unwrap();
+    assert!(err.contains("Unknown checksum 'MD5'"));
+}
+
+#[test]
+fn strip_local_symbols() {
+    use std::collections::HashSet;
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+
+    let object = || compile(".global start
+    .section \"text\"
+    start:
+    loadid message r0
+    again:
+    jpr again
+    .section \"data\"
+    .db 1, 2
+    message:
+    .db 3
+    table:
+    .dd (table - start)
+    .dd (again + 1)
+    .dd table_size
+    ").unwrap();
+    let link = |obj: ObjectFormat| -> Vec<u8> {
+        let mut linker = Linker::new();
+        linker.load_symbols(obj).unwrap();
+        linker.generate_binary(Some(&script_file("SECTIONS { text data }", "ld"))).unwrap()
+    };
+
+    let mut stripped = object();
+    stripped.strip_local_symbols(&HashSet::new());
+    let path = std::env::temp_dir().join("sarch_asm_strip_local_symbols.sao");
+    stripped.save_object(path.to_str().unwrap()).unwrap();
+    let stripped = ObjectFormat::from_file(path.to_str().unwrap()).unwrap();
+
+    // Only the global, the label whose size is used and the section starts are left
+    let names: Vec<String> = stripped.symbol_table().into_iter().map(|s| s.name).collect();
+    assert_eq!(names, vec!["#data", "table", "#text", "start"]);
+    assert!(stripped.debug_lines.is_empty());
+
+    assert_eq!(link(stripped), link(object()));
+
+    // Retained labels survive, also after a link gave them a suffix
+    let retain: HashSet<String> = ["again".to_string()].into_iter().collect();
+    let mut linker = Linker::new();
+    linker.load_symbols(object()).unwrap();
+    linker.strip_local = true;
+    linker.retain_symbols = retain;
+    linker.save_object(path.to_str().unwrap()).unwrap();
+    let names: Vec<String> = ObjectFormat::from_file(path.to_str().unwrap()).unwrap()
+        .symbol_table().into_iter().map(|s| s.name).collect();
+    assert_eq!(names, vec!["#data", "table#1", "start", "again#1"]);
+}
+
+#[test]
+fn archive_member_names() {
+    use crate::objgen::ObjectFormat;
+    use crate::archive::Archive;
+
+    let mut archive = Archive::new();
+    let object = ObjectFormat::new();
+    for name in ["", "..", "../evil.sao", "/tmp/evil.sao", "dir\\evil.sao"] {
+        let err = archive.add_object(name, &object).err().unwrap();
+        assert!(err.contains("members must be plain file names"));
+    }
+
+    // Archives written by something else are checked when read
+    let mut bytes = Vec::<u8>::new();
+    bytes.extend_from_slice(&0x3A6863FC616C6173u64.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.extend_from_slice(b"../../evil.sao\0");
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+
+    let path = std::env::temp_dir().join("sarch_asm_archive_member_names.sal");
+    std::fs::write(&path, &bytes).unwrap();
+    let err = Archive::from_file(path.to_str().unwrap()).err().unwrap();
+    assert!(err.contains("Invalid member name '../../evil.sao'"));
+}
+
+#[test]
+fn align_code() {
+    use crate::objgen::InstructionData;
+
+    let unit = InstructionData::alignment(4, 0);
+    let sizes: Vec<usize> = [0, 1, 2, 3, 4, 5, 8].iter().map(|p| unit.get_binary_size(*p)).collect();
+    assert_eq!(sizes, vec![0, 3, 2, 1, 0, 3, 0]);
+    assert_eq!(InstructionData::alignment(1, 0).get_binary_size(7), 0);
+
+    let binary = link_with_script(".section \"text\"
+    halt
+    .align_code 4
+    ret
+    .align_code 8 halt
+    ret
+    .align_code 2
+    ret
+    ", "SECTIONS { text ALIGN(8) }").unwrap();
+
+    // nop (0x00) pads to 4, halt (0x01) to 8 and nop again from 9 to 10
+    assert_eq!(&binary[..11], &[0x01, 0x00, 0x00, 0x00, 0x11, 0x01, 0x01, 0x01, 0x11, 0x00, 0x11]);
+
+    for (alignment, error) in [
+        ("3", "Code alignment must be a power of two, 3 provided"),
+        ("0", "Code alignment must be a power of two, 0 provided"),
+        ("4 loadid", "cannot be used as padding")
+    ] {
+        let code = format!(".section \"text\"
+        .align_code {}
+        ", alignment);
+        let err = compile(&code).unwrap_err();
+        assert!(err.contains(error), "{}", err);
+    }
+}
+
+#[test]
+fn entry_keeps_section_live() {
+    use crate::linker::Linker;
+
+    let obj = compile(".section \"vectors\"
+    halt
+    .section \"text\"
+    start:
+    callr helper
+    halt
+    .section \"lib\"
+    helper:
+    ret
+    .section \"unused\"
+    nop
+    ").unwrap();
+
+    let mut linker = Linker::new();
+    linker.entry = Some("start".to_string());
+    // No boot stub, so nothing in 'vectors' references the entry point
+    linker.image_header = true;
+    linker.load_symbols(obj).unwrap();
+    linker.generate_binary(Some(&script_file("SECTIONS { vectors text lib unused }", "ld"))).unwrap();
+
+    assert_eq!(linker.garbage_sections(), vec!["unused".to_string()]);
+    assert_eq!(linker.why_live("helper").unwrap(), "'helper' is live:
+  'text' holds the entry point 'start'
+  'text' references 'helper' defined in 'lib'
+  'helper' is defined in 'lib'
+");
+    assert!(linker.why_live("vectors").unwrap().contains("'vectors' is the start of the image"));
+}
+
+#[test]
+fn macro_local_labels() {
+    use crate::objgen::ObjectFormat;
+    use crate::linker::Linker;
+    use crate::preprocessor::Preprocessor;
+
+    let code = "%macro wait()
+%%loop:
+    nop
+    loadid %%loop r0
+%endmacro
+.section \"text\"
+start:
+    wait()
+    wait()
+    halt
+";
+    let tokens = super::lex(code, false);
+    let tokens = super::preprocess(tokens, &mut Preprocessor::new()).unwrap();
+    let node = super::parse(tokens.iter().map(|t| t.as_token()).collect(), false).unwrap();
+    let mut obj = ObjectFormat::new();
+    obj.load_parser_node(&node).unwrap();
+
+    // Each expansion gets its own label
+    let labels: Vec<&String> = obj.sections["text"].labels.keys()
+        .filter(|name| name.contains("loop"))
+        .collect();
+    assert_eq!(labels, vec!["start@__wait_1_loop", "start@__wait_2_loop"]);
+
+    let mut linker = Linker::new();
+    linker.load_symbols(obj).unwrap();
+    let binary = linker.generate_binary(Some(&script_file("SECTIONS { text }", "ld"))).unwrap();
+
+    // nop, then loadid with the address of its own expansion's label
+    assert_eq!(binary, vec![
+        0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x05, 0x07, 0x00, 0x00, 0x00, 0x00,
+        0x01
+    ]);
+}
+
+#[test]
+fn dry_run() {
+    use std::process::ExitCode;
+
+    let dir = std::env::temp_dir().join("sarch_asm_dry_run");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    std::fs::write(path("good.s"), ".section \"text\"
+    start:
+    jpr start
+    ").unwrap();
+    std::fs::write(path("bad.s"), ".section \"text\"
+    jpr missing
+    ").unwrap();
+    std::fs::write(path("link.ld"), "SECTIONS { text }").unwrap();
+
+    let run = |flags: &[&str], input: &str| -> ExitCode {
+        let mut args = vec!["sarch_asm".to_string(), path(input)];
+        args.extend(flags.iter().map(|f| f.to_string()));
+        super::run(args)
+    };
+    let link = ["--dry-run", "-k", "-o", &path("out.bin"), "--map", &path("out.map"), "-c", &path("link.ld")];
+
+    // Links, so errors still show up and the map is written, but no image or object
+    assert_eq!(run(&link, "good.s"), ExitCode::SUCCESS);
+    assert_eq!(run(&link, "bad.s"), ExitCode::FAILURE);
+    // The undefined symbol is a link error, assembling alone is fine
+    assert_eq!(run(&["--dry-run", "-b", "-o", &path("bad.sao")], "bad.s"), ExitCode::SUCCESS);
+    assert_eq!(run(&["--dry-run", "-b", "-o", &path("out.sao")], "good.s"), ExitCode::SUCCESS);
+    assert_eq!(run(&["--dry-run", "-r", "-o", &path("out.sao")], "good.s"), ExitCode::SUCCESS);
+
+    let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["bad.s", "good.s", "link.ld", "out.map"]);
+    assert!(std::fs::read_to_string(dir.join("out.map")).unwrap().contains("text"));
+
+    // Without it the same flags write the image and the object too
+    assert_eq!(run(&link[1..], "good.s"), ExitCode::SUCCESS);
+    for file in ["out.bin", "out.map", "out.bin.sao"] {
+        assert!(dir.join(file).exists(), "{} not written", file);
+    }
+}
+
+#[test]
+fn warn_misaligned() {
+    use crate::linker::Linker;
+
+    let link = |warn_misaligned: bool| -> Linker {
+        let obj = compile(".section \"text\"
+        loadmd value r0
+        loadmb value r00l
+        loadmd aligned r2
+        halt
+        .section \"data\"
+        .db 1, 2, 3
+        value:
+        .db 4
+        aligned:
+        .dd 5
+        ").unwrap();
+
+        let mut linker = Linker::new();
+        linker.warn_misaligned = warn_misaligned;
+        linker.load_symbols(obj).unwrap();
+        linker.generate_binary(Some(&script_file("SECTIONS { text data ALIGN(4) }", "ld"))).unwrap();
+        linker
+    };
+
+    // Data starts at 0x14, so only the 4-byte load of 'value' is misaligned
+    assert_eq!(link(true).warnings(), vec![
+        "misaligned 4-byte access to 'value' (0x0017) by 'loadm dw' at offset 0x0000".to_string()
+    ]);
+    assert_eq!(link(false).warnings(), Vec::<String>::new());
+}
+
+#[test]
+fn overlapping_sections() {
+    let link = |script: &str| -> Result<Vec<u8>, String> {
+        link_with_script(".section \"vectors\"
+        .dd start
+        .dd start
+        .section \"text\"
+        start:
+        jpr start
+        .section \"bss\" \"rwn\"
+        .resb 4
+        ", script)
+    };
+
+    // Two sections pinned inside the same region, and a noload one over both
+    let err = link("MEMORY { ROM : ORIGIN = 0x100, LENGTH = 0x100 }
+    SECTIONS {
+        vectors at 0x100 > ROM
+        text at 0x104 > ROM
+        bss at 0x106 > ROM
+    }").err().unwrap();
+    assert_eq!(err, "Sections 'vectors' (0x0100..0x0108) and 'text' (0x0104..0x0109) overlap by 4 bytes
+Sections 'vectors' (0x0100..0x0108) and 'bss' (0x0106..0x010a) overlap by 2 bytes
+Sections 'text' (0x0104..0x0109) and 'bss' (0x0106..0x010a) overlap by 3 bytes");
+
+    // Sections that only touch don't overlap
+    let binary = link("MEMORY { ROM : ORIGIN = 0x100, LENGTH = 0x100 }
+    SECTIONS {
+        vectors at 0x100 > ROM
+        text at 0x108 > ROM
+        bss at 0x10D > ROM
+    }").unwrap();
+    assert_eq!(binary.len(), 0x0D);
+}